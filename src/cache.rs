@@ -0,0 +1,164 @@
+//! Content-addressed cache of distributables, keyed by their SHA256.
+//!
+//! Storing the same distributable twice, for example when it is downloaded again or depended on by multiple
+//! packages, only keeps one copy on disk.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::{Result, Context, anyhow};
+use sha2::{Sha256 as Sha256Hasher, Digest};
+
+use crate::package::distribute::EXTENSION;
+use crate::rom::{to_hex, from_hex};
+
+/// Environment variable that overrides where the cache is stored.
+pub const CACHE_DIR_ENV_VAR: &str = "MERLON_CACHE_DIR";
+
+/// A SHA256 hash, displayed as lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Sha256([u8; 32]);
+
+impl Sha256 {
+    /// Hashes the given bytes.
+    pub fn of(bytes: &[u8]) -> Self {
+        Self(Sha256Hasher::digest(bytes).into())
+    }
+
+    /// Returns the raw bytes of the hash.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", to_hex(&self.0))
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        from_hex(s).map(Self).context("invalid SHA256")
+    }
+}
+
+/// A directory of distributables named by their SHA256.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    root: PathBuf,
+}
+
+impl Cache {
+    /// Uses the given directory as the cache. It is created when something is first stored.
+    pub fn at(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Uses the user's cache directory, or `MERLON_CACHE_DIR` if it is set.
+    pub fn user() -> Result<Self> {
+        user_cache_dir().map(Self::at)
+    }
+
+    /// Returns the directory the cache is stored in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path_of(&self, hash: &Sha256) -> PathBuf {
+        self.root.join(format!("{}.{}", hash, EXTENSION))
+    }
+
+    /// Stores the bytes in the cache, unless they are already there, and returns their hash.
+    pub fn store(&self, bytes: &[u8]) -> Result<Sha256> {
+        let hash = Sha256::of(bytes);
+        let path = self.path_of(&hash);
+        if path.is_file() {
+            return Ok(hash);
+        }
+
+        fs::create_dir_all(&self.root)
+            .with_context(|| format!("failed to create cache directory {}", self.root.display()))?;
+        // Write to a temporary name first so a partially written file is never found by `get`
+        let partial = self.root.join(format!("{}.partial-{}", hash, uuid::Uuid::new_v4()));
+        fs::write(&partial, bytes)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        fs::rename(&partial, &path)
+            .with_context(|| format!("failed to move {} to {}", partial.display(), path.display()))?;
+        Ok(hash)
+    }
+
+    /// Returns the path of the cached file with the given hash, if it has been stored.
+    pub fn get(&self, hash: &Sha256) -> Option<PathBuf> {
+        let path = self.path_of(hash);
+        path.is_file().then_some(path)
+    }
+}
+
+/// Stores the bytes in the user's cache and returns their hash. See [`Cache::store`].
+pub fn store(bytes: &[u8]) -> Result<Sha256> {
+    Cache::user()?.store(bytes)
+}
+
+/// Returns the path of the file with the given hash in the user's cache. See [`Cache::get`].
+pub fn get(hash: &Sha256) -> Option<PathBuf> {
+    Cache::user().ok()?.get(hash)
+}
+
+fn user_cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(CACHE_DIR_ENV_VAR).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Caches"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+    base.map(|base| base.join("merlon").join("distributables"))
+        .ok_or_else(|| anyhow!("could not find a cache directory; set {}", CACHE_DIR_ENV_VAR))
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn storing_identical_bytes_twice_keeps_one_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let cache = Cache::at(dir.path().to_owned());
+
+        let first = cache.store(b"distributable")?;
+        let second = cache.store(b"distributable")?;
+        assert_eq!(first, second);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        let path = cache.get(&first).expect("stored bytes should be cached");
+        assert_eq!(fs::read(path)?, b"distributable");
+        assert!(cache.get(&Sha256::of(b"something else")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn sha256_round_trips_through_hex() -> Result<()> {
+        let hash = Sha256::of(b"");
+        assert_eq!(hash.to_string(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hash.to_string().parse::<Sha256>()?, hash);
+        assert_eq!(hash.to_string().to_uppercase().parse::<Sha256>()?, hash);
+        assert!("not a hash".parse::<Sha256>().is_err());
+        // u8::from_str_radix alone would accept a sign
+        assert!(format!("+{}", &hash.to_string()[1..]).parse::<Sha256>().is_err());
+        Ok(())
+    }
+}
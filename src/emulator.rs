@@ -1,33 +1,99 @@
 //! Utilities for interfacing with N64 emulators.
 
-use std::path::PathBuf;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use anyhow::{Result, bail};
 use pyo3::prelude::*;
 
+use crate::package::Package;
 use crate::rom::Rom;
 
+/// Environment variable that overrides which emulator is used.
+pub const EMULATOR_ENV_VAR: &str = "MERLON_EMULATOR";
+
+/// Emulator executables searched for on `PATH`, in order of preference.
+const EMULATOR_NAMES: &[&str] = &[
+    "ares",
+    "mupen64plus",
+    "project64",
+    "Project64.exe",
+    "cen64",
+    "retroarch",
+];
+
+/// Well-known install locations that are not usually on `PATH`, checked after `PATH`.
+const EMULATOR_PATHS: &[&str] = &[
+    "/usr/bin/cen64",
+    "/usr/bin/ares",
+    "/Applications/ares.app/Contents/MacOS/ares",
+    "/usr/bin/mupen64plus",
+    "/usr/bin/retroarch",
+    "C:\\Program Files (x86)\\Project64 2.3\\Project64.exe",
+];
+
 /// Runs the given ROM in an emulator.
 #[pyfunction]
 pub fn run_rom(rom: &Rom) -> Result<()> {
-    let emulator = find_emulator()?;
-    Command::new(emulator)
+    emulator_command(None, None)?
         .arg(rom.path())
         .status()
         .map(|_| ())
         .map_err(Into::into)
 }
 
-fn find_emulator() -> Result<PathBuf> {
-    const EMULATOR_PATHS: &[&str] = &[
-        "/usr/bin/cen64",
-        "/usr/bin/ares",
-        "/Applications/ares.app/Contents/MacOS/ares",
-        "/usr/bin/mupen64plus",
-        "/usr/bin/retroarch",
-        "C:\\Program Files (x86)\\Project64 2.3\\Project64.exe",
-    ];
+/// Returns a [`Command`] that runs an emulator, without any arguments. The first of these is used:
+///
+/// 1. `explicit`, e.g. from `--emulator`
+/// 2. The `MERLON_EMULATOR` environment variable
+/// 3. `configured`, e.g. from `[tools] emulator` in `merlon.toml`
+/// 4. The first known emulator found on `PATH` or in a well-known install location
+pub fn emulator_command(explicit: Option<&Path>, configured: Option<&str>) -> Result<Command> {
+    let emulator = resolve_emulator(
+        explicit,
+        env::var_os(EMULATOR_ENV_VAR),
+        configured,
+        env::var_os("PATH"),
+    )?;
+    Ok(Command::new(emulator))
+}
+
+impl Package {
+    /// Returns a [`Command`] that runs an emulator, taking the package's `[tools] emulator` setting into account.
+    /// See [`emulator_command`] for the order in which emulators are looked for.
+    pub fn emulator_command(&self, explicit: Option<&Path>) -> Result<Command> {
+        let manifest = self.manifest()?;
+        emulator_command(explicit, manifest.tools().emulator.as_deref())
+    }
+}
+
+fn resolve_emulator(
+    explicit: Option<&Path>,
+    env_override: Option<OsString>,
+    configured: Option<&str>,
+    path_var: Option<OsString>,
+) -> Result<PathBuf> {
+    if let Some(explicit) = explicit {
+        return Ok(explicit.to_owned());
+    }
+    if let Some(env_override) = env_override.filter(|value| !value.is_empty()) {
+        return Ok(PathBuf::from(env_override));
+    }
+    if let Some(configured) = configured {
+        return Ok(PathBuf::from(configured));
+    }
 
+    if let Some(path_var) = &path_var {
+        for name in EMULATOR_NAMES {
+            for dir in env::split_paths(path_var) {
+                let candidate = dir.join(name);
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+            }
+        }
+    }
     for path in EMULATOR_PATHS {
         let path = PathBuf::from(path);
         if path.exists() {
@@ -35,5 +101,36 @@ fn find_emulator() -> Result<PathBuf> {
         }
     }
 
-    bail!("no known emulator installed");
+    bail!(
+        "no emulator found. Looked for {} on PATH and in {}. \
+        Pass --emulator, set {}, or set `emulator` in the [tools] section of merlon.toml",
+        EMULATOR_NAMES.join(", "),
+        EMULATOR_PATHS.join(", "),
+        EMULATOR_ENV_VAR,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn env_override_wins_over_path() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("ares"), "")?;
+        let path_var = Some(dir.path().as_os_str().to_owned());
+
+        let found = resolve_emulator(None, None, None, path_var.clone())?;
+        assert_eq!(found, dir.path().join("ares"));
+
+        let found = resolve_emulator(None, Some("my-emulator".into()), Some("configured"), path_var.clone())?;
+        assert_eq!(found, PathBuf::from("my-emulator"));
+
+        let found = resolve_emulator(Some(Path::new("explicit")), Some("my-emulator".into()), None, path_var)?;
+        assert_eq!(found, PathBuf::from("explicit"));
+        Ok(())
+    }
 }
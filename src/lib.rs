@@ -4,8 +4,15 @@
 //!
 //! Merlon is also available as a Python library: https://pypi.org/project/merlon/
 
+pub mod cache;
 pub mod package;
 pub mod emulator;
 pub mod rom;
 
 mod python;
+
+/// Returns the current version of Merlon as a string.
+#[pyo3::pyfunction]
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
@@ -1,9 +1,17 @@
 #![cfg_attr(feature = "gui", windows_subsystem = "windows")]
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use anyhow::{Result, Context, bail};
 use merlon::package::{Package, InitialisedPackage, Distributable};
-use std::path::PathBuf;
+use merlon::package::distribute::{format_bytes, ExportError, BASEROM_ENV_VAR};
+use merlon::package::progress::{Phase, Progress, Update};
+use merlon::package::manifest::Dependency;
+use merlon::package::resolve::LocalIndex;
+use owo_colors::OwoColorize;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 mod new;
 
@@ -33,6 +41,47 @@ struct Args {
     /// If not set, a `merlon.toml` file will be searched for in the current directory and its parents.
     #[arg(short, long)]
     directory: Option<PathBuf>,
+
+    /// When to color output. `auto` colors output when writing to a terminal and `NO_COLOR` is not set.
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// Never color output. Shorthand for `--color never`.
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Error instead of creating or changing `merlon.lock`.
+    #[arg(long, global = true)]
+    locked: bool,
+
+    /// Error instead of accessing the network, for example to clone or update the decomp.
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Equivalent to `--locked --offline`.
+    #[arg(long, global = true)]
+    frozen: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether output written to stderr should be colored.
+    fn use_color(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                !no_color && std::io::stderr().is_terminal()
+            },
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -44,36 +93,124 @@ enum SubCommand {
     Init(merlon::package::init::InitialiseOptions),
 
     /// Export this package as a `.merlon` file for distribution.
-    Export(merlon::package::distribute::ExportOptions),
+    Export(ExportArgs),
 
     /// Apply a distributable to a base ROM.
     Apply(ApplyArgs),
 
+    /// Build a playable ROM from a distributable and a base ROM, keeping the build directory if it fails.
+    Install(InstallArgs),
+
     /// Open a distributable's source code.
     Open(OpenArgs),
 
+    /// Show information about a distributable, such as the Merlon version that created it.
+    Info(InfoArgs),
+
+    /// Check that a distributable is well-formed and opens with a base ROM, without applying it.
+    Verify(VerifyArgs),
+
+    /// List the patches and files in a distributable, to inspect it before applying it.
+    List(ListArgs),
+
+    /// Show how the patches of two distributables differ, such as two releases of a package.
+    Diff(DiffArgs),
+
     /// Run the current package in an emulator.
-    Run(merlon::package::init::BuildRomOptions),
+    Run(RunArgs),
 
     /// Build the current package into a ROM.
-    Build(merlon::package::init::BuildRomOptions),
+    Build(BuildArgs),
 
     /// Update all dependencies, including packages and the decomp.
-    Update,
+    Update(UpdateArgs),
 
     /// Add a dependency to the current package.
-    Add(merlon::package::init::AddDependencyOptions),
+    Add(AddArgs),
+
+    /// Find distributables in a local directory that satisfy this package's dependencies.
+    Resolve(ResolveArgs),
+
+    /// Check the current package's `merlon.toml` for errors, exiting with a non-zero status if there are any.
+    Validate(ValidateArgs),
+
+    /// Summarise the current package's state: its decomp commit, commits on top of it, uncommitted changes,
+    /// problems with `merlon.toml`, and the base ROM.
+    Status(StatusArgs),
+
+    /// Bump the current package's version in `merlon.toml`.
+    Bump(BumpArgs),
+
+    /// Remove files that Merlon generated in the current package's `.merlon` directory, such as export intermediates.
+    ///
+    /// Installed dependencies, `papermario/`, and the package's own files are kept.
+    Clean(CleanArgs),
+
+    /// Upgrade the current package's `merlon.toml` to the format used by this version of Merlon.
+    ///
+    /// Older formats are read without this, but are only written back when asked.
+    Migrate,
+
+    /// Print a shell completion script for Merlon to stdout.
+    ///
+    /// For example, for bash: `merlon completions bash > ~/.local/share/bash-completion/completions/merlon`
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
 
     /// Launch the GUI.
     #[cfg(feature = "gui")]
     Gui,
 }
 
+#[derive(Parser, Debug)]
+struct ExportArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::distribute::ExportOptions,
+
+    /// How to report the exported distributable.
+    ///
+    /// `json` prints a single JSON object to stdout for CI to record, and sends all other output to stderr. If the
+    /// export fails, the object has an `error` message and, for failures like a missing base ROM, an error `kind`.
+    #[arg(long, visible_alias = "format", value_enum, default_value_t = SummaryFormat::Human)]
+    pub summary: SummaryFormat,
+
+    /// List the patches and files that would be exported, and their total size, without writing a distributable.
+    ///
+    /// Patches are still regenerated from the decomp repository's commits, but untracked files are not committed.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SummaryFormat {
+    Human,
+    Json,
+}
+
 #[derive(Parser, Debug)]
 struct ApplyArgs {
     #[clap(flatten)]
     pub options: merlon::package::distribute::ApplyOptions,
 
+    /// Apply the patches to an existing decomp checkout instead of building a ROM.
+    ///
+    /// The checkout must have no uncommitted changes. If the patches conflict, it is left unchanged.
+    #[arg(long)]
+    pub into: Option<PathBuf>,
+
+    /// With `--into`, leave the `git am` session in progress if the patches conflict, so the conflicts can be
+    /// resolved by hand and applying continued with `git am --continue`.
+    #[arg(long, requires = "into")]
+    pub keep_conflicts: bool,
+
+    pub distributable: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct InstallArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::distribute::InstallOptions,
+
     pub distributable: PathBuf,
 }
 
@@ -82,12 +219,184 @@ struct OpenArgs {
     #[clap(flatten)]
     pub options: merlon::package::distribute::OpenOptions,
 
-    pub distributable: PathBuf,
+    #[clap(flatten)]
+    pub input: DistributableInput,
+}
+
+#[derive(Parser, Debug)]
+struct InfoArgs {
+    /// The base ROM, used to decrypt the distributable and show its manifest.
+    ///
+    /// If not specified, only the unencrypted header is shown.
+    #[arg(long)]
+    pub baserom: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub input: DistributableInput,
+}
+
+#[derive(Parser, Debug)]
+struct VerifyArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::distribute::VerifyOptions,
+
+    #[clap(flatten)]
+    pub input: DistributableInput,
+}
+
+#[derive(Parser, Debug)]
+struct ListArgs {
+    /// The base ROM, used to decrypt the distributable. Defaults to the `MERLON_BASEROM` environment variable.
+    #[arg(long, env = BASEROM_ENV_VAR, required_unless_present = "no_decrypt")]
+    pub baserom: Option<PathBuf>,
+
+    /// Only show what the unencrypted header says, without decrypting the distributable.
+    #[arg(long, conflicts_with = "baserom")]
+    pub no_decrypt: bool,
+
+    #[clap(flatten)]
+    pub input: DistributableInput,
+}
+
+#[derive(Parser, Debug)]
+struct DiffArgs {
+    /// The base ROM, used to decrypt both distributables. Defaults to the `MERLON_BASEROM` environment variable.
+    #[arg(long, env = BASEROM_ENV_VAR)]
+    pub baserom: PathBuf,
+
+    /// Only list the patch files that were added, removed or changed, rather than printing a unified diff.
+    #[arg(long)]
+    pub summary: bool,
+
+    /// The older distributable.
+    pub old: PathBuf,
+
+    /// The newer distributable.
+    pub new: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct RunArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::init::BuildRomOptions,
+
+    /// The emulator to run the ROM with.
+    ///
+    /// If not specified, `MERLON_EMULATOR`, then `emulator` in the `[tools]` section of `merlon.toml`, is used.
+    /// Otherwise, known emulators are searched for on `PATH`.
+    #[arg(long)]
+    pub emulator: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct BuildArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::init::BuildRomOptions,
+
+    /// List the targets that can be built, instead of building.
+    #[arg(long)]
+    pub list_targets: bool,
+}
+
+#[derive(Parser, Debug)]
+struct UpdateArgs {
+    /// Error instead of warning if dependencies modify the same files.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Parser, Debug)]
+struct AddArgs {
+    #[clap(flatten)]
+    pub options: merlon::package::init::AddDependencyOptions,
+
+    /// Error instead of warning if dependencies modify the same files.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ResolveArgs {
+    /// Directory of `.merlon` files to resolve dependencies from.
+    #[arg(long)]
+    pub packages_dir: PathBuf,
+
+    /// Also resolve dependencies of dependencies, choosing versions that satisfy every requirement, and write the
+    /// result to `merlon.lock`.
+    ///
+    /// This opens the distributables to read their dependencies, so it needs the base ROM.
+    #[arg(long)]
+    pub lock: bool,
+
+    /// The base ROM to open distributables with when locking.
+    ///
+    /// If not specified, it is found like `merlon export --baserom` is: from the `MERLON_BASEROM` environment
+    /// variable, `baserom` in `merlon.toml`, or `papermario/ver/<region>/baserom.z64`.
+    #[arg(long, requires = "lock")]
+    pub baserom: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct StatusArgs {
+    /// Print the status as a JSON object instead.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ValidateArgs {
+    /// Print nothing, and only report whether the package is valid through the exit status.
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+#[derive(Parser, Debug)]
+struct BumpArgs {
+    /// Which part of the version to increment: `major`, `minor`, or `patch`. Or, an exact version to set.
+    #[arg(value_name = "major|minor|patch|VERSION")]
+    pub bump: merlon::package::bump::Bump,
+}
+
+#[derive(Parser, Debug)]
+struct CleanArgs {
+    /// List what would be removed, without removing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CompletionsArgs {
+    /// The shell to generate completions for.
+    #[arg(value_enum)]
+    pub shell: clap_complete::Shell,
+}
+
+/// A distributable to read, either from a file or from stdin.
+#[derive(Parser, Debug)]
+struct DistributableInput {
+    /// Path to the distributable, or `-` to read it from stdin.
+    #[arg(required_unless_present = "stdin")]
+    pub distributable: Option<PathBuf>,
+
+    /// Read the distributable from stdin.
+    #[arg(long, conflicts_with = "distributable")]
+    pub stdin: bool,
+}
+
+impl DistributableInput {
+    fn open(self) -> Result<Distributable> {
+        match self.distributable {
+            Some(path) if path.as_os_str() != "-" => Distributable::try_from(path)
+                .context("failed to open distributable file"),
+            _ => Distributable::from_reader(std::io::stdin().lock())
+                .context("failed to read distributable from stdin"),
+        }
+    }
 }
 
 #[cfg(feature = "gui")]
 fn main() -> Result<()> {
-    pretty_env_logger::init();
+    init_logger();
 
     // If TERM is not set, or MERLON_GUI=1, run the GUI.
     let is_gui = std::env::var("TERM").is_err() || matches!(std::env::var("MERLON_GUI"), Ok(v) if v == "1");
@@ -101,10 +410,20 @@ fn main() -> Result<()> {
 
 #[cfg(not(feature = "gui"))]
 fn main() -> Result<()> {
-    pretty_env_logger::init();
+    init_logger();
     main_cli()
 }
 
+/// Initialises logging. Warnings are shown by default, and `RUST_LOG` can override the filter.
+fn init_logger() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(log::LevelFilter::Warn);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
 fn main_cli() -> Result<()> {
     let args = Args::parse();
     args.run()
@@ -126,6 +445,8 @@ fn main_gui() -> Result<()> {
 
 impl Args {
     pub fn run(self) -> Result<()> {
+        log::info!("Merlon {}", merlon::version());
+
         // Get package from args, or current directory if not specified.
         let mut package = if let Some(directory) = self.directory.as_ref() {
             // If a directory is provided and its invalid, error.
@@ -135,12 +456,21 @@ impl Args {
             Package::current()?
         };
 
-        if let Some(package) = &mut package {
-            for warning in package.manifest()?.metadata().validate() {
-                eprintln!("warning: {}", warning);
-            }
+        let color = if self.no_color { ColorChoice::Never } else { self.color };
+        // `merlon validate` reports these itself, as errors
+        if let (Some(package), false) = (&mut package, matches!(self.subcmd, SubCommand::Validate(_))) {
+            print_validation_warnings(&package.manifest()?.validate(), color.use_color());
         }
 
+        let locked = self.locked || self.frozen;
+        let offline = self.offline || self.frozen;
+        let to_initialised = |package: Package| -> Result<InitialisedPackage> {
+            let mut initialised = InitialisedPackage::try_from(package)?;
+            initialised.set_locked(locked);
+            initialised.set_offline(offline);
+            Ok(initialised)
+        };
+
         // Run subcommand.
         match self.subcmd {
             SubCommand::New(new_args) => {
@@ -152,23 +482,136 @@ impl Args {
             },
             SubCommand::Init(init_args) => {
                 if let Some(package) = package {
+                    if offline {
+                        bail!("cannot initialise package in offline mode, as the decomp needs to be cloned");
+                    }
                     InitialisedPackage::initialise(package, init_args)?;
                     Ok(())
                 } else {
                     bail!("cannot initialise package: not in a package directory.");
                 }
             },
-            SubCommand::Export(export_args) => {
+            SubCommand::Export(ExportArgs { options: mut export_args, summary, dry_run }) => {
                 if let Some(package) = package {
-                    // If the package is initialised, sync it so the patches dir updates
-                    if InitialisedPackage::is_initialised(&package)? {
-                        let initialised = InitialisedPackage::try_from(package.clone())?;
-                        initialised.setup_git_branches()?;
-                    }
+                    // Checked before stdout is redirected to stderr below
+                    let progress = cli_progress(std::io::stdout().is_terminal());
+                    let to_stdout = !dry_run && export_args.output.as_deref() == Some(Path::new("-"));
 
-                    let exported = package.export_distributable(export_args)?;
-                    println!("Exported distributable: {}", exported);
-                    Ok(())
+                    // Keep stdout for the JSON summary, including by redirecting the output of git and friends
+                    let mut summary_output = match summary {
+                        SummaryFormat::Json => Some(redirect_stdout_to_stderr()?),
+                        SummaryFormat::Human => None,
+                    };
+
+                    let result = (|| -> Result<()> {
+                        if to_stdout && summary == SummaryFormat::Json {
+                            bail!("cannot print a JSON summary to stdout while also writing the distributable to it");
+                        }
+
+                        // Likewise for the exported file itself, which is written to a temporary file first
+                        let stdout_dir = if to_stdout { Some(temp_dir::TempDir::new()?) } else { None };
+                        let mut file_output = None;
+                        if let Some(dir) = &stdout_dir {
+                            file_output = Some(redirect_stdout_to_stderr()?);
+                            let file_name = if export_args.plain { "stdout.patches.tar" } else { "stdout.merlon" };
+                            export_args.output = Some(dir.path().join(file_name));
+                        }
+
+                        // Fail before regenerating patches if the export would fail at the end anyway
+                        if !dry_run && !export_args.plain {
+                            package.export_baserom(&export_args)?;
+                        }
+
+                        // If the package is initialised, sync it so the patches dir updates
+                        if InitialisedPackage::is_initialised(&package)? {
+                            let mut initialised = to_initialised(package.clone())?;
+                            initialised.set_allow_dirty(export_args.allow_dirty);
+                            initialised.set_progress(progress.clone());
+                            initialised.verify_decomp_rev()?;
+                            if export_args.include_untracked && !dry_run {
+                                for path in initialised.commit_untracked_files()? {
+                                    println!("Committed untracked file: {}", path);
+                                }
+                            }
+                            if !export_args.allow_empty {
+                                initialised.ensure_has_changes()?;
+                            }
+                            if export_args.allow_dirty && initialised.is_git_dirty()? {
+                                // Rebuilding the branches needs a clean repository, so only bring the patches up to
+                                // date
+                                initialised.update_patches_dir()?;
+                            } else {
+                                initialised.setup_git_branches()?;
+                            }
+                        }
+
+                        if dry_run {
+                            let plan = package.plan_export(&export_args)?;
+                            if let Some(output) = &mut summary_output {
+                                serde_json::to_writer_pretty(&mut *output, &plan)?;
+                                writeln!(output)?;
+                            } else {
+                                println!("Would export {} to {}", plan.package, plan.path.display());
+                                println!("Patches ({}):", plan.patches.len());
+                                for path in &plan.patches {
+                                    println!("  {}", path);
+                                }
+                                println!("Files:");
+                                for path in &plan.files {
+                                    println!("  {}", path);
+                                }
+                                println!("Total size: {} uncompressed", format_bytes(plan.uncompressed_size));
+                                if !plan.largest_patches.is_empty() {
+                                    println!("Largest patches:");
+                                    for patch in &plan.largest_patches {
+                                        println!("  {} {}", format_bytes(patch.size), patch.path);
+                                    }
+                                }
+                            }
+                            return Ok(());
+                        }
+
+                        if export_args.plain {
+                            let path = package.export_plain(&export_args)?;
+                            if let Some(output) = &mut file_output {
+                                output.write_all(&std::fs::read(&path)?)?;
+                                output.flush()?;
+                                println!("Wrote unencrypted patches to stdout");
+                            } else if let Some(output) = &mut summary_output {
+                                serde_json::to_writer_pretty(&mut *output, &serde_json::json!({ "path": path }))?;
+                                writeln!(output)?;
+                            } else {
+                                println!("Wrote unencrypted patches to {}", path.display());
+                            }
+                            return Ok(());
+                        }
+
+                        let exported = package.export_distributable_with_progress(export_args, &progress)?;
+                        if let Some(output) = &mut file_output {
+                            output.write_all(&std::fs::read(exported.path())?)?;
+                            output.flush()?;
+                            println!("Wrote distributable to stdout ({})", exported.size_summary()?);
+                        } else if let Some(output) = &mut summary_output {
+                            let summary = exported.export_summary().context("export did not produce a summary")?;
+                            serde_json::to_writer_pretty(&mut *output, summary)?;
+                            writeln!(output)?;
+                        } else {
+                            let path = exported.path().display();
+                            println!("Wrote distributable to {} ({})", path, exported.size_summary()?);
+                        }
+                        Ok(())
+                    })();
+
+                    // Scripts reading the summary get an object either way, and the exit code still says it failed
+                    if let (Err(error), Some(output)) = (&result, &mut summary_output) {
+                        let kind = ExportError::of(error).map(ExportError::kind);
+                        serde_json::to_writer_pretty(&mut *output, &serde_json::json!({
+                            "error": format!("{:#}", error),
+                            "kind": kind,
+                        }))?;
+                        writeln!(output)?;
+                    }
+                    result
                 } else {
                     bail!("cannot export package: not in a package directory.");
                 }
@@ -179,24 +622,126 @@ impl Args {
                     println!("{}", package.copyright_notice()?);
                     Ok(())
                 })?;
+                if let Some(into) = apply_args.into {
+                    distributable.apply_to_repo(apply_args.options.baserom, into.clone(), apply_args.keep_conflicts)?;
+                    println!("Applied to: {}", into.display());
+                    return Ok(());
+                }
+                if offline {
+                    bail!("cannot apply to a base ROM in offline mode, as the decomp needs to be cloned; use --into instead");
+                }
+                let verify_after = apply_args.options.verify_after;
                 let rom = distributable.apply(apply_args.options)?;
                 println!("Patched: {}", rom);
+                if verify_after {
+                    println!("Verified: the ROM is identical to the one the author built");
+                }
+                Ok(())
+            },
+            SubCommand::Install(install_args) => {
+                if offline {
+                    bail!("cannot install in offline mode, as the decomp needs to be cloned");
+                }
+                let distributable = Distributable::try_from(install_args.distributable)?;
+                let verify_after = install_args.options.apply_options.verify_after;
+                let rom = distributable.install_with(install_args.options, |package| {
+                    println!("{}", package.copyright_notice()?);
+                    Ok(())
+                })?;
+                println!("Installed: {}", rom);
+                println!("SHA1: {}", rom.sha1_string()?);
+                if verify_after {
+                    println!("Verified: the ROM is identical to the one the author built");
+                }
                 Ok(())
             },
             SubCommand::Open(open_args) => {
-                let distributable = Distributable::try_from(open_args.distributable)
-                    .context("failed to open distributable file")?;
+                let distributable = open_args.input.open()?;
                 let package = distributable.open_to_dir(open_args.options)
                     .context("failed to open distributable to package directory")?;
                 println!("{}", package.copyright_notice()?);
                 println!("Opened {} to directory {}", package, package.path().display());
                 Ok(())
             },
-            SubCommand::Run(build_args) => {
+            SubCommand::Verify(verify_args) => {
+                let distributable = verify_args.input.open()?;
+                let report = distributable.verify(verify_args.options)?;
+                for check in &report.checks {
+                    println!("{}", check);
+                }
+                if !report.passed() {
+                    bail!("{} failed verification", distributable.path().display());
+                }
+                println!("Verified: {}", distributable.path().display());
+                if let Some(signature) = distributable.header()?.and_then(|header| header.signature) {
+                    println!("Signed by: ed25519 key {}", signature.public_key);
+                }
+                Ok(())
+            },
+            SubCommand::Diff(diff_args) => {
+                let old = Distributable::try_from(diff_args.old)?;
+                let new = Distributable::try_from(diff_args.new)?;
+                let diff = old.diff(&new, diff_args.baserom)?;
+                if diff.is_empty() {
+                    println!("No differences in patches.");
+                } else if diff_args.summary {
+                    for name in &diff.added {
+                        println!("Added: {}", name);
+                    }
+                    for name in &diff.removed {
+                        println!("Removed: {}", name);
+                    }
+                    for name in &diff.changed {
+                        println!("Changed: {}", name);
+                    }
+                } else {
+                    print!("{}", diff.unified);
+                }
+                Ok(())
+            },
+            SubCommand::List(list_args) => {
+                let distributable = list_args.input.open()?;
+                print_header(&distributable)?;
+                let Some(baserom) = list_args.baserom else {
+                    return Ok(());
+                };
+                let listing = distributable.list(baserom)?;
+                let metadata = listing.manifest.metadata();
+                println!("Package: {} {}", metadata.name(), metadata.version());
+                println!("Patches:");
+                for patch in &listing.patches {
+                    match &patch.subject {
+                        Some(subject) => println!("  {} ({}): {}", patch.path, format_bytes(patch.size), subject),
+                        None => println!("  {} ({})", patch.path, format_bytes(patch.size)),
+                    }
+                }
+                println!("Files:");
+                for file in &listing.files {
+                    println!("  {}", file);
+                }
+                println!("Total: {} uncompressed", format_bytes(listing.uncompressed_size));
+                Ok(())
+            },
+            SubCommand::Info(info_args) => {
+                let distributable = info_args.input.open()?;
+                print_header(&distributable)?;
+                if let Some(baserom) = info_args.baserom {
+                    let manifest = distributable.manifest(baserom)?;
+                    let metadata = manifest.metadata();
+                    println!("Package: {} {}", metadata.name(), metadata.version());
+                    println!("Authors: {}", metadata.authors().join(", "));
+                    println!("Description: {}", metadata.description());
+                    println!("License: {}", metadata.license());
+                }
+                Ok(())
+            },
+            SubCommand::Run(run_args) => {
                 if let Some(package) = package {
-                    let initialised: InitialisedPackage = package.try_into()?;
-                    let rom = initialised.build_rom(build_args)?;
-                    merlon::emulator::run_rom(&rom)?;
+                    let mut emulator = package.emulator_command(run_args.emulator.as_deref())?;
+                    let initialised = to_initialised(package)?;
+                    let rom = initialised.build_rom(run_args.options)?;
+                    emulator.arg(rom.path()).status()
+                        .context("failed to run emulator")?;
                     Ok(())
                 } else {
                     bail!("cannot run package: not in a package directory.");
@@ -204,8 +749,14 @@ impl Args {
             },
             SubCommand::Build(build_args) => {
                 if let Some(package) = package {
-                    let initialised: InitialisedPackage = package.try_into()?;
-                    let rom = initialised.build_rom(build_args)?;
+                    let initialised = to_initialised(package)?;
+                    if build_args.list_targets {
+                        for target in initialised.available_targets()? {
+                            println!("{}", target);
+                        }
+                        return Ok(());
+                    }
+                    let rom = initialised.build_rom(build_args.options)?;
                     println!("Built: {}", rom);
                     println!("You can run this ROM with `merlon run`.");
                     println!("Warning: do not distribute this ROM. To distribute this package, use `merlon export`.");
@@ -214,10 +765,14 @@ impl Args {
                     bail!("cannot build package: not in a package directory.");
                 }
             },
-            SubCommand::Update => {
+            SubCommand::Update(update_args) => {
                 if let Some(package) = package {
-                    let initialised: InitialisedPackage = package.try_into()?;
+                    if locked {
+                        bail!("cannot update dependencies with --locked, as that would change merlon.lock");
+                    }
+                    let initialised = to_initialised(package)?;
                     initialised.update_decomp()?;
+                    initialised.check_dependency_overlaps(update_args.strict)?;
                     initialised.setup_git_branches()?;
                     Ok(())
                 } else {
@@ -226,7 +781,7 @@ impl Args {
             }
             SubCommand::Add(add_args) => {
                 if let Some(package) = package {
-                    let mut initialised: InitialisedPackage = package.try_into()?;
+                    let mut initialised = to_initialised(package)?;
 
                     // Make sure everything is OK to edit
                     if initialised.is_git_dirty()? {
@@ -234,21 +789,308 @@ impl Args {
                     }
 
                     // Add the dependency
-                    let id = initialised.add_dependency(add_args)?;
+                    let id = initialised.add_dependency(add_args.options)?;
                     let package = initialised.registry().get_or_error(id)?;
                     println!("Added dependency: {}", package);
+                    let manifest = initialised.package().manifest()?;
+                    let declared = manifest.iter_direct_dependencies()
+                        .find(|dependency| matches!(dependency, Dependency::Package { id: dep_id, .. } if *dep_id == id));
+                    if let Some(dependency) = declared {
+                        println!("Declared: {}", dependency);
+                    }
+                    initialised.check_dependency_overlaps(add_args.strict)?;
                     initialised.setup_git_branches()
                         .context("failed to setup git branches with dependency, there might be a merge issue")
                 } else {
                     bail!("cannot add dependency: not in a package directory.");
                 }
             },
+            SubCommand::Resolve(resolve_args) => {
+                if let Some(package) = package {
+                    let index = LocalIndex::scan(&resolve_args.packages_dir)?;
+                    if resolve_args.lock {
+                        let baserom = package.resolve_baserom(resolve_args.baserom.as_deref())?;
+                        let lockfile = index.lock(&package, &baserom)?;
+                        for entry in &lockfile.packages {
+                            println!("Locked: {}@{}", entry.name, entry.version);
+                        }
+                        if package.lockfile()?.as_ref() != Some(&lockfile) {
+                            if locked {
+                                bail!("merlon.lock needs to be updated, but --locked was passed");
+                            }
+                            lockfile.write_to_file(&package.lockfile_path())
+                                .context("failed to write merlon.lock")?;
+                        }
+                        return Ok(());
+                    }
+                    let resolution = index.with_path_dependencies(&package)?.resolve(&package.manifest()?);
+                    for resolved in &resolution.resolved {
+                        println!("Resolved: {}", resolved);
+                    }
+                    for dependency in &resolution.unmet {
+                        println!("Unmet: {}", dependency);
+                    }
+                    if !resolution.is_complete() {
+                        bail!("{} dependencies could not be resolved from {}", resolution.unmet.len(), resolve_args.packages_dir.display());
+                    }
+                    Ok(())
+                } else {
+                    bail!("cannot resolve dependencies: not in a package directory.");
+                }
+            },
+            SubCommand::Status(status_args) => {
+                let Some(package) = package else {
+                    bail!("cannot show status: not in a package directory.");
+                };
+                let status = package.status()?;
+                if status_args.json {
+                    println!("{}", serde_json::to_string_pretty(&status)?);
+                    return Ok(());
+                }
+                println!("Package: {}", status.package.as_deref().unwrap_or("unknown"));
+                match (&status.decomp_rev, status.commits) {
+                    (Some(rev), Some(commits)) => println!("Decomp: {} ({} commits on top)", rev, commits),
+                    (Some(rev), None) => println!("Decomp: {}", rev),
+                    (None, _) => println!("Decomp: no commit recorded"),
+                }
+                match status.dirty {
+                    Some(true) => println!("Changes: uncommitted"),
+                    Some(false) => println!("Changes: all committed"),
+                    None => println!("Changes: not initialised, run `merlon init`"),
+                }
+                if status.problems.is_empty() {
+                    println!("merlon.toml: valid");
+                } else {
+                    println!("merlon.toml: {} problem(s)", status.problems.len());
+                    print_validation_errors(&status.problems, color.use_color());
+                }
+                if let Some(baserom) = &status.baserom {
+                    match (&baserom.sha1, baserom.verified) {
+                        (Some(sha1), true) => println!("Base ROM: {} (SHA1 {}, unmodified US ROM)", baserom.path.display(), sha1),
+                        (Some(sha1), false) => println!("Base ROM: {} (SHA1 {}, not an unmodified US ROM)", baserom.path.display(), sha1),
+                        (None, _) => println!("Base ROM: missing from {}", baserom.path.display()),
+                    }
+                }
+                Ok(())
+            },
+            SubCommand::Validate(validate_args) => {
+                let Some(package) = package else {
+                    bail!("cannot validate package: not in a package directory.");
+                };
+                let errors = match package.manifest() {
+                    Ok(manifest) => manifest.validate(),
+                    Err(error) => vec![format!("{:#}", error)],
+                };
+                if validate_args.quiet {
+                    if !errors.is_empty() {
+                        std::process::exit(1);
+                    }
+                    return Ok(());
+                }
+                print_validation_errors(&errors, color.use_color());
+                if !errors.is_empty() {
+                    bail!("merlon.toml has {} validation error(s)", errors.len());
+                }
+                println!("Valid: {}", package);
+                Ok(())
+            },
+            SubCommand::Bump(bump_args) => {
+                if let Some(package) = package {
+                    let (old, new) = package.bump_version(&bump_args.bump)?;
+                    println!("Bumped version: {} → {}", old, new);
+                    Ok(())
+                } else {
+                    bail!("cannot bump version: not in a package directory.");
+                }
+            },
+            SubCommand::Clean(clean_args) => {
+                if let Some(package) = package {
+                    let removed = package.clean(clean_args.dry_run)?;
+                    let verb = if clean_args.dry_run { "Would remove" } else { "Removed" };
+                    for path in &removed {
+                        println!("{}: {}", verb, path.display());
+                    }
+                    if removed.is_empty() {
+                        println!("Nothing to clean.");
+                    }
+                    Ok(())
+                } else {
+                    bail!("cannot clean package: not in a package directory.");
+                }
+            },
+            SubCommand::Migrate => {
+                if let Some(package) = package {
+                    let old = package.migrate_manifest()?;
+                    let new = merlon::package::manifest::FORMAT_VERSION;
+                    if old == new {
+                        println!("merlon.toml is already in format version {}.", new);
+                    } else {
+                        println!("Migrated merlon.toml: format version {} → {}", old, new);
+                    }
+                    Ok(())
+                } else {
+                    bail!("cannot migrate manifest: not in a package directory.");
+                }
+            },
+            SubCommand::Completions(CompletionsArgs { shell }) => {
+                clap_complete::generate(shell, &mut Args::command(), "merlon", &mut std::io::stdout());
+                Ok(())
+            },
             #[cfg(feature = "gui")]
             SubCommand::Gui => main_gui(),
         }
     }
 }
 
+/// Prints what the unencrypted header of a distributable says about it.
+fn print_header(distributable: &Distributable) -> Result<()> {
+    println!("Distributable: {}", distributable.path().display());
+    match distributable.header()? {
+        Some(header) => {
+            println!("Created with: Merlon {}", header.merlon_version);
+            println!("Format: version {}, {} compression", header.format_version, header.compression);
+            println!(
+                "Key derivation: {}-{} with {} iterations",
+                header.kdf.to_uppercase(),
+                header.kdf_digest,
+                header.kdf_iterations,
+            );
+            if let Some(package) = &header.package {
+                println!("Contains: {}", package);
+            }
+            if header.patch_empty {
+                println!("Patches: none (metadata-only package)");
+            }
+            for dependency in &header.bundled_dependencies {
+                println!("Bundles: {}", dependency);
+            }
+            if let Some(signature) = &header.signature {
+                println!("Signed by: ed25519 key {}", signature.public_key);
+            }
+        },
+        None => println!("Created with: Merlon (unknown version, predates distributable headers)"),
+    }
+    Ok(())
+}
+
+/// Points stdout at stderr, so that everything printed from now on, including by child processes, goes to stderr.
+/// Returns a handle to the original stdout.
+#[cfg(unix)]
+fn redirect_stdout_to_stderr() -> Result<Box<dyn Write>> {
+    use std::os::unix::io::FromRawFd;
+
+    std::io::stdout().flush()?;
+    // SAFETY: these only duplicate file descriptors 1 and 2, which are open for the lifetime of the process, and
+    // the duplicate of stdout is owned solely by the returned File.
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 {
+            bail!("failed to duplicate stdout: {}", std::io::Error::last_os_error());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            bail!("failed to redirect stdout: {}", std::io::Error::last_os_error());
+        }
+        Ok(Box::new(std::fs::File::from_raw_fd(original)))
+    }
+}
+
+/// On platforms where stdout cannot be redirected, output from child processes may still be written to stdout.
+#[cfg(not(unix))]
+fn redirect_stdout_to_stderr() -> Result<Box<dyn Write>> {
+    Ok(Box::new(std::io::stdout()))
+}
+
+/// How often progress is logged when it can't be shown as progress bars.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shows export progress on stderr: as progress bars if stdout and stderr are terminals, otherwise as a line when each
+/// phase starts and finishes, and every [`PROGRESS_LOG_INTERVAL`] in between.
+fn cli_progress(stdout_is_terminal: bool) -> Progress {
+    if stdout_is_terminal && std::io::stderr().is_terminal() {
+        let bar: Mutex<Option<(Phase, indicatif::ProgressBar)>> = Mutex::new(None);
+        Progress::new(move |update| {
+            let mut bar = bar.lock().unwrap();
+            if bar.as_ref().map(|(phase, _)| *phase) != Some(update.phase) {
+                if let Some((_, previous)) = bar.take() {
+                    previous.finish();
+                }
+                *bar = Some((update.phase, progress_bar(update)));
+            }
+            let (_, bar) = bar.as_ref().unwrap();
+            if let Some(total) = update.total {
+                bar.set_length(total);
+            }
+            // Patches are staged on several threads, so updates can arrive out of order
+            bar.set_position(update.done.max(bar.position()));
+        })
+    } else {
+        let last: Mutex<Option<(Phase, Instant)>> = Mutex::new(None);
+        Progress::new(move |update| {
+            let mut last = last.lock().unwrap();
+            let finished = update.total == Some(update.done);
+            let due = match *last {
+                Some((phase, at)) => phase != update.phase || finished || at.elapsed() >= PROGRESS_LOG_INTERVAL,
+                None => true,
+            };
+            if due {
+                eprintln!("{}", format_progress_update(update));
+                *last = Some((update.phase, Instant::now()));
+            }
+        })
+    }
+}
+
+/// Creates a progress bar for the phase of `update`, measured in bytes when compressing.
+fn progress_bar(update: Update) -> indicatif::ProgressBar {
+    let bar = match update.total {
+        Some(total) => indicatif::ProgressBar::new(total),
+        None => indicatif::ProgressBar::new_spinner(),
+    };
+    let template = match update.phase {
+        Phase::Compressing => "{msg:>20} [{bar:40}] {bytes}/{total_bytes}",
+        _ => "{msg:>20} [{bar:40}] {pos}/{len}",
+    };
+    if let Ok(style) = indicatif::ProgressStyle::with_template(template) {
+        bar.set_style(style.progress_chars("=> "));
+    }
+    bar.set_message(update.phase.to_string());
+    bar
+}
+
+/// Formats an update as a line such as `Writing patches: 3/10`.
+fn format_progress_update(update: Update) -> String {
+    let format = |units: u64| match update.phase {
+        Phase::Compressing => format_bytes(units),
+        _ => units.to_string(),
+    };
+    match update.total {
+        Some(total) => format!("{}: {}/{}", update.phase, format(update.done), format(total)),
+        None => format!("{}: {}", update.phase, format(update.done)),
+    }
+}
+
+/// Prints each warning to stderr as `warning: <message>`, with the prefix in yellow if `color` is set.
+fn print_validation_warnings(warnings: &[String], color: bool) {
+    for warning in warnings {
+        if color {
+            eprintln!("{}: {}", "warning".yellow().bold(), warning);
+        } else {
+            eprintln!("warning: {}", warning);
+        }
+    }
+}
+
+/// Prints each error to stderr as `error: <message>`, with the prefix in red if `color` is set.
+fn print_validation_errors(errors: &[String], color: bool) {
+    for error in errors {
+        if color {
+            eprintln!("{}: {}", "error".red().bold(), error);
+        } else {
+            eprintln!("error: {}", error);
+        }
+    }
+}
+
 #[test]
 fn verify_cli() {
     use clap::CommandFactory;
@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use clap::Parser;
-use anyhow::Result;
+use anyhow::{Result, Context, bail};
 use heck::AsKebabCase;
 use merlon::package::Package;
 
@@ -9,13 +10,34 @@ pub struct Args {
     /// The name of the mod. This will be used as the mod's directory name.
     /// It is recommended that mods be named in the snake-case format.
     name: String,
+
+    /// The author to credit in the manifest and license, as `Name <email>`.
+    ///
+    /// If not set, the author is taken from `git config user.name` and `git config user.email`, or the `USER` and
+    /// `EMAIL` environment variables if those are not set.
+    #[arg(long)]
+    author: Option<String>,
+
+    /// Create the package even if the directory already exists and is not empty.
+    /// Existing files are kept unless the package has a file with the same name.
+    #[arg(long)]
+    force: bool,
+
+    /// Don't initialise a git repository for the package.
+    ///
+    /// A repository is not created if the directory is already inside one.
+    #[arg(long)]
+    no_git: bool,
 }
 
 pub fn run(dir: Option<PathBuf>, args: Args) -> Result<()> {
     // Create the package
     let current_dir = std::env::current_dir()?;
     let dir = dir.unwrap_or_else(|| current_dir.join(format!("{}", AsKebabCase(&args.name))));
-    let package = Package::new(args.name, dir)?;
+    let package = Package::new_in_dir(args.name, dir, args.author, args.force)?;
+    if !args.no_git && !is_inside_git_work_tree(package.path()) {
+        git_init(package.path())?;
+    }
 
     // Try and make path relative to current directory, but if that fails, just use the absolute path
     let path_relative_to_current = package.path()
@@ -34,3 +56,27 @@ pub fn run(dir: Option<PathBuf>, args: Args) -> Result<()> {
 
     Ok(())
 }
+
+fn is_inside_git_work_tree(dir: &Path) -> bool {
+    Command::new("git")
+        .arg("rev-parse")
+        .arg("--is-inside-work-tree")
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn git_init(dir: &Path) -> Result<()> {
+    let status = Command::new("git")
+        .arg("init")
+        .arg("--quiet")
+        .current_dir(dir)
+        .status()
+        .context("failed to run git init")?;
+    if !status.success() {
+        bail!("failed to initialise a git repository in {}", dir.display());
+    }
+    Ok(())
+}
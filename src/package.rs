@@ -4,10 +4,15 @@
 const ROOT_DIR_NAME: &str = "merlon_v1";
 
 const MANIFEST_FILE_NAME: &str = "merlon.toml";
+const LOCKFILE_FILE_NAME: &str = "merlon.lock";
 const README_FILE_NAME: &str = "README.md";
 const LICENSE_FILE_NAME: &str = "LICENSE";
 const PATCHES_DIR_NAME: &str = "patches";
 
+/// Directory of a package holding the distributables of dependencies that were bundled with it by
+/// `merlon export --bundle-deps`. They are added as dependencies when the package is initialised.
+const BUNDLED_DEPS_DIR_NAME: &str = "deps";
+
 use std::{
     fs,
     process::Command,
@@ -15,27 +20,101 @@ use std::{
     fmt::{self, Display, Formatter},
     hash::Hash,
     cmp::{Eq, PartialEq},
-    io::prelude::*,
+    io::{self, prelude::*},
+    sync::{Arc, Mutex},
 };
-use anyhow::{Result, bail};
+use anyhow::{Result, bail, Context};
+use sha1::{Sha1, Digest};
 use pyo3::prelude::*;
 
 pub mod manifest;
 pub use manifest::{
     Manifest,
     Id,
+    PackageId,
     name::Name,
 };
 
 pub mod registry;
 pub use registry::Registry;
 
+pub mod lockfile;
+pub use lockfile::Lockfile;
+
 pub mod init;
 pub use init::InitialisedPackage;
 
 pub mod distribute;
 pub use distribute::Distributable;
 
+pub mod bump;
+
+pub mod clean;
+
+pub mod status;
+
+pub mod format_patch;
+
+pub mod patch;
+
+pub mod progress;
+
+pub mod resolve;
+
+/// Resolves a relative path from the manifest against `root`, the directory it is relative to.
+///
+/// `.` and `..` components are resolved lexically. Absolute paths, and paths that would escape `root`, are rejected,
+/// so that a manifest cannot refer to files outside of its package.
+pub fn resolve_relative_path(root: &Path, relative: &Path) -> Result<PathBuf> {
+    Ok(root.join(normalize_relative_path(relative)?))
+}
+
+/// Resolves `.` and `..` components of a relative path, erroring if it is absolute or escapes its root.
+pub(crate) fn normalize_relative_path(relative: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            std::path::Component::Normal(part) => normalized.push(part),
+            std::path::Component::CurDir => {},
+            std::path::Component::ParentDir => {
+                if !normalized.pop() {
+                    bail!("path {} escapes the directory it is relative to", relative.display());
+                }
+            },
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => {
+                bail!("path {} must be relative", relative.display());
+            },
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        bail!("path {:?} does not name a file", relative);
+    }
+    Ok(normalized)
+}
+
+/// Writes a file by writing to a temporary file next to it, then renaming that over `path`.
+/// If `write` fails, or the process is killed partway through, any existing file at `path` is left intact.
+pub(crate) fn write_file_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut io::BufWriter<fs::File>) -> Result<()>,
+{
+    let file_name = path.file_name()
+        .with_context(|| format!("cannot write to {}: not a file path", path.display()))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp-{}", file_name.to_string_lossy(), uuid::Uuid::new_v4()));
+    let result: Result<()> = (|| {
+        let mut writer = io::BufWriter::new(fs::File::create(&temp_path)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("failed to write {}", path.display()))
+}
+
 /// Returns true if the given directory is probably a Merlon package.
 pub fn is_unexported_package(path: &Path) -> bool {
     path.is_dir() && path.join(MANIFEST_FILE_NAME).is_file()
@@ -46,6 +125,9 @@ pub fn is_unexported_package(path: &Path) -> bool {
 #[pyclass(module = "merlon.package")]
 pub struct Package {
     path: PathBuf,
+
+    /// Parsed `merlon.toml`, shared between clones so that an edit through one clone is seen by all of them.
+    manifest: Arc<Mutex<Option<Manifest>>>,
 }
 
 impl TryFrom<PathBuf> for Package {
@@ -53,7 +135,7 @@ impl TryFrom<PathBuf> for Package {
 
     fn try_from(path: PathBuf) -> Result<Self> {
         if is_unexported_package(&path) {
-            Ok(Self { path })
+            Ok(Self { path, manifest: Default::default() })
         } else {
             bail!("{} is not an unexported Merlon package", path.display());
         }
@@ -61,36 +143,98 @@ impl TryFrom<PathBuf> for Package {
 }
 
 impl Package {
+    /// Finds the package that `start` is in, by looking for `merlon.toml` in `start` and then each of its parents, like
+    /// Cargo does for `Cargo.toml`. This lets commands be run from anywhere inside a package, including `papermario/`.
+    pub fn find(start: &Path) -> Result<Self> {
+        Self::find_from(start)?.with_context(|| format!(
+            "{} is not inside a Merlon package: no {} was found in it or any of its parents. \
+            Run `merlon new` to create a package, or pass --directory",
+            start.display(),
+            MANIFEST_FILE_NAME,
+        ))
+    }
+
+    fn find_from(start: &Path) -> Result<Option<Self>> {
+        // Relative paths run out of parents before reaching the root
+        let mut dir = if start.is_absolute() { start.to_owned() } else { std::env::current_dir()?.join(start) };
+        while !dir.join(MANIFEST_FILE_NAME).is_file() {
+            if !dir.pop() {
+                return Ok(None);
+            }
+        }
+        Self::try_from(dir).map(Some)
+    }
+
     /// Create a new package at the given path. The path must not exist.
     pub fn new<N>(name: N, path: PathBuf) -> Result<Self>
     where
         N: manifest::name::TryIntoName,
     {
-        let name: Result<Name> = name.try_into_name().map_err(Into::into);
-        let name = name?;
+        Self::new_with_author(name, path, None)
+    }
 
+    /// Create a new package at the given path, crediting the given author (`Name <email>`).
+    /// If `author` is `None`, it is taken from git config. The path must not exist.
+    pub fn new_with_author<N>(name: N, path: PathBuf, author: Option<String>) -> Result<Self>
+    where
+        N: manifest::name::TryIntoName,
+    {
         if path.exists() {
             bail!("{} already exists", path.display());
         }
+        Self::new_in_dir(name, path, author, false)
+    }
+
+    /// Create a new package in the given directory, crediting the given author (`Name <email>`).
+    /// If `author` is `None`, it is taken from git config.
+    ///
+    /// The directory is created if it does not exist. If it does, it must be empty unless `force` is set, in which
+    /// case files already in it are kept, except for any that the new package would overwrite.
+    pub fn new_in_dir<N>(name: N, path: PathBuf, author: Option<String>, force: bool) -> Result<Self>
+    where
+        N: manifest::name::TryIntoName,
+    {
+        let name: Result<Name> = name.try_into_name().map_err(Into::into);
+        let name = name?;
+
+        let existed = path.exists();
+        if existed {
+            if !path.is_dir() {
+                bail!("{} already exists and is not a directory", path.display());
+            }
+            if is_unexported_package(&path) {
+                bail!("{} is already a Merlon package", path.display());
+            }
+            if !force && fs::read_dir(&path)?.next().is_some() {
+                bail!("{} is not empty; use --force to create the package there anyway", path.display());
+            }
+        }
 
         let path_clone = path.clone();
 
         let create_package = || {
-            fs::create_dir(&path)?;
-            fs::create_dir(&path.join(PATCHES_DIR_NAME))?;
+            if !existed {
+                fs::create_dir(&path)?;
+            }
+            fs::create_dir_all(path.join(PATCHES_DIR_NAME))?;
             fs::write(&path.join(README_FILE_NAME), generate_readme(&name))?;
-            let manifest = manifest::Manifest::new(name)?;
+            let manifest = match author {
+                Some(author) => manifest::Manifest::with_author(name, author)?,
+                None => manifest::Manifest::new(name)?,
+            };
             manifest.write_to_file(&path.join(MANIFEST_FILE_NAME))?;
             fs::write(&path.join(LICENSE_FILE_NAME), generate_license(&manifest))?;
 
             debug_assert!(Package::try_from(path.clone()).is_ok());
-            Ok(Self { path })
+            Ok(Self { path, manifest: Default::default() })
         };
 
-        // If it errors, delete the directory
+        // If it errors, delete the directory, unless it was there before
         match create_package() {
             Err(e) => {
-                let _ = fs::remove_dir_all(path_clone);
+                if !existed {
+                    let _ = fs::remove_dir_all(path_clone);
+                }
                 Err(e)
             }
             result => result,
@@ -103,10 +247,26 @@ impl Package {
     where
         F: FnOnce(&mut Manifest) -> Result<()>,
     {
-        let path = self.path.join(MANIFEST_FILE_NAME);
         let mut manifest = self.manifest()?;
         f(&mut manifest)?;
-        manifest.write_to_file(&path)
+        manifest.write_to_file(&self.manifest_path())?;
+        *self.manifest.lock().expect("manifest cache poisoned") = Some(manifest);
+        Ok(())
+    }
+
+    /// Upgrades `merlon.toml` to the current [format version](manifest::FORMAT_VERSION), and returns the format
+    /// version it was in. The file is left untouched if it is current already.
+    pub fn migrate_manifest(&self) -> Result<u32> {
+        let path = self.manifest_path();
+        let mut table: toml::Table = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .parse()?;
+        let format_version = Manifest::migrate(&mut table)?;
+        if format_version != manifest::FORMAT_VERSION {
+            self.reload();
+            self.edit_manifest(|_| Ok(()))?;
+        }
+        Ok(format_version)
     }
 }
 
@@ -121,13 +281,7 @@ impl Package {
     /// Gets the current package, if any, by looking for `merlon.toml` in the current directory and its parents.
     #[staticmethod]
     pub fn current() -> Result<Option<Self>> {
-        let mut dir = std::env::current_dir()?;
-        while !dir.join(MANIFEST_FILE_NAME).is_file() {
-            if !dir.pop() {
-                return Ok(None);
-            }
-        }
-        Self::try_from(dir).map(|pkg| Some(pkg))
+        Self::find_from(&std::env::current_dir()?)
     }
 
     /// The package ID.
@@ -147,14 +301,36 @@ impl Package {
         fs::read_to_string(self.path.join(README_FILE_NAME)).map_err(Into::into)
     }
 
-    /// Returns the manifest of the package by parsing the `merlon.toml` file.
+    /// The path to the package's `merlon.toml` file.
+    pub fn manifest_path(&self) -> PathBuf {
+        self.path.join(MANIFEST_FILE_NAME)
+    }
+
+    /// Returns the manifest of the package. The `merlon.toml` file is parsed on first use and cached afterwards.
     pub fn manifest(&self) -> Result<Manifest> {
-        let path = self.path.join(MANIFEST_FILE_NAME);
-        Manifest::read_from_path(&path)
+        let mut cached = self.manifest.lock().expect("manifest cache poisoned");
+        if let Some(manifest) = cached.as_ref() {
+            return Ok(manifest.clone());
+        }
+        let path = self.manifest_path();
+        let manifest = Manifest::read_from_path(&path)
             .map_err(|err| err.context(format!(
                 "Error reading package manifest {}",
                 &path.display(),
-            )))
+            )))?;
+        *cached = Some(manifest.clone());
+        Ok(manifest)
+    }
+
+    /// Discards the cached manifest, so the next read parses `merlon.toml` again.
+    /// Only needed if the file was changed by something other than [`Package::edit_manifest`].
+    pub fn reload(&self) {
+        *self.manifest.lock().expect("manifest cache poisoned") = None;
+    }
+
+    /// The `name@version` identifier of this release of the package.
+    pub fn versioned_id(&self) -> Result<PackageId> {
+        Ok(self.manifest()?.metadata().versioned_id())
     }
 
     /// Compares two packages by ID.
@@ -176,21 +352,72 @@ impl Package {
 }
 
 impl Package {
-    pub(crate) fn apply_patches_to_decomp_repo(&self, repo: &Path) -> Result<()> {
+    /// Returns the paths of the `.patch` files in the package's patches directory, in the order they apply.
+    pub fn patch_files(&self) -> Result<Vec<PathBuf>> {
         let patches_path = self.path.join(PATCHES_DIR_NAME);
         if !patches_path.exists() {
             log::warn!("{} directory does not exist", PATCHES_DIR_NAME);
-            return Ok(())
+            return Ok(Vec::new())
         }
         let mut patch_files = fs::read_dir(patches_path)?
-            .map(|entry| entry.unwrap().path())
-            .filter(|path| path.extension().map(|ext| ext == "patch").unwrap_or(false))
-            .map(|path| path.canonicalize())
+            .map(|entry| entry.map(|entry| entry.path()))
             .collect::<Result<Vec<_>, _>>()?;
+        patch_files.retain(|path| path.extension().map(|ext| ext == "patch").unwrap_or(false));
         patch_files.sort_unstable();
+        Ok(patch_files)
+    }
+
+    /// Returns the SHA1 of the package's patches, covering both the file names and their contents.
+    pub fn patches_checksum(&self) -> Result<String> {
+        let mut hasher = Sha1::new();
+        for path in self.patch_files()? {
+            if let Some(file_name) = path.file_name() {
+                hasher.update(file_name.to_string_lossy().as_bytes());
+            }
+            hasher.update(fs::read(&path)?);
+        }
+        Ok(crate::rom::to_hex(&hasher.finalize()))
+    }
+
+    /// The path to the package's `merlon.lock` file. It may not exist.
+    pub fn lockfile_path(&self) -> PathBuf {
+        self.path.join(LOCKFILE_FILE_NAME)
+    }
+
+    /// Resolves a path declared in the manifest, such as an entry of `extra_files` or `authors_file`.
+    /// Such paths are relative to the package directory, the directory containing `merlon.toml`.
+    /// See [`resolve_relative_path`].
+    pub fn resolve(&self, relative: &Path) -> Result<PathBuf> {
+        resolve_relative_path(&self.path, relative)
+    }
+
+    /// Reads the package's lockfile, if it has one.
+    pub fn lockfile(&self) -> Result<Option<Lockfile>> {
+        let path = self.lockfile_path();
+        if path.is_file() {
+            Lockfile::read_from_path(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn apply_patches_to_decomp_repo(&self, repo: &Path) -> Result<()> {
+        self.apply_patches(repo, false)
+    }
+
+    /// Applies the package's patches to `repo`. On conflict, the `git am` session is aborted unless `keep_conflicts`
+    /// is set, in which case it is left for the user to resolve.
+    fn apply_patches(&self, repo: &Path, keep_conflicts: bool) -> Result<()> {
+        let patch_files = self.patch_files()?
+            .into_iter()
+            .map(|path| path.canonicalize())
+            .collect::<Result<Vec<_>, _>>()?;
         if patch_files.is_empty() {
             return Ok(())
         }
+        if self.manifest()?.metadata().patch_format() == manifest::PatchFormat::Diff {
+            return self.apply_diff_to_decomp_repo(repo, &patch_files, keep_conflicts);
+        }
         let status = Command::new("git")
             .arg("am")
             .arg("--3way")
@@ -198,7 +425,20 @@ impl Package {
             .current_dir(&repo)
             .status()?;
         if !status.success() {
-            // The merge failed! Abort the merge and tell the user to fix it.
+            // The merge failed! Work out what conflicted before aborting, since aborting forgets it.
+            let conflict = describe_git_am_conflict(repo);
+            if keep_conflicts {
+                bail!(
+                    "failed to apply patches of {}: {}\n\
+                    Resolve the conflicts in {}, `git add` the fixed files, then run `git am --continue`. \
+                    To give up instead, run `git am --abort`.",
+                    self,
+                    conflict,
+                    repo.display(),
+                );
+            }
+
+            // Abort the merge and tell the user to fix it.
             let status = Command::new("git")
                 .arg("am")
                 .arg("--abort")
@@ -207,8 +447,78 @@ impl Package {
             if !status.success() {
                 panic!("failed to abort merge");
             }
+            bail!("failed to apply patches of {}: {}", self, conflict);
+        }
+        Ok(())
+    }
+
+    /// Applies the package's patches to an existing decomp checkout, such as one with other packages already applied.
+    ///
+    /// The checkout must have no uncommitted changes, and must contain the package's `min_decomp_rev` if it has one.
+    /// If the patches conflict, the error lists the conflicting commit and files. The checkout is left as it was,
+    /// unless `keep_conflicts` is set, in which case the `git am` session is left in progress to be resolved and
+    /// continued by hand.
+    pub fn apply_patches_to_existing_repo(&self, repo: &Path, keep_conflicts: bool) -> Result<()> {
+        let output = Command::new("git")
+            .arg("status")
+            .arg("--porcelain")
+            .current_dir(repo)
+            .output()
+            .with_context(|| format!("failed to run git status in {}", repo.display()))?;
+        if !output.status.success() {
+            bail!("{} is not a git repository", repo.display());
+        }
+        if !output.stdout.is_empty() {
+            bail!("{} has uncommitted changes, commit or stash them first", repo.display());
+        }
+        let manifest = self.manifest()?;
+        if let Some(min_rev) = manifest.metadata().min_decomp_rev() {
+            init::ensure_rev_contains(repo, "HEAD", min_rev)?;
+        }
+        // Three-way merges need the commit the patches were made on, which the repo may not have if it is elsewhere
+        if let Some(rev) = manifest.get_direct_decomp_dependency_rev() {
+            if let Err(error) = init::fetch_decomp_rev(repo, rev, manifest.metadata()) {
+                log::warn!("{:#}; the patches may not apply cleanly", error);
+            }
+        }
+        self.apply_patches(repo, keep_conflicts)
+    }
+
+    /// Applies unified diffs with `git apply` and commits the result, so that the package still has a branch.
+    fn apply_diff_to_decomp_repo(&self, repo: &Path, patch_files: &[PathBuf], keep_conflicts: bool) -> Result<()> {
+        let status = Command::new("git")
+            .arg("apply")
+            .arg("--3way")
+            .arg("--index")
+            .args(patch_files)
+            .current_dir(repo)
+            .status()?;
+        if !status.success() {
+            if keep_conflicts {
+                bail!(
+                    "failed to apply patches of {}\n\
+                    Resolve the conflicts in {}, `git add` the fixed files, then `git commit`.",
+                    self,
+                    repo.display(),
+                );
+            }
+            // Leave the repo as it was
+            let _ = Command::new("git")
+                .arg("reset")
+                .arg("--hard")
+                .current_dir(repo)
+                .status();
             bail!("failed to apply patches");
         }
+        let status = Command::new("git")
+            .arg("commit")
+            .arg("--allow-empty")
+            .arg("-m").arg(self.to_string())
+            .current_dir(repo)
+            .status()?;
+        if !status.success() {
+            bail!("failed to commit applied patches");
+        }
         Ok(())
     }
 
@@ -300,6 +610,42 @@ fn nearest_git_root() -> Result<PathBuf> {
     bail!("not in a git repository");
 }
 
+/// Describes the patch that `git am` stopped at and the files that conflicted, for error messages.
+/// Must be called before `git am --abort`.
+fn describe_git_am_conflict(repo: &Path) -> String {
+    let current_patch = Command::new("git")
+        .arg("am")
+        .arg("--show-current-patch")
+        .current_dir(repo)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    let subject = patch::subject(&current_patch).unwrap_or_else(|| "unknown commit".to_owned());
+
+    let mut files: Vec<String> = Command::new("git")
+        .arg("diff")
+        .arg("--name-only")
+        .arg("--diff-filter=U")
+        .current_dir(repo)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().map(|line| line.to_owned()).collect())
+        .unwrap_or_default();
+    if files.is_empty() {
+        // The patch didn't apply at all, so there are no conflict markers. Report what it touches instead.
+        files = patch::touched_paths(&current_patch);
+    }
+
+    if files.is_empty() {
+        format!("conflict in {:?}", subject)
+    } else {
+        format!("conflict in {:?} affecting {}", subject, files.join(", "))
+    }
+}
+
 fn generate_readme(package_name: &Name) -> String {
     include_str!("../templates/README.md")
         .replace("{{package_name}}", &format!("{}", package_name))
@@ -318,3 +664,230 @@ fn generate_license(manifest: &Manifest) -> String {
         .replace("{{year}}", &chrono::Utc::now().format("%Y").to_string())
         .replace("{{author_names}}", &author_names)
 }
+
+/// Runs git in `repo` as a test user and returns its stdout, for tests that need a repository.
+#[cfg(test)]
+pub(crate) fn test_git(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .env("GIT_COMMITTER_NAME", "Test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .current_dir(repo)
+        .output()?;
+    if !output.status.success() {
+        bail!("git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Commits whatever is staged in `repo` as a test user, even if that is nothing.
+#[cfg(test)]
+pub(crate) fn test_commit(repo: &Path, message: &str) -> Result<()> {
+    test_git(repo, &["commit", "--quiet", "--allow-empty", "-m", message])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+    use anyhow::Result;
+
+    use super::*;
+    use super::manifest::Version;
+
+    #[test]
+    fn manifest_cache_is_shared_and_reloadable() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author("Test", dir.path().join("test"), Some("Test <test@example.com>".to_owned()))?;
+
+        // Edits through a clone are seen by the original
+        let clone = package.clone();
+        clone.edit_manifest(|manifest| {
+            manifest.metadata_mut().set_version(Version::new(1, 0, 0));
+            Ok(())
+        })?;
+        assert_eq!(package.manifest()?.metadata().version(), &Version::new(1, 0, 0));
+
+        // Edits made behind our back are only seen after a reload
+        let mut manifest = package.manifest()?;
+        manifest.metadata_mut().set_version(Version::new(2, 0, 0));
+        manifest.write_to_file(&package.manifest_path())?;
+        assert_eq!(package.manifest()?.metadata().version(), &Version::new(1, 0, 0));
+        package.reload();
+        assert_eq!(package.manifest()?.metadata().version(), &Version::new(2, 0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_manifest_writes_back_only_old_formats() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author("Test", dir.path().join("test"), Some("Test <test@example.com>".to_owned()))?;
+        let current = fs::read_to_string(package.manifest_path())?;
+        assert_eq!(package.migrate_manifest()?, manifest::FORMAT_VERSION);
+        assert_eq!(fs::read_to_string(package.manifest_path())?, current);
+
+        // An old manifest is read as it is, and only rewritten when migrated
+        let old = current.replace(&format!("format_version = {}\n", manifest::FORMAT_VERSION), "");
+        fs::write(package.manifest_path(), &old)?;
+        package.reload();
+        assert_eq!(package.manifest()?.format_version(), manifest::FORMAT_VERSION);
+        assert_eq!(fs::read_to_string(package.manifest_path())?, old);
+
+        assert_eq!(package.migrate_manifest()?, 0);
+        let migrated = fs::read_to_string(package.manifest_path())?;
+        assert!(migrated.contains(&format!("format_version = {}", manifest::FORMAT_VERSION)), "{}", migrated);
+        assert_eq!(Manifest::from_toml_str(&migrated)?, Manifest::from_toml_str(&current)?);
+        Ok(())
+    }
+
+    #[test]
+    fn find_walks_up_to_the_package() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author("Found", dir.path().join("found"), Some("Test <test@example.com>".to_owned()))?;
+        let nested = package.path().join("src/world/area");
+        fs::create_dir_all(&nested)?;
+        assert_eq!(Package::find(&nested)?.path(), package.path());
+        assert_eq!(Package::find(package.path())?.path(), package.path());
+
+        let error = Package::find(dir.path()).unwrap_err();
+        assert!(error.to_string().contains("is not inside a Merlon package"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn new_in_dir_refuses_non_empty_dir_without_force() -> Result<()> {
+        let dir = TempDir::new()?;
+        let author = Some("Test <test@example.com>".to_owned());
+
+        let empty = dir.path().join("empty");
+        fs::create_dir(&empty)?;
+        let package = Package::new_in_dir("Empty", empty.clone(), author.clone(), false)?;
+        for file in [MANIFEST_FILE_NAME, README_FILE_NAME, LICENSE_FILE_NAME] {
+            assert!(package.path().join(file).is_file(), "{} is missing", file);
+        }
+        assert!(package.path().join(PATCHES_DIR_NAME).is_dir());
+        assert!(Package::new_in_dir("Again", empty, author.clone(), true).is_err(), "should not replace a package");
+
+        let non_empty = dir.path().join("non-empty");
+        fs::create_dir(&non_empty)?;
+        fs::write(non_empty.join("notes.txt"), "keep me")?;
+        let error = Package::new_in_dir("Non Empty", non_empty.clone(), author.clone(), false).unwrap_err();
+        assert!(error.to_string().contains("--force"), "{}", error);
+        assert!(!non_empty.join(MANIFEST_FILE_NAME).exists());
+
+        Package::new_in_dir("Non Empty", non_empty.clone(), author, true)?;
+        assert!(non_empty.join(MANIFEST_FILE_NAME).is_file());
+        assert_eq!(fs::read_to_string(non_empty.join("notes.txt"))?, "keep me");
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_patch_leaves_repo_untouched() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path().join("papermario");
+        fs::create_dir(&repo)?;
+        test_git(&repo, &["init", "-q"])?;
+        // `git am` refuses to start without a committer identity
+        test_git(&repo, &["config", "user.name", "Test"])?;
+        test_git(&repo, &["config", "user.email", "test@example.com"])?;
+        fs::write(repo.join("file.c"), "int x = 1;\n")?;
+        test_git(&repo, &["add", "file.c"])?;
+        test_git(&repo, &["commit", "-q", "-m", "base"])?;
+
+        // Make a patch that changes x, then move the repo somewhere the patch conflicts with
+        fs::write(repo.join("file.c"), "int x = 2;\n")?;
+        test_git(&repo, &["commit", "-q", "-a", "-m", "set x to 2"])?;
+        let patch = test_git(&repo, &["format-patch", "-1", "--stdout"])?;
+        test_git(&repo, &["reset", "-q", "--hard", "HEAD~1"])?;
+        fs::write(repo.join("file.c"), "int x = 3;\n")?;
+        test_git(&repo, &["commit", "-q", "-a", "-m", "set x to 3"])?;
+        let head = test_git(&repo, &["rev-parse", "HEAD"])?;
+
+        let package = Package::new_with_author("Conflict", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        fs::write(package.path().join(PATCHES_DIR_NAME).join("0001-set-x-to-2.patch"), patch)?;
+
+        let error = package.apply_patches_to_existing_repo(&repo, false).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("set x to 2"), "{}", message);
+        assert!(message.contains("file.c"), "{}", message);
+
+        assert_eq!(test_git(&repo, &["rev-parse", "HEAD"])?, head);
+        assert_eq!(test_git(&repo, &["status", "--porcelain"])?, "");
+        assert_eq!(fs::read_to_string(repo.join("file.c"))?, "int x = 3;\n");
+        Ok(())
+    }
+
+    #[test]
+    fn patches_reapply_cleanly_or_keep_the_session() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path().join("papermario");
+        fs::create_dir(&repo)?;
+        test_git(&repo, &["init", "-q"])?;
+        test_git(&repo, &["config", "user.name", "Test"])?;
+        test_git(&repo, &["config", "user.email", "test@example.com"])?;
+        fs::write(repo.join("file.c"), "int x = 1;\n")?;
+        test_git(&repo, &["add", "file.c"])?;
+        test_git(&repo, &["commit", "-q", "-m", "base"])?;
+        let base = test_git(&repo, &["rev-parse", "HEAD"])?.trim().to_owned();
+
+        // Two commits, exported as patches and then undone
+        let package = Package::new_with_author("Two Commits", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        fs::write(repo.join("file.c"), "int x = 2;\n")?;
+        test_git(&repo, &["commit", "-q", "-a", "-m", "set x to 2"])?;
+        fs::write(repo.join("other.c"), "int y = 1;\n")?;
+        test_git(&repo, &["add", "other.c"])?;
+        test_git(&repo, &["commit", "-q", "-m", "add y"])?;
+        let patches_dir = package.path().join(PATCHES_DIR_NAME);
+        test_git(&repo, &["format-patch", "-q", "-o", &patches_dir.to_string_lossy(), &base])?;
+        test_git(&repo, &["reset", "-q", "--hard", &base])?;
+
+        package.apply_patches_to_existing_repo(&repo, true)?;
+        assert_eq!(test_git(&repo, &["log", "--format=%s", &format!("{}..HEAD", base)])?, "add y\nset x to 2\n");
+        assert_eq!(fs::read_to_string(repo.join("other.c"))?, "int y = 1;\n");
+
+        // Now make the first patch conflict, and check the session is kept for the user to resolve
+        test_git(&repo, &["reset", "-q", "--hard", &base])?;
+        fs::write(repo.join("file.c"), "int x = 3;\n")?;
+        test_git(&repo, &["commit", "-q", "-a", "-m", "set x to 3"])?;
+        let error = package.apply_patches_to_existing_repo(&repo, true).unwrap_err();
+        assert!(error.to_string().contains("git am --continue"), "{}", error);
+        assert!(repo.join(".git").join("rebase-apply").is_dir());
+        test_git(&repo, &["am", "--abort"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn checkout_older_than_min_decomp_rev_is_refused() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path().join("papermario");
+        fs::create_dir(&repo)?;
+        test_git(&repo, &["init", "-q"])?;
+        test_git(&repo, &["commit", "-q", "--allow-empty", "-m", "old"])?;
+        test_git(&repo, &["commit", "-q", "--allow-empty", "-m", "new"])?;
+        let new = test_git(&repo, &["rev-parse", "HEAD"])?.trim().to_owned();
+        test_git(&repo, &["reset", "-q", "--hard", "HEAD~1"])?;
+
+        let package = Package::new_with_author("Too New", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        package.edit_manifest(|manifest| {
+            manifest.metadata_mut().set_min_decomp_rev(Some(new.clone()));
+            Ok(())
+        })?;
+
+        let error = package.apply_patches_to_existing_repo(&repo, false).unwrap_err();
+        assert!(error.to_string().contains("older than"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_paths_cannot_escape_the_package() -> Result<()> {
+        let root = Path::new("package");
+        assert_eq!(resolve_relative_path(root, Path::new("docs/INSTALL.md"))?, root.join("docs/INSTALL.md"));
+        assert_eq!(resolve_relative_path(root, Path::new("./docs/../LICENSE"))?, root.join("LICENSE"));
+        assert!(resolve_relative_path(root, Path::new("../secret")).is_err());
+        assert!(resolve_relative_path(root, Path::new("docs/../../secret")).is_err());
+        assert!(resolve_relative_path(root, Path::new("/etc/passwd")).is_err());
+        assert!(resolve_relative_path(root, Path::new(".")).is_err());
+        Ok(())
+    }
+}
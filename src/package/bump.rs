@@ -0,0 +1,159 @@
+//! Bumping the package version in `merlon.toml`.
+
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use anyhow::{Result, Context, anyhow, bail};
+use toml_edit::Document;
+
+use super::Package;
+use super::manifest::{Version, parse_version};
+
+/// How to change a package's version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bump {
+    /// `1.2.3` to `2.0.0`.
+    Major,
+    /// `1.2.3` to `1.3.0`.
+    Minor,
+    /// `1.2.3` to `1.2.4`. A pre-release such as `1.2.4-rc1` becomes its release, `1.2.4`.
+    Patch,
+    /// Any version greater than the current one.
+    Exact(Version),
+}
+
+impl Bump {
+    /// Returns the version that comes after `current`. Errors if an exact version is not greater than `current`.
+    pub fn apply(&self, current: &Version) -> Result<Version> {
+        let next = match self {
+            Bump::Major => Version::new(current.major + 1, 0, 0),
+            Bump::Minor => Version::new(current.major, current.minor + 1, 0),
+            Bump::Patch if !current.pre.is_empty() => Version::new(current.major, current.minor, current.patch),
+            Bump::Patch => Version::new(current.major, current.minor, current.patch + 1),
+            Bump::Exact(version) => {
+                if version <= current {
+                    bail!("cannot bump version from {} to {}, which is not greater", current, version);
+                }
+                version.clone()
+            }
+        };
+        Ok(next)
+    }
+}
+
+impl FromStr for Bump {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "major" => Ok(Bump::Major),
+            "minor" => Ok(Bump::Minor),
+            "patch" => Ok(Bump::Patch),
+            _ => parse_version(s)
+                .map(Bump::Exact)
+                .map_err(|error| anyhow!("expected major, minor, patch, or a version: {}", error)),
+        }
+    }
+}
+
+impl fmt::Display for Bump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bump::Major => write!(f, "major"),
+            Bump::Minor => write!(f, "minor"),
+            Bump::Patch => write!(f, "patch"),
+            Bump::Exact(version) => write!(f, "{}", version),
+        }
+    }
+}
+
+impl Package {
+    /// Bumps the version in `merlon.toml`, keeping its formatting, and returns the old and new versions.
+    ///
+    /// Errors if the current version is not valid semver, since there is nothing sensible to bump it from.
+    pub fn bump_version(&self, bump: &Bump) -> Result<(Version, Version)> {
+        let current = self.read_raw_version()
+            .with_context(|| format!("cannot bump version in {}", self.manifest_path().display()))?;
+        let next = bump.apply(&current)?;
+        self.edit_manifest(|manifest| {
+            manifest.metadata_mut().set_version(next.clone());
+            Ok(())
+        })?;
+        Ok((current, next))
+    }
+
+    /// Reads `package.version` straight from `merlon.toml`, so that an invalid version is reported as such rather
+    /// than as a manifest that fails to load.
+    fn read_raw_version(&self) -> Result<Version> {
+        let path = self.manifest_path();
+        let toml = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let document: Document = toml.parse()
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        let Some(version) = document.get("package").and_then(|package| package.get("version")) else {
+            bail!("package.version is missing");
+        };
+        let Some(version) = version.as_str() else {
+            bail!("package.version must be a string, like \"1.0.0\"");
+        };
+        parse_version(version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn version(s: &str) -> Version {
+        Version::parse(s).unwrap()
+    }
+
+    #[test]
+    fn each_bump_kind() -> Result<()> {
+        let current = version("1.2.3");
+        assert_eq!(Bump::Major.apply(&current)?, version("2.0.0"));
+        assert_eq!(Bump::Minor.apply(&current)?, version("1.3.0"));
+        assert_eq!(Bump::Patch.apply(&current)?, version("1.2.4"));
+        assert_eq!(Bump::Patch.apply(&version("1.2.4-rc1"))?, version("1.2.4"));
+        assert_eq!("1.5.0-beta".parse::<Bump>()?.apply(&current)?, version("1.5.0-beta"));
+        assert!(Bump::Exact(version("1.2.3")).apply(&current).is_err());
+        assert!(Bump::Exact(version("0.9.0")).apply(&current).is_err());
+        assert!("huge".parse::<Bump>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bump_keeps_manifest_formatting() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new("Test", dir.path().join("test"))?;
+        let toml = fs::read_to_string(package.manifest_path())?;
+        fs::write(package.manifest_path(), format!("# My mod\n{}", toml))?;
+        package.reload();
+
+        let (old, new) = package.bump_version(&Bump::Minor)?;
+        assert_eq!((old, new.clone()), (version("0.1.0"), version("0.2.0")));
+        let toml = fs::read_to_string(package.manifest_path())?;
+        assert!(toml.starts_with("# My mod\n"), "{}", toml);
+        assert!(toml.contains("version = \"0.2.0\""), "{}", toml);
+        assert_eq!(package.manifest()?.metadata().version(), &new);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_version_is_rejected() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new("Test", dir.path().join("test"))?;
+        let toml = fs::read_to_string(package.manifest_path())?;
+        assert!(toml.contains("version = \"0.1.0\""));
+        fs::write(package.manifest_path(), toml.replace("version = \"0.1.0\"", "version = \"1.0\""))?;
+        package.reload();
+
+        let error = package.bump_version(&Bump::Patch).unwrap_err();
+        assert!(format!("{:#}", error).contains("must be valid semver"), "{:#}", error);
+        let unchanged = fs::read_to_string(package.manifest_path())?;
+        assert!(unchanged.contains("version = \"1.0\""));
+        Ok(())
+    }
+}
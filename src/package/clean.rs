@@ -0,0 +1,75 @@
+//! Removing files that Merlon generates in a package's `.merlon` directory.
+
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Result, Context};
+use pyo3::prelude::*;
+
+use super::Package;
+use super::init::{MERLON_DIR_NAME, DEPENDENCIES_DIR_NAME};
+
+#[pymethods]
+impl Package {
+    /// Removes everything in the package's `.merlon` directory that Merlon can recreate, such as intermediate files
+    /// kept by `merlon export --keep-intermediates`, and returns what was removed.
+    ///
+    /// Installed dependencies in `.merlon/dependencies` are kept, since the package cannot be built without them.
+    /// `papermario/` and the package's own files are never touched. If `dry_run` is set, nothing is removed.
+    pub fn clean(&self, dry_run: bool) -> Result<Vec<PathBuf>> {
+        let merlon_dir = self.path().join(MERLON_DIR_NAME);
+        if !merlon_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let keep = self.path().join(DEPENDENCIES_DIR_NAME);
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&merlon_dir).with_context(|| format!("failed to read {}", merlon_dir.display()))? {
+            let path = entry?.path();
+            if path != keep {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        if !dry_run {
+            for path in &paths {
+                let result = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+                result.with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn clean_removes_junk_but_keeps_dependencies() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author("Test", dir.path().join("test"), Some("Test <test@example.com>".to_owned()))?;
+        let merlon_dir = package.path().join(MERLON_DIR_NAME);
+        fs::create_dir_all(merlon_dir.join("export/merlon_v1/patches"))?;
+        fs::write(merlon_dir.join("export/package.tar.bz2"), "junk")?;
+        fs::write(merlon_dir.join("build.log"), "junk")?;
+        fs::create_dir_all(package.path().join(DEPENDENCIES_DIR_NAME).join("dep"))?;
+        fs::create_dir_all(package.path().join("papermario/src"))?;
+        fs::write(package.path().join("papermario/src/main.c"), "int main(void) {}\n")?;
+
+        let expected = vec![merlon_dir.join("build.log"), merlon_dir.join("export")];
+        assert_eq!(package.clean(true)?, expected);
+        assert!(merlon_dir.join("build.log").exists(), "dry run should not remove anything");
+
+        assert_eq!(package.clean(false)?, expected);
+        assert!(!merlon_dir.join("build.log").exists());
+        assert!(!merlon_dir.join("export").exists());
+        assert!(package.path().join(DEPENDENCIES_DIR_NAME).join("dep").is_dir());
+        assert!(package.path().join("papermario/src/main.c").is_file());
+        assert!(package.manifest_path().is_file());
+        assert!(package.clean(false)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
-//! Distributables are encrypted tarballs with a specific directory structure.
-//! They are used to store source code patches and metadata for a mod.
+//! Distributables are encrypted tarballs with a specific directory structure, preceded by an unencrypted
+//! [`Header`]. They are used to store source code patches and metadata for a mod.
 //! 
 //! The internal directory structure of a distributable is:
 //! 
@@ -9,67 +9,402 @@
 //!  │   ├── 0002-Add-bar.patch
 //!  │   └── 0003-Add-baz.patch
 //!  ├── merlon.toml             - Manifest
+//!  ├── merlon.lock             - Lockfile, if the package has one
 //!  ├── LICENSE                 - License
-//!  └── README.md               - Documentation
+//!  ├── README.md               - Documentation
+//!  ├── SHA1SUMS                - SHA1 of every other file, checked when opening
+//!  └── ...                     - Extra files listed in the manifest's `extra_files`, if any
 //!
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
 use std::fmt;
-use std::fs;
-use std::process::{Command, Stdio};
+use std::fs::{self, File};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use clap::Parser;
 use anyhow::{Result, bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder};
 use temp_dir::TempDir;
 use pyo3::prelude::*;
+use serde::Serialize;
+use sha1::{Sha1, Digest};
 
+use crate::cache::{self, Sha256};
 use crate::package::InitialisedPackage;
-use crate::rom::Rom;
+use crate::rom::{Rom, Region};
 
-use super::init::{InitialiseOptions, BuildRomOptions};
+use super::init::{InitialiseOptions, BuildRomOptions, DEFAULT_TARGET};
+use super::patch;
+use super::progress::{Phase, Progress};
 use super::{
     Package,
     ROOT_DIR_NAME,
     MANIFEST_FILE_NAME,
+    LOCKFILE_FILE_NAME,
     README_FILE_NAME,
     LICENSE_FILE_NAME,
-    PATCHES_DIR_NAME, Manifest,
+    PATCHES_DIR_NAME,
+    BUNDLED_DEPS_DIR_NAME,
+    Manifest,
+    PackageId,
+    normalize_relative_path,
 };
+use super::manifest::PatchFormat;
+use super::resolve::{IndexEntry, LocalIndex};
 
-const EXTENSION: &str = "merlon";
+pub mod archive;
+pub mod crypto;
+pub mod diff;
+pub mod error;
+pub mod header;
+pub mod install;
+pub mod list;
+pub mod signing;
+pub mod verify;
+mod parallel;
+pub use archive::Compression;
+pub use diff::PatchDiff;
+pub use error::ExportError;
+pub use header::Header;
+pub use install::InstallOptions;
+pub use list::{Listing, ListedPatch};
+pub use signing::PayloadSignature;
+pub use verify::{VerifyOptions, VerifyReport};
+
+pub(crate) const EXTENSION: &str = "merlon";
+
+/// File inside distributables listing the SHA1 of every other file, checked when opening.
+const CHECKSUMS_FILE_NAME: &str = "SHA1SUMS";
+
+/// Distributables created before [`Header`] existed begin with openssl's magic bytes instead.
+const LEGACY_MAGIC: &[u8] = crypto::SALTED_MAGIC;
+
+/// Directory inside the package that intermediate files are kept in when exporting with `keep_intermediates`.
+const INTERMEDIATES_DIR_NAME: &str = ".merlon/export";
+
+/// Name of the directory that intermediate files are written to inside a target directory.
+const INTERMEDIATES_SUBDIR_NAME: &str = "export";
+
+/// Environment variable that sets [`ExportOptions::target_dir`] if it is not given.
+pub const TARGET_DIR_ENV_VAR: &str = "MERLON_TARGET_DIR";
+
+/// Environment variable with the path to a base ROM, used when `--baserom` is not given. See
+/// [`Package::resolve_baserom`].
+pub const BASEROM_ENV_VAR: &str = "MERLON_BASEROM";
+
+/// Patches at least this large that are mostly `GIT binary patch` data are stored gzip-compressed in the archive.
+const LARGE_BINARY_PATCH_SIZE: u64 = 1024 * 1024;
+
+/// Extension appended to patches that are stored gzip-compressed inside a distributable.
+/// The extension is what records the codec, so opening a distributable knows to decompress them.
+const COMPRESSED_PATCH_EXTENSION: &str = "gz";
+
+/// Distributables larger than this get a warning when exported, unless `warn_size` is set, as they probably contain a
+/// large file by accident.
+pub const DEFAULT_WARN_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Number of patches listed in [`ExportPlan::largest_patches`].
+const LARGEST_PATCHES_LISTED: usize = 5;
 
 /// A package in the form of a distributable file.
 #[derive(Debug)]
 #[pyclass(module = "merlon.package.distribute")]
 pub struct Distributable {
     path: PathBuf,
+
+    /// Directory holding the file at `path` if it was read from memory. Deleted when the distributable is dropped.
+    _temp_dir: Option<TempDir>,
+
+    /// Facts about the export, if this distributable was just exported.
+    export_summary: Option<ExportSummary>,
+}
+
+/// Facts about a distributable gathered while exporting it, for reporting.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportSummary {
+    /// Path the distributable was written to.
+    pub path: PathBuf,
+
+    /// The `name@version` of the exported package.
+    pub package: String,
+
+    /// Name of the exported package.
+    pub name: String,
+
+    /// Version of the exported package.
+    pub version: String,
+
+    /// Size of the distributable file in bytes.
+    pub size: u64,
+
+    /// Total size in bytes of the package's files before compression and encryption.
+    pub uncompressed_size: u64,
+
+    /// SHA256 of the distributable file.
+    pub sha256: String,
+
+    /// SHA1 of the encrypted payload, as recorded in the distributable's header.
+    pub payload_sha1: String,
+
+    /// The decomp revision the package is based on.
+    pub decomp_rev: Option<String>,
+
+    /// Number of patch files. With the default patch format, this is the number of commits.
+    pub patches: usize,
+
+    /// Files included in the distributable, relative to its root.
+    pub files: Vec<String>,
+}
+
+/// What exporting a package would produce, from [`Package::plan_export`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportPlan {
+    /// Path the distributable would be written to.
+    pub path: PathBuf,
+
+    /// The `name@version` of the package.
+    pub package: String,
+
+    /// Total size in bytes of the files that would be packaged, before compression and encryption.
+    pub uncompressed_size: u64,
+
+    /// The decomp revision the package is based on.
+    pub decomp_rev: Option<String>,
+
+    /// Patch files that would be included, relative to the distributable's root.
+    pub patches: Vec<String>,
+
+    /// Every other file that would be included, such as `merlon.toml` and `README.md`.
+    pub files: Vec<String>,
+
+    /// The largest patch files, largest first, to find what is making a distributable big.
+    pub largest_patches: Vec<ListedPatch>,
 }
 
 /// Options for [`Package::export_distributable`].
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug, Clone, Default)]
 #[pyclass(module = "merlon.package.distribute")]
 pub struct ExportOptions {
     /// The output path to write the distributable to.
     ///
     /// If not specified, the default is `NAME VERSION.merlon`, where `NAME` is the name of the package
-    /// and `VERSION` is the package version as specified in `merlon.toml`.
+    /// and `VERSION` is the package version as specified in `merlon.toml`. On the command line, `-` writes it to
+    /// stdout, and everything else that would be printed goes to stderr.
     #[arg(short, long)]
     #[pyo3(get, set)]
     pub output: Option<PathBuf>,
 
     /// The base ROM to use as the encryption key.
     ///
-    /// If not specified and the package is initialised, `papermario/ver/us/baserom.z64` will be used.
+    /// If not specified, the `MERLON_BASEROM` environment variable is used, then `baserom` in the `[package]`
+    /// section of `merlon.toml`, and finally `papermario/ver/<region>/baserom.z64` if the package is initialised.
     #[arg(long)]
     #[pyo3(get, set)]
     pub baserom: Option<PathBuf>,
+
+    /// Keep intermediate files in `.merlon/export` for debugging.
+    ///
+    /// By default, the staged package directory, tarball, and encrypted payload are deleted once the distributable
+    /// has been written. Note that the intermediates include an unencrypted copy of the package.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub keep_intermediates: bool,
+
+    /// Commit untracked files in the decomp repository that would otherwise be left out of the package.
+    ///
+    /// Without this, exporting errors if there are untracked files that would be exported once committed.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub include_untracked: bool,
+
+    /// Export even if the decomp repository has uncommitted changes, which are left out of the distributable.
+    ///
+    /// Without this, exporting errors if any file that would be exported has changes that are not committed, since
+    /// they would silently be missing from the package.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub allow_dirty: bool,
+
+    /// Export even if the package has no patches, for packages that only carry metadata and documentation.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub allow_empty: bool,
+
+    /// Build the package's ROM and record its SHA1 in the distributable.
+    ///
+    /// This lets people applying the distributable check with `merlon apply --verify-after` that they built exactly
+    /// the same ROM. Requires the package to be initialised.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub record_rom: bool,
+
+    /// Check that the exported patches apply cleanly onto the package's decomp revision before writing the output.
+    ///
+    /// The distributable is opened as a recipient would open it, and its patches are applied to a scratch clone of
+    /// the decomp repository. If any fail, nothing is written. Requires the package to be initialised.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub verify: bool,
+
+    /// Directory to write intermediate files to, instead of a temporary directory.
+    ///
+    /// Can also be set with the `MERLON_TARGET_DIR` environment variable. Intermediates are written to `export/`
+    /// inside it and deleted afterwards unless `--keep-intermediates` is set. If `--keep-intermediates` is set without
+    /// a target directory, the package's `.merlon` directory is used. This does not affect where the distributable
+    /// itself is written.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub target_dir: Option<PathBuf>,
+
+    /// Number of PBKDF2 iterations used to derive the encryption key from the base ROM.
+    ///
+    /// Defaults to 100000. The value is recorded in the distributable so it can be opened again.
+    #[arg(long, visible_alias = "iter")]
+    #[pyo3(get, set)]
+    pub kdf_iter: Option<u32>,
+
+    /// Digest used by PBKDF2 to derive the encryption key from the base ROM, either `sha256` or `sha512`.
+    ///
+    /// Defaults to `sha512`. The value is recorded in the distributable so it can be opened again.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub kdf_digest: Option<String>,
+
+    /// How to store the package's changes in the distributable.
+    ///
+    /// `git-am` stores one patch per commit, preserving history. `diff` stores a single unified diff that can be
+    /// applied without Git. Defaults to `patch_format` in `merlon.toml`, which is `git-am` unless set. A different
+    /// format only applies to the distributable; the package's own `merlon.toml` and patches are left as they are.
+    #[arg(long, value_enum)]
+    #[pyo3(get, set)]
+    pub patch_format: Option<PatchFormat>,
+
+    /// How to compress the package, either `bzip2`, `zstd`, or `none`.
+    ///
+    /// Defaults to `bzip2`, which every version of Merlon can open. `zstd` is much faster for large packages, but
+    /// needs this version of Merlon or later to open. The choice is recorded in the distributable.
+    #[arg(long, value_enum)]
+    #[pyo3(get, set)]
+    pub compression: Option<Compression>,
+
+    /// Compression level, from 1 to 9 for `bzip2` or 1 to 22 for `zstd`.
+    ///
+    /// Defaults to 9 for `bzip2` and 3 for `zstd`.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub level: Option<u32>,
+
+    /// Directory of `.merlon` files to bundle the package's dependencies from.
+    ///
+    /// Each dependency, and each of their dependencies, must be satisfied by a distributable in the directory. They
+    /// are embedded in the distributable under `deps/`, so that it can be applied without obtaining them separately.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub bundle_deps: Option<PathBuf>,
+
+    /// Sign the distributable with the ed25519 private key in this file.
+    ///
+    /// The key may be PKCS#8 PEM, as made by `openssl genpkey -algorithm ed25519`, or 32 bytes in hex. The
+    /// signature and public key are recorded in the distributable, so recipients can check who made it with
+    /// `merlon verify --check-signature`.
+    #[arg(long, value_name = "KEYFILE")]
+    #[pyo3(get, set)]
+    pub sign: Option<PathBuf>,
+
+    /// Write an unencrypted, uncompressed tar of the package's patches and files instead of a distributable.
+    ///
+    /// This is meant for reviewing changes with people who already have the decomp, so no base ROM is needed.
+    /// Since it cannot be opened or applied like a distributable, it defaults to `NAME VERSION.patches.tar` and may
+    /// not be given a `.merlon` extension.
+    #[arg(long, conflicts_with_all = ["baserom", "sign", "bundle_deps", "record_rom", "verify", "compression", "level"])]
+    #[pyo3(get, set)]
+    pub plain: bool,
+
+    /// Number of threads to prepare patches and checksum files with. Defaults to the number of CPUs.
+    ///
+    /// Compression itself always runs on a single thread, so the distributable is identical whatever this is set to.
+    #[arg(short, long)]
+    #[pyo3(get, set)]
+    pub jobs: Option<usize>,
+
+    /// Overwrite the output file if it already exists.
+    ///
+    /// Without this, exporting refuses to replace an existing file, so that a published distributable is not lost.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub force: bool,
+
+    /// Make an output path without the `.merlon` extension an error, rather than a warning.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub strict: bool,
+
+    /// Warn if the distributable is larger than this, like `500K`, `100M` or `1G`. Defaults to 50M.
+    ///
+    /// A large distributable usually means a file was committed by accident, such as a baserom or build output.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[pyo3(get, set)]
+    pub warn_size: Option<u64>,
+
+    /// Refuse to write a distributable larger than this, like `500K`, `100M` or `1G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[pyo3(get, set)]
+    pub max_size: Option<u64>,
+}
+
+impl ExportOptions {
+    /// The number of jobs to export with.
+    fn jobs(&self) -> Result<usize> {
+        match self.jobs {
+            Some(0) => bail!("--jobs must be at least 1"),
+            Some(jobs) => Ok(jobs),
+            None => Ok(parallel::default_jobs()),
+        }
+    }
+}
+
+/// Errors if a distributable of `size` bytes is larger than `max_size`, and returns a warning to show if it is larger
+/// than `warn_size`.
+fn check_export_size(size: u64, options: &ExportOptions) -> Result<Option<String>> {
+    if let Some(max_size) = options.max_size {
+        if size > max_size {
+            return Err(ExportError::TooLarge { size, max_size }.into());
+        }
+    }
+    let warn_size = options.warn_size.unwrap_or(DEFAULT_WARN_SIZE);
+    if size <= warn_size {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "distributable is {}, more than {}; run `merlon export --dry-run` to see the largest patches, and check for \
+        files like a baserom or build output that were committed by accident",
+        format_bytes(size),
+        format_bytes(warn_size),
+    )))
+}
+
+/// Parses a size like `500K`, `100M` or `1G`, in binary units, or a number of bytes.
+fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&size[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&size[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    number.trim().parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {:?}, expected a number of bytes or a size like 500K, 100M or 1G", size))
 }
 
 /// Options for [`Distributable::apply`].
 #[derive(Parser, Debug, Clone)]
 #[pyclass(module = "merlon.package.distribute")]
 pub struct ApplyOptions {
-    /// The base ROM path.
-    #[arg(long)]
+    /// The base ROM path. Defaults to the `MERLON_BASEROM` environment variable.
+    #[arg(long, env = BASEROM_ENV_VAR)]
     #[pyo3(get, set)]
     pub baserom: PathBuf,
 
@@ -77,6 +412,13 @@ pub struct ApplyOptions {
     #[clap(flatten)]
     #[pyo3(get, set)]
     pub build_rom_options: BuildRomOptions,
+
+    /// After building, check that the ROM's SHA1 matches the one recorded by the package author.
+    ///
+    /// A mismatch means the ROM was built differently, usually because of a different toolchain or environment.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub verify_after: bool,
 }
 
 /// Options for [`Distributable::open_to_dir`].
@@ -91,8 +433,8 @@ pub struct OpenOptions {
     #[pyo3(get, set)]
     pub output: Option<PathBuf>,
 
-    /// The base ROM path.
-    #[arg(long)]
+    /// The base ROM path. Defaults to the `MERLON_BASEROM` environment variable.
+    #[arg(long, env = BASEROM_ENV_VAR)]
     #[pyo3(get, set)]
     pub baserom: PathBuf,
 }
@@ -101,126 +443,562 @@ pub struct OpenOptions {
 impl Package {
     /// Exports the package as a distributable `.merlon` file.
     pub fn export_distributable(&self, options: ExportOptions) -> Result<Distributable> {
-        let baserom_path = match options.baserom {
-            Some(baserom) => baserom,
-            None => {
-                InitialisedPackage::try_from(self.clone())
-                    .context("failed to get baserom from package, try specifying --baserom or running `merlon init`")?
-                    .baserom_path()
-            },
+        self.export_distributable_with_progress(options, &Progress::default())
+    }
+}
+
+impl Package {
+    /// Exports the package as [`Package::export_distributable`] does, reporting each phase of the export to
+    /// `progress`.
+    pub fn export_distributable_with_progress(&self, options: ExportOptions, progress: &Progress) -> Result<Distributable> {
+        if options.plain {
+            bail!("a plain export is not a distributable, use export_plain instead");
+        }
+        self.ensure_has_metadata_files()?;
+        let baserom_path = self.export_baserom(&options)?;
+        let output_path = self.export_output_path(&options)?;
+        ensure_can_write_output(&output_path, options.force)?;
+        let jobs = options.jobs()?;
+        let signing_key = options.sign.as_deref().map(signing::read_signing_key).transpose()?;
+
+        let mut header = Header::new();
+        if let Some(kdf_iter) = options.kdf_iter {
+            header.kdf_iterations = kdf_iter;
+        }
+        if let Some(kdf_digest) = &options.kdf_digest {
+            header.kdf_digest = kdf_digest.clone();
+        }
+        header.validate_kdf()?;
+        if let Some(compression) = options.compression {
+            header.compression = compression;
+        }
+        if let Some(level) = options.level {
+            header.compression.validate_level(level)?;
+        }
+        {
+            let manifest = self.manifest()?;
+            header.package_id = Some(manifest.metadata().id());
+            header.package = Some(manifest.metadata().versioned_id());
+        }
+
+        // The package itself is left in its own format, so only the export's patches are regenerated
+        let regenerate_patches = match options.patch_format {
+            Some(patch_format) if patch_format != self.manifest()?.metadata().patch_format() => {
+                let mut initialised = InitialisedPackage::try_from(self.clone())
+                    .context("--patch-format requires the package to be initialised, to regenerate its patches")?;
+                initialised.set_allow_dirty(options.allow_dirty);
+                initialised.set_progress(progress.clone());
+                Some((initialised, patch_format))
+            }
+            _ => None,
         };
-        if !baserom_path.is_file() {
-            bail!("baserom {:?} is not a file", baserom_path);
+        if !options.allow_empty {
+            if let Ok(initialised) = InitialisedPackage::try_from(self.clone()) {
+                initialised.ensure_has_changes()?;
+            }
         }
+        self.warn_about_generated_files()?;
 
-        let output_path = match options.output {
-            Some(output) => output,
-            None => {
-                let manifest = self.manifest()?;
-                let metadata = manifest.metadata();
-                PathBuf::from(format!("{} {}.merlon", metadata.name(), metadata.version()))
-            },
+        let record_rom_package = if options.record_rom {
+            let initialised = InitialisedPackage::try_from(self.clone())
+                .context("--record-rom requires the package to be initialised")?;
+            Some(initialised)
+        } else {
+            None
+        };
+        let verify_repo = if options.verify {
+            let initialised = InitialisedPackage::try_from(self.clone())
+                .context("--verify requires the package to be initialised")?;
+            Some(initialised.subrepo_path())
+        } else {
+            None
         };
 
-        let tempdir = temp_dir::TempDir::new()?;
-        let tar_path = tempdir.path().join("package.tar.bz2");
-        let encrypted_path = tempdir.path().join("package.merlon");
+        if let Ok(initialised) = InitialisedPackage::try_from(self.clone()) {
+            ensure_outside_dir(&output_path, &initialised.subrepo_path())?;
+        }
+
+        let target_dir = options.target_dir.clone()
+            .or_else(|| std::env::var_os(TARGET_DIR_ENV_VAR).map(PathBuf::from));
+        if let (Some(target_dir), Ok(initialised)) = (&target_dir, InitialisedPackage::try_from(self.clone())) {
+            ensure_outside_dir(&target_dir.join(INTERMEDIATES_SUBDIR_NAME), &initialised.subrepo_path())?;
+        }
+
+        // Intermediate files live in a temporary directory that is deleted on drop, unless we were asked to keep them
+        // or to put them in a target directory
+        let tempdir;
+        let work_dir = if options.keep_intermediates || target_dir.is_some() {
+            let dir = match &target_dir {
+                Some(target_dir) => target_dir.join(INTERMEDIATES_SUBDIR_NAME),
+                None => self.path.join(INTERMEDIATES_DIR_NAME),
+            };
+            if dir.exists() {
+                fs::remove_dir_all(&dir)
+                    .with_context(|| format!("failed to clear intermediates directory {}", dir.display()))?;
+            }
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("failed to create intermediates directory {}", dir.display()))?;
+            dir
+        } else {
+            tempdir = TempDir::new()?;
+            tempdir.path().to_owned()
+        };
+        let _cleanup = scopeguard::guard(work_dir.clone(), |dir| {
+            if target_dir.is_some() && !options.keep_intermediates {
+                let _ = fs::remove_dir_all(dir);
+            }
+        });
+        let tar_path = work_dir.join(format!("package.{}", header.compression.extension()));
 
         // TODO: include a binary patch for the baserom so basic users dont have to build from source
 
         // Copy files into a temporary directory with the correct structure
-        let root_dir = tempdir.path().join(ROOT_DIR_NAME);
-        fs::create_dir(&root_dir)?;
-        fs::copy(&self.path.join(MANIFEST_FILE_NAME), &root_dir.join(MANIFEST_FILE_NAME))?;
-        fs::copy(&self.path.join(README_FILE_NAME), &root_dir.join(README_FILE_NAME))?;
-        fs::copy(&self.path.join(LICENSE_FILE_NAME), &root_dir.join(LICENSE_FILE_NAME))?;
-        fs::create_dir(&root_dir.join(PATCHES_DIR_NAME))?;
-        for entry in fs::read_dir(&self.path.join(PATCHES_DIR_NAME))? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                fs::copy(&path, &root_dir.join(PATCHES_DIR_NAME).join(path.file_name().unwrap()))?;
-            }
+        let root_dir = work_dir.join(ROOT_DIR_NAME);
+        self.stage_export(&root_dir, jobs, progress)?;
+        if let Some((initialised, patch_format)) = &regenerate_patches {
+            let regenerated = TempDir::new().context("failed to create temporary directory")?;
+            initialised.write_patches_to(regenerated.path(), *patch_format)
+                .context("failed to regenerate patches in the new format")?;
+            let staged_patches = root_dir.join(PATCHES_DIR_NAME);
+            fs::remove_dir_all(&staged_patches)?;
+            fs::create_dir(&staged_patches)?;
+            stage_patches(regenerated.path(), &staged_patches, jobs, progress)?;
+            let staged_manifest = root_dir.join(MANIFEST_FILE_NAME);
+            let mut manifest = Manifest::read_from_path(&staged_manifest)?;
+            manifest.metadata_mut().set_patch_format(*patch_format);
+            manifest.write_to_file(&staged_manifest)?;
+        }
+        if let Some(packages_dir) = &options.bundle_deps {
+            header.bundled_dependencies = self.bundle_dependencies(packages_dir, &baserom_path, &root_dir.join(BUNDLED_DEPS_DIR_NAME))?;
         }
 
-        // Compress directory into a tar
-        let status = Command::new("tar")
-            .arg("--no-xattrs") // Avoid com.apple.provenance
-            .arg("-cjvf")
-            .arg(&tar_path)
-            .arg("-C").arg(tempdir.path())
-            .arg(ROOT_DIR_NAME)
-            .stderr(Stdio::null())
-            .status()?;
-        if !status.success() {
-            bail!("failed to compress to tar {}", tar_path.display());
+        // The staged patches, which --patch-format may have regenerated
+        header.patch_empty = fs::read_dir(root_dir.join(PATCHES_DIR_NAME))?.next().is_none();
+        if header.patch_empty {
+            log::info!("exporting {} without patches", self);
         }
 
+        write_checksums(&root_dir, jobs)?;
+        let files = relative_file_paths(&root_dir)?;
+        let mut uncompressed_size = 0;
+        for path in &files {
+            uncompressed_size += fs::metadata(root_dir.join(path))?.len();
+        }
+
+        // Compress directory into a reproducible tar
+        let tar = archive::tar_dir_compressed(&work_dir, ROOT_DIR_NAME, Some(CHECKSUMS_FILE_NAME), header.compression, options.level, jobs, progress)?;
+        fs::write(&tar_path, &tar)
+            .with_context(|| format!("failed to write {}", tar_path.display()))?;
+
         // Encrypt the tar using baserom as hash
-        let status = Command::new("openssl")
-            .arg("enc")
-            .arg("-aes-256-cbc")
-            .arg("-md").arg("sha512")
-            .arg("-pbkdf2")
-            .arg("-iter").arg("100000")
-            .arg("-salt")
-            .arg("-in").arg(&tar_path)
-            .arg("-out").arg(&encrypted_path)
-            .arg("-pass").arg(format!("file:{}", baserom_path.display()))
-            .status()?;
-        if !status.success() {
-            bail!("failed to encrypt tar to {}", encrypted_path.display());
+        progress.report(Phase::Encrypting, 0, Some(1));
+        let passphrase = crypto::passphrase_from_file(&baserom_path)?;
+        let encrypted = crypto::encrypt(&tar, &passphrase, &header)
+            .context("failed to encrypt package")?;
+        progress.report(Phase::Encrypting, 1, Some(1));
+        if let Some(warning) = check_export_size(encrypted.len() as u64, &options)? {
+            log::warn!("{}", warning);
         }
+        let payload_sha1 = header::payload_sha1(&encrypted);
+        header.payload_sha1 = Some(payload_sha1.clone());
+        header.signature = signing_key.map(|key| PayloadSignature::sign(&key, &encrypted));
 
-        // Copy encrypted tar to output
-        fs::copy(&encrypted_path, &output_path)?;
+        if let Some(repo) = &verify_repo {
+            if header.patch_empty {
+                log::info!("not verifying patches: the package has none");
+            } else if !self.manifest()?.package_dependency_ids().is_empty() {
+                log::warn!("not verifying patches: they apply on top of the package's dependencies");
+            } else {
+                // Check the distributable itself, so that anything lost in staging or compression is caught too
+                let staged = Distributable { path: work_dir.join("verify.merlon"), _temp_dir: None, export_summary: None };
+                let mut file = io::BufWriter::new(File::create(staged.path())?);
+                header.write_to(&mut file)?;
+                io::Write::write_all(&mut file, &encrypted)?;
+                io::Write::flush(&mut file)?;
+                drop(file);
+                staged.open_scoped(baserom_path.clone(), |package| verify::check_patches_apply(&package, repo))
+                    .context("the exported patches do not apply cleanly onto the package's decomp revision, so the \
+                        distributable was not written")?;
+            }
+        }
+
+        // Building the ROM takes the longest, so it is left until everything else has been checked
+        if let Some(initialised) = &record_rom_package {
+            let rom = initialised.build_rom(BuildRomOptions::default())
+                .context("failed to build ROM to record its SHA1")?;
+            header.rom_sha1 = Some(rom.sha1_string()?);
+        }
 
-        Distributable::try_from(output_path)
+        // Write header followed by encrypted tar to output
+        let mut output = io::BufWriter::new(create_output_file(&output_path, options.force)?);
+        header.write_to(&mut output)?;
+        io::Write::write_all(&mut output, &encrypted)?;
+        io::Write::flush(&mut output)?;
+        drop(output);
+        if options.keep_intermediates {
+            log::info!("kept intermediate files in {}", work_dir.display());
+        }
+
+        let manifest = self.manifest()?;
+        let patches = files.iter().filter(|path| path.starts_with(&format!("{}/", PATCHES_DIR_NAME))).count();
+        // Not `Distributable::try_from`, which would refuse an output path without the .merlon extension
+        let mut distributable = Distributable { path: output_path.clone(), _temp_dir: None, export_summary: None };
+        distributable.export_summary = Some(ExportSummary {
+            path: output_path.clone(),
+            package: manifest.metadata().versioned_id().to_string(),
+            name: manifest.metadata().name().to_string(),
+            version: manifest.metadata().version().to_string(),
+            size: distributable.size()?,
+            uncompressed_size,
+            sha256: Sha256::of(&fs::read(&output_path)?).to_string(),
+            payload_sha1,
+            decomp_rev: manifest.get_direct_decomp_dependency_rev().map(str::to_owned),
+            patches,
+            files,
+        });
+        Ok(distributable)
+    }
+}
+
+impl Package {
+    /// Finds the base ROM that [`Package::export_distributable`] would encrypt with, and checks that it exists and is
+    /// an unmodified US ROM.
+    ///
+    /// This is the first thing exporting does, but callers that regenerate patches before exporting should call it
+    /// first themselves, so that a missing or wrong base ROM is reported before any work is done.
+    pub fn export_baserom(&self, options: &ExportOptions) -> Result<PathBuf> {
+        let baserom_path = self.resolve_baserom(options.baserom.as_deref())?;
+        check_baserom(&baserom_path, self.manifest()?.metadata().region())?;
+        Ok(baserom_path)
+    }
+
+    /// Finds the base ROM to use for this package. In order of precedence, it is:
+    ///
+    /// 1. `explicit`, which is `--baserom` on the command line
+    /// 2. the `MERLON_BASEROM` environment variable, so that one copy can be shared between packages
+    /// 3. `baserom` in the `[package]` section of `merlon.toml`, relative to the package directory
+    /// 4. `papermario/ver/<region>/baserom.z64`, if the package is initialised
+    ///
+    /// The file itself is not checked; [`Package::export_baserom`] also checks that it exists and is the right ROM.
+    pub fn resolve_baserom(&self, explicit: Option<&Path>) -> Result<PathBuf> {
+        let from_env = std::env::var_os(BASEROM_ENV_VAR).filter(|path| !path.is_empty()).map(PathBuf::from);
+        self.resolve_baserom_with_env(explicit, from_env)
+    }
+
+    fn resolve_baserom_with_env(&self, explicit: Option<&Path>, from_env: Option<PathBuf>) -> Result<PathBuf> {
+        if let Some(path) = explicit {
+            return Ok(path.to_owned());
+        }
+        if let Some(path) = from_env {
+            log::info!("using baserom {} from {}", path.display(), BASEROM_ENV_VAR);
+            return Ok(path);
+        }
+        if let Some(path) = self.manifest()?.metadata().baserom() {
+            return Ok(self.path().join(path));
+        }
+        InitialisedPackage::try_from(self.clone())
+            .with_context(|| format!(
+                "failed to get baserom from package, try specifying --baserom, setting {} or running `merlon init`",
+                BASEROM_ENV_VAR,
+            ))?
+            .baserom_path()
+    }
+
+    /// Reports what [`Package::export_distributable`] would include, without writing a distributable.
+    ///
+    /// Files are staged in a temporary directory exactly as they would be for a real export, so the listed files and
+    /// size are accurate. No base ROM is needed, and `merlon.toml` is not changed even if `patch_format` differs.
+    pub fn plan_export(&self, options: &ExportOptions) -> Result<ExportPlan> {
+        if let Some(patch_format) = options.patch_format {
+            if self.manifest()?.metadata().patch_format() != patch_format {
+                log::warn!("listing patches in the current format; exporting will regenerate them as {:?}", patch_format);
+            }
+        }
+
+        let work_dir = TempDir::new().context("failed to create temporary directory")?;
+        let root_dir = work_dir.path().join(ROOT_DIR_NAME);
+        let jobs = options.jobs()?;
+        self.stage_export(&root_dir, jobs, &Progress::default())?;
+        write_checksums(&root_dir, jobs)?;
+
+        let mut uncompressed_size = 0;
+        let mut patches = Vec::new();
+        let mut files = Vec::new();
+        let mut largest_patches = Vec::new();
+        for path in relative_file_paths(&root_dir)? {
+            let size = fs::metadata(root_dir.join(&path))?.len();
+            uncompressed_size += size;
+            if path.starts_with(&format!("{}/", PATCHES_DIR_NAME)) {
+                // Compressed patches have no readable subject
+                let subject = fs::read(root_dir.join(&path)).ok()
+                    .and_then(|contents| patch::subject(&String::from_utf8_lossy(&contents)));
+                largest_patches.push(ListedPatch { path: path.clone(), subject, size });
+                patches.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+        largest_patches.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        largest_patches.truncate(LARGEST_PATCHES_LISTED);
+
+        let manifest = self.manifest()?;
+        Ok(ExportPlan {
+            path: self.export_output_path(options)?,
+            package: manifest.metadata().versioned_id().to_string(),
+            uncompressed_size,
+            decomp_rev: manifest.get_direct_decomp_dependency_rev().map(str::to_owned),
+            patches,
+            files,
+            largest_patches,
+        })
+    }
+
+    /// Writes the package's patches and files to an unencrypted tar for review, as `--plain` does, and returns its
+    /// path. The tar contains a single `merlon_v1/` directory laid out as in a distributable.
+    pub fn export_plain(&self, options: &ExportOptions) -> Result<PathBuf> {
+        let output_path = self.export_output_path(&ExportOptions { plain: true, ..options.clone() })?;
+        ensure_can_write_output(&output_path, options.force)?;
+        if let Ok(initialised) = InitialisedPackage::try_from(self.clone()) {
+            ensure_outside_dir(&output_path, &initialised.subrepo_path())?;
+        }
+        self.warn_about_generated_files()?;
+
+        let work_dir = TempDir::new().context("failed to create temporary directory")?;
+        self.stage_export(&work_dir.path().join(ROOT_DIR_NAME), options.jobs()?, &Progress::default())?;
+        let tar = archive::tar_dir(work_dir.path(), ROOT_DIR_NAME, Some(CHECKSUMS_FILE_NAME))?;
+        io::Write::write_all(&mut create_output_file(&output_path, options.force)?, &tar)
+            .with_context(|| format!("failed to write {}", output_path.display()))?;
+        Ok(output_path)
+    }
+
+    /// Copies the distributables that satisfy this package's dependencies, and their dependencies in turn, from
+    /// `packages_dir` into `dest`. Returns the bundled packages.
+    ///
+    /// Bundled distributables are opened with `baserom` to read their own dependencies.
+    fn bundle_dependencies(&self, packages_dir: &Path, baserom: &Path, dest: &Path) -> Result<Vec<PackageId>> {
+        let index = LocalIndex::scan(packages_dir)?;
+        let own_id = self.id()?;
+        let mut bundled: Vec<IndexEntry> = Vec::new();
+        let mut pending = vec![self.manifest()?];
+        while let Some(manifest) = pending.pop() {
+            let resolution = index.resolve(&manifest);
+            if let Some(unmet) = resolution.unmet.first() {
+                bail!(
+                    "cannot bundle dependency {} of {}: no distributable in {} satisfies it",
+                    unmet,
+                    manifest.metadata().versioned_id(),
+                    packages_dir.display(),
+                );
+            }
+            for resolved in resolution.resolved {
+                if resolved.entry.id == own_id {
+                    continue;
+                }
+                if let Some(existing) = bundled.iter().find(|entry| entry.id == resolved.entry.id) {
+                    if !resolved.dependency.matches(&existing.package.version) {
+                        bail!(
+                            "cannot bundle dependencies: {} requires {}, but {} is already bundled",
+                            manifest.metadata().versioned_id(),
+                            resolved.dependency,
+                            existing.package,
+                        );
+                    }
+                    continue;
+                }
+                log::info!("bundling dependency {}", resolved);
+                let distributable = Distributable::try_from(resolved.entry.path.clone())?;
+                pending.push(distributable.manifest(baserom.to_owned())
+                    .with_context(|| format!("failed to read dependencies of {}", resolved))?);
+                bundled.push(resolved.entry);
+            }
+        }
+
+        fs::create_dir_all(dest)?;
+        for entry in &bundled {
+            fs::copy(&entry.path, dest.join(format!("{}.{}", entry.id, EXTENSION)))
+                .with_context(|| format!("failed to bundle {}", entry.path.display()))?;
+        }
+        Ok(bundled.into_iter().map(|entry| entry.package).collect())
+    }
+
+    /// The path a distributable will be written to, `NAME VERSION.merlon` unless `options.output` is given.
+    fn export_output_path(&self, options: &ExportOptions) -> Result<PathBuf> {
+        let path = match &options.output {
+            Some(output) => output.clone(),
+            None => {
+                let manifest = self.manifest()?;
+                let metadata = manifest.metadata();
+                let extension = if options.plain { "patches.tar" } else { "merlon" };
+                PathBuf::from(format!("{} {}.{}", metadata.name(), metadata.version(), extension))
+            },
+        };
+        let has_extension = path.extension() == Some(EXTENSION.as_ref());
+        if options.plain && has_extension {
+            bail!("refusing to write a plain export to {}: the .merlon extension is for distributables", path.display());
+        }
+        if !options.plain && !has_extension {
+            if options.strict {
+                bail!("refusing to write a distributable to {}: it must have the .merlon extension", path.display());
+            }
+            log::warn!("{} does not have the .merlon extension, so it will not be recognised as a distributable", path.display());
+        }
+        Ok(path)
+    }
+
+    /// Errors with [`ExportError::MissingMetadata`] if a file that every distributable includes is missing.
+    fn ensure_has_metadata_files(&self) -> Result<()> {
+        for file_name in [MANIFEST_FILE_NAME, README_FILE_NAME, LICENSE_FILE_NAME] {
+            if !self.path.join(file_name).is_file() {
+                return Err(ExportError::MissingMetadata(file_name).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the files that make up a distributable into `root_dir`, which must not exist yet. Patches are prepared
+    /// on up to `jobs` threads, and reported to `progress` as they are staged.
+    fn stage_export(&self, root_dir: &Path, jobs: usize, progress: &Progress) -> Result<()> {
+        self.ensure_has_metadata_files()?;
+        fs::create_dir(root_dir)?;
+        let staged_manifest = root_dir.join(MANIFEST_FILE_NAME);
+        fs::copy(self.manifest_path(), &staged_manifest)?;
+        // The authors file is not bundled, so its authors are listed in the manifest instead
+        let mut manifest = self.manifest()?;
+        if manifest.metadata_mut().inline_authors_file() {
+            manifest.write_to_file(&staged_manifest)?;
+        }
+        if self.lockfile_path().is_file() {
+            fs::copy(self.lockfile_path(), root_dir.join(LOCKFILE_FILE_NAME))?;
+        }
+        fs::copy(self.path.join(README_FILE_NAME), root_dir.join(README_FILE_NAME))?;
+        fs::copy(self.path.join(LICENSE_FILE_NAME), root_dir.join(LICENSE_FILE_NAME))?;
+        for extra_file in self.manifest()?.metadata().extra_files() {
+            let relative = match normalize_relative_path(extra_file) {
+                Ok(relative) => relative,
+                Err(error) => {
+                    log::warn!("skipping extra file: {}", error);
+                    continue;
+                },
+            };
+            let source = self.path.join(&relative);
+            if !source.is_file() {
+                log::warn!("skipping extra file {:?}: {} is not a file", extra_file, source.display());
+                continue;
+            }
+            let dest = root_dir.join(&relative);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&source, &dest)
+                .with_context(|| format!("failed to copy extra file {:?}", extra_file))?;
+        }
+        let patches_dir = root_dir.join(PATCHES_DIR_NAME);
+        fs::create_dir(&patches_dir)?;
+        stage_patches(&self.path.join(PATCHES_DIR_NAME), &patches_dir, jobs, progress)
+    }
+
+    /// Logs a warning for each path touched by the package's patches that looks like a build artifact, or that
+    /// decomp's `.gitignore` would ignore. This is a heuristic, so it only warns.
+    fn warn_about_generated_files(&self) -> Result<()> {
+        let touched = patch::touched_paths_in_dir(&self.path.join(PATCHES_DIR_NAME))?;
+        let mut suspicious: BTreeSet<String> = touched.iter()
+            .filter(|path| patch::is_generated_path(path))
+            .cloned()
+            .collect();
+        if !touched.is_empty() {
+            if let Ok(initialised) = InitialisedPackage::try_from(self.clone()) {
+                suspicious.extend(initialised.git_check_ignore(&touched)?);
+            }
+        }
+        for path in suspicious {
+            log::warn!("patches touch {}, which looks like a generated or ignored file", path);
+        }
+        Ok(())
     }
 }
 
 #[pymethods]
 impl Distributable {
+    /// Reads the distributable's unencrypted header. This does not need the base ROM.
+    ///
+    /// Returns `None` for distributables created by versions of Merlon before headers were added.
+    pub fn header(&self) -> Result<Option<Header>> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        Header::read_from(&mut file)
+            .with_context(|| format!("failed to read header of {}", self.path.display()))
+    }
+
     /// Opens the distributable into a directory.
     pub fn open_to_dir(&self, options: OpenOptions) -> Result<Package> {
         let temp_dir = TempDir::new()
             .context("failed to create temporary directory")?;
-        let tar_path = temp_dir.path().join("package.tar.bz2");
 
         // Check baserom exists
         if !options.baserom.is_file() {
             bail!("baserom {:?} is not a file", options.baserom);
         }
 
-        // Decrypt tar using baserom as hash
-        let status = Command::new("openssl")
-            .arg("enc")
-            .arg("-d") // decrypt
-            .arg("-aes-256-cbc")
-            .arg("-md").arg("sha512")
-            .arg("-pbkdf2")
-            .arg("-iter").arg("100000")
-            .arg("-salt")
-            .arg("-in").arg(&self.path)
-            .arg("-out").arg(&tar_path)
-            .arg("-pass").arg(format!("file:{}", options.baserom.display()))
-            .status()
-            .context("failed run openssl")?;
-        if !status.success() {
-            bail!("failed to decrypt {}", self.path.display());
-        }
-
-        // Decompress tar into temp dir
-        let status = Command::new("tar")
-            .arg("-xjvf")
-            .arg(&tar_path)
-            .arg("-C").arg(&temp_dir.path())
-            .arg(ROOT_DIR_NAME)
-            .stderr(Stdio::null())
-            .status()
-            .context("failed run tar")?;
-        if !status.success() {
-            bail!("failed to decompress {}", tar_path.display());
+        let mut input = File::open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        let header = Header::read_from(&mut input)
+            .with_context(|| format!("failed to read header of {}", self.path.display()))?
+            .unwrap_or_else(Header::legacy);
+        header.validate_kdf()
+            .with_context(|| format!("{} has invalid key derivation parameters", self.path.display()))?;
+
+        // Signatures can only be checked over the whole payload, so only signed distributables are read into memory
+        let signed_payload = match &header.signature {
+            Some(signature) => {
+                let mut encrypted = Vec::new();
+                io::Read::read_to_end(&mut input, &mut encrypted)
+                    .with_context(|| format!("failed to read {}", self.path.display()))?;
+                header.verify_payload(&encrypted)
+                    .with_context(|| format!("failed to open {}", self.path.display()))?;
+                signature.verify(&encrypted)
+                    .with_context(|| format!("{} has an invalid signature", self.path.display()))?;
+                Some(encrypted)
+            }
+            None => None,
+        };
+        let payload: Box<dyn io::Read + '_> = match &signed_payload {
+            Some(encrypted) => Box::new(encrypted.as_slice()),
+            None => Box::new(io::BufReader::new(input)),
+        };
+
+        // Decrypt using baserom as hash, streaming straight into the tar reader
+        let passphrase = crypto::passphrase_from_file(&options.baserom)?;
+        // A wrong baserom is by far the most likely cause of either step failing
+        let wrong_baserom_hint = || {
+            format!("check that {} is an unmodified Paper Mario (US) z64 ROM", options.baserom.display())
+        };
+        let mut payload = header::HashingReader::new(payload);
+        let mut decrypting = crypto::decrypting_reader(&mut payload, &passphrase, &header)
+            .with_context(|| format!("failed to decrypt {}; {}", self.path.display(), wrong_baserom_hint()))?;
+        // Files are checked as they are extracted, so a corrupt one stops extraction before anything after it is read
+        let mut checksums = ChecksumVerifier::default();
+        let extracted = archive::extract(
+            &mut decrypting,
+            header.compression,
+            temp_dir.path(),
+            ROOT_DIR_NAME,
+            |path, data| checksums.check(path, data),
+        );
+        // The tar can end before the ciphertext does, and the padding is only checked once all of it is read
+        let decrypted = io::copy(&mut decrypting, &mut io::sink());
+        drop(decrypting);
+
+        // Corruption would also make decryption or decompression fail, so check for it first to report it as such
+        let payload_sha1 = payload.finish()
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        header.verify_payload_sha1(&payload_sha1)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        decrypted
+            .with_context(|| format!("failed to decrypt {}; {}", self.path.display(), wrong_baserom_hint()))?;
+        match extracted {
+            Err(error) if checksums.failed => return Err(error.context(format!("{} is corrupt", self.path.display()))),
+            extracted => extracted
+                .with_context(|| format!("failed to decompress {}; {}", self.path.display(), wrong_baserom_hint()))?,
         }
 
         // Validate structure
@@ -242,48 +1020,54 @@ impl Distributable {
             bail!("{} is missing {PATCHES_DIR_NAME}/", self.path.display());
         }
 
+        // Check no listed file is missing before writing anything to the output directory
+        let verified = checksums.finish()
+            .with_context(|| format!("{} is corrupt", self.path.display()))?;
+        if !verified {
+            log::info!("{} has no {}, skipping verification", self.path.display(), CHECKSUMS_FILE_NAME);
+        }
+
         // Ensure output directory exists and is empty
+        let manifest = Manifest::read_from_path(&root_dir.join(MANIFEST_FILE_NAME))
+            .context("failed to read manifest")?;
         let output_dir = match options.output {
             Some(output_dir) => output_dir,
-            None => {
-                let manifest = Manifest::read_from_path(&root_dir.join(MANIFEST_FILE_NAME))
-                    .context("failed to read manifest")?;
-                PathBuf::from(manifest.metadata().name().as_kebab_case())
-            },
+            None => PathBuf::from(manifest.metadata().name().as_kebab_case()),
         };
-        if output_dir.exists() {
+        let created_output_dir = !output_dir.exists();
+        if created_output_dir {
+            fs::create_dir(&output_dir)
+                .with_context(|| format!("failed to create output directory {:?}", &output_dir))?;
+        } else {
             if !output_dir.is_dir() {
                 bail!("output directory {:?} is not a directory", &output_dir);
             }
             if fs::read_dir(&output_dir)?.next().is_some() {
                 bail!("output directory {:?} is not empty", &output_dir);
             }
-        } else {
-            fs::create_dir(&output_dir)
-                .with_context(|| format!("failed to create output directory {:?}", &output_dir))?;
         }
 
-        // Copy files into the output directory
-        fs::copy(&root_dir.join(MANIFEST_FILE_NAME), &output_dir.join(MANIFEST_FILE_NAME))
-            .context("failed to copy manifest")?;
-        fs::copy(&root_dir.join(README_FILE_NAME), &output_dir.join(README_FILE_NAME))
-            .context("failed to copy readme")?;
-        fs::create_dir(&output_dir.join(PATCHES_DIR_NAME))
-            .context("failed to create patches directory")?;
-        for entry in fs::read_dir(&root_dir.join(PATCHES_DIR_NAME)).context("failed to read patches directory")? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(file_name) = path.file_name() {
-                    fs::copy(&path, &output_dir.join(PATCHES_DIR_NAME).join(file_name))
-                        .context("failed to copy patch")?;
-                } else {
-                    log::warn!("patch {:?} has no file name", path);
-                }
+        let opened = copy_opened_files(&root_dir, &output_dir, &manifest)
+            .and_then(|()| Package::try_from(output_dir.clone()));
+        if opened.is_err() {
+            // Don't leave a partially opened package behind, but keep an output directory that was already there
+            let cleaned = if created_output_dir {
+                fs::remove_dir_all(&output_dir)
+            } else {
+                fs::read_dir(&output_dir).and_then(|mut entries| entries.try_for_each(|entry| {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        fs::remove_dir_all(path)
+                    } else {
+                        fs::remove_file(path)
+                    }
+                }))
+            };
+            if let Err(error) = cleaned {
+                log::warn!("failed to clean up output directory {:?}: {}", &output_dir, error);
             }
         }
-
-        Package::try_from(output_dir)
+        opened
     }
 
     /// Returns the path to the distributable.
@@ -292,23 +1076,22 @@ impl Distributable {
     }
 
     /// Applies the distributable to a base ROM, and returns the output ROM.
-    pub fn apply(&self, mut options: ApplyOptions) -> Result<Rom> {
-        self.open_scoped(options.baserom.clone(), |package| {
-            let initialised = package.to_initialised(InitialiseOptions {
-                baserom: options.baserom,
-                rev: None,
-            })?;
-
-            // Default output path - since we're using a open_scoped tempdir, we need to set output to Some because
-            // if it is None the output ROM will be in the tempdir and will be thrown away.
-            if options.build_rom_options.output.is_none() {
-                let manifest = initialised.package().manifest()?;
-                let metadata = manifest.metadata();
-                let output_path = PathBuf::from(format!("{} {}.z64", metadata.name(), metadata.version()));
-                options.build_rom_options.output = Some(output_path)
-            }
+    pub fn apply(&self, options: ApplyOptions) -> Result<Rom> {
+        let expected_sha1 = self.expected_rom_sha1(options.verify_after)?;
+        let rom = self.open_scoped(options.baserom.clone(), |package| build_applied(package, options))?;
+        check_rom_sha1(&rom, expected_sha1)?;
+        Ok(rom)
+    }
 
-            initialised.build_rom(options.build_rom_options)
+    /// Applies the distributable's patches to an existing decomp checkout, for stacking packages.
+    /// See [`Package::apply_patches_to_existing_repo`].
+    pub fn apply_to_repo(&self, baserom: PathBuf, repo: PathBuf, keep_conflicts: bool) -> Result<()> {
+        if self.header()?.is_some_and(|header| header.patch_empty) {
+            log::info!("{} has no patches, so there is nothing to apply", self);
+            return Ok(());
+        }
+        self.open_scoped(baserom, |package| {
+            package.apply_patches_to_existing_repo(&repo, keep_conflicts)
         })
     }
 
@@ -321,6 +1104,21 @@ impl Distributable {
 }
 
 impl Distributable {
+    /// The SHA1 recorded by the package author that a ROM built with `verify_after` is checked against.
+    fn expected_rom_sha1(&self, verify_after: bool) -> Result<Option<String>> {
+        if !verify_after {
+            return Ok(None);
+        }
+        match self.header()?.and_then(|header| header.rom_sha1) {
+            Some(sha1) => Ok(Some(sha1)),
+            None => bail!(
+                "{} does not record the SHA1 of the author's ROM, so it cannot be verified; \
+                the author can record it with `merlon export --record-rom`",
+                self,
+            ),
+        }
+    }
+
     /// Opens the package into a temporary directory, and calls the given closure with the package.
     pub fn open_scoped<F, R>(&self, baserom: PathBuf, f: F) -> Result<R>
     where
@@ -340,20 +1138,863 @@ impl TryFrom<PathBuf> for Distributable {
 
     fn try_from(path: PathBuf) -> Result<Self> {
         if is_distributable_package(&path) {
-            Ok(Self { path })
+            Ok(Self { path, _temp_dir: None, export_summary: None })
         } else {
             bail!("{} is not a Merlon distributable", path.display());
         }
     }
 }
 
+impl Distributable {
+    /// Creates a distributable from its bytes, for example when read from stdin.
+    /// The bytes are stored in the user's [cache](crate::cache), so identical input is only stored once.
+    /// If the cache is unavailable, they are written to a temporary file that is deleted when the distributable
+    /// is dropped.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = io::Cursor::new(bytes);
+        if Header::read_from(&mut reader)?.is_none() && !bytes.starts_with(LEGACY_MAGIC) {
+            bail!("input is not a Merlon distributable");
+        }
+
+        match cache::store(bytes) {
+            Ok(hash) => if let Some(path) = cache::get(&hash) {
+                return Ok(Self { path, _temp_dir: None, export_summary: None });
+            },
+            Err(error) => log::warn!("not caching distributable: {:#}", error),
+        }
+
+        let temp_dir = TempDir::new()
+            .context("failed to create temporary directory")?;
+        let path = temp_dir.path().join(format!("stdin.{}", EXTENSION));
+        fs::write(&path, bytes)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(Self { path, _temp_dir: Some(temp_dir), export_summary: None })
+    }
+
+    /// Reads a distributable from a reader until EOF, see [`Distributable::from_bytes`].
+    pub fn from_reader<R: io::Read>(mut reader: R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)
+            .context("failed to read distributable")?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Distributable {
+    /// Returns the size of the distributable file in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.path)
+            .with_context(|| format!("failed to read size of {}", self.path.display()))?
+            .len())
+    }
+
+    /// Returns facts about the export, such as the files included.
+    /// This is only known for distributables returned by [`Package::export_distributable`].
+    pub fn export_summary(&self) -> Option<&ExportSummary> {
+        self.export_summary.as_ref()
+    }
+
+    /// Returns the total size of the package's files before compression and encryption.
+    /// This is only known for distributables returned by [`Package::export_distributable`].
+    pub fn uncompressed_size(&self) -> Option<u64> {
+        self.export_summary.as_ref().map(|summary| summary.uncompressed_size)
+    }
+
+    /// Describes the size of the distributable, e.g. `2.3 MiB, 28% of 8.1 MiB uncompressed`.
+    pub fn size_summary(&self) -> Result<String> {
+        let size = self.size()?;
+        Ok(match self.uncompressed_size() {
+            Some(uncompressed) if uncompressed > 0 => format!(
+                "{}, {:.0}% of {} uncompressed",
+                format_bytes(size),
+                size as f64 / uncompressed as f64 * 100.0,
+                format_bytes(uncompressed),
+            ),
+            _ => format_bytes(size),
+        })
+    }
+}
+
+/// Formats a number of bytes for humans, e.g. `512 B`, `3.0 KiB` or `2.3 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 impl fmt::Display for Distributable {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} (distributable)", self.path.display())
     }
 }
 
+/// Initialises an opened distributable's package, which applies it and its bundled dependencies, and builds its ROM.
+fn build_applied(package: Package, mut options: ApplyOptions) -> Result<Rom> {
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(options.baserom),
+        rev: None,
+        from_shortlog: false,
+    })?;
+
+    // The package is usually in a directory that is thrown away, so the ROM must be built somewhere else
+    if options.build_rom_options.output.is_none() {
+        let manifest = initialised.package().manifest()?;
+        let metadata = manifest.metadata();
+        let output_path = PathBuf::from(format!("{} {}.z64", metadata.name(), metadata.version()));
+        options.build_rom_options.output = Some(output_path)
+    }
+
+    initialised.build_rom(options.build_rom_options)
+}
+
+/// Checks a ROM built by [`build_applied`] against the SHA1 from [`Distributable::expected_rom_sha1`], if any.
+fn check_rom_sha1(rom: &Rom, expected_sha1: Option<String>) -> Result<()> {
+    if let Some(expected_sha1) = expected_sha1 {
+        let sha1 = rom.sha1_string()?;
+        if sha1 != expected_sha1 {
+            bail!(
+                "built ROM has SHA1 {}, but the author's ROM has SHA1 {}; \
+                your toolchain or build environment probably differs from theirs",
+                sha1,
+                expected_sha1,
+            );
+        }
+        log::info!("built ROM matches the author's ROM (SHA1 {})", sha1);
+    }
+    Ok(())
+}
+
+/// Checks that a baserom exists and is an unmodified ROM of the package's `region`. Without this, a missing or wrong
+/// baserom used as the encryption key only shows up as an obscure decryption error.
+///
+/// Only the US ROM's SHA1 is known, so ROMs of other regions are only checked by the region in their header.
+pub(crate) fn check_baserom(path: &Path, region: &str) -> Result<()> {
+    if !path.is_file() {
+        return Err(ExportError::MissingBaserom { path: path.to_owned(), region: region.to_owned() }.into());
+    }
+    let rom = Rom::from(path.to_owned());
+    if region != DEFAULT_TARGET {
+        let Some(expected) = Region::from_decomp_version(region) else {
+            log::warn!("cannot check that {} is the {} ROM", path.display(), region);
+            return Ok(());
+        };
+        let actual = rom.region()
+            .with_context(|| format!("baserom at {} is not a Paper Mario z64 ROM", path.display()))?;
+        if actual != expected {
+            bail!("baserom at {} is the {} ROM, but this package is made for the {} ROM", path.display(), actual, expected);
+        }
+        return Ok(());
+    }
+
+    rom.validate_size()
+        .with_context(|| format!("baserom at {} is not an unmodified Paper Mario (US) z64 ROM", path.display()))?;
+    let region = rom.region()
+        .with_context(|| format!("baserom at {} is not an unmodified Paper Mario (US) z64 ROM", path.display()))?;
+    if region != Region::Us {
+        bail!("baserom at {} is the {} ROM, but Merlon packages are made for the US ROM", path.display(), region);
+    }
+    rom.verify_baserom()
+        .with_context(|| format!("baserom at {} is not an unmodified Paper Mario (US) z64 ROM", path.display()))
+}
+
+/// Lists the files under `root`, as sorted `/`-separated paths relative to `root`.
+fn relative_file_paths(root: &Path) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(relative);
+            } else {
+                let components: Vec<_> = relative.iter().map(|c| c.to_string_lossy()).collect();
+                paths.push(components.join("/"));
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Errors if `path` exists, unless `force` is set, so that exporting doesn't replace a file by accident.
+fn ensure_can_write_output(path: &Path, force: bool) -> Result<()> {
+    if !force && path.exists() {
+        return Err(ExportError::OutputExists(path.to_owned()).into());
+    }
+    Ok(())
+}
+
+/// Creates the file an export is written to. Unless `force` is set, an existing file is never replaced, even one
+/// created since [`ensure_can_write_output`] was checked.
+fn create_output_file(path: &Path, force: bool) -> Result<File> {
+    ensure_can_write_output(path, force)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))
+}
+
+/// Copies the patches in `from` to `to` on up to `jobs` threads, compressing large binary patches and normalising line
+/// endings as [`Package::stage_export`] does.
+fn stage_patches(from: &Path, to: &Path, jobs: usize, progress: &Progress) -> Result<()> {
+    let mut patches = Vec::new();
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        if path.is_file() {
+            patches.push(path);
+        }
+    }
+    let total = Some(patches.len() as u64);
+    let staged = AtomicU64::new(0);
+    progress.report(Phase::WritingPatches, 0, total);
+    parallel::map(&patches, jobs, |path| {
+        if is_large_binary_patch(path)? {
+            log::info!("compressing binary patch {}", path.display());
+            write_compressed_patch(path, to)?;
+        } else {
+            fs::write(to.join(path.file_name().unwrap()), read_patch_with_lf(path)?)
+                .with_context(|| format!("failed to stage patch {}", path.display()))?;
+        }
+        progress.report(Phase::WritingPatches, staged.fetch_add(1, Ordering::Relaxed) + 1, total);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn sha1_of_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha1::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(crate::rom::to_hex(&hasher.finalize()))
+}
+
+/// Writes [`CHECKSUMS_FILE_NAME`] listing the SHA1 of every file under `root`, in `sha1sum` format, hashing on up to
+/// `jobs` threads.
+fn write_checksums(root: &Path, jobs: usize) -> Result<()> {
+    let paths = relative_file_paths(root)?;
+    let sha1s = parallel::map(&paths, jobs, |path| sha1_of_file(&root.join(path)))?;
+    let mut checksums = String::new();
+    for (path, sha1) in paths.iter().zip(sha1s) {
+        checksums.push_str(&format!("{}  {}\n", sha1, path));
+    }
+    fs::write(root.join(CHECKSUMS_FILE_NAME), checksums)
+        .with_context(|| format!("failed to write {}", CHECKSUMS_FILE_NAME))
+}
+
+/// Checks files against [`CHECKSUMS_FILE_NAME`] as [`archive::extract`] passes them, which sees it before the files
+/// it lists. Checking stops at the first file that is unlisted or has the wrong SHA1.
+#[derive(Debug, Default)]
+struct ChecksumVerifier {
+    /// SHA1s of the listed files that haven't been checked yet, once [`CHECKSUMS_FILE_NAME`] has been read.
+    expected: Option<BTreeMap<String, String>>,
+
+    /// Whether a file came before [`CHECKSUMS_FILE_NAME`], as every file does in distributables without one.
+    unchecked: bool,
+
+    /// Whether a check failed.
+    failed: bool,
+}
+
+impl ChecksumVerifier {
+    fn check(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let checked = self.check_file(path, data);
+        self.failed |= checked.is_err();
+        checked
+    }
+
+    fn check_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        if path == CHECKSUMS_FILE_NAME {
+            if self.unchecked || self.expected.is_some() {
+                bail!("{} must come before the files it lists", CHECKSUMS_FILE_NAME);
+            }
+            let checksums = std::str::from_utf8(data)
+                .with_context(|| format!("{} is not valid UTF-8", CHECKSUMS_FILE_NAME))?;
+            let mut expected = BTreeMap::new();
+            for line in checksums.lines() {
+                let (sha1, path) = line.split_once("  ")
+                    .with_context(|| format!("malformed line in {}: {:?}", CHECKSUMS_FILE_NAME, line))?;
+                expected.insert(path.to_owned(), sha1.to_owned());
+            }
+            self.expected = Some(expected);
+            return Ok(());
+        }
+
+        let Some(expected) = &mut self.expected else {
+            self.unchecked = true;
+            return Ok(());
+        };
+        let Some(expected_sha1) = expected.remove(path) else {
+            bail!("{} is not listed in {}", path, CHECKSUMS_FILE_NAME);
+        };
+        let sha1 = crate::rom::to_hex(&Sha1::digest(data));
+        if sha1 != expected_sha1 {
+            bail!("{} has SHA1 {}, expected {}", path, sha1, expected_sha1);
+        }
+        Ok(())
+    }
+
+    /// Errors if a listed file was never passed to [`ChecksumVerifier::check`]. Returns whether there was a
+    /// [`CHECKSUMS_FILE_NAME`] to check against.
+    fn finish(self) -> Result<bool> {
+        let Some(expected) = self.expected else {
+            return Ok(false);
+        };
+        if let Some(path) = expected.keys().next() {
+            bail!("{} is missing", path);
+        }
+        Ok(true)
+    }
+}
+
+/// Errors if `path` resolves to somewhere inside `dir`. Used to stop distributables from being written into the
+/// decomp repository, where they could be committed or picked up by later exports.
+fn ensure_outside_dir(path: &Path, dir: &Path) -> Result<()> {
+    let Ok(dir) = dir.canonicalize() else {
+        return Ok(());
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(parent) = parent.canonicalize() else {
+        return Ok(());
+    };
+    if parent.starts_with(&dir) {
+        bail!(
+            "refusing to write {} inside the decomp repository {}; choose an output path outside it",
+            path.display(),
+            dir.display(),
+        );
+    }
+    Ok(())
+}
+
+/// Returns true if the patch at `path` is large and consists mostly of `GIT binary patch` data.
+/// Such patches are compressed individually rather than left to the tarball's bzip2, which handles them poorly.
+fn is_large_binary_patch(path: &Path) -> Result<bool> {
+    if fs::metadata(path)?.len() < LARGE_BINARY_PATCH_SIZE {
+        return Ok(false);
+    }
+    let contents = fs::read(path)?;
+    Ok(binary_fraction(&contents) > 0.5)
+}
+
+/// Returns the fraction of a patch's bytes that belong to `GIT binary patch` sections.
+fn binary_fraction(patch: &[u8]) -> f64 {
+    if patch.is_empty() {
+        return 0.0;
+    }
+    let mut binary_bytes = 0;
+    let mut in_binary = false;
+    for line in patch.split(|&byte| byte == b'\n') {
+        if line.starts_with(b"GIT binary patch") {
+            in_binary = true;
+        } else if line.starts_with(b"diff --git ") {
+            in_binary = false;
+        }
+        if in_binary {
+            binary_bytes += line.len() + 1;
+        }
+    }
+    binary_bytes as f64 / patch.len() as f64
+}
+
+/// Reads a patch, converting CRLF line endings to LF.
+///
+/// Patches generated on Windows can end up with CRLF line endings, which `git am` on other platforms rejects.
+/// Decomp's sources use LF, so a CR in a patch is never meaningful.
+fn read_patch_with_lf(path: &Path) -> Result<Vec<u8>> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read patch {}", path.display()))?;
+    Ok(crlf_to_lf(&bytes))
+}
+
+fn crlf_to_lf(bytes: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        if byte == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+    normalized
+}
+
+/// Writes a gzip-compressed copy of the patch at `source` into `dest_dir`, named with [`COMPRESSED_PATCH_EXTENSION`].
+fn write_compressed_patch(source: &Path, dest_dir: &Path) -> Result<()> {
+    let mut file_name = source.file_name().context("patch has no file name")?.to_owned();
+    file_name.push(".");
+    file_name.push(COMPRESSED_PATCH_EXTENSION);
+    let mut encoder = GzEncoder::new(File::create(dest_dir.join(file_name))?, flate2::Compression::best());
+    io::Write::write_all(&mut encoder, &read_patch_with_lf(source)?)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Copies the files of a package extracted by [`Distributable::open_to_dir`] into its output directory.
+fn copy_opened_files(root_dir: &Path, output_dir: &Path, manifest: &Manifest) -> Result<()> {
+    // Copy files into the output directory
+    fs::copy(root_dir.join(MANIFEST_FILE_NAME), output_dir.join(MANIFEST_FILE_NAME))
+        .context("failed to copy manifest")?;
+    fs::copy(root_dir.join(README_FILE_NAME), output_dir.join(README_FILE_NAME))
+        .context("failed to copy readme")?;
+    fs::copy(root_dir.join(LICENSE_FILE_NAME), output_dir.join(LICENSE_FILE_NAME))
+        .context("failed to copy license")?;
+    if root_dir.join(LOCKFILE_FILE_NAME).is_file() {
+        fs::copy(root_dir.join(LOCKFILE_FILE_NAME), output_dir.join(LOCKFILE_FILE_NAME))
+            .context("failed to copy lockfile")?;
+    }
+    for extra_file in manifest.metadata().extra_files() {
+        let Ok(relative) = normalize_relative_path(extra_file) else {
+            continue;
+        };
+        let source = root_dir.join(&relative);
+        if !source.is_file() {
+            continue;
+        }
+        let dest = output_dir.join(&relative);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&source, &dest)
+            .with_context(|| format!("failed to copy extra file {:?}", extra_file))?;
+    }
+    let bundled_deps_dir = root_dir.join(BUNDLED_DEPS_DIR_NAME);
+    if bundled_deps_dir.is_dir() {
+        fs::create_dir(output_dir.join(BUNDLED_DEPS_DIR_NAME))
+            .context("failed to create bundled dependencies directory")?;
+        for entry in fs::read_dir(&bundled_deps_dir).context("failed to read bundled dependencies")? {
+            let path = entry?.path();
+            if !is_distributable_package(&path) {
+                log::warn!("ignoring {:?} in bundled dependencies: not a distributable", path.file_name());
+                continue;
+            }
+            if let Some(file_name) = path.file_name() {
+                fs::copy(&path, output_dir.join(BUNDLED_DEPS_DIR_NAME).join(file_name))
+                    .context("failed to copy bundled dependency")?;
+            }
+        }
+    }
+    fs::create_dir(output_dir.join(PATCHES_DIR_NAME))
+        .context("failed to create patches directory")?;
+    for entry in fs::read_dir(root_dir.join(PATCHES_DIR_NAME)).context("failed to read patches directory")? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_file() {
+            if let Some(file_name) = path.file_name() {
+                let dest = output_dir.join(PATCHES_DIR_NAME).join(file_name);
+                if path.extension() == Some(OsStr::new(COMPRESSED_PATCH_EXTENSION)) {
+                    read_compressed_patch(&path, &dest.with_extension(""))
+                        .context("failed to decompress patch")?;
+                } else {
+                    fs::copy(&path, &dest)
+                        .context("failed to copy patch")?;
+                }
+            } else {
+                log::warn!("patch {:?} has no file name", path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses a patch written by [`write_compressed_patch`] to `dest`.
+fn read_compressed_patch(source: &Path, dest: &Path) -> Result<()> {
+    let mut decoder = GzDecoder::new(File::open(source)?);
+    io::copy(&mut decoder, &mut File::create(dest)?)?;
+    Ok(())
+}
+
 /// Returns true if the given path is probably a distributable package.
 pub fn is_distributable_package(path: &Path) -> bool {
     path.is_file() && path.extension().unwrap_or_default() == EXTENSION
 }
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn baserom_is_resolved_in_order_of_precedence() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new("Shared Baserom", dir.path().join("package"))?;
+        let explicit = dir.path().join("explicit.z64");
+        let from_env = dir.path().join("env.z64");
+
+        // Not initialised, so there is no default
+        let error = package.resolve_baserom_with_env(None, None).unwrap_err();
+        assert!(error.to_string().contains(BASEROM_ENV_VAR), "{}", error);
+
+        package.edit_manifest(|manifest| {
+            manifest.metadata_mut().set_baserom(Some(PathBuf::from("../shared/baserom.z64")));
+            Ok(())
+        })?;
+        assert_eq!(package.resolve_baserom_with_env(None, None)?, package.path().join("../shared/baserom.z64"));
+        assert_eq!(package.resolve_baserom_with_env(None, Some(from_env.clone()))?, from_env);
+        assert_eq!(package.resolve_baserom_with_env(Some(&explicit), Some(from_env))?, explicit);
+
+        // Wherever it came from, exporting checks that it is there
+        let error = package.export_baserom(&ExportOptions { baserom: Some(explicit.clone()), ..Default::default() })
+            .unwrap_err();
+        assert_eq!(ExportError::of(&error), Some(&ExportError::MissingBaserom { path: explicit, region: "us".into() }));
+        Ok(())
+    }
+
+    #[test]
+    fn binary_patches_round_trip_through_compression() -> Result<()> {
+        let dir = TempDir::new()?;
+        let patch = "diff --git a/assets/x.png b/assets/x.png\nGIT binary patch\nliteral 4\nLcmZ?l00001\n\n";
+        assert!(binary_fraction(patch.as_bytes()) > 0.5);
+        assert_eq!(binary_fraction(b"diff --git a/src/x.c b/src/x.c\n+int x;\n"), 0.0);
+
+        let source = dir.path().join("0001-add-x.patch");
+        fs::write(&source, patch)?;
+        write_compressed_patch(&source, dir.path())?;
+        let compressed = dir.path().join("0001-add-x.patch.gz");
+        assert!(compressed.is_file());
+
+        let restored = dir.path().join("restored.patch");
+        read_compressed_patch(&compressed, &restored)?;
+        assert_eq!(fs::read_to_string(restored)?, patch);
+        Ok(())
+    }
+
+    #[test]
+    fn sizes_parse_with_binary_units() {
+        assert_eq!(parse_size("1234"), Ok(1234));
+        assert_eq!(parse_size("500K"), Ok(500 * 1024));
+        assert_eq!(parse_size("100m"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+
+    #[test]
+    fn oversized_export_warns_or_errors() -> Result<()> {
+        let options = ExportOptions { warn_size: Some(1024), ..Default::default() };
+        assert_eq!(check_export_size(1024, &options)?, None);
+        let warning = check_export_size(4096, &options)?.expect("an oversized export should warn");
+        assert!(warning.contains("4.0 KiB"), "{}", warning);
+        assert_eq!(check_export_size(DEFAULT_WARN_SIZE, &ExportOptions::default())?, None);
+        assert!(check_export_size(DEFAULT_WARN_SIZE + 1, &ExportOptions::default())?.is_some());
+
+        let options = ExportOptions { max_size: Some(1024), ..options };
+        let error = check_export_size(4096, &options).unwrap_err();
+        assert_eq!(ExportError::of(&error), Some(&ExportError::TooLarge { size: 4096, max_size: 1024 }));
+        Ok(())
+    }
+
+    #[test]
+    fn format_bytes_picks_unit() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(2_411_724), "2.3 MiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024 * 1024), "5120.0 GiB");
+    }
+
+    /// Writes a distributable encrypted with `passphrase` by hand, since exporting needs a real baserom.
+    fn write_fixture(dir: &Path, passphrase: &[u8], compression: Compression) -> Result<PathBuf> {
+        write_fixture_with(dir, passphrase, compression, |_| Ok(()))
+    }
+
+    /// Like [`write_fixture`], calling `edit` on the package directory before it is archived.
+    fn write_fixture_with(
+        dir: &Path,
+        passphrase: &[u8],
+        compression: Compression,
+        edit: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<PathBuf> {
+        let root_dir = dir.join("work").join(ROOT_DIR_NAME);
+        fs::create_dir_all(root_dir.join(PATCHES_DIR_NAME))?;
+        Manifest::with_author(crate::package::Name::new("Fixture".to_owned())?, "Test <test@example.com>".to_owned())?
+            .write_to_file(&root_dir.join(MANIFEST_FILE_NAME))?;
+        fs::write(root_dir.join(README_FILE_NAME), "# Fixture\n")?;
+        fs::write(root_dir.join(LICENSE_FILE_NAME), "Copyright Test\n")?;
+        fs::write(root_dir.join(PATCHES_DIR_NAME).join("0001-x.patch"), "diff --git a/x b/x\n")?;
+        edit(&root_dir)?;
+        let tar = compression.compress(&archive::tar_dir(&dir.join("work"), ROOT_DIR_NAME, Some(CHECKSUMS_FILE_NAME))?, None)?;
+
+        let header = Header { kdf_iterations: header::MIN_KDF_ITERATIONS, compression, ..Header::new() };
+        let encrypted = crypto::encrypt(&tar, passphrase, &header)?;
+        let header = Header { payload_sha1: Some(header::payload_sha1(&encrypted)), ..header };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        bytes.extend(encrypted);
+        let path = dir.join("fixture.merlon");
+        fs::write(&path, bytes)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn opening_with_wrong_baserom_fails_clearly() -> Result<()> {
+        let dir = TempDir::new()?;
+        let distributable = Distributable::try_from(write_fixture(dir.path(), b"right", Compression::Bzip2)?)?;
+        let right = dir.path().join("right.z64");
+        let wrong = dir.path().join("wrong.z64");
+        fs::write(&right, b"right")?;
+        fs::write(&wrong, b"wrong")?;
+
+        let error = distributable.open_to_dir(OpenOptions {
+            output: Some(dir.path().join("wrong")),
+            baserom: wrong.clone(),
+        }).unwrap_err();
+        assert!(error.to_string().contains(&format!("check that {} is an unmodified", wrong.display())));
+        assert!(!dir.path().join("wrong").exists());
+
+        let package = distributable.open_to_dir(OpenOptions {
+            output: Some(dir.path().join("right")),
+            baserom: right,
+        })?;
+        assert!(package.path().join(LICENSE_FILE_NAME).is_file());
+        assert!(package.path().join(PATCHES_DIR_NAME).join("0001-x.patch").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_distributable_is_refused_before_decrypting() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write_fixture(dir.path(), b"baserom", Compression::Bzip2)?;
+        let bytes = fs::read(&path)?;
+        fs::write(&path, &bytes[..bytes.len() - 16])?;
+        let baserom = dir.path().join("baserom.z64");
+        fs::write(&baserom, b"baserom")?;
+
+        let error = Distributable::try_from(path)?.open_to_dir(OpenOptions {
+            output: Some(dir.path().join("opened")),
+            baserom,
+        }).unwrap_err();
+        assert!(format!("{:#}", error).contains("truncated or corrupt"), "{:#}", error);
+        assert!(!dir.path().join("opened").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn patch_format_only_changes_the_export() -> Result<()> {
+        let dir = TempDir::new()?;
+        let initialised = crate::package::init::test_initialised_package(dir.path(), "Formats")?;
+        let package = initialised.package();
+        let manifest = fs::read_to_string(package.manifest_path())?;
+        let baserom = initialised.subrepo_path().join("ver/jp/baserom.z64");
+        let options = |output: &str| ExportOptions {
+            baserom: Some(baserom.clone()),
+            output: Some(dir.path().join(output)),
+            patch_format: Some(PatchFormat::Diff),
+            ..Default::default()
+        };
+
+        // Fails only once the patch format has been looked at, as the package has no changes
+        let error = package.export_distributable(options("empty.merlon")).unwrap_err();
+        assert!(matches!(ExportError::of(&error), Some(ExportError::NoCommits { .. })), "{:#}", error);
+        assert_eq!(fs::read_to_string(package.manifest_path())?, manifest);
+
+        let subrepo = initialised.subrepo_path();
+        fs::create_dir(subrepo.join("src"))?;
+        fs::write(subrepo.join("src/formats.c"), "int formats;\n")?;
+        crate::package::test_git(&subrepo, &["add", "src/formats.c"])?;
+        crate::package::test_commit(&subrepo, "Add formats.c")?;
+        let distributable = package.export_distributable(options("diff.merlon"))?;
+        assert_eq!(fs::read_to_string(package.manifest_path())?, manifest);
+        assert_eq!(fs::read_dir(package.path().join(PATCHES_DIR_NAME))?.count(), 0);
+
+        let opened = distributable.open_to_dir(OpenOptions { output: Some(dir.path().join("opened")), baserom })?;
+        assert_eq!(opened.manifest()?.metadata().patch_format(), PatchFormat::Diff);
+        let patches: Vec<_> = fs::read_dir(opened.path().join(PATCHES_DIR_NAME))?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<_>>()?;
+        assert_eq!(patches, ["combined.patch"]);
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_files_are_refused_when_opening() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write_fixture_with(dir.path(), b"baserom", Compression::Bzip2, |root_dir| {
+            write_checksums(root_dir, 1)?;
+            fs::write(root_dir.join(PATCHES_DIR_NAME).join("0001-x.patch"), "diff --git a/evil b/evil\n")?;
+            Ok(())
+        })?;
+        let baserom = dir.path().join("baserom.z64");
+        fs::write(&baserom, b"baserom")?;
+
+        let error = Distributable::try_from(path)?.open_to_dir(OpenOptions {
+            output: Some(dir.path().join("opened")),
+            baserom,
+        }).unwrap_err();
+        assert!(format!("{:#}", error).contains("is corrupt"), "{:#}", error);
+        assert!(format!("{:#}", error).contains("patches/0001-x.patch has SHA1"), "{:#}", error);
+        assert!(!dir.path().join("opened").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_open_removes_partial_output() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write_fixture_with(dir.path(), b"baserom", Compression::Bzip2, |root_dir| {
+            // Fails to decompress only after the other files have been copied
+            fs::write(root_dir.join(PATCHES_DIR_NAME).join("0002-y.patch.gz"), "not gzip")?;
+            Ok(())
+        })?;
+        let distributable = Distributable::try_from(path)?;
+        let baserom = dir.path().join("baserom.z64");
+        fs::write(&baserom, b"baserom")?;
+
+        assert!(distributable.open_to_dir(OpenOptions {
+            output: Some(dir.path().join("opened")),
+            baserom: baserom.clone(),
+        }).is_err());
+        assert!(!dir.path().join("opened").exists());
+
+        // An empty output directory that was already there is kept, but emptied again
+        let existing = dir.path().join("existing");
+        fs::create_dir(&existing)?;
+        assert!(distributable.open_to_dir(OpenOptions {
+            output: Some(existing.clone()),
+            baserom,
+        }).is_err());
+        assert!(existing.is_dir());
+        assert_eq!(fs::read_dir(&existing)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn opens_every_compression() -> Result<()> {
+        for compression in [Compression::Bzip2, Compression::Zstd, Compression::None] {
+            let dir = TempDir::new()?;
+            let distributable = Distributable::try_from(write_fixture(dir.path(), b"baserom", compression)?)?;
+            assert_eq!(distributable.header()?.map(|header| header.compression), Some(compression));
+            let baserom = dir.path().join("baserom.z64");
+            fs::write(&baserom, b"baserom")?;
+            let package = distributable.open_to_dir(OpenOptions {
+                output: Some(dir.path().join("opened")),
+                baserom,
+            })?;
+            assert_eq!(
+                fs::read_to_string(package.path().join(PATCHES_DIR_NAME).join("0001-x.patch"))?,
+                "diff --git a/x b/x\n",
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn patches_are_normalised_to_lf() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("0001-crlf.patch");
+        fs::write(&source, "diff --git a/src/x.c b/src/x.c\r\n+int x;\r\n+char *s = \"\\r\";\n")?;
+        assert_eq!(read_patch_with_lf(&source)?, b"diff --git a/src/x.c b/src/x.c\n+int x;\n+char *s = \"\\r\";\n");
+
+        write_compressed_patch(&source, dir.path())?;
+        let restored = dir.path().join("restored.patch");
+        read_compressed_patch(&dir.path().join("0001-crlf.patch.gz"), &restored)?;
+        assert!(!fs::read(restored)?.contains(&b'\r'));
+        Ok(())
+    }
+
+    #[test]
+    fn from_bytes_detects_magic() -> Result<()> {
+        let mut bytes = Vec::new();
+        Header::new().write_to(&mut bytes)?;
+        bytes.extend_from_slice(b"Salted__payload");
+        let distributable = Distributable::from_bytes(&bytes)?;
+        assert_eq!(fs::read(distributable.path())?, bytes);
+        assert_eq!(distributable.header()?, Some(Header::new()));
+
+        let legacy = Distributable::from_bytes(b"Salted__payload")?;
+        assert_eq!(legacy.header()?, None);
+
+        assert!(Distributable::from_bytes(b"not a distributable").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn output_inside_subrepo_is_refused() -> Result<()> {
+        let dir = TempDir::new()?;
+        let subrepo = dir.path().join("papermario");
+        fs::create_dir_all(subrepo.join("src"))?;
+
+        assert!(ensure_outside_dir(&subrepo.join("mod.merlon"), &subrepo).is_err());
+        assert!(ensure_outside_dir(&subrepo.join("src/../mod.merlon"), &subrepo).is_err());
+        assert!(ensure_outside_dir(&subrepo.join("src/mod.merlon"), &subrepo).is_err());
+        assert!(ensure_outside_dir(&dir.path().join("mod.merlon"), &subrepo).is_ok());
+        assert!(ensure_outside_dir(&subrepo.join("../mod.merlon"), &subrepo).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn checksums_detect_corruption() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("patches"))?;
+        fs::write(dir.path().join("merlon.toml"), "[package]")?;
+        fs::write(dir.path().join("patches/0001-a.patch"), "diff --git a/x b/x")?;
+        write_checksums(dir.path(), 1)?;
+        let checksums = fs::read(dir.path().join(CHECKSUMS_FILE_NAME))?;
+        let verify = |files: &[(&str, &[u8])]| {
+            let mut verifier = ChecksumVerifier::default();
+            for (path, data) in files {
+                verifier.check(path, data)?;
+            }
+            verifier.finish()
+        };
+
+        assert!(verify(&[
+            (CHECKSUMS_FILE_NAME, &checksums),
+            ("merlon.toml", b"[package]"),
+            ("patches/0001-a.patch", b"diff --git a/x b/x"),
+        ])?);
+
+        let error = verify(&[(CHECKSUMS_FILE_NAME, &checksums), ("patches/0001-a.patch", b"diff --git a/y b/y")]);
+        assert!(error.unwrap_err().to_string().contains("patches/0001-a.patch has SHA1"));
+
+        let error = verify(&[(CHECKSUMS_FILE_NAME, &checksums), ("patches/0002-b.patch", b"")]);
+        assert!(error.unwrap_err().to_string().contains("not listed"));
+
+        let error = verify(&[(CHECKSUMS_FILE_NAME, &checksums), ("merlon.toml", b"[package]")]);
+        assert!(error.unwrap_err().to_string().contains("patches/0001-a.patch is missing"));
+
+        // Without checksums there is nothing to check, but they can't be slipped in after the files they list
+        assert!(!verify(&[("merlon.toml", b"[package]")])?);
+        assert!(verify(&[("merlon.toml", b"[package]"), (CHECKSUMS_FILE_NAME, &checksums)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn export_reports_missing_baserom() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author(
+            "Missing baserom",
+            dir.path().join("package"),
+            Some("Test <test@example.com>".to_owned()),
+        )?;
+        let baserom = dir.path().join("baserom.z64");
+        let error = package.export_distributable(ExportOptions {
+            baserom: Some(baserom.clone()),
+            ..Default::default()
+        }).unwrap_err();
+        assert!(error.to_string().contains(&format!("baserom not found at {}", baserom.display())));
+
+        fs::write(&baserom, b"not a rom")?;
+        let error = package.export_distributable(ExportOptions {
+            baserom: Some(baserom),
+            ..Default::default()
+        }).unwrap_err();
+        assert!(error.to_string().contains("not an unmodified Paper Mario (US) z64 ROM"));
+        Ok(())
+    }
+}
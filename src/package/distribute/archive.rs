@@ -0,0 +1,448 @@
+//! Reproducible archives of distributable contents, their compression, and extracting them again.
+//!
+//! Archives are built in-process rather than with the `tar` binary, so that exporting the same package twice gives
+//! byte-identical output: entries are sorted, and timestamps, ownership and permissions are normalised.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+use std::sync::mpsc::{self, SyncSender};
+use anyhow::{Result, Context, anyhow, bail};
+use bzip2::{read::BzDecoder, write::BzEncoder};
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::package::progress::{Phase, Progress};
+
+/// Mode of every directory in an archive.
+const DIR_MODE: u32 = 0o755;
+
+/// Mode of every file in an archive.
+const FILE_MODE: u32 = 0o644;
+
+/// Size of the chunks that [`tar_dir_compressed`] passes from the archiver to the compressor.
+const PIPELINE_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Archives `parent/dir_name` as a tar containing `dir_name/` and everything under it.
+///
+/// If `first` names a file directly in `dir_name`, it is archived before everything else, so that [`extract`] reads
+/// it first.
+pub fn tar_dir(parent: &Path, dir_name: &str, first: Option<&str>) -> Result<Vec<u8>> {
+    write_tar(parent, dir_name, first, Vec::new())
+}
+
+/// Archives `parent/dir_name` as [`tar_dir`] does and compresses it, giving the same bytes as
+/// `compression.compress(&tar_dir(parent, dir_name, first)?, level)`.
+///
+/// With more than one job, the archive is written on a separate thread and handed to the compressor in chunks
+/// as it is produced. The compressed stream is the same either way.
+///
+/// The number of archive bytes compressed so far is reported to `progress`. The total is only known with one job.
+pub fn tar_dir_compressed(
+    parent: &Path,
+    dir_name: &str,
+    first: Option<&str>,
+    compression: Compression,
+    level: Option<u32>,
+    jobs: usize,
+    progress: &Progress,
+) -> Result<Vec<u8>> {
+    if let Some(level) = level {
+        compression.validate_level(level)?;
+    }
+    let mut done = 0;
+    let mut report = |chunk: &[u8], total: Option<u64>| {
+        done += chunk.len() as u64;
+        progress.report(Phase::Compressing, done, total);
+    };
+
+    if jobs <= 1 {
+        let tar = tar_dir(parent, dir_name, first)?;
+        let total = Some(tar.len() as u64);
+        progress.report(Phase::Compressing, 0, total);
+        let chunks = tar.chunks(PIPELINE_CHUNK_SIZE).inspect(|chunk| report(chunk, total));
+        return compression.compress_chunks(chunks, level);
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(jobs);
+    let compressed = std::thread::scope(|scope| {
+        let archiver = scope.spawn(move || -> Result<()> {
+            let writer = io::BufWriter::with_capacity(PIPELINE_CHUNK_SIZE, ChunkSender(sender));
+            write_tar(parent, dir_name, first, writer)?
+                .into_inner()
+                .map_err(|error| error.into_error())
+                .context("failed to finish archive")?;
+            Ok(())
+        });
+        // The receiver is consumed here, so if compression fails the archiver sees a closed channel and stops
+        let compressed = compression.compress_chunks(receiver.into_iter().inspect(|chunk| report(chunk, None)), level);
+        archiver.join().unwrap_or_else(|_| Err(anyhow!("archiver thread panicked")))?;
+        compressed
+    })?;
+    progress.report(Phase::Compressing, done, Some(done));
+    Ok(compressed)
+}
+
+fn write_tar<W: Write>(parent: &Path, dir_name: &str, first: Option<&str>, writer: W) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    append_dir(&mut builder, &parent.join(dir_name), dir_name, first)?;
+    builder.into_inner().context("failed to finish archive")
+}
+
+/// Sends everything written to it down a channel, for [`tar_dir_compressed`].
+struct ChunkSender(SyncSender<Vec<u8>>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "compressor stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &Path,
+    archive_path: &str,
+    first: Option<&str>,
+) -> Result<()> {
+    let mut header = normalised_header(tar::EntryType::Directory, DIR_MODE, 0);
+    builder.append_data(&mut header, format!("{}/", archive_path), io::empty())
+        .with_context(|| format!("failed to archive {}", dir.display()))?;
+
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| (first.is_none_or(|first| entry.file_name() != first), entry.file_name()));
+    for entry in entries {
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            bail!("cannot archive {}: file name is not valid UTF-8", path.display());
+        };
+        let entry_path = format!("{}/{}", archive_path, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            append_dir(builder, &path, &entry_path, None)?;
+        } else if file_type.is_file() {
+            let data = fs::read(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let mut header = normalised_header(tar::EntryType::Regular, FILE_MODE, data.len() as u64);
+            builder.append_data(&mut header, &entry_path, data.as_slice())
+                .with_context(|| format!("failed to archive {}", path.display()))?;
+        } else {
+            log::warn!("not archiving {}: only files and directories are supported", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn normalised_header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+/// How the archive inside a distributable is compressed. This is recorded in its [`Header`](super::Header).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+#[pyclass(module = "merlon.package.distribute")]
+pub enum Compression {
+    /// bzip2, as used by every version of Merlon before this was configurable.
+    #[default]
+    Bzip2,
+    /// Zstandard, which is much faster than bzip2 for large packages.
+    Zstd,
+    /// No compression.
+    None,
+}
+
+impl Compression {
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// File extension of an archive compressed this way.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Bzip2 => "tar.bz2",
+            Self::Zstd => "tar.zst",
+            Self::None => "tar",
+        }
+    }
+
+    /// Errors if `level` is not a valid level for this algorithm.
+    pub fn validate_level(&self, level: u32) -> Result<()> {
+        let range = match self {
+            Self::Bzip2 => 1..=9,
+            Self::Zstd => 1..=22,
+            Self::None => bail!("a compression level cannot be used without compression"),
+        };
+        if !range.contains(&level) {
+            bail!("{} compression level must be between {} and {}, got {}", self, range.start(), range.end(), level);
+        }
+        Ok(())
+    }
+
+    /// Compresses an archive. Without a `level`, bzip2 uses its highest level as `tar -j` does, and zstd uses its
+    /// default level.
+    pub fn compress(&self, data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        if let Some(level) = level {
+            self.validate_level(level)?;
+        }
+        self.compress_chunks(std::iter::once(data), level)
+    }
+
+    /// Compresses an archive given as consecutive chunks. Both encoders buffer whole blocks internally, so how the
+    /// archive is split doesn't change the output. `level` must already have been validated.
+    fn compress_chunks<C: AsRef<[u8]>>(&self, chunks: impl IntoIterator<Item = C>, level: Option<u32>) -> Result<Vec<u8>> {
+        match self {
+            Self::Bzip2 => {
+                let level = level.map_or(bzip2::Compression::best(), bzip2::Compression::new);
+                let mut encoder = BzEncoder::new(Vec::new(), level);
+                for chunk in chunks {
+                    encoder.write_all(chunk.as_ref()).context("failed to compress archive")?;
+                }
+                encoder.finish().context("failed to compress archive")
+            }
+            Self::Zstd => {
+                let level = level.map_or(0, |level| level as i32); // 0 is zstd's default level
+                let mut encoder = zstd::Encoder::new(Vec::new(), level).context("failed to compress archive")?;
+                for chunk in chunks {
+                    encoder.write_all(chunk.as_ref()).context("failed to compress archive")?;
+                }
+                encoder.finish().context("failed to compress archive")
+            }
+            Self::None => {
+                let mut data = Vec::new();
+                for chunk in chunks {
+                    data.extend_from_slice(chunk.as_ref());
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    fn decoder<'a, R: Read + 'a>(&self, data: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::Bzip2 => Box::new(BzDecoder::new(data)),
+            Self::Zstd => Box::new(zstd::Decoder::new(data).context("failed to decompress archive")?),
+            Self::None => Box::new(data),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bzip2 => write!(f, "bzip2"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Decompresses a tar and extracts the files and directories under `dir_name/` into `dest`.
+///
+/// Each file is passed to `check` with its `/`-separated path relative to `dir_name` and its contents before it is
+/// written, in the order they were archived. If `check` errors, extraction stops there and nothing more is written.
+///
+/// Entries anywhere else in the archive are ignored, and entries that would escape `dest` are refused. Reading
+/// stops at the end of the tar, which may be before the end of `data`.
+pub fn extract<R: Read>(
+    data: R,
+    compression: Compression,
+    dest: &Path,
+    dir_name: &str,
+    mut check: impl FnMut(&str, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut archive = tar::Archive::new(compression.decoder(data)?);
+    for entry in archive.entries().context("failed to read archive")? {
+        let mut entry = entry.context("failed to read archive")?;
+        let path = entry.path().context("archive entry has an invalid path")?.into_owned();
+        let mut components = path.components();
+        if components.next() != Some(Component::Normal(dir_name.as_ref())) {
+            continue;
+        }
+        let Some(relative) = components
+            .map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            bail!("refusing to extract {} outside of the archive", path.display());
+        };
+        let out = relative.iter().fold(dest.join(dir_name), |out, name| out.join(name));
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            fs::create_dir_all(&out)
+                .with_context(|| format!("failed to extract {}", path.display()))?;
+        } else if entry_type.is_file() {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)
+                .with_context(|| format!("failed to read {} from archive", path.display()))?;
+            check(&relative.join("/"), &data)?;
+            if let Some(parent) = out.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to extract {}", path.display()))?;
+            }
+            fs::write(&out, data)
+                .with_context(|| format!("failed to extract {}", path.display()))?;
+        } else {
+            log::warn!("not extracting {}: only files and directories are supported", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn archiving_twice_gives_identical_bytes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path().join("merlon_v1");
+        fs::create_dir_all(root.join("patches"))?;
+        fs::write(root.join("merlon.toml"), "[package]\n")?;
+        fs::write(root.join("patches").join("0002-b.patch"), "b")?;
+        fs::write(root.join("patches").join("0001-a.patch"), "a")?;
+
+        let first = tar_dir(dir.path(), "merlon_v1", None)?;
+        // Rewriting a file changes its mtime, which must not affect the archive
+        fs::write(root.join("merlon.toml"), "[package]\n")?;
+        let second = tar_dir(dir.path(), "merlon_v1", None)?;
+        assert_eq!(first, second);
+        assert_eq!(Compression::Bzip2.compress(&first, None)?, Compression::Bzip2.compress(&second, None)?);
+
+        let mut archive = tar::Archive::new(first.as_slice());
+        let paths: Vec<String> = archive.entries()?
+            .map(|entry| Ok(entry?.path()?.to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        assert_eq!(paths, [
+            "merlon_v1/",
+            "merlon_v1/merlon.toml",
+            "merlon_v1/patches/",
+            "merlon_v1/patches/0001-a.patch",
+            "merlon_v1/patches/0002-b.patch",
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_what_was_archived() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path().join("merlon_v1");
+        fs::create_dir_all(root.join("patches"))?;
+        fs::write(root.join("merlon.toml"), "[package]\n")?;
+        fs::write(root.join("patches").join("0001-a.patch"), "diff --git a/x b/x\n")?;
+        fs::write(dir.path().join("outside.txt"), "not archived")?;
+        let tar = tar_dir(dir.path(), "merlon_v1", None)?;
+
+        for (compression, level) in [
+            (Compression::Bzip2, None),
+            (Compression::Bzip2, Some(1)),
+            (Compression::Zstd, None),
+            (Compression::Zstd, Some(19)),
+            (Compression::None, None),
+        ] {
+            let compressed = compression.compress(&tar, level)?;
+            let out = TempDir::new()?;
+            extract(compressed.as_slice(), compression, out.path(), "merlon_v1", |_, _| Ok(()))?;
+            let mut files: Vec<_> = fs::read_dir(out.path().join("merlon_v1"))?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<_>>()?;
+            files.sort();
+            assert_eq!(files, ["merlon.toml", "patches"], "{compression}");
+            assert!(!out.path().join("outside.txt").exists());
+            assert_eq!(fs::read_to_string(out.path().join("merlon_v1/merlon.toml"))?, "[package]\n");
+            assert_eq!(
+                fs::read_to_string(out.path().join("merlon_v1/patches/0001-a.patch"))?,
+                "diff --git a/x b/x\n",
+            );
+
+            // Anything under another directory is ignored
+            let out = TempDir::new()?;
+            extract(compressed.as_slice(), compression, out.path(), "something_else", |_, _| Ok(()))?;
+            assert_eq!(fs::read_dir(out.path())?.count(), 0);
+        }
+
+        let out = TempDir::new()?;
+        assert!(extract(&b"not compressed"[..], Compression::Bzip2, out.path(), "merlon_v1", |_, _| Ok(())).is_err());
+        assert!(extract(&b"not compressed"[..], Compression::Zstd, out.path(), "merlon_v1", |_, _| Ok(())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn extraction_stops_at_the_first_refused_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path().join("merlon_v1");
+        fs::create_dir_all(root.join("patches"))?;
+        fs::write(root.join("merlon.toml"), "[package]\n")?;
+        fs::write(root.join("patches").join("0001-a.patch"), "a")?;
+        fs::write(root.join("patches").join("0002-b.patch"), "b")?;
+        fs::write(root.join("index"), "")?;
+        let tar = tar_dir(dir.path(), "merlon_v1", Some("index"))?;
+
+        let out = TempDir::new()?;
+        let mut checked = Vec::new();
+        let error = extract(tar.as_slice(), Compression::None, out.path(), "merlon_v1", |path, data| {
+            checked.push(path.to_owned());
+            if data == b"a" {
+                bail!("refused {}", path);
+            }
+            Ok(())
+        }).unwrap_err();
+        assert_eq!(error.to_string(), "refused patches/0001-a.patch");
+        assert_eq!(checked, ["index", "merlon.toml", "patches/0001-a.patch"]);
+        assert!(out.path().join("merlon_v1/merlon.toml").is_file());
+        assert!(!out.path().join("merlon_v1/patches/0001-a.patch").exists());
+        assert!(!out.path().join("merlon_v1/patches/0002-b.patch").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn pipelined_compression_matches_sequential() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path().join("merlon_v1");
+        fs::create_dir_all(root.join("patches"))?;
+        for i in 0..200 {
+            let patch: String = (0..i * 50).map(|j| format!("+line {} of patch {}\n", j, i)).collect();
+            fs::write(root.join("patches").join(format!("{:04}-p.patch", i)), patch)?;
+        }
+
+        let tar = tar_dir(dir.path(), "merlon_v1", None)?;
+        for compression in [Compression::Bzip2, Compression::Zstd, Compression::None] {
+            let sequential = tar_dir_compressed(dir.path(), "merlon_v1", None, compression, None, 1, &Progress::default())?;
+            assert_eq!(sequential, compression.compress(&tar, None)?, "{compression}");
+            let pipelined = tar_dir_compressed(dir.path(), "merlon_v1", None, compression, None, 4, &Progress::default())?;
+            assert!(sequential == pipelined, "{compression} output differs with 4 jobs");
+        }
+        assert!(tar_dir_compressed(dir.path(), "merlon_v1", None, Compression::None, Some(1), 4, &Progress::default()).is_err());
+        assert!(tar_dir_compressed(dir.path(), "missing", None, Compression::Bzip2, None, 4, &Progress::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn levels_are_checked() {
+        assert!(Compression::Bzip2.validate_level(9).is_ok());
+        assert!(Compression::Bzip2.validate_level(10).is_err());
+        assert!(Compression::Zstd.validate_level(22).is_ok());
+        assert!(Compression::Zstd.validate_level(0).is_err());
+        assert!(Compression::None.validate_level(1).is_err());
+        assert!(Compression::None.compress(b"data", Some(1)).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Encryption of distributable contents, compatible with `openssl enc -aes-256-cbc -pbkdf2 -salt`.
+//!
+//! Older versions of Merlon ran the `openssl` binary to encrypt and decrypt distributables, passing the base ROM
+//! with `-pass file:`. This module reproduces that format exactly so existing distributables can still be opened,
+//! and new ones can still be decrypted with `openssl enc -d`:
+//!
+//! ```text
+//! Salted__ | 8-byte salt | AES-256-CBC ciphertext with PKCS#7 padding
+//! ```
+//!
+//! The 32-byte key and 16-byte IV are derived together by PBKDF2-HMAC over the passphrase and salt, using the
+//! digest and iteration count recorded in the [`Header`].
+
+use std::io::{self, Read};
+use std::path::Path;
+use aes::Aes256;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7, generic_array::GenericArray};
+use anyhow::{Result, Context, anyhow, bail};
+use rand::RngCore;
+use sha2::{Sha256, Sha512};
+
+use super::Header;
+use super::header::DEFAULT_KDF;
+
+/// Magic bytes at the start of the encrypted data, followed by the salt.
+pub const SALTED_MAGIC: &[u8] = b"Salted__";
+
+const SALT_LEN: usize = 8;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const BLOCK_LEN: usize = 16;
+
+/// How much ciphertext [`DecryptingReader`] decrypts at a time. Must be a multiple of the block size.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// `openssl` reads at most this many bytes minus one from a `-pass file:` passphrase file.
+const OPENSSL_PASS_LEN: usize = 1024;
+
+/// Reads the passphrase that `openssl enc -pass file:{path}` would use.
+///
+/// `openssl` only uses the first line of the file, up to the first newline or NUL byte. For a z64 ROM this is the
+/// start of its header, so the SHA1 of the base ROM should be checked separately before relying on decryption.
+///
+/// A base ROM that is gzip-compressed or in a zip archive is decompressed first, so that it gives the same
+/// passphrase as the ROM itself.
+pub fn passphrase_from_file(path: &Path) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(OPENSSL_PASS_LEN - 1);
+    crate::rom::compressed::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .take(OPENSSL_PASS_LEN as u64 - 1)
+        .read_to_end(&mut buffer)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let len = buffer.iter().position(|&byte| byte == b'\n' || byte == 0).unwrap_or(buffer.len());
+    buffer.truncate(len);
+    if buffer.is_empty() {
+        bail!("{} does not start with a usable passphrase", path.display());
+    }
+    Ok(buffer)
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase` using the header's KDF parameters.
+pub fn encrypt(plaintext: &[u8], passphrase: &[u8], header: &Header) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    encrypt_with_salt(plaintext, passphrase, header, salt)
+}
+
+fn encrypt_with_salt(plaintext: &[u8], passphrase: &[u8], header: &Header, salt: [u8; SALT_LEN]) -> Result<Vec<u8>> {
+    let (key, iv) = derive_key_and_iv(passphrase, &salt, header)?;
+    let ciphertext = cbc::Encryptor::<Aes256>::new(&key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+
+    let mut output = Vec::with_capacity(SALTED_MAGIC.len() + SALT_LEN + ciphertext.len());
+    output.extend_from_slice(SALTED_MAGIC);
+    output.extend_from_slice(&salt);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+/// Decrypts data produced by [`encrypt`] or `openssl enc`.
+///
+/// A wrong passphrase almost always shows up as invalid padding, which is reported as such.
+pub fn decrypt(encrypted: &[u8], passphrase: &[u8], header: &Header) -> Result<Vec<u8>> {
+    let Some(rest) = encrypted.strip_prefix(SALTED_MAGIC) else {
+        bail!("encrypted data does not start with {:?}", String::from_utf8_lossy(SALTED_MAGIC));
+    };
+    if rest.len() < SALT_LEN {
+        bail!("encrypted data is truncated");
+    }
+    let (salt, ciphertext) = rest.split_at(SALT_LEN);
+    let (key, iv) = derive_key_and_iv(passphrase, salt, header)?;
+    cbc::Decryptor::<Aes256>::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| anyhow!("decryption failed (bad padding); the base ROM is probably wrong"))
+}
+
+/// Decrypts data produced by [`encrypt`] or `openssl enc` as it is read, so that it never has to be held in memory
+/// all at once.
+///
+/// The salt is read straight away; the padding can only be checked at the end, so a wrong passphrase is reported
+/// by the read that reaches it.
+pub fn decrypting_reader<R: Read>(mut encrypted: R, passphrase: &[u8], header: &Header) -> Result<DecryptingReader<R>> {
+    let mut magic = [0; SALTED_MAGIC.len()];
+    let mut salt = [0; SALT_LEN];
+    encrypted.read_exact(&mut magic)
+        .and_then(|_| encrypted.read_exact(&mut salt))
+        .context("encrypted data is truncated")?;
+    if magic != SALTED_MAGIC {
+        bail!("encrypted data does not start with {:?}", String::from_utf8_lossy(SALTED_MAGIC));
+    }
+    let (key, iv) = derive_key_and_iv(passphrase, &salt, header)?;
+    Ok(DecryptingReader {
+        inner: encrypted,
+        decryptor: cbc::Decryptor::<Aes256>::new(&key.into(), &iv.into()),
+        plaintext: Vec::with_capacity(CHUNK_LEN),
+        position: 0,
+        last_block: None,
+        done: false,
+    })
+}
+
+/// Reader returned by [`decrypting_reader`].
+pub struct DecryptingReader<R> {
+    inner: R,
+    decryptor: cbc::Decryptor<Aes256>,
+    /// Decrypted data, of which `position..` has not been read yet.
+    plaintext: Vec<u8>,
+    position: usize,
+    /// The most recently decrypted block, held back until it is known whether it is the padded final one.
+    last_block: Option<[u8; BLOCK_LEN]>,
+    done: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Decrypts the next chunk of ciphertext into `plaintext`.
+    fn fill(&mut self) -> io::Result<()> {
+        self.plaintext.clear();
+        self.position = 0;
+
+        let mut chunk = vec![0; CHUNK_LEN];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.inner.read(&mut chunk[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if len % BLOCK_LEN != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted data is truncated"));
+        }
+
+        if len == 0 {
+            self.done = true;
+            let Some(block) = self.last_block.take() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted data is truncated"));
+            };
+            let padding = block[BLOCK_LEN - 1] as usize;
+            let valid = (1..=BLOCK_LEN).contains(&padding)
+                && block[BLOCK_LEN - padding..].iter().all(|&byte| byte as usize == padding);
+            if !valid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decryption failed (bad padding); the base ROM is probably wrong",
+                ));
+            }
+            self.plaintext.extend_from_slice(&block[..BLOCK_LEN - padding]);
+            return Ok(());
+        }
+
+        if let Some(block) = self.last_block.take() {
+            self.plaintext.extend_from_slice(&block);
+        }
+        let chunk = &mut chunk[..len];
+        for block in chunk.chunks_exact_mut(BLOCK_LEN) {
+            self.decryptor.decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        let (body, last) = chunk.split_at(len - BLOCK_LEN);
+        self.plaintext.extend_from_slice(body);
+        let mut block = [0; BLOCK_LEN];
+        block.copy_from_slice(last);
+        self.last_block = Some(block);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() && !self.done {
+            self.fill()?;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+fn derive_key_and_iv(passphrase: &[u8], salt: &[u8], header: &Header) -> Result<([u8; KEY_LEN], [u8; IV_LEN])> {
+    if header.kdf != DEFAULT_KDF {
+        bail!("unsupported KDF {:?}; the distributable may need a newer version of Merlon to open", header.kdf);
+    }
+    let mut derived = [0; KEY_LEN + IV_LEN];
+    match header.kdf_digest.as_str() {
+        "sha256" => pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, header.kdf_iterations, &mut derived),
+        "sha512" => pbkdf2::pbkdf2_hmac::<Sha512>(passphrase, salt, header.kdf_iterations, &mut derived),
+        digest => bail!("unsupported KDF digest {:?}", digest),
+    }
+    let mut key = [0; KEY_LEN];
+    let mut iv = [0; IV_LEN];
+    key.copy_from_slice(&derived[..KEY_LEN]);
+    iv.copy_from_slice(&derived[KEY_LEN..]);
+    Ok((key, iv))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::process::Command;
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn passphrase_stops_at_newline_or_nul() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("pass");
+        fs::write(&path, [0x80, 0x37, 0x12, 0x40, 0x00, 0x00, 0x00, 0x0F])?;
+        assert_eq!(passphrase_from_file(&path)?, [0x80, 0x37, 0x12, 0x40]);
+        fs::write(&path, b"first line\nsecond line")?;
+        assert_eq!(passphrase_from_file(&path)?, b"first line");
+        fs::write(&path, b"\n")?;
+        assert!(passphrase_from_file(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase_is_read_from_decompressed_rom() -> Result<()> {
+        use std::io::Write;
+        use flate2::{Compression, write::GzEncoder};
+
+        let dir = TempDir::new()?;
+        let raw = [0x80, 0x37, 0x12, 0x40, 0x00, 0x00, 0x00, 0x0F];
+        let path = dir.path().join("baserom.z64.gz");
+        let mut encoder = GzEncoder::new(fs::File::create(&path)?, Compression::default());
+        encoder.write_all(&raw)?;
+        encoder.finish()?;
+        assert_eq!(passphrase_from_file(&path)?, [0x80, 0x37, 0x12, 0x40]);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_and_rejects_wrong_passphrase() -> Result<()> {
+        let header = Header { kdf_iterations: 1_000, ..Header::new() };
+        let plaintext = b"patches go here".repeat(10);
+        let encrypted = encrypt(&plaintext, b"baserom", &header)?;
+        assert!(encrypted.starts_with(SALTED_MAGIC));
+        assert_eq!(decrypt(&encrypted, b"baserom", &header)?, plaintext);
+        assert!(decrypt(&encrypted, b"not the baserom", &header).is_err());
+        assert!(decrypt(b"Salted__", b"baserom", &header).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decrypting_reader_matches_decrypt() -> Result<()> {
+        let header = Header { kdf_iterations: 1_000, ..Header::new() };
+        // Spans several chunks, and ends exactly on a chunk boundary once padded
+        for len in [0, 15, 16, CHUNK_LEN - 1, CHUNK_LEN * 2 + 7] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&plaintext, b"baserom", &header)?;
+            let mut decrypted = Vec::new();
+            decrypting_reader(encrypted.as_slice(), b"baserom", &header)?.read_to_end(&mut decrypted)?;
+            assert_eq!(decrypted, plaintext);
+        }
+        let encrypted = encrypt(b"patches go here", b"baserom", &header)?;
+        let error = decrypting_reader(encrypted.as_slice(), b"not the baserom", &header)?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(error.to_string().contains("bad padding"), "{}", error);
+        assert!(decrypting_reader(&b"Salted__"[..], b"baserom", &header).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn output_is_compatible_with_openssl() -> Result<()> {
+        Command::new("openssl").arg("version").output()
+            .context("openssl must be installed to check compatibility with it")?;
+        let dir = TempDir::new()?;
+        let pass_path = dir.path().join("pass");
+        fs::write(&pass_path, b"baserom")?;
+        let plaintext = b"patches go here".repeat(10);
+
+        for digest in ["sha256", "sha512"] {
+            let header = Header { kdf_iterations: 1_000, kdf_digest: digest.to_owned(), ..Header::new() };
+            let passphrase = passphrase_from_file(&pass_path)?;
+
+            // Ours -> openssl
+            let encrypted_path = dir.path().join("ours.enc");
+            fs::write(&encrypted_path, encrypt(&plaintext, &passphrase, &header)?)?;
+            let output = Command::new("openssl")
+                .args(["enc", "-d", "-aes-256-cbc", "-md", digest, "-pbkdf2", "-iter", "1000"])
+                .arg("-in").arg(&encrypted_path)
+                .arg("-pass").arg(format!("file:{}", pass_path.display()))
+                .output()?;
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            assert_eq!(output.stdout, plaintext);
+
+            // openssl -> ours
+            let plaintext_path = dir.path().join("plain");
+            fs::write(&plaintext_path, &plaintext)?;
+            let theirs_path = dir.path().join("theirs.enc");
+            let status = Command::new("openssl")
+                .args(["enc", "-aes-256-cbc", "-md", digest, "-pbkdf2", "-iter", "1000", "-salt"])
+                .arg("-in").arg(&plaintext_path)
+                .arg("-out").arg(&theirs_path)
+                .arg("-pass").arg(format!("file:{}", pass_path.display()))
+                .status()?;
+            assert!(status.success());
+            assert_eq!(decrypt(&fs::read(&theirs_path)?, &passphrase, &header)?, plaintext);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn salt_is_the_only_source_of_variation() -> Result<()> {
+        let header = Header { kdf_iterations: 1_000, ..Header::new() };
+        let a = encrypt_with_salt(b"data", b"baserom", &header, [1; SALT_LEN])?;
+        let b = encrypt_with_salt(b"data", b"baserom", &header, [1; SALT_LEN])?;
+        assert_eq!(a, b);
+        let c = encrypt_with_salt(b"data", b"baserom", &header, [2; SALT_LEN])?;
+        assert_ne!(a, c);
+        Ok(())
+    }
+}
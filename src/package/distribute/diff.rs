@@ -0,0 +1,140 @@
+//! Comparing the patches of two distributables, such as two releases of a package.
+//!
+//! Both distributables are opened into temporary directories, whatever compression they were exported with, and
+//! their patches are compared with `git diff --no-index`. Nothing is built.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{Result, Context, bail};
+use temp_dir::TempDir;
+
+use crate::package::Package;
+use super::Distributable;
+
+/// The differences between the patches of two packages, as returned by [`Distributable::diff`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchDiff {
+    /// Names of the patch files that only the new package has.
+    pub added: Vec<String>,
+
+    /// Names of the patch files that only the old package has.
+    pub removed: Vec<String>,
+
+    /// Names of the patch files that both packages have, but with different contents.
+    pub changed: Vec<String>,
+
+    /// Unified diff of the added, removed and changed patch files. Paths start with `old/` or `new/`.
+    pub unified: String,
+}
+
+impl PatchDiff {
+    /// Returns true if the packages have the same patches.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Distributable {
+    /// Compares the patches of this distributable with those of `new`, such as a later release of the same package.
+    /// Both are opened with `baserom`.
+    pub fn diff(&self, new: &Distributable, baserom: PathBuf) -> Result<PatchDiff> {
+        self.open_scoped(baserom.clone(), |old| {
+            new.open_scoped(baserom, |new| diff_packages(&old, &new))
+        })
+    }
+}
+
+/// Compares the patches of two packages.
+pub fn diff_packages(old: &Package, new: &Package) -> Result<PatchDiff> {
+    let old_patches = patches_by_name(old)?;
+    let new_patches = patches_by_name(new)?;
+
+    let mut diff = PatchDiff::default();
+    for (name, path) in &new_patches {
+        match old_patches.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(old_path) if fs::read(old_path)? != fs::read(path)? => diff.changed.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = old_patches.keys()
+        .filter(|name| !new_patches.contains_key(*name))
+        .cloned()
+        .collect();
+    if diff.is_empty() {
+        return Ok(diff);
+    }
+
+    // Copy the patches next to each other so that the paths in the diff are short and don't depend on where the
+    // packages were opened
+    let work_dir = TempDir::new().context("failed to create temporary directory")?;
+    copy_patches(&old_patches, &work_dir.path().join("old"))?;
+    copy_patches(&new_patches, &work_dir.path().join("new"))?;
+    let output = Command::new("git")
+        .args(["diff", "--no-index", "--no-color", "--no-ext-diff", "--no-renames", "--no-prefix", "old", "new"])
+        .current_dir(work_dir.path())
+        .output()
+        .context("failed to run git diff")?;
+    // git diff --no-index exits with 1 when the files differ, which they do
+    if output.status.code() != Some(1) {
+        bail!("git diff failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    diff.unified = String::from_utf8_lossy(&output.stdout).into_owned();
+    Ok(diff)
+}
+
+/// Maps the file name of each of the package's patches to its path.
+fn patches_by_name(package: &Package) -> Result<BTreeMap<String, PathBuf>> {
+    let mut patches = BTreeMap::new();
+    for path in package.patch_files()? {
+        if let Some(name) = path.file_name() {
+            patches.insert(name.to_string_lossy().into_owned(), path);
+        }
+    }
+    Ok(patches)
+}
+
+fn copy_patches(patches: &BTreeMap<String, PathBuf>, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (name, path) in patches {
+        fs::copy(path, dir.join(name))
+            .with_context(|| format!("failed to copy {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lists_added_removed_and_changed_patches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let old = Package::new("Old", dir.path().join("old"))?;
+        let new = Package::new("New", dir.path().join("new"))?;
+        let write = |package: &Package, name: &str, contents: &str| {
+            fs::write(package.path().join("patches").join(name), contents)
+        };
+        write(&old, "0001-same.patch", "diff --git a/same.c b/same.c\n")?;
+        write(&new, "0001-same.patch", "diff --git a/same.c b/same.c\n")?;
+        write(&old, "0002-tweak.patch", "+int x = 1;\n")?;
+        write(&new, "0002-tweak.patch", "+int x = 2;\n")?;
+        write(&old, "0003-gone.patch", "+gone\n")?;
+        write(&new, "0003-new.patch", "+new\n")?;
+
+        let diff = diff_packages(&old, &new)?;
+        assert_eq!(diff.added, ["0003-new.patch"]);
+        assert_eq!(diff.removed, ["0003-gone.patch"]);
+        assert_eq!(diff.changed, ["0002-tweak.patch"]);
+        assert!(diff.unified.contains("-+int x = 1;\n++int x = 2;\n"), "{}", diff.unified);
+        assert!(diff.unified.contains("new/0003-new.patch"), "{}", diff.unified);
+        assert!(!diff.unified.contains("0001-same.patch"), "{}", diff.unified);
+
+        let same = diff_packages(&old, &old)?;
+        assert!(same.is_empty());
+        assert!(same.unified.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+//! Errors that exporting a distributable can fail with, so that callers can tell them apart.
+//!
+//! Export functions return [`anyhow::Error`]s like the rest of Merlon, with extra context attached. The failures a
+//! user can fix are raised as an [`ExportError`], which can be recovered with [`ExportError::of`]:
+//!
+//! ```no_run
+//! # use merlon::package::{Package, distribute::{ExportError, ExportOptions}};
+//! # let package = Package::current().unwrap().unwrap();
+//! match package.export_distributable(ExportOptions::default()) {
+//!     Ok(distributable) => println!("exported {}", distributable),
+//!     Err(error) => match ExportError::of(&error) {
+//!         Some(ExportError::NoCommits { .. }) => println!("commit something first"),
+//!         _ => println!("{:#}", error),
+//!     },
+//! }
+//! ```
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::package::{MANIFEST_FILE_NAME, init::SUBREPO_DIR_NAME};
+use super::format_bytes;
+
+/// A failure to export a package that the user can fix.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ExportError {
+    /// The base ROM, which the distributable is encrypted with, does not exist.
+    #[error("baserom not found at {}; place the {region} ROM there or pass --baserom", .path.display())]
+    MissingBaserom {
+        /// Where the base ROM was looked for.
+        path: PathBuf,
+        /// The decomp version of the ROM that is needed, such as `us`.
+        region: String,
+    },
+
+    /// A file that every distributable includes, such as `README.md` or `LICENSE`, is missing from the package.
+    #[error("package is missing {0}, which every distributable includes; create it and export again")]
+    MissingMetadata(&'static str),
+
+    /// The decomp repository has files that are not tracked by git, which would be left out of the patches.
+    #[error(
+        "repo has untracked files that would not be exported: {}. \
+        Add and commit them with git, or pass --include-untracked to `merlon export`",
+        .0.join(", ")
+    )]
+    UntrackedFiles(Vec<String>),
+
+    /// The decomp repository has uncommitted changes, which would be left out of the patches.
+    #[error(
+        "repo has uncommitted changes that would not be exported: {}. \
+        Commit them with git, or pass --allow-dirty to `merlon export` to leave them out",
+        .0.join(", ")
+    )]
+    DirtyTree(Vec<String>),
+
+    /// The package has no commits and no dependencies, so its distributable would change nothing.
+    #[error(
+        "package has no commits in {SUBREPO_DIR_NAME}/ since {base}, so there is nothing to export. \
+        Commit your changes with `git -C {SUBREPO_DIR_NAME} commit`, \
+        and check that the decomp revision in {MANIFEST_FILE_NAME} is the commit you started from. \
+        To export a package without patches, pass --allow-empty"
+    )]
+    NoCommits {
+        /// The branch that commits were counted from.
+        base: String,
+    },
+
+    /// The distributable would be larger than the `max_size` it was exported with.
+    #[error(
+        "distributable would be {}, more than the maximum of {}; run `merlon export --dry-run` to see the largest \
+        patches, and check for files like a baserom or build output that were committed by accident",
+        format_bytes(.size.to_owned()),
+        format_bytes(.max_size.to_owned())
+    )]
+    TooLarge {
+        /// Size of the distributable in bytes.
+        size: u64,
+        /// The maximum size in bytes.
+        max_size: u64,
+    },
+
+    /// The output file already exists.
+    #[error("{} already exists, pass --force to overwrite it", .0.display())]
+    OutputExists(PathBuf),
+}
+
+impl ExportError {
+    /// Finds the [`ExportError`] that caused `error`, if there is one. Context added to it is looked through.
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.downcast_ref()
+    }
+
+    /// A stable name for the kind of error, such as `no_commits`, for scripts to match on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MissingBaserom { .. } => "missing_baserom",
+            Self::MissingMetadata(_) => "missing_metadata",
+            Self::UntrackedFiles(_) => "untracked_files",
+            Self::DirtyTree(_) => "dirty_tree",
+            Self::NoCommits { .. } => "no_commits",
+            Self::TooLarge { .. } => "too_large",
+            Self::OutputExists(_) => "output_exists",
+        }
+    }
+}
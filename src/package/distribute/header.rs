@@ -0,0 +1,415 @@
+//! The unencrypted header at the start of a distributable.
+//!
+//! A distributable is laid out as:
+//!
+//!  MERLONHD                    - Magic bytes
+//!  u32 (little-endian)         - Length of the header TOML in bytes
+//!  TOML                        - The [`Header`]
+//!  ...                         - The encrypted tarball, whose SHA1 is in the header
+//!
+//! Distributables created before the header existed are just the encrypted tarball, which begins with openssl's
+//! `Salted__` magic instead. These are still supported, and have no header.
+
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+use pyo3::prelude::*;
+use sha1::{Sha1, Digest};
+
+use crate::package::{Id, PackageId};
+
+use super::archive::Compression;
+use super::signing::PayloadSignature;
+
+/// Magic bytes that identify a distributable with a header.
+pub const MAGIC: &[u8; 8] = b"MERLONHD";
+
+/// Headers larger than this are rejected when reading, as the file is almost certainly corrupt.
+const MAX_HEADER_LEN: u32 = 1024 * 1024;
+
+/// Key derivation function used by every distributable so far. Recorded so that a future one can be told apart.
+pub const DEFAULT_KDF: &str = "pbkdf2";
+
+/// Key derivation functions that this version of Merlon can derive keys with.
+pub const KDFS: &[&str] = &[DEFAULT_KDF];
+
+/// PBKDF2 iteration count used when none is specified, and by distributables that predate it being configurable.
+pub const DEFAULT_KDF_ITERATIONS: u32 = 100_000;
+
+/// PBKDF2 digest used when none is specified, and by distributables that predate it being configurable.
+pub const DEFAULT_KDF_DIGEST: &str = "sha512";
+
+/// Fewer PBKDF2 iterations than this are not considered secure.
+pub const MIN_KDF_ITERATIONS: u32 = 10_000;
+
+/// More PBKDF2 iterations than this would make opening a distributable take unreasonably long.
+pub const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+
+/// Digests that may be used for PBKDF2. These are named as `openssl enc -md` names them.
+pub const KDF_DIGESTS: &[&str] = &["sha256", "sha512"];
+
+/// Version of the distributable format written by this version of Merlon. Distributables with a newer format
+/// version are refused, since they may not be readable. Headers without a format version are version 1.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Metadata about a distributable that can be read without the base ROM.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[pyclass(module = "merlon.package.distribute")]
+pub struct Header {
+    /// The version of Merlon that created the distributable.
+    #[pyo3(get)]
+    pub merlon_version: String,
+
+    /// Version of the distributable format, see [`FORMAT_VERSION`].
+    #[serde(default = "default_format_version")]
+    #[pyo3(get)]
+    pub format_version: u32,
+
+    /// Key derivation function used to derive the encryption key from the base ROM, see [`KDFS`].
+    #[serde(default = "default_kdf")]
+    #[pyo3(get)]
+    pub kdf: String,
+
+    /// Number of PBKDF2 iterations used to derive the encryption key from the base ROM.
+    #[serde(default = "default_kdf_iterations")]
+    #[pyo3(get)]
+    pub kdf_iterations: u32,
+
+    /// Digest used by PBKDF2 to derive the encryption key from the base ROM.
+    #[serde(default = "default_kdf_digest")]
+    #[pyo3(get)]
+    pub kdf_digest: String,
+
+    /// The ID of the package in the distributable, so that it can be found without decrypting it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_id: Option<Id>,
+
+    /// The name and version of the package in the distributable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<PackageId>,
+
+    /// Whether the distributable contains no patches, only metadata and documentation.
+    /// Applying such a distributable does not run `git am`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[pyo3(get)]
+    pub patch_empty: bool,
+
+    /// SHA1 of the ROM the author built from the package, if they recorded it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[pyo3(get)]
+    pub rom_sha1: Option<String>,
+
+    /// How the tarball is compressed. Distributables that predate this being configurable use bzip2.
+    #[serde(default, skip_serializing_if = "Compression::is_default")]
+    #[pyo3(get)]
+    pub compression: Compression,
+
+    /// Dependencies bundled inside the distributable by `merlon export --bundle-deps`, including indirect ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bundled_dependencies: Vec<PackageId>,
+
+    /// SHA1 of the encrypted tarball that follows the header, to detect truncated or corrupt distributables before
+    /// decrypting them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[pyo3(get)]
+    pub payload_sha1: Option<String>,
+
+    /// Signature over the encrypted tarball by the package author, if they exported with `--sign`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[pyo3(get)]
+    pub signature: Option<PayloadSignature>,
+}
+
+fn default_format_version() -> u32 {
+    1
+}
+
+fn default_kdf() -> String {
+    DEFAULT_KDF.to_owned()
+}
+
+fn default_kdf_iterations() -> u32 {
+    DEFAULT_KDF_ITERATIONS
+}
+
+fn default_kdf_digest() -> String {
+    DEFAULT_KDF_DIGEST.to_owned()
+}
+
+impl Header {
+    /// Creates a header for a distributable created by this version of Merlon.
+    pub fn new() -> Self {
+        Self {
+            merlon_version: crate::version().to_owned(),
+            format_version: FORMAT_VERSION,
+            kdf: DEFAULT_KDF.to_owned(),
+            kdf_iterations: DEFAULT_KDF_ITERATIONS,
+            kdf_digest: DEFAULT_KDF_DIGEST.to_owned(),
+            package_id: None,
+            package: None,
+            patch_empty: false,
+            rom_sha1: None,
+            compression: Compression::default(),
+            bundled_dependencies: Vec::new(),
+            payload_sha1: None,
+            signature: None,
+        }
+    }
+
+    /// Returns the header that distributables without a header implicitly have.
+    /// The Merlon version that created them is unknown.
+    pub fn legacy() -> Self {
+        Self {
+            merlon_version: "unknown".to_owned(),
+            format_version: 0,
+            ..Self::new()
+        }
+    }
+
+    /// Errors if the key derivation parameters are too weak, too slow, or not supported.
+    pub fn validate_kdf(&self) -> Result<()> {
+        if !KDFS.contains(&self.kdf.as_str()) {
+            bail!("unsupported KDF {:?}, this version of Merlon supports: {}", self.kdf, KDFS.join(", "));
+        }
+        if self.kdf_iterations < MIN_KDF_ITERATIONS {
+            bail!("{} KDF iterations is too few to be secure, use at least {}", self.kdf_iterations, MIN_KDF_ITERATIONS);
+        }
+        if self.kdf_iterations > MAX_KDF_ITERATIONS {
+            bail!("{} KDF iterations is too many to be usable, use at most {}", self.kdf_iterations, MAX_KDF_ITERATIONS);
+        }
+        if !KDF_DIGESTS.contains(&self.kdf_digest.as_str()) {
+            bail!("unsupported KDF digest {:?}, expected one of: {}", self.kdf_digest, KDF_DIGESTS.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Errors if `payload` is not the encrypted tarball this header was written with.
+    ///
+    /// Headers without a recorded checksum accept any payload.
+    pub fn verify_payload(&self, payload: &[u8]) -> Result<()> {
+        self.verify_payload_sha1(&payload_sha1(payload))
+    }
+
+    /// Like [`Header::verify_payload`], given the payload's SHA1 as computed by [`HashingReader`].
+    pub fn verify_payload_sha1(&self, actual: &str) -> Result<()> {
+        let Some(expected) = &self.payload_sha1 else {
+            return Ok(());
+        };
+        if actual != expected {
+            bail!("distributable is truncated or corrupt: its contents have SHA1 {}, but its header expects {}", actual, expected);
+        }
+        Ok(())
+    }
+
+    /// Writes the magic bytes and header.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let toml = toml::to_string(self).context("failed to serialize header")?;
+        let len = u32::try_from(toml.len()).context("header is too large")?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(toml.as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads the header from the start of a distributable, leaving the reader positioned at the encrypted tarball.
+    ///
+    /// Returns `None` if the distributable predates headers, in which case the reader is rewound to the start.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Option<Self>> {
+        let mut magic = [0; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => {},
+            Ok(()) => {
+                reader.seek(SeekFrom::Start(0))?;
+                return Ok(None);
+            },
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                bail!("file is too short to be a distributable");
+            },
+            Err(error) => return Err(error.into()),
+        }
+
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).context("failed to read header length")?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_HEADER_LEN {
+            bail!("header is implausibly large ({} bytes), the distributable is probably corrupt", len);
+        }
+        let mut toml = vec![0; len as usize];
+        reader.read_exact(&mut toml).context("failed to read header")?;
+        let toml = String::from_utf8(toml).context("header is not valid UTF-8")?;
+        let header: Self = toml::from_str(&toml).context("failed to parse header")?;
+        if header.format_version > FORMAT_VERSION {
+            bail!(
+                "distributable has format version {}, but this version of Merlon only supports up to {}; \
+                 it was created by Merlon {}, try upgrading",
+                header.format_version, FORMAT_VERSION, header.merlon_version,
+            );
+        }
+        Ok(Some(header))
+    }
+}
+
+/// Computes the SHA1 of an encrypted tarball, as recorded in [`Header::payload_sha1`].
+pub fn payload_sha1(payload: &[u8]) -> String {
+    crate::rom::to_hex(&Sha1::digest(payload))
+}
+
+/// Computes [`payload_sha1`] of everything read through it, so a payload can be checked as it is streamed.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha1,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Wraps a reader positioned at the start of the payload.
+    pub fn new(inner: R) -> Self {
+        Self { inner, hasher: Sha1::new() }
+    }
+
+    /// Reads the rest of the payload and returns its SHA1.
+    pub fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(crate::rom::to_hex(&self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn header_round_trips() -> Result<()> {
+        let mut bytes = Vec::new();
+        Header::new().write_to(&mut bytes)?;
+        bytes.extend_from_slice(b"Salted__payload");
+
+        let mut reader = Cursor::new(bytes);
+        let header = Header::read_from(&mut reader)?;
+        assert_eq!(header, Some(Header::new()));
+        let mut payload = Vec::new();
+        reader.read_to_end(&mut payload)?;
+        assert_eq!(payload, b"Salted__payload");
+        Ok(())
+    }
+
+    #[test]
+    fn package_identity_round_trips() -> Result<()> {
+        let header = Header {
+            package_id: Some(Id::new()),
+            package: Some("Skip intro@1.2.3".parse()?),
+            ..Header::new()
+        };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        assert_eq!(Header::read_from(&mut Cursor::new(bytes))?, Some(header));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_kdf_parameters_use_defaults() -> Result<()> {
+        let header: Header = toml::from_str("merlon_version = \"1.3.0\"")?;
+        assert_eq!(header.kdf, DEFAULT_KDF);
+        assert_eq!(header.kdf_iterations, DEFAULT_KDF_ITERATIONS);
+        assert_eq!(header.kdf_digest, DEFAULT_KDF_DIGEST);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_round_trips_and_defaults_to_bzip2() -> Result<()> {
+        let header: Header = toml::from_str("merlon_version = \"1.3.0\"")?;
+        assert_eq!(header.compression, Compression::Bzip2);
+
+        let header = Header { compression: Compression::Zstd, ..Header::new() };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        assert_eq!(Header::read_from(&mut Cursor::new(bytes))?, Some(header));
+        Ok(())
+    }
+
+    #[test]
+    fn absurd_kdf_parameters_are_rejected() {
+        assert!(Header::new().validate_kdf().is_ok());
+        assert!(Header { kdf_iterations: 1, ..Header::new() }.validate_kdf().is_err());
+        assert!(Header { kdf_iterations: u32::MAX, ..Header::new() }.validate_kdf().is_err());
+        assert!(Header { kdf_digest: "md5".to_owned(), ..Header::new() }.validate_kdf().is_err());
+        assert!(Header { kdf: "argon2id".to_owned(), ..Header::new() }.validate_kdf().is_err());
+    }
+
+    #[test]
+    fn headers_without_format_version_are_version_1() -> Result<()> {
+        let header: Header = toml::from_str("merlon_version = \"1.3.0\"")?;
+        assert_eq!(header.format_version, 1);
+        assert_eq!(header.payload_sha1, None);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_format_versions_are_refused() -> Result<()> {
+        let mut bytes = Vec::new();
+        Header { format_version: FORMAT_VERSION + 1, ..Header::new() }.write_to(&mut bytes)?;
+        let error = Header::read_from(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(error.to_string().contains("try upgrading"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn payload_checksum_detects_corruption() -> Result<()> {
+        let payload = b"Salted__payload".to_vec();
+        let header = Header { payload_sha1: Some(payload_sha1(&payload)), ..Header::new() };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        assert_eq!(Header::read_from(&mut Cursor::new(bytes))?, Some(header.clone()));
+
+        assert!(header.verify_payload(&payload).is_ok());
+        let error = header.verify_payload(&payload[..payload.len() - 1]).unwrap_err();
+        assert!(error.to_string().contains("truncated or corrupt"), "{}", error);
+        let mut flipped = payload.clone();
+        flipped[10] ^= 1;
+        assert!(header.verify_payload(&flipped).is_err());
+
+        // Older headers have no checksum to check
+        assert!(Header::new().verify_payload(&flipped).is_ok());
+
+        // Streaming gives the same checksum
+        let mut reader = HashingReader::new(payload.as_slice());
+        reader.read_exact(&mut [0; 4])?;
+        assert!(header.verify_payload_sha1(&reader.finish()?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn signature_round_trips() -> Result<()> {
+        let header = Header {
+            signature: Some(PayloadSignature { public_key: "ab".repeat(32), signature: "cd".repeat(64) }),
+            ..Header::new()
+        };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        assert_eq!(Header::read_from(&mut Cursor::new(bytes))?, Some(header));
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_distributable_has_no_header() -> Result<()> {
+        let mut reader = Cursor::new(b"Salted__payload".to_vec());
+        assert_eq!(Header::read_from(&mut reader)?, None);
+        assert_eq!(reader.position(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+//! Turning a distributable and a base ROM into a playable ROM in one step.
+//!
+//! Installing does what [`Distributable::apply`] does, but builds in a directory that is kept if anything goes
+//! wrong, so that a failed build can be looked into rather than starting again from scratch.
+
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use pyo3::prelude::*;
+
+use crate::package::Package;
+use crate::rom::Rom;
+use super::{Distributable, ApplyOptions, OpenOptions, TARGET_DIR_ENV_VAR};
+
+/// Name of the directory inside the target directory that is built in.
+const INSTALL_SUBDIR_NAME: &str = "install";
+
+/// Options for [`Distributable::install`].
+#[derive(Parser, Debug, Clone)]
+#[pyclass(module = "merlon.package.distribute")]
+pub struct InstallOptions {
+    /// Options to apply the distributable with.
+    #[clap(flatten)]
+    #[pyo3(get, set)]
+    pub apply_options: ApplyOptions,
+
+    /// Directory to build in, which must not exist or be empty.
+    ///
+    /// Defaults to `install/` in the `MERLON_TARGET_DIR` environment variable if it is set, or a new directory in
+    /// the system's temporary directory. It is deleted once the ROM is built, and kept if anything fails.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub work_dir: Option<PathBuf>,
+
+    /// Keep the build directory even if installing succeeds.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub keep_intermediates: bool,
+}
+
+impl Distributable {
+    /// Opens the distributable, applies it and its bundled dependencies to the decomp revision it was made for, and
+    /// builds the ROM. See [`InstallOptions`] for what happens to the build directory.
+    pub fn install(&self, options: InstallOptions) -> Result<Rom> {
+        self.install_with(options, |_| Ok(()))
+    }
+
+    /// Like [`Distributable::install`], calling `on_opened` with the package as soon as it has been opened, so that
+    /// it can be looked at without decrypting the distributable again.
+    pub fn install_with(&self, options: InstallOptions, on_opened: impl FnOnce(&Package) -> Result<()>) -> Result<Rom> {
+        let expected_sha1 = self.expected_rom_sha1(options.apply_options.verify_after)?;
+        let work_dir = match options.work_dir {
+            Some(work_dir) => {
+                if work_dir.is_dir() && fs::read_dir(&work_dir)?.next().is_some() {
+                    bail!("build directory {} is not empty; delete it or pass another --work-dir", work_dir.display());
+                }
+                work_dir
+            }
+            None => match std::env::var_os(TARGET_DIR_ENV_VAR) {
+                Some(target_dir) => {
+                    // Left over from a failed install, like the intermediates of an export
+                    let work_dir = PathBuf::from(target_dir).join(INSTALL_SUBDIR_NAME);
+                    if work_dir.exists() {
+                        fs::remove_dir_all(&work_dir)
+                            .with_context(|| format!("failed to clear build directory {}", work_dir.display()))?;
+                    }
+                    work_dir
+                }
+                None => std::env::temp_dir().join(format!("merlon-install-{}", uuid::Uuid::new_v4())),
+            },
+        };
+        if let Some(parent) = work_dir.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+
+        let apply_options = options.apply_options;
+        let result = self
+            .open_to_dir(OpenOptions {
+                output: Some(work_dir.clone()),
+                baserom: apply_options.baserom.clone(),
+            })
+            .and_then(|package| {
+                on_opened(&package)?;
+                super::build_applied(package, apply_options)
+            })
+            .and_then(|rom| {
+                super::check_rom_sha1(&rom, expected_sha1)?;
+                Ok(rom)
+            });
+        match result {
+            Ok(rom) if options.keep_intermediates => {
+                log::info!("kept build directory {}", work_dir.display());
+                Ok(rom)
+            }
+            Ok(rom) => {
+                fs::remove_dir_all(&work_dir)
+                    .with_context(|| format!("failed to remove build directory {}", work_dir.display()))?;
+                Ok(rom)
+            }
+            Err(error) => {
+                if work_dir.exists() {
+                    log::error!("kept build directory {} for debugging", work_dir.display());
+                }
+                Err(error).with_context(|| format!("failed to install {}", self))
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Listing what a distributable contains, so that it can be inspected before it is applied.
+
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::package::Manifest;
+use crate::package::patch;
+use super::{Distributable, PATCHES_DIR_NAME, relative_file_paths};
+
+/// The contents of a distributable, as returned by [`Distributable::list`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    /// The manifest of the package in the distributable.
+    pub manifest: Manifest,
+
+    /// The patch files, in the order they apply.
+    pub patches: Vec<ListedPatch>,
+
+    /// Every other file, such as `merlon.toml`, `README.md` and extra files, relative to the package's root.
+    pub files: Vec<String>,
+
+    /// Total size in bytes of the patches and files.
+    pub uncompressed_size: u64,
+}
+
+/// A patch file in a [`Listing`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedPatch {
+    /// Path of the patch file relative to the package's root, such as `patches/0001-skip-logos.patch`.
+    pub path: String,
+
+    /// The subject of the commit the patch was made from. Patches exported with `--patch-format diff` have none.
+    pub subject: Option<String>,
+
+    /// Size of the patch file in bytes.
+    pub size: u64,
+}
+
+impl Distributable {
+    /// Opens the distributable with `baserom` and lists its patches and files, without applying it.
+    pub fn list(&self, baserom: PathBuf) -> Result<Listing> {
+        self.open_scoped(baserom, |package| {
+            let mut listing = Listing {
+                manifest: package.manifest()?,
+                patches: Vec::new(),
+                files: Vec::new(),
+                uncompressed_size: 0,
+            };
+            for path in relative_file_paths(package.path())? {
+                let contents = fs::read(package.path().join(&path))?;
+                let size = contents.len() as u64;
+                listing.uncompressed_size += size;
+                if path.starts_with(&format!("{}/", PATCHES_DIR_NAME)) {
+                    let subject = patch::subject(&String::from_utf8_lossy(&contents));
+                    listing.patches.push(ListedPatch { path, subject, size });
+                } else {
+                    listing.files.push(path);
+                }
+            }
+            Ok(listing)
+        })
+    }
+}
@@ -0,0 +1,76 @@
+//! Running independent per-file work on several threads while keeping results in a deterministic order.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{Result, anyhow};
+
+/// The number of jobs to use when none is given: the number of CPUs, or 1 if that is unknown.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Calls `f` on every item using up to `jobs` threads, and returns the results in the same order as `items`.
+///
+/// If any call fails, the error for the earliest failing item is returned, so the outcome doesn't depend on which
+/// thread finishes first. With one job or one item, everything runs on the calling thread.
+pub fn map<T, R, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let jobs = jobs.min(items.len());
+    if jobs <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<R>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| scope.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        break done;
+                    };
+                    done.push((index, f(item)));
+                }
+            }))
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|_| vec![(0, Err(anyhow!("worker thread panicked")))]))
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::bail;
+
+    use super::*;
+
+    #[test]
+    fn results_keep_item_order() -> Result<()> {
+        let items: Vec<u32> = (0..100).collect();
+        for jobs in [1, 4, 200] {
+            assert_eq!(map(&items, jobs, |item| Ok(item * 2))?, items.iter().map(|item| item * 2).collect::<Vec<_>>());
+        }
+        assert!(map(&[] as &[u32], 4, |item| Ok(*item))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn earliest_error_is_returned() {
+        let items: Vec<u32> = (0..100).collect();
+        let error = map(&items, 4, |&item| {
+            if item % 30 == 29 {
+                bail!("item {} failed", item);
+            }
+            Ok(item)
+        }).unwrap_err();
+        assert_eq!(error.to_string(), "item 29 failed");
+    }
+}
@@ -0,0 +1,115 @@
+//! Ed25519 signatures over the encrypted payload of a distributable.
+//!
+//! Anyone with the base ROM can decrypt a distributable, so encryption says nothing about who made it. A signature
+//! does: it is made with the author's private key over the encrypted tarball, and stored in the [`Header`] along with
+//! the author's public key. Recipients compare the public key against one the author published to know who signed it.
+//!
+//! Signing keys are read from either a PKCS#8 PEM file, such as one made by
+//! `openssl genpkey -algorithm ed25519 -out key.pem`, or a file containing the 32-byte secret key in hex.
+//!
+//! [`Header`]: super::Header
+
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, Context, anyhow};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey, SECRET_KEY_LENGTH, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::rom::{to_hex, from_hex};
+
+/// A signature over a distributable's encrypted payload, and the public key that made it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[pyclass(module = "merlon.package.distribute")]
+pub struct PayloadSignature {
+    /// The signer's ed25519 public key, in hex.
+    #[pyo3(get)]
+    pub public_key: String,
+
+    /// The ed25519 signature of the encrypted payload, in hex.
+    #[pyo3(get)]
+    pub signature: String,
+}
+
+impl PayloadSignature {
+    /// Signs `payload` with `key`.
+    pub fn sign(key: &SigningKey, payload: &[u8]) -> Self {
+        Self {
+            public_key: to_hex(key.verifying_key().as_bytes()),
+            signature: to_hex(&key.sign(payload).to_bytes()),
+        }
+    }
+
+    /// Errors if the signature was not made over `payload` by the private key matching [`Self::public_key`].
+    pub fn verify(&self, payload: &[u8]) -> Result<()> {
+        let public_key: [u8; PUBLIC_KEY_LENGTH] = from_hex(&self.public_key)
+            .context("invalid public key")?;
+        let public_key = VerifyingKey::from_bytes(&public_key)
+            .map_err(|error| anyhow!("invalid public key: {}", error))?;
+        let signature: [u8; SIGNATURE_LENGTH] = from_hex(&self.signature)
+            .context("invalid signature")?;
+        let signature = ed25519_dalek::Signature::from_bytes(&signature);
+        public_key.verify_strict(payload, &signature)
+            .map_err(|_| anyhow!("signature does not match the contents, or was not made by key {}", self.public_key))
+    }
+}
+
+/// Reads an ed25519 signing key from a PKCS#8 PEM file or a hex-encoded secret key file.
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read signing key {}", path.display()))?;
+    let contents = contents.trim();
+    let key = if contents.starts_with("-----BEGIN") {
+        SigningKey::from_pkcs8_pem(contents)
+            .map_err(|error| anyhow!("{}", error))
+    } else {
+        from_hex::<SECRET_KEY_LENGTH>(contents).map(|secret| SigningKey::from_bytes(&secret))
+    };
+    key.with_context(|| format!("{} is not an ed25519 private key in PKCS#8 PEM or hex", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; SECRET_KEY_LENGTH])
+    }
+
+    #[test]
+    fn valid_signature_verifies() -> Result<()> {
+        let signature = PayloadSignature::sign(&key(1), b"Salted__payload");
+        signature.verify(b"Salted__payload")
+    }
+
+    #[test]
+    fn signature_from_another_key_is_rejected() {
+        let signature = PayloadSignature {
+            public_key: PayloadSignature::sign(&key(2), b"").public_key,
+            ..PayloadSignature::sign(&key(1), b"Salted__payload")
+        };
+        assert!(signature.verify(b"Salted__payload").is_err());
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let signature = PayloadSignature::sign(&key(1), b"Salted__payload");
+        let error = signature.verify(b"Salted__paylobd").unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+    }
+
+    #[test]
+    fn hex_key_file_is_read() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("key");
+        fs::write(&path, format!("{}\n", to_hex(&[7; SECRET_KEY_LENGTH])))?;
+        assert_eq!(read_signing_key(&path)?.to_bytes(), key(7).to_bytes());
+
+        fs::write(&path, "not a key")?;
+        assert!(read_signing_key(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Checking that a distributable is well-formed without applying it.
+//!
+//! Verification opens the distributable into a temporary directory, and checks that its patches apply in a temporary
+//! clone of a decomp repository, so neither the current directory nor the repository are changed.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{Result, Context, anyhow, bail};
+use clap::Parser;
+use pyo3::prelude::*;
+use temp_dir::TempDir;
+
+use crate::package::Package;
+use super::{Distributable, Header, OpenOptions};
+
+/// Options for [`Distributable::verify`].
+#[derive(Parser, Debug, Clone)]
+#[pyclass(module = "merlon.package.distribute")]
+pub struct VerifyOptions {
+    /// The base ROM, used to decrypt the distributable. Defaults to the `MERLON_BASEROM` environment variable.
+    #[arg(long, env = super::BASEROM_ENV_VAR)]
+    #[pyo3(get, set)]
+    pub baserom: PathBuf,
+
+    /// A clone of the decomp repository to check that the patches apply to.
+    ///
+    /// The patches are applied to a temporary clone of it at the package's decomp revision, so it is not changed.
+    /// If not specified, the patches are not checked.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub repo: Option<PathBuf>,
+
+    /// Fail if the distributable is not signed with `merlon export --sign`.
+    ///
+    /// Signatures are always checked when present. Compare the public key printed by `merlon info` with the one the
+    /// author published to know who signed it.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub check_signature: bool,
+}
+
+/// The outcome of one check made by [`Distributable::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The check found nothing wrong.
+    Passed,
+    /// The check found a problem, described by the message.
+    Failed(String),
+    /// The check could not be made, such as because an earlier check failed.
+    Skipped(String),
+}
+
+/// One check made by [`Distributable::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// What was checked, such as `payload checksum`.
+    pub name: &'static str,
+
+    /// Whether the check passed.
+    pub outcome: Outcome,
+}
+
+/// The result of [`Distributable::verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Every check made, in order.
+    pub checks: Vec<Check>,
+}
+
+impl VerifyReport {
+    /// Returns true if no check failed. Skipped checks do not count as failures.
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|check| matches!(check.outcome, Outcome::Failed(_)))
+    }
+
+    /// Records the result of a check, returning whether it passed.
+    fn record(&mut self, name: &'static str, result: Result<()>) -> bool {
+        let outcome = match result {
+            Ok(()) => Outcome::Passed,
+            Err(error) => Outcome::Failed(format!("{:#}", error)),
+        };
+        let passed = outcome == Outcome::Passed;
+        self.checks.push(Check { name, outcome });
+        passed
+    }
+
+    fn skip(&mut self, name: &'static str, reason: impl Into<String>) {
+        self.checks.push(Check { name, outcome: Outcome::Skipped(reason.into()) });
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed => write!(f, "PASS {}", self.name),
+            Outcome::Failed(reason) => write!(f, "FAIL {}: {}", self.name, reason),
+            Outcome::Skipped(reason) => write!(f, "SKIP {}: {}", self.name, reason),
+        }
+    }
+}
+
+const HEADER: &str = "header";
+const PAYLOAD_CHECKSUM: &str = "payload checksum";
+const SIGNATURE: &str = "signature";
+const CONTENTS: &str = "decryption and contents";
+const MANIFEST: &str = "manifest";
+const PATCHES: &str = "patches apply";
+
+impl Distributable {
+    /// Checks that the distributable is well-formed and can be opened with the base ROM, and optionally that its
+    /// patches apply to the decomp. Failed checks are reported rather than returned as errors.
+    pub fn verify(&self, options: VerifyOptions) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        let header = match self.header() {
+            Ok(header) => {
+                report.record(HEADER, Ok(()));
+                header.unwrap_or_else(Header::legacy)
+            }
+            Err(error) => {
+                report.record(HEADER, Err(error));
+                for name in [PAYLOAD_CHECKSUM, SIGNATURE, CONTENTS, MANIFEST, PATCHES] {
+                    report.skip(name, "the header could not be read");
+                }
+                return Ok(report);
+            }
+        };
+
+        let payload = self.read_payload()?;
+        if header.payload_sha1.is_none() {
+            report.skip(PAYLOAD_CHECKSUM, "the distributable predates payload checksums");
+        } else if !report.record(PAYLOAD_CHECKSUM, header.verify_payload(&payload)) {
+            for name in [SIGNATURE, CONTENTS, MANIFEST, PATCHES] {
+                report.skip(name, "the payload is corrupt");
+            }
+            return Ok(report);
+        }
+
+        match &header.signature {
+            Some(signature) => {
+                if !report.record(SIGNATURE, signature.verify(&payload)) {
+                    for name in [CONTENTS, MANIFEST, PATCHES] {
+                        report.skip(name, "the signature is invalid");
+                    }
+                    return Ok(report);
+                }
+            }
+            None if options.check_signature => {
+                report.record(SIGNATURE, Err(anyhow!("the distributable is not signed")));
+            }
+            None => report.skip(SIGNATURE, "the distributable is not signed, use --check-signature to require one"),
+        }
+
+        let temp_dir = TempDir::new().context("failed to create temporary directory")?;
+        let opened = self.open_to_dir(OpenOptions {
+            output: Some(temp_dir.path().join("package")),
+            baserom: options.baserom,
+        });
+        let package = match opened {
+            Ok(package) => {
+                report.record(CONTENTS, Ok(()));
+                package
+            }
+            Err(error) => {
+                report.record(CONTENTS, Err(error));
+                report.skip(MANIFEST, "the distributable could not be opened");
+                report.skip(PATCHES, "the distributable could not be opened");
+                return Ok(report);
+            }
+        };
+
+        report.record(MANIFEST, package.manifest().and_then(|manifest| {
+            let errors = manifest.validate();
+            if !errors.is_empty() {
+                bail!("{}", errors.join("; "));
+            }
+            Ok(())
+        }));
+
+        match &options.repo {
+            None => report.skip(PATCHES, "no decomp repository given, use --repo to check"),
+            Some(_) if header.patch_empty => report.skip(PATCHES, "the package has no patches"),
+            Some(repo) => {
+                if !package.manifest()?.package_dependency_ids().is_empty() {
+                    report.skip(PATCHES, "the patches apply on top of the package's dependencies");
+                } else {
+                    report.record(PATCHES, check_patches_apply(&package, repo));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Reads the encrypted payload that follows the header.
+    fn read_payload(&self) -> Result<Vec<u8>> {
+        let mut file = File::open(self.path())
+            .with_context(|| format!("failed to open {}", self.path().display()))?;
+        Header::read_from(&mut file)?;
+        let mut payload = Vec::new();
+        file.read_to_end(&mut payload)
+            .with_context(|| format!("failed to read {}", self.path().display()))?;
+        Ok(payload)
+    }
+}
+
+/// Applies the package's patches to a temporary clone of `repo` at the package's decomp revision.
+pub(super) fn check_patches_apply(package: &Package, repo: &Path) -> Result<()> {
+    let manifest = package.manifest()?;
+    let Some(rev) = manifest.get_direct_decomp_dependency_rev() else {
+        bail!("the package does not declare which decomp revision it is based on");
+    };
+
+    // --shared borrows the objects of `repo` without writing anything to it
+    let temp_dir = TempDir::new().context("failed to create temporary directory")?;
+    let clone = temp_dir.path().join("papermario");
+    let mut command = Command::new("git");
+    command.args(["clone", "--quiet", "--shared", "--no-checkout"]).arg(repo).arg(&clone);
+    run_git(command).with_context(|| format!("failed to clone {}", repo.display()))?;
+    let mut command = Command::new("git");
+    command.args(["checkout", "--quiet", "--detach", rev]).current_dir(&clone);
+    run_git(command).with_context(|| format!("decomp revision {} is not in {}", rev, repo.display()))?;
+    package.apply_patches_to_existing_repo(&clone, false)
+}
+
+fn run_git(mut command: Command) -> Result<()> {
+    let output = command.output().context("failed to run git")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Generating `git format-patch`-style patches from commits, using libgit2.
+//!
+//! The output of `git format-patch` depends on the user's Git version and configuration, such as `format.useAutoBase`
+//! or `diff.noprefix`. Generating patches with libgit2 instead gives the same patches everywhere.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use git2::{DiffOptions, Email, EmailCreateOptions, Repository, Sort};
+
+use super::progress::{Phase, Progress};
+
+/// Longest subject slug used in patch file names, as in `git format-patch`.
+const MAX_SLUG_LEN: usize = 64;
+
+/// Writes one mailbox-format patch to `out_dir` for each non-merge commit in `since..HEAD` that changes a path in
+/// `pathspec`, named like `git format-patch` names them, e.g. `0001-fix-the-thing.patch`. Returns the paths written,
+/// in the order the patches should be applied.
+///
+/// `pathspec` uses Git's command-line syntax, but only plain paths and `:(exclude,glob)` patterns are supported.
+pub fn format_patches(repo_path: &Path, since: &str, pathspec: &[String], out_dir: &Path, progress: &Progress) -> Result<Vec<PathBuf>> {
+    let repo = Repository::open(repo_path)
+        .with_context(|| format!("failed to open git repository {}", repo_path.display()))?;
+    let since_commit = repo.revparse_single(since)
+        .and_then(|object| object.peel_to_commit())
+        .with_context(|| format!("failed to find {} in {}", since, repo_path.display()))?;
+
+    let mut walk = repo.revwalk()?;
+    walk.push_head().context("failed to find HEAD")?;
+    walk.hide(since_commit.id())?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut oids = Vec::new();
+    for oid in walk {
+        oids.push(oid?);
+        progress.report(Phase::EnumeratingCommits, oids.len() as u64, None);
+    }
+    let count = oids.len() as u64;
+    let total = Some(count);
+    progress.report(Phase::EnumeratingCommits, count, total);
+
+    let mut written = Vec::new();
+    for (done, oid) in oids.into_iter().enumerate() {
+        progress.report(Phase::WritingPatches, done as u64, total);
+        let commit = repo.find_commit(oid)?;
+        // Like --no-merges
+        if commit.parent_count() != 1 {
+            continue;
+        }
+        let parent_tree = commit.parent(0)?.tree()?;
+        let tree = commit.tree()?;
+        let mut diff_options = diff_options(pathspec)?;
+        let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), Some(&mut diff_options))
+            .with_context(|| format!("failed to diff commit {}", oid))?;
+        // Like `git format-patch -- <pathspec>`, commits that don't touch the pathspec are left out
+        if diff.deltas().next().is_none() {
+            continue;
+        }
+
+        let summary = commit.summary().unwrap_or_default();
+        let body = commit.body().unwrap_or_default();
+        let mut email_options = EmailCreateOptions::new();
+        // Like --keep-subject: without a prefix or numbers, libgit2 leaves the subject as it is
+        email_options.subject_prefix("").omit_numbers(true);
+        let email = Email::from_diff(&diff, 1, 1, &oid, summary, body, &commit.author(), &mut email_options)
+            .with_context(|| format!("failed to create patch for commit {}", oid))?;
+
+        let path = out_dir.join(format!("{:04}-{}.patch", written.len() + 1, slug(summary)));
+        fs::write(&path, email.as_slice())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        written.push(path);
+    }
+    progress.report(Phase::WritingPatches, count, total);
+    Ok(written)
+}
+
+fn diff_options(pathspec: &[String]) -> Result<DiffOptions> {
+    let mut options = DiffOptions::new();
+    options.minimal(true).show_binary(true);
+    // libgit2 uses the first pattern that matches a path, so exclusions have to come before what they exclude from
+    let mut specs = pathspec.iter().map(|spec| libgit2_pathspec(spec)).collect::<Result<Vec<_>>>()?;
+    specs.sort_by_key(|spec| !spec.starts_with('!'));
+    for spec in specs {
+        options.pathspec(spec);
+    }
+    Ok(options)
+}
+
+/// Converts a pathspec from Git's command-line syntax to libgit2's, which excludes patterns with a `!` prefix.
+fn libgit2_pathspec(spec: &str) -> Result<String> {
+    let Some(magic) = spec.strip_prefix(':') else {
+        return Ok(spec.to_owned());
+    };
+    match magic.strip_prefix("(exclude,glob)") {
+        Some(glob) => Ok(format!("!{}", glob)),
+        None => bail!("unsupported pathspec {:?}", spec),
+    }
+}
+
+/// Turns a commit subject into a file name like `git format-patch` does, e.g. `Fix the thing!` into `Fix-the-thing`.
+fn slug(subject: &str) -> String {
+    let mut slug = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if c == '.' {
+            if !slug.ends_with('.') {
+                slug.push(c);
+            }
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= MAX_SLUG_LEN {
+            break;
+        }
+    }
+    slug.trim_end_matches(['-', '.']).to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()?;
+        if !output.status.success() {
+            bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+
+    #[test]
+    fn subjects_become_file_names() {
+        assert_eq!(slug("set bSkipIntro to true"), "set-bSkipIntro-to-true");
+        assert_eq!(slug("Fix the thing!"), "Fix-the-thing");
+        assert_eq!(slug("  version 1..2 "), "version-1.2");
+        assert_eq!(slug(&"a".repeat(100)).len(), MAX_SLUG_LEN);
+    }
+
+    #[test]
+    fn patches_match_commits() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path().join("repo");
+        fs::create_dir_all(repo.join("src/.merlon"))?;
+        git(&repo, &["init", "--quiet"])?;
+        fs::write(repo.join("README.md"), "base\n")?;
+        git(&repo, &["add", "."])?;
+        git(&repo, &["commit", "--quiet", "-m", "base"])?;
+        let base = git(&repo, &["rev-parse", "HEAD"])?;
+
+        fs::write(repo.join("src/main.c"), "int main(void) {}\n")?;
+        git(&repo, &["add", "."])?;
+        git(&repo, &["commit", "--quiet", "-m", "add main", "-m", "With a body."])?;
+        fs::write(repo.join("README.md"), "outside the pathspec\n")?;
+        git(&repo, &["commit", "--quiet", "-a", "-m", "edit readme"])?;
+        fs::write(repo.join("src/main.c"), "int main(void) { return 1; }\n")?;
+        fs::write(repo.join("src/.merlon/state"), "internal\n")?;
+        git(&repo, &["add", "--force", "."])?;
+        git(&repo, &["commit", "--quiet", "-m", "return 1"])?;
+
+        let out_dir = dir.path().join("patches");
+        fs::create_dir(&out_dir)?;
+        let pathspec = vec!["src".to_owned(), ":(exclude,glob)**/.merlon/**".to_owned()];
+        let patches = format_patches(&repo, &base, &pathspec, &out_dir, &Progress::default())?;
+        let names: Vec<_> = patches.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["0001-add-main.patch", "0002-return-1.patch"]);
+
+        let first = fs::read_to_string(&patches[0])?;
+        assert!(first.contains("Subject: add main\n"), "{}", first);
+        assert!(first.contains("With a body."), "{}", first);
+        let second = fs::read_to_string(&patches[1])?;
+        assert!(!second.contains(".merlon"), "{}", second);
+
+        // Applying the patches to the base reproduces the commits
+        let clone = dir.path().join("clone");
+        git(dir.path(), &["clone", "--quiet", &repo.to_string_lossy(), &clone.to_string_lossy()])?;
+        git(&clone, &["reset", "--quiet", "--hard", &base])?;
+        let mut args = vec!["am".to_owned(), "--quiet".to_owned()];
+        args.extend(patches.iter().map(|path| path.to_string_lossy().into_owned()));
+        git(&clone, &args.iter().map(String::as_str).collect::<Vec<_>>())?;
+        assert_eq!(git(&clone, &["log", "--format=%s", &format!("{}..HEAD", base)])?, "return 1\nadd main");
+        assert_eq!(fs::read_to_string(clone.join("src/main.c"))?, "int main(void) { return 1; }\n");
+        assert!(!clone.join("src/.merlon").exists());
+        Ok(())
+    }
+}
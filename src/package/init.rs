@@ -22,41 +22,80 @@
 //!
 //! Being initialised means that the package is ready to be built.
 
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::fs::{create_dir, create_dir_all, remove_dir_all, write, copy, remove_file};
+use std::fs::{create_dir, create_dir_all, remove_dir_all, write, remove_file};
 use anyhow::{Result, Error, bail, anyhow, Context};
 use clap::Parser;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use scopeguard::defer;
 use semver::VersionReq;
 use pyo3::prelude::*;
 
-use super::manifest::Dependency;
-use super::{Package, Id, Registry, PATCHES_DIR_NAME, Distributable};
+use super::manifest::{Dependency, Metadata, PatchFormat, Source, get_author};
+use super::patch;
+use super::distribute::{ExportError, check_baserom};
+use super::progress::Progress;
+use super::{Package, Id, Registry, Lockfile, MANIFEST_FILE_NAME, PATCHES_DIR_NAME, LOCKFILE_FILE_NAME, Distributable};
+use super::BUNDLED_DEPS_DIR_NAME;
+use super::normalize_relative_path;
 use crate::rom::Rom;
 
-const MERLON_DIR_NAME: &str = ".merlon";
-const DEPENDENCIES_DIR_NAME: &str = ".merlon/dependencies";
-const SUBREPO_DIR_NAME: &str = "papermario";
+pub(crate) const MERLON_DIR_NAME: &str = ".merlon";
+pub(crate) const DEPENDENCIES_DIR_NAME: &str = ".merlon/dependencies";
+pub(crate) const SUBREPO_DIR_NAME: &str = "papermario";
+const VER_DIR_NAME: &str = "ver";
+
+/// Where the decomp is cloned from.
+pub const DECOMP_REPO_URL: &str = "https://github.com/pmret/papermario.git";
+
+/// Game version that packages are made for, and that is built, unless `region` is set in `merlon.toml`.
+pub const DEFAULT_TARGET: &str = "us";
+
+/// Name of the base ROM file in each of the decomp's `ver/` directories.
+const BASEROM_FILE_NAME: &str = "baserom.z64";
+
+/// File name of the single patch written in [`PatchFormat::Diff`] format.
+const COMBINED_DIFF_FILE_NAME: &str = "combined.patch";
 const VSCODE_DIR_NAME: &str = ".vscode";
 const GITIGNORE_FILE_NAME: &str = ".gitignore";
 
+/// File in the package directory listing decomp paths to leave out of patches, in gitignore syntax.
+pub const MERLONIGNORE_FILE_NAME: &str = ".merlonignore";
+
 /// An initialised package. Initialised packages are ready to be built.
 #[derive(Debug)]
 #[pyclass(module = "merlon.package.init")]
 pub struct InitialisedPackage {
     registry: Registry,
     package_id: Id,
+
+    /// Error instead of changing `merlon.lock`.
+    locked: bool,
+
+    /// Error instead of accessing the network.
+    offline: bool,
+
+    /// Warn instead of erroring when updating the patches dir with uncommitted changes in the decomp repository.
+    allow_dirty: bool,
+
+    /// Where to report the progress of updating the patches dir.
+    progress: Progress,
 }
 
 /// Options for [`InitialisedPackage::initialise`].
 #[derive(Parser, Debug, Clone)]
 #[pyclass(module = "merlon.package.init")]
 pub struct InitialiseOptions {
-    /// Path to an unmodified US-release Paper Mario (N64) ROM.
+    /// Path to an unmodified Paper Mario (N64) ROM of the package's region, which is US unless `region` is set in
+    /// `merlon.toml`.
+    ///
+    /// If not specified, the `MERLON_BASEROM` environment variable is used, then `baserom` in the `[package]`
+    /// section of `merlon.toml`.
     #[arg(long)]
     #[pyo3(get, set)]
-    pub baserom: PathBuf,
+    pub baserom: Option<PathBuf>,
 
     /// Git revision of decomp to use.
     /// 
@@ -64,6 +103,14 @@ pub struct InitialiseOptions {
     #[arg(long)]
     #[pyo3(get, set)]
     pub rev: Option<String>,   
+
+    /// Credit everyone who authored a commit in the package's history, rather than only you.
+    ///
+    /// The authors in `merlon.toml` are replaced with those listed by `git shortlog`, once the package's patches have
+    /// been applied. If there are no commits, you are credited as usual.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub from_shortlog: bool,
 }
 
 /// Options for [`InitialisedPackage::build_rom`].
@@ -86,6 +133,13 @@ pub struct BuildRomOptions {
     #[arg(long)]
     #[pyo3(get, set)]
     pub clean: bool,
+
+    /// The game version to build, as named by a directory in `papermario/ver/`.
+    ///
+    /// Defaults to the package's region, which is `us` unless `region` is set in `merlon.toml`.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub target: Option<String>,
 }
 
 /// Options for [`InitialisedPackage::add_dependency`].
@@ -96,6 +150,13 @@ pub struct AddDependencyOptions {
     #[arg(long)]
     #[pyo3(get, set)]
     pub path: PathBuf,
+
+    /// Version requirement for the dependency, such as `^1.2` or `>=0.3, <0.5`.
+    ///
+    /// It must be satisfied by the version of the package being added. Defaults to `~` its version.
+    #[arg(long)]
+    #[pyo3(get, set)]
+    pub version: Option<String>,
 }
 
 #[pymethods]
@@ -108,6 +169,46 @@ impl Package {
             InitialisedPackage::initialise(self.clone(), initialise_options)
         }
     }
+
+    /// Clones the decomp repository from `repo_url` into `papermario/`, checks out `rev`, and records `rev` as the
+    /// package's decomp dependency, along with the clone's remote URL and default branch.
+    ///
+    /// If `papermario/` is already a clone, it is reused: `rev` is fetched from `repo_url` if it is missing, then
+    /// checked out. This errors if the clone has uncommitted changes, unless it is already at `rev`.
+    pub fn init_subrepo(&self, repo_url: &str, rev: &str) -> Result<()> {
+        let subrepo = self.path().join(SUBREPO_DIR_NAME);
+        if subrepo.join(".git").exists() {
+            let head = git_output(&subrepo, &["rev-parse", "HEAD"])?;
+            let target = git_output(&subrepo, &["rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", rev)]).ok();
+            if target.as_deref() != Some(head.as_str()) {
+                if !git_output(&subrepo, &["status", "--porcelain"])?.is_empty() {
+                    bail!("cannot check out {} in {}: there are uncommitted changes", rev, subrepo.display());
+                }
+                if target.is_none() {
+                    git_output(&subrepo, &["fetch", repo_url, rev])
+                        .with_context(|| format!("failed to fetch {} from {}", rev, repo_url))?;
+                }
+                git_output(&subrepo, &["checkout", "--quiet", "--detach", rev])
+                    .with_context(|| format!("failed to check out {} in {}", rev, subrepo.display()))?;
+            }
+        } else {
+            let output = Command::new("git")
+                .arg("clone")
+                .arg(repo_url)
+                .arg(SUBREPO_DIR_NAME)
+                .current_dir(self.path())
+                .output()
+                .context("failed to run git clone")?;
+            if !output.status.success() {
+                bail!("failed to clone {}: {}", repo_url, String::from_utf8_lossy(&output.stderr).trim());
+            }
+            git_output(&subrepo, &["reset", "--hard", rev])
+                .with_context(|| format!("failed to check out revision {}", rev))?;
+        }
+
+        let rev = git_output(&subrepo, &["rev-parse", "HEAD"])?;
+        record_decomp_dependency(self, &subrepo, rev)
+    }
 }
 
 #[pymethods]
@@ -116,7 +217,11 @@ impl InitialisedPackage {
     #[staticmethod]
     pub fn from_initialised(package: Package) -> Result<Self> {
         if !Self::is_initialised(&package)? {
-            bail!("package is not initialised");
+            let subrepo = package.path().join(SUBREPO_DIR_NAME);
+            if !subrepo.join(".git").exists() {
+                bail!("package is not initialised: {} is not a clone of the decomp; run `merlon init`", subrepo.display());
+            }
+            bail!("package is not initialised; run `merlon init`");
         }
 
         let dependencies_dir_path = package.path().join(DEPENDENCIES_DIR_NAME);
@@ -137,9 +242,29 @@ impl InitialisedPackage {
         Ok(Self {
             registry,
             package_id,
+            locked: false,
+            offline: false,
+            allow_dirty: false,
+            progress: Progress::default(),
         })
     }
 
+    /// Sets whether operations that would change `merlon.lock` should error instead, like Cargo's `--locked`.
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// Sets whether operations that would access the network, such as updating the decomp, should error instead.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// Sets whether uncommitted changes in the decomp repository only warn when updating the patches dir, rather than
+    /// erroring. The changes are left out of the patches either way.
+    pub fn set_allow_dirty(&mut self, allow_dirty: bool) {
+        self.allow_dirty = allow_dirty;
+    }
+
     /// The package that this InitialisedPackage was created from.
     #[getter]
     fn get_package(&self) -> Package {
@@ -151,9 +276,11 @@ impl InitialisedPackage {
         self.package_id
     }
 
-    /// The path to base ROM.
-    pub fn baserom_path(&self) -> PathBuf {
-        self.subrepo_path().join("ver/us/baserom.z64")
+    /// The path to the base ROM of the package's region, `papermario/ver/<region>/baserom.z64`.
+    /// Errors if the decomp clone has no such region.
+    pub fn baserom_path(&self) -> Result<PathBuf> {
+        let region = self.package().manifest()?.metadata().region().to_owned();
+        Ok(region_dir(&self.subrepo_path(), &region)?.join(BASEROM_FILE_NAME))
     }
 
     /// The path to the papermario repository used to build this package.
@@ -191,56 +318,48 @@ impl InitialisedPackage {
             None => manifest.get_direct_decomp_dependency_rev(),
         };
 
+        let repo_url = manifest.metadata().decomp_repo().unwrap_or(DECOMP_REPO_URL).to_owned();
+
+        // Find the baserom before cloning, which takes a while
+        let baserom = package.resolve_baserom(options.baserom.as_deref())?;
+        if !baserom.is_file() {
+            bail!("baserom {:?} is not a file", baserom);
+        }
+
         let path_clone = package.path().to_owned();
         let error_context = format!("failed to initialise package {}", &package);
         let do_it = || {
-            let package_id_string = package.id()?.to_string();
-
             // Clone decomp subrepo
-            let mut command = Command::new("git");
-            command
-                .arg("clone");
-            // If we're not using a specific revision, only clone the latest commit
-            if rev.is_none() {
-                command.arg("--depth=1");
-            }
-            // TODO: if existing clone, reference that
-            /*if let Some(repo) = local_decomp_repo.as_ref() {
-                command.arg("--reference").arg(repo.path());
-            }*/
-            let status = command
-                .arg("https://github.com/pmret/papermario.git")
-                .arg(SUBREPO_DIR_NAME)
-                .current_dir(package.path())
-                .status()?;
-            if !status.success() {
-                bail!("failed to clone decomp repository");
-            }
-
             if let Some(rev) = &rev {
-                // Reset to revision
+                package.init_subrepo(&repo_url, rev)?;
+                if let Some(min_rev) = manifest.metadata().min_decomp_rev() {
+                    ensure_rev_contains(&package.path().join(SUBREPO_DIR_NAME), rev, min_rev)?;
+                }
+            } else {
+                // If we're not using a specific revision, only clone the latest commit
+                // TODO: if existing clone, reference that
                 let status = Command::new("git")
-                    .arg("reset")
-                    .arg("--hard")
-                    .arg(rev)
-                    .current_dir(package.path().join(SUBREPO_DIR_NAME))
+                    .arg("clone")
+                    .arg("--depth=1")
+                    .arg(&repo_url)
+                    .arg(SUBREPO_DIR_NAME)
+                    .current_dir(package.path())
                     .status()?;
                 if !status.success() {
-                    bail!("failed to checkout revision");
+                    bail!("failed to clone decomp repository");
                 }
             }
 
             // Create assets dir for this mod
-            create_dir_all(package.path().join(SUBREPO_DIR_NAME).join("assets").join(&package_id_string))
+            let assets_dir = package.manifest()?.metadata().assets_dir();
+            create_dir_all(package.path().join(SUBREPO_DIR_NAME).join("assets").join(assets_dir))
                 .context("failed to create assets subdirectory")?;
 
-            // Copy baserom
-            if !options.baserom.is_file() {
-                bail!("baserom {:?} is not a file", options.baserom);
-            }
-            // TODO: check baserom sha1 is valid
-            let baserom_path = package.path().join(SUBREPO_DIR_NAME).join("ver/us/baserom.z64");
-            copy(options.baserom, &baserom_path)
+            // Copy baserom, making sure it is the package's region's ROM in the right byte order
+            let region = manifest.metadata().region();
+            let baserom_path = region_dir(&package.path().join(SUBREPO_DIR_NAME), region)?.join(BASEROM_FILE_NAME);
+            Rom::from(baserom)
+                .copy_to_checked(&baserom_path, |copy| check_baserom(copy.path(), region))
                 .with_context(|| format!("failed to copy baserom to {:?}", baserom_path))?;
 
             // Create merlon dir
@@ -276,13 +395,12 @@ impl InitialisedPackage {
                 bail!("failed to run decomp install.sh");
             }
 
-            let initialised = Self::from_initialised(package)?;
+            let mut initialised = Self::from_initialised(package)?;
+            initialised.install_bundled_dependencies()?;
 
             // Add decomp as dependency
             let main_head = initialised.git_head_commit()?;
-            initialised.package().edit_manifest(|manifest| {
-                manifest.upsert_decomp_dependency(main_head)
-            })?;
+            record_decomp_dependency(initialised.package(), &initialised.subrepo_path(), main_head)?;
 
             // In case there are patches in the package already, apply them
             // i.e. sync patches ---> repo
@@ -294,6 +412,14 @@ impl InitialisedPackage {
             // Load dependency patches
             initialised.setup_git_branches()?;
 
+            if options.from_shortlog {
+                let authors = initialised.authors_from_history()?;
+                initialised.package().edit_manifest(|manifest| {
+                    manifest.metadata_mut().set_authors(authors);
+                    Ok(())
+                })?;
+            }
+
             Ok(initialised)
         };
         match do_it() {
@@ -314,8 +440,8 @@ impl InitialisedPackage {
     pub fn is_initialised(package: &Package) -> Result<bool> {
         let path = package.path();
 
-        // Check subrepo exists and is a git repo
-        if !path.join(SUBREPO_DIR_NAME).is_dir() {
+        // Check subrepo exists and is a git repo of its own, rather than a directory inside the package's repo
+        if !path.join(SUBREPO_DIR_NAME).join(".git").exists() {
             return Ok(false);
         }
         let status = Command::new("git")
@@ -373,8 +499,11 @@ impl InitialisedPackage {
                         patch: Some(version.patch),
                         pre: version.pre.clone(),
                     }],
-                }
+                },
+                source: Source::Index,
             });
+            let mut deps: Vec<Dependency> = deps.into_iter().collect();
+            deps.sort_by_key(Dependency::sort_key);
             deps
         };
         for dependency in dependencies_including_self {
@@ -402,6 +531,52 @@ impl InitialisedPackage {
             bail!("patch order was incorrect");
         }
 
+        self.write_lockfile()
+    }
+
+    /// Writes `merlon.lock` describing the dependencies currently in the registry.
+    pub fn write_lockfile(&self) -> Result<()> {
+        let lockfile = Lockfile::from_registry(&self.registry, self.package_id)?;
+        let previous = self.package().lockfile()?;
+        if previous.as_ref() == Some(&lockfile) {
+            return Ok(());
+        }
+        if self.locked {
+            bail!("{} needs to be updated, but --locked was passed", LOCKFILE_FILE_NAME);
+        }
+        if previous.is_some() {
+            log::info!("dependencies changed, updating {}", LOCKFILE_FILE_NAME);
+        }
+        lockfile.write_to_file(&self.package().lockfile_path())
+            .with_context(|| format!("failed to write {}", LOCKFILE_FILE_NAME))
+    }
+
+    /// Finds files in the decomp that are modified by the patches of more than one package in the dependency tree,
+    /// including this package. Overlapping patches are likely to conflict when applied.
+    ///
+    /// Logs a warning listing each overlapping file, or errors instead if `strict` is set.
+    pub fn check_dependency_overlaps(&self, strict: bool) -> Result<()> {
+        let mut touched_by: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for id in self.registry.calc_dependency_patch_order(self.package_id)? {
+            let package = self.registry.get_or_error(id)?;
+            for path in patch::touched_paths_in_dir(&package.path().join(PATCHES_DIR_NAME))? {
+                touched_by.entry(path).or_default().push(package.to_string());
+            }
+        }
+
+        let overlaps: Vec<String> = touched_by.into_iter()
+            .filter(|(_, packages)| packages.len() > 1)
+            .map(|(path, packages)| format!("{} (modified by {})", path, packages.join(", ")))
+            .collect();
+        if overlaps.is_empty() {
+            return Ok(());
+        }
+        if strict {
+            bail!("dependencies modify the same files:\n  {}", overlaps.join("\n  "));
+        }
+        for overlap in overlaps {
+            log::warn!("dependencies modify the same file, which may conflict: {}", overlap);
+        }
         Ok(())
     }
 
@@ -418,9 +593,20 @@ impl InitialisedPackage {
         Ok(!output.stdout.is_empty())
     }
 
+    /// Returns the game versions the decomp clone can build, i.e. the directories in `papermario/ver/`.
+    pub fn available_targets(&self) -> Result<Vec<String>> {
+        list_targets(&self.subrepo_path())
+    }
+
     /// Builds the ROM and returns the path to the output ROM.
     pub fn build_rom(&self, options: BuildRomOptions) -> Result<Rom> {
         let dir = self.subrepo_path();
+        let manifest = self.package().manifest()?;
+        let target = options.target.as_deref().unwrap_or(manifest.metadata().region());
+        let targets = self.available_targets()?;
+        if !targets.iter().any(|available| available == target) {
+            bail!("unsupported target {:?}, available targets are: {}", target, targets.join(", "));
+        }
 
         // Configure
         // TODO: only do this if we have to (i.e. file tree changed) - maybe ask git?
@@ -430,30 +616,24 @@ impl InitialisedPackage {
                 //.arg("--non-matching")
                 //.arg("--debug")
                 .arg("--shift")
-                .arg("us");
+                .arg(target);
             if options.clean {
                 command.arg("--clean");
             }
-            let status = command
-                .current_dir(&dir)
-                .status()?;
-            if !status.success() {
-                bail!("failed to configure");
-            }
+            run_build_step(command, &dir).context("failed to configure")?;
         }
 
         // Build
-        let status = Command::new("ninja")
-            .current_dir(&dir)
-            .status()?;
-        if !status.success() {
-            bail!("failed to build");
-        }
+        run_build_step(Command::new("ninja"), &dir).context("failed to build")?;
 
         // Copy output file if needed
-        let rom = dir.join("ver/us/build/papermario.z64");
+        let rom = dir.join(VER_DIR_NAME).join(target).join("build/papermario.z64");
+        if !rom.is_file() {
+            bail!("build succeeded but did not produce {}", rom.display());
+        }
         if let Some(output) = options.output {
-            std::fs::copy(rom, &output)?;
+            std::fs::copy(&rom, &output)
+                .with_context(|| format!("failed to copy {} to {}", rom.display(), output.display()))?;
             Ok(output.into())
         } else {
             Ok(rom.into())
@@ -462,85 +642,145 @@ impl InitialisedPackage {
 
     /// Writes the patches required to take the repo from the nearest dependency to this package's branch into the patches dir.
     pub fn update_patches_dir(&self) -> Result<()> {
+        let patch_format = self.package().manifest()?.metadata().patch_format();
+        self.write_patches_to(&self.package().path().join(PATCHES_DIR_NAME), patch_format)
+    }
+
+    /// Lists files in the decomp repository that are neither tracked nor ignored, but would be exported if they were
+    /// committed.
+    pub fn untracked_files(&self) -> Result<Vec<String>> {
+        let output = Command::new("git")
+            .arg("ls-files")
+            .arg("--others")
+            .arg("--exclude-standard")
+            .arg("--")
+            .args(self.export_pathspec()?)
+            .current_dir(self.subrepo_path())
+            .output()?;
+        if !output.status.success() {
+            bail!("failed to list untracked files: {}", String::from_utf8_lossy(&output.stderr));
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        self.without_ignored_paths(stdout.lines().map(|line| line.to_owned()).collect())
+    }
+
+    /// Lists files that would be exported, but have changes that are not committed, including untracked files.
+    pub fn uncommitted_changes(&self) -> Result<Vec<String>> {
+        self.without_ignored_paths(uncommitted_paths(&self.subrepo_path(), &self.export_pathspec()?)?)
+    }
+
+    /// Commits the files listed by [`InitialisedPackage::untracked_files`] to the package branch, so that they are
+    /// included in the package's patches. Returns the files that were committed.
+    pub fn commit_untracked_files(&self) -> Result<Vec<String>> {
+        let untracked = self.untracked_files()?;
+        if untracked.is_empty() {
+            return Ok(untracked);
+        }
         let package_id_str = self.package_id.to_string();
         if self.git_current_branch()? != package_id_str {
             bail!("repo is not on package branch {}", package_id_str);
         }
-        if self.is_git_dirty()? {
-            bail!("repo is dirty, commit changes and try again");
+        let status = Command::new("git")
+            .arg("add")
+            .arg("--")
+            .args(&untracked)
+            .current_dir(self.subrepo_path())
+            .status()?;
+        if !status.success() {
+            bail!("failed to stage untracked files");
         }
-
-        let dir = self.package().path().join(PATCHES_DIR_NAME);
-        remove_dir_all(&dir)
-            .with_context(|| format!("failed to remove patches dir {}", dir.display()))?;
-        create_dir(&dir)
-            .with_context(|| format!("failed to create patches dir {}", dir.display()))?;
-
-        // Figure out which branch to diff against.
-        // We want to diff against the nearest dependency, but if that doesn't exist, we want to diff against main.
-        let branch_order =
-            std::iter::once("main".to_string())
-                .chain(
-                    self.registry()
-                        .calc_dependency_patch_order(self.package_id)?
-                        .into_iter()
-                        .map(|id| id.to_string())
-                );
-        let mut diff_against = None;
-        for branch in branch_order.rev() {
-            if branch != package_id_str && self.git_branch_exists(&branch)? {
-                diff_against = Some(branch);
-                break;
-            }
-        };
-        let diff_against = diff_against.ok_or_else(|| anyhow!("no branch to diff against"))?;
-        let diff_against_package_name = match diff_against.as_str() {
-            "main" => "Paper Mario (N64) decompilation".to_string(),
-            _ => {
-                let package = self.registry.get_or_error(diff_against.parse()?)?;
-                format!("{}", package)
-            },
-        };
-        log::info!("saving patches since dependency: {}", &diff_against_package_name);
-
-        // Create patches
         let status = Command::new("git")
-            .arg("format-patch")
-            .arg(format!("{}..HEAD", diff_against))
-            .arg("-o").arg(&dir.canonicalize()?)
-            .arg("--minimal")
-            .arg("--binary")
-            .arg("--ignore-cr-at-eol")
-            .arg("--function-context") // Maybe?
-            .arg("--keep-subject")
-            .arg("--no-merges")
-            .arg("--no-stdout")
+            .arg("commit")
+            .arg("-m").arg("Add untracked files")
             .arg("--")
-            .arg("src")
-            .arg("include")
-            .arg("assets") //.arg(format!("assets/{}", package_name))
-            .arg("ver/us")
-            .arg("--no-track") // Don't track the branch on origin, since origin is the original decomp repo
+            .args(&untracked)
             .current_dir(self.subrepo_path())
             .status()?;
         if !status.success() {
-            bail!("failed git format-patch");
+            bail!("failed to commit untracked files");
+        }
+        for path in &untracked {
+            log::info!("committed untracked file {}", path);
         }
+        Ok(untracked)
+    }
 
-        // List patches
-        let patches = std::fs::read_dir(&dir)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let path = entry.path();
-                if path.extension()? == "patch" {
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-        log::info!("saved {} patches", patches.len());
+    /// Checks that the decomp revision in the manifest exists in the decomp repository, and that it is at or after
+    /// the package's `min_decomp_rev`, if set.
+    ///
+    /// A missing revision usually means the repository is a shallow clone, or that history was rewritten.
+    pub fn verify_decomp_rev(&self) -> Result<()> {
+        let manifest = self.package().manifest()?;
+        let Some(rev) = manifest.get_direct_decomp_dependency_rev() else {
+            return Ok(());
+        };
+        if commit_exists(&self.subrepo_path(), rev)? {
+            if let Some(min_rev) = manifest.metadata().min_decomp_rev() {
+                ensure_rev_contains(&self.subrepo_path(), rev, min_rev)?;
+            }
+            return Ok(());
+        }
+
+        let output = Command::new("git")
+            .arg("rev-parse")
+            .arg("--is-shallow-repository")
+            .current_dir(self.subrepo_path())
+            .output()?;
+        if String::from_utf8_lossy(&output.stdout).trim() == "true" {
+            bail!(
+                "decomp revision {} not found; {} is a shallow clone, run `git -C {} fetch --unshallow`",
+                rev,
+                SUBREPO_DIR_NAME,
+                SUBREPO_DIR_NAME,
+            );
+        }
+        bail!(
+            "decomp revision {} not found in {}; check the decomp dependency in {}, or run `git -C {} fetch`",
+            rev,
+            SUBREPO_DIR_NAME,
+            MANIFEST_FILE_NAME,
+            SUBREPO_DIR_NAME,
+        );
+    }
+
+    /// Lists the authors of the commits on the package branch as `Name <email>`, most prolific first, using
+    /// `git shortlog`. Each email address is only listed once. If there are no commits, the author from git config is
+    /// listed instead.
+    pub fn authors_from_history(&self) -> Result<Vec<String>> {
+        let range = format!("{}..{}", self.patch_base_branch()?, self.package_id);
+        let mut authors = shortlog_authors(&self.subrepo_path(), &range)?;
+        if authors.is_empty() {
+            authors.push(get_author()?);
+        }
+        Ok(authors)
+    }
+
+    /// Errors if exporting this package would produce a distributable that changes nothing: there are no commits
+    /// on the package branch, and no package dependencies whose changes it would carry along.
+    pub fn ensure_has_changes(&self) -> Result<()> {
+        let package_id_str = self.package_id.to_string();
+        if !self.git_branch_exists(&package_id_str)? {
+            return Ok(());
+        }
+        if !self.package().manifest()?.package_dependency_ids().is_empty() {
+            return Ok(());
+        }
 
+        let base = self.patch_base_branch()?;
+        let output = Command::new("git")
+            .arg("rev-list")
+            .arg("--count")
+            .arg(format!("{}..{}", base, package_id_str))
+            .current_dir(self.subrepo_path())
+            .output()?;
+        if !output.status.success() {
+            bail!("failed to count commits: {}", String::from_utf8_lossy(&output.stderr));
+        }
+        let count: usize = String::from_utf8(output.stdout)?.trim().parse()
+            .context("failed to parse commit count")?;
+        if count == 0 {
+            return Err(ExportError::NoCommits { base }.into());
+        }
         Ok(())
     }
 
@@ -548,7 +788,30 @@ impl InitialisedPackage {
     /// If the dependency already exists, it will be updated.
     /// Specifically, it will be copied into `.merlon/dependencies/<package_id>`.
     pub fn add_dependency(&mut self, options: AddDependencyOptions) -> Result<Id> {
-        let path = options.path;
+        let requirement = options.version;
+        let id = self.install_dependency(options.path)?;
+        let package = self.registry.get_or_error(id)
+            .context("dependency not added to registry correctly")?;
+        let dependency = match &requirement {
+            Some(requirement) => Dependency::on_package(package.manifest()?.metadata(), requirement)?,
+            None => package.try_into()?,
+        };
+        self.package().edit_manifest(move |manifest| {
+            manifest.declare_direct_dependency(dependency)
+        })?;
+        Ok(id)
+    }
+}
+
+impl InitialisedPackage {
+    /// Sets where to report the progress of updating the patches dir, such as enumerating commits and writing patches.
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    /// Copies a package or distributable into the dependencies directory and registers it, without declaring it as
+    /// a dependency in the manifest.
+    fn install_dependency(&mut self, path: PathBuf) -> Result<Id> {
         let dependencies_dir = self.package().path().join(DEPENDENCIES_DIR_NAME);
         create_dir_all(&dependencies_dir)
             .with_context(|| format!("failed to create dependencies dir {}", dependencies_dir.display()))?;
@@ -572,6 +835,7 @@ impl InitialisedPackage {
                     if !self.registry.has(id) {
                         self.add_dependency(AddDependencyOptions {
                             path: self.registry.get_or_error(id)?.path().to_owned(),
+                            version: None,
                         })?;
                     }
                 }
@@ -581,7 +845,7 @@ impl InitialisedPackage {
         } else if super::distribute::is_distributable_package(&path) {
             let distributable = Distributable::try_from(path)
                 .context("failed to open dependency as distributable")?;
-            let manifest = distributable.manifest(self.baserom_path())
+            let manifest = distributable.manifest(self.baserom_path()?)
                 .context("failed to read dependency manifest")?;
             let package_id = manifest.metadata().id().to_string();
             let path = dependencies_dir.join(package_id);
@@ -591,7 +855,7 @@ impl InitialisedPackage {
             }
             distributable.open_to_dir(super::distribute::OpenOptions {
                 output: Some(path),
-                baserom: self.baserom_path(),
+                baserom: self.baserom_path()?,
             }).context("failed to open distributable to dependencies dir")?
         } else {
             bail!("not a package directory or distributable file: {}", path.display());
@@ -602,17 +866,229 @@ impl InitialisedPackage {
             true => id,
             false => self.registry.register(package)?,
         };
-        let dependency: Dependency = self.registry.get_or_error(id)
-            .context("dependency not added to registry correctly")?
-            .try_into()?;
-        self.package().edit_manifest(move |manifest| {
-            manifest.declare_direct_dependency(dependency)
-        })?;
         Ok(id)
     }
+
+    /// Installs the dependencies bundled in the package's `deps/` directory by `merlon export --bundle-deps`.
+    /// They are already declared by the manifests of the package and its dependencies.
+    fn install_bundled_dependencies(&mut self) -> Result<()> {
+        let bundled_dir = self.package().path().join(BUNDLED_DEPS_DIR_NAME);
+        if !bundled_dir.is_dir() {
+            return Ok(());
+        }
+        let mut paths = bundled_dir.read_dir()?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        paths.sort();
+        for path in paths {
+            if !super::distribute::is_distributable_package(&path) {
+                continue;
+            }
+            let id = self.install_dependency(path.clone())
+                .with_context(|| format!("failed to install bundled dependency {}", path.display()))?;
+            log::info!("installed bundled dependency {}", self.registry.get_or_error(id)?);
+        }
+        Ok(())
+    }
 }
 
 impl InitialisedPackage {
+    /// Writes the patches that [`InitialisedPackage::update_patches_dir`] would, in `patch_format`, replacing
+    /// everything in `dir`. Used to export patches in another format without changing the package's own.
+    pub(crate) fn write_patches_to(&self, dir: &Path, patch_format: PatchFormat) -> Result<()> {
+        let package_id_str = self.package_id.to_string();
+        if self.git_current_branch()? != package_id_str {
+            bail!("repo is not on package branch {}", package_id_str);
+        }
+        if self.is_git_dirty()? {
+            let changes = self.uncommitted_changes()?;
+            if self.allow_dirty {
+                for path in &changes {
+                    log::warn!("uncommitted change to {} will not be exported", path);
+                }
+            } else {
+                let untracked = self.untracked_files()?;
+                if !untracked.is_empty() && untracked.len() == changes.len() {
+                    return Err(ExportError::UntrackedFiles(untracked).into());
+                }
+                if !changes.is_empty() {
+                    return Err(ExportError::DirtyTree(changes).into());
+                }
+                bail!("repo is dirty, commit changes and try again");
+            }
+        }
+
+        // Check what the patches are relative to before removing the old ones, as git's own errors are confusing
+        self.verify_decomp_rev()?;
+        let diff_against = self.patch_base_branch()?;
+        if !commit_exists(&self.subrepo_path(), &diff_against)? {
+            bail!(
+                "branch {} that the patches are relative to was not found in {}; check the dependencies in {}, or \
+                recreate the branch with git",
+                diff_against,
+                SUBREPO_DIR_NAME,
+                MANIFEST_FILE_NAME,
+            );
+        }
+
+        remove_dir_all(dir)
+            .with_context(|| format!("failed to remove patches dir {}", dir.display()))?;
+        create_dir(dir)
+            .with_context(|| format!("failed to create patches dir {}", dir.display()))?;
+
+        let diff_against_package_name = match diff_against.as_str() {
+            "main" => "Paper Mario (N64) decompilation".to_string(),
+            _ => {
+                let package = self.registry.get_or_error(diff_against.parse()?)?;
+                format!("{}", package)
+            },
+        };
+        log::info!("saving patches since dependency: {}", &diff_against_package_name);
+
+        let assets_pathspec = self.assets_pathspec()?;
+        if !self.subrepo_path().join(&assets_pathspec).is_dir() {
+            log::warn!("assets directory {} does not exist", assets_pathspec);
+        }
+        for path in self.git_changed_paths(&diff_against, &["assets".to_owned()])? {
+            if !Path::new(&path).starts_with(&assets_pathspec) {
+                log::warn!(
+                    "not exporting changes to {} because it is outside {}; set `shared_assets = true` in {} to export them",
+                    path,
+                    assets_pathspec,
+                    MANIFEST_FILE_NAME,
+                );
+            }
+        }
+
+        // Create patches
+        let mut pathspec = self.export_pathspec()?;
+        if let Some(ignore) = self.merlonignore()? {
+            let changed = self.git_changed_paths(&diff_against, &pathspec)?;
+            for path in exclude_ignored_paths(&ignore, &changed, &mut pathspec) {
+                log::info!("not exporting {} because it matches {}", path, MERLONIGNORE_FILE_NAME);
+            }
+        }
+        match patch_format {
+            PatchFormat::GitAm => {
+                super::format_patch::format_patches(&self.subrepo_path(), &diff_against, &pathspec, dir, &self.progress)
+                    .context("failed to create patches")?;
+            },
+            PatchFormat::Diff => {
+                let output = Command::new("git")
+                    .arg("diff")
+                    .arg(format!("{}..HEAD", diff_against))
+                    .arg("--minimal")
+                    .arg("--binary")
+                    .arg("--")
+                    .args(pathspec)
+                    .current_dir(self.subrepo_path())
+                    .output()?;
+                if !output.status.success() {
+                    bail!("failed git diff: {}", String::from_utf8_lossy(&output.stderr));
+                }
+                if !output.stdout.is_empty() {
+                    write(dir.join(COMBINED_DIFF_FILE_NAME), &output.stdout)
+                        .context("failed to write combined diff")?;
+                }
+            },
+        }
+
+        // List patches
+        let patches = std::fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                if path.extension()? == "patch" {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        log::info!("saved {} patches", patches.len());
+
+        Ok(())
+    }
+
+    /// Returns the branch that this package's patches are relative to.
+    /// This is the nearest dependency's branch, or `main` if there are no package dependencies.
+    fn patch_base_branch(&self) -> Result<String> {
+        let package_id_str = self.package_id.to_string();
+        let branch_order =
+            std::iter::once("main".to_string())
+                .chain(
+                    self.registry()
+                        .calc_dependency_patch_order(self.package_id)?
+                        .into_iter()
+                        .map(|id| id.to_string())
+                );
+        for branch in branch_order.rev() {
+            if branch != package_id_str && self.git_branch_exists(&branch)? {
+                return Ok(branch);
+            }
+        }
+        Err(anyhow!("no branch to diff against"))
+    }
+
+    /// Returns the path, relative to the subrepo, of the assets that this package's patches include.
+    fn assets_pathspec(&self) -> Result<String> {
+        let manifest = self.package().manifest()?;
+        let metadata = manifest.metadata();
+        if metadata.shared_assets() {
+            Ok("assets".to_owned())
+        } else {
+            Ok(format!("assets/{}", metadata.assets_dir()))
+        }
+    }
+
+    /// Returns the paths, relative to the subrepo, that are included in this package's patches.
+    /// Errors if any of the package's `include_paths` do not exist in the subrepo.
+    fn export_pathspec(&self) -> Result<Vec<String>> {
+        let mut include_paths = Vec::new();
+        for path in self.package().manifest()?.metadata().include_paths() {
+            let relative = normalize_relative_path(path).context("invalid include path in merlon.toml")?;
+            if !self.subrepo_path().join(&relative).exists() {
+                bail!("include path {} in merlon.toml does not exist in the decomp repository", relative.display());
+            }
+            include_paths.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+        let region = self.package().manifest()?.metadata().region().to_owned();
+        region_dir(&self.subrepo_path(), &region)?;
+        Ok(export_pathspec_with_paths(&region, self.assets_pathspec()?, include_paths))
+    }
+
+    /// Lists the paths under `pathspec` that changed between `since` and HEAD. Both sides of a rename are listed.
+    fn git_changed_paths(&self, since: &str, pathspec: &[String]) -> Result<Vec<String>> {
+        let output = Command::new("git")
+            .arg("diff")
+            .arg("--name-only")
+            .arg("--no-renames")
+            .arg(format!("{}..HEAD", since))
+            .arg("--")
+            .args(pathspec)
+            .current_dir(self.subrepo_path())
+            .output()?;
+        if !output.status.success() {
+            bail!("failed to list changed paths: {}", String::from_utf8_lossy(&output.stderr));
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        Ok(stdout.lines().map(|line| line.to_owned()).collect())
+    }
+
+    /// Reads the package's [`MERLONIGNORE_FILE_NAME`], if it has one. Its patterns are relative to the decomp
+    /// repository, not the package.
+    fn merlonignore(&self) -> Result<Option<Gitignore>> {
+        read_merlonignore(&self.package().path().join(MERLONIGNORE_FILE_NAME), &self.subrepo_path())
+    }
+
+    /// Removes paths, relative to the decomp repository, that are ignored by [`MERLONIGNORE_FILE_NAME`].
+    fn without_ignored_paths(&self, paths: Vec<String>) -> Result<Vec<String>> {
+        Ok(match self.merlonignore()? {
+            Some(ignore) => paths.into_iter().filter(|path| !is_merlonignored(&ignore, path)).collect(),
+            None => paths,
+        })
+    }
+
     /// The package that this InitialisedPackage was created from.
     pub fn package(&self) -> &Package {
         self.registry.get(self.package_id).expect("package somehow removed from registry")
@@ -700,6 +1176,29 @@ impl InitialisedPackage {
         Ok(!output.stdout.is_empty())
     }
 
+    /// Returns the given paths that decomp's `.gitignore` rules would ignore, even if they are tracked.
+    pub(crate) fn git_check_ignore<I, S>(&self, paths: I) -> Result<Vec<String>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let output = Command::new("git")
+            .arg("check-ignore")
+            .arg("--no-index")
+            .arg("--")
+            .args(paths)
+            .current_dir(self.subrepo_path())
+            .output()?;
+        // Exit code 1 means none of the paths are ignored
+        if !matches!(output.status.code(), Some(0) | Some(1)) {
+            bail!("failed to run git check-ignore");
+        }
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(str::to_owned)
+            .collect())
+    }
+
     fn git_delete_branch(&self, branch_name: &str) -> Result<()> {
         let status = Command::new("git")
             .arg("branch")
@@ -716,6 +1215,9 @@ impl InitialisedPackage {
     /// Stashes if needed, switches to the main branch, pulls, then switches back, merges, and pops stash.
     /// Also updates the decomp dependency in the package manifest to the main's HEAD commit.
     pub fn update_decomp(&self) -> Result<()> {
+        if self.offline {
+            bail!("cannot update the decomp in offline mode, as it needs to fetch from the network");
+        }
         let main_branch = "main";
         let prev_branch = self.git_current_branch()?;
 
@@ -757,23 +1259,16 @@ impl InitialisedPackage {
 
         // Update decomp dependency in manifest
         let main_head = self.git_head_commit()?;
-        self.package().edit_manifest(|manifest| {
-            manifest.upsert_decomp_dependency(main_head)
-        })
+        record_decomp_dependency(self.package(), &self.subrepo_path(), main_head)
     }
 
     fn git_head_commit(&self) -> Result<String> {
-        let output = Command::new("git")
-            .arg("rev-parse")
-            .arg("HEAD")
-            .current_dir(self.subrepo_path())
-            .output()?;
-        if !output.status.success() {
-            bail!("failed to run git rev-parse");
+        let subrepo = self.subrepo_path();
+        if !subrepo.join(".git").exists() {
+            bail!("{} is not a clone of the decomp; run `merlon init`", subrepo.display());
         }
-        String::from_utf8(output.stdout)
-            .map(|s| s.trim().to_string())
-            .map_err(Into::into)
+        git_output(&subrepo, &["rev-parse", "HEAD"])
+            .with_context(|| format!("failed to get the current decomp commit in {}", subrepo.display()))
     }
 }
 
@@ -785,8 +1280,525 @@ impl TryFrom<Package> for InitialisedPackage {
     }
 }
 
+/// Merlon's own files, which must never end up in patches even if they are inside the decomp repository.
+const INTERNAL_PATH_GLOBS: &[&str] = &[
+    "**/.merlon",
+    "**/.merlon/**",
+    "**/merlon.toml",
+    "**/merlon.lock",
+    "**/*.merlon",
+];
+
+/// Returns the pathspec of the directories that patches include, given the assets directory and any additional paths
+/// to include, with Merlon's internal files excluded.
+fn export_pathspec_with_paths(region: &str, assets_pathspec: String, include_paths: Vec<String>) -> Vec<String> {
+    let mut pathspec = vec![
+        "src".to_owned(),
+        "include".to_owned(),
+        assets_pathspec,
+        format!("{}/{}", VER_DIR_NAME, region),
+    ];
+    pathspec.extend(include_paths);
+    pathspec.extend(INTERNAL_PATH_GLOBS.iter().map(|glob| format!(":(exclude,glob){}", glob)));
+    pathspec
+}
+
+/// Parses the gitignore-style patterns in `path`, matching them against paths relative to `subrepo`. Returns `None` if
+/// the file does not exist.
+fn read_merlonignore(path: &Path, subrepo: &Path) -> Result<Option<Gitignore>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(subrepo);
+    if let Some(error) = builder.add(path) {
+        return Err(error).with_context(|| format!("failed to read {}", path.display()));
+    }
+    let ignore = builder.build().with_context(|| format!("invalid pattern in {}", path.display()))?;
+    Ok(Some(ignore))
+}
+
+/// Whether `ignore` matches `path`, which is relative to the decomp repository, or any directory containing it.
+fn is_merlonignored(ignore: &Gitignore, path: &str) -> bool {
+    ignore.matched_path_or_any_parents(path, false).is_ignore()
+}
+
+/// Adds an exclude to `pathspec` for each of `paths` that `ignore` matches, and returns the paths that were excluded.
+fn exclude_ignored_paths(ignore: &Gitignore, paths: &[String], pathspec: &mut Vec<String>) -> Vec<String> {
+    let ignored: Vec<String> = paths.iter()
+        .filter(|path| is_merlonignored(ignore, path))
+        .cloned()
+        .collect();
+    for path in &ignored {
+        // Escape glob characters so that the pattern only matches this path
+        let mut glob = String::with_capacity(path.len());
+        for c in path.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                glob.push('\\');
+            }
+            glob.push(c);
+        }
+        pathspec.push(format!(":(exclude,glob){}", glob));
+    }
+    ignored
+}
+
+/// Records `rev` as the package's decomp dependency, along with the URL and default branch of the `origin` remote
+/// of the decomp clone at `subrepo`. Where these can't be read, the previously recorded values are kept.
+fn record_decomp_dependency(package: &Package, subrepo: &Path, rev: String) -> Result<()> {
+    let repo = git_output(subrepo, &["remote", "get-url", "origin"]).ok();
+    let branch = git_output(subrepo, &["rev-parse", "--abbrev-ref", "origin/HEAD"]).ok()
+        .and_then(|branch| branch.strip_prefix("origin/").map(str::to_owned));
+    package.edit_manifest(|manifest| {
+        let metadata = manifest.metadata_mut();
+        if repo.is_some() {
+            let branch = branch.or_else(|| metadata.decomp_branch().map(str::to_owned));
+            metadata.set_decomp_remote(repo, branch);
+        }
+        manifest.upsert_decomp_dependency(rev)
+    })
+}
+
+/// Fetches `rev` into the decomp clone at `repo` if the clone doesn't have it, from the decomp repository recorded in
+/// `metadata`, or the upstream decomp if none is. If the commit can't be fetched directly, the recorded branch is.
+pub(crate) fn fetch_decomp_rev(repo: &Path, rev: &str, metadata: &Metadata) -> Result<()> {
+    if commit_exists(repo, rev)? {
+        return Ok(());
+    }
+    let url = metadata.decomp_repo().unwrap_or(DECOMP_REPO_URL);
+    log::info!("fetching decomp revision {} from {}", rev, url);
+    if git_output(repo, &["fetch", "--quiet", url, rev]).is_err() {
+        if let Some(branch) = metadata.decomp_branch() {
+            git_output(repo, &["fetch", "--quiet", url, branch])
+                .with_context(|| format!("failed to fetch branch {} from {}", branch, url))?;
+        }
+    }
+    if !commit_exists(repo, rev)? {
+        bail!("decomp revision {} not found in {}; check the decomp dependency in {}", rev, url, MANIFEST_FILE_NAME);
+    }
+    Ok(())
+}
+
+/// Whether `rev` names a commit in the given repository.
+fn commit_exists(repo: &Path, rev: &str) -> Result<bool> {
+    let status = Command::new("git")
+        .arg("cat-file")
+        .arg("-e")
+        .arg(format!("{}^{{commit}}", rev))
+        .current_dir(repo)
+        .stderr(Stdio::null())
+        .status()
+        .context("failed to run git cat-file")?;
+    Ok(status.success())
+}
+
+/// Runs git in the given repository and returns its trimmed stdout, or an error containing its stderr.
+fn git_output(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(repo)
+        .output()
+        .with_context(|| format!("failed to run git {}", args.join(" ")))?;
+    if !output.status.success() {
+        bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Lists the authors of the commits in `range` as `Name <email>`, most commits first, keeping only the first name
+/// seen for each email address.
+fn shortlog_authors(repo: &Path, range: &str) -> Result<Vec<String>> {
+    let output = git_output(repo, &["shortlog", "--summary", "--numbered", "--email", range])?;
+    let mut authors = Vec::new();
+    let mut emails = Vec::new();
+    for line in output.lines() {
+        // Each line is `<count>\t<name> <<email>>`
+        let Some((_, author)) = line.split_once('\t') else {
+            continue;
+        };
+        let author = author.trim();
+        let email = author.rsplit_once('<').map_or(author, |(_, email)| email).to_lowercase();
+        if !emails.contains(&email) {
+            emails.push(email);
+            authors.push(author.to_owned());
+        }
+    }
+    Ok(authors)
+}
+
+/// Lists the paths in `pathspec` with staged, unstaged, or untracked changes in the given repository.
+fn uncommitted_paths(repo: &Path, pathspec: &[String]) -> Result<Vec<String>> {
+    let output = Command::new("git")
+        .arg("status")
+        .arg("--porcelain")
+        .arg("-z")
+        .arg("--untracked-files=all")
+        .arg("--")
+        .args(pathspec)
+        .current_dir(repo)
+        .output()?;
+    if !output.status.success() {
+        bail!("failed to run git status: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut paths = Vec::new();
+    let mut entries = stdout.split('\0').filter(|entry| !entry.is_empty());
+    while let Some(entry) = entries.next() {
+        // Each entry is `XY path`, where X and Y are the staged and unstaged status
+        let (status, path) = entry.split_at(3.min(entry.len()));
+        paths.push(path.to_owned());
+        // Renames and copies are followed by the original path
+        if status.starts_with(['R', 'C']) {
+            entries.next();
+        }
+    }
+    Ok(paths)
+}
+
+/// Returns the names of the directories in `ver/` of the given decomp clone, sorted.
+pub fn list_targets(subrepo: &Path) -> Result<Vec<String>> {
+    let ver_dir = subrepo.join(VER_DIR_NAME);
+    let mut targets = Vec::new();
+    for entry in std::fs::read_dir(&ver_dir)
+        .with_context(|| format!("failed to read {}", ver_dir.display()))?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            targets.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    targets.sort();
+    Ok(targets)
+}
+
+/// Returns `ver/<region>` of the given decomp clone, erroring if the clone has no such game version.
+fn region_dir(subrepo: &Path, region: &str) -> Result<PathBuf> {
+    let regions = list_targets(subrepo)?;
+    if !regions.iter().any(|available| available == region) {
+        bail!(
+            "region {:?} in {} is not a game version the decomp has, available versions are: {}",
+            region,
+            MANIFEST_FILE_NAME,
+            regions.join(", "),
+        );
+    }
+    Ok(subrepo.join(VER_DIR_NAME).join(region))
+}
+
+/// Runs a step of the decomp's build in `dir`, erroring with its exit status if it fails.
+fn run_build_step(mut command: Command, dir: &Path) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .current_dir(dir)
+        .status()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !status.success() {
+        bail!("`{}` failed with {}", program, status);
+    }
+    Ok(())
+}
+
 fn warn_if_err<T, E: std::fmt::Debug>(result: Result<T, E>) {
     if let Err(err) = result {
         log::warn!("{:?}", err);
     }
 }
+
+/// Errors unless `rev` is `min_rev` or one of its descendants in the given repository.
+pub(crate) fn ensure_rev_contains(repo: &Path, rev: &str, min_rev: &str) -> Result<()> {
+    let status = Command::new("git")
+        .arg("merge-base")
+        .arg("--is-ancestor")
+        .arg(min_rev)
+        .arg(rev)
+        .current_dir(repo)
+        .status()?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(1) => bail!(
+            "decomp revision {} is older than {}, the oldest revision this package supports (min_decomp_rev in {})",
+            rev,
+            min_rev,
+            MANIFEST_FILE_NAME,
+        ),
+        _ => bail!(
+            "failed to check decomp revision {} against min_decomp_rev {}; is {} fetched?",
+            rev,
+            min_rev,
+            min_rev,
+        ),
+    }
+}
+
+/// Initialises a package in `dir` against a stub decomp that only has `ver/jp`, for tests that can't clone decomp or
+/// use a real ROM. Only the US ROM's SHA1 is known, so the JP ROM it is made for is only checked by its header.
+#[cfg(test)]
+pub(crate) fn test_initialised_package(dir: &Path, name: &str) -> Result<InitialisedPackage> {
+    let upstream = dir.join("upstream");
+    create_dir_all(upstream.join("ver/jp"))?;
+    write(upstream.join("ver/jp/.gitkeep"), "")?;
+    write(upstream.join("install.sh"), "exit 0\n")?;
+    write(upstream.join(".gitignore"), "*.z64\n")?;
+    git_output(&upstream, &["init", "--quiet"])?;
+    git_output(&upstream, &["symbolic-ref", "HEAD", "refs/heads/main"])?;
+    git_output(&upstream, &["add", "."])?;
+    super::test_commit(&upstream, "decomp")?;
+    let rev = git_output(&upstream, &["rev-parse", "HEAD"])?;
+
+    let mut header = [0; 0x40];
+    header[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+    header[0x3E] = b'J';
+    let baserom = dir.join("papermario-jp.z64");
+    write(&baserom, header)?;
+
+    let package = Package::new_with_author(name, dir.join("package"), Some("Test <test@example.com>".to_owned()))?;
+    package.edit_manifest(|manifest| {
+        manifest.metadata_mut().set_region(Some("jp".to_owned()));
+        manifest.metadata_mut().set_decomp_remote(Some(upstream.to_string_lossy().into_owned()), None);
+        Ok(())
+    })?;
+    InitialisedPackage::initialise(package, InitialiseOptions { baserom: Some(baserom), rev: Some(rev), from_shortlog: false })
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::package::test_commit;
+
+    #[test]
+    fn missing_subrepo_is_explained() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new_with_author("No Subrepo", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        create_dir(package.path().join(MERLON_DIR_NAME))?;
+        assert!(!InitialisedPackage::is_initialised(&package)?);
+        let error = InitialisedPackage::try_from(package.clone()).unwrap_err().to_string();
+        assert!(error.contains("run `merlon init`"), "{}", error);
+
+        // An empty directory is not a clone either, even if the package itself is a git repository
+        create_dir(package.path().join(SUBREPO_DIR_NAME))?;
+        git_output(package.path(), &["init", "--quiet"])?;
+        assert!(!InitialisedPackage::is_initialised(&package)?);
+        let error = InitialisedPackage::try_from(package).unwrap_err().to_string();
+        assert!(error.contains("is not a clone of the decomp"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn init_subrepo_is_idempotent() -> Result<()> {
+        let dir = TempDir::new()?;
+        let upstream = dir.path().join("upstream");
+        create_dir(&upstream)?;
+        git_output(&upstream, &["init", "--quiet"])?;
+        test_commit(&upstream, "first")?;
+        let first = git_output(&upstream, &["rev-parse", "HEAD"])?;
+        test_commit(&upstream, "second")?;
+
+        let package = Package::new_with_author("Subrepo", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        let url = upstream.to_string_lossy();
+        package.init_subrepo(&url, &first)?;
+        package.init_subrepo(&url, &first)?;
+
+        let subrepo = package.path().join(SUBREPO_DIR_NAME);
+        assert_eq!(git_output(&subrepo, &["rev-parse", "HEAD"])?, first);
+        assert_eq!(package.manifest()?.get_direct_decomp_dependency_rev(), Some(first.as_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn shortlog_authors_are_deduplicated_by_email() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path();
+        let commit = |name: &str, email: &str, message: &str| git_output(repo, &[
+            "-c", &format!("user.name={}", name), "-c", &format!("user.email={}", email),
+            "commit", "--quiet", "--allow-empty", "-m", message,
+        ]);
+        git_output(repo, &["init", "--quiet"])?;
+        commit("Base", "base@example.com", "base")?;
+        git_output(repo, &["tag", "base"])?;
+        assert!(shortlog_authors(repo, "base..HEAD")?.is_empty());
+
+        commit("Alice", "alice@example.com", "one")?;
+        commit("Bob", "bob@example.com", "two")?;
+        commit("Bob", "bob@example.com", "three")?;
+        commit("Alice Smith", "Alice@example.com", "four")?;
+        let authors = shortlog_authors(repo, "base..HEAD")?;
+        assert_eq!(authors.len(), 2, "{:?}", authors);
+        assert!(authors.contains(&"Bob <bob@example.com>".to_owned()), "{:?}", authors);
+        assert!(authors.iter().any(|author| author.to_lowercase().ends_with("<alice@example.com>")), "{:?}", authors);
+        Ok(())
+    }
+
+    #[test]
+    fn decomp_remote_is_recorded_and_fetched_from() -> Result<()> {
+        let dir = TempDir::new()?;
+        let upstream = dir.path().join("upstream");
+        create_dir(&upstream)?;
+        git_output(&upstream, &["init", "--quiet"])?;
+        git_output(&upstream, &["symbolic-ref", "HEAD", "refs/heads/trunk"])?;
+        test_commit(&upstream, "first")?;
+        let first = git_output(&upstream, &["rev-parse", "HEAD"])?;
+
+        let package = Package::new_with_author("Remote", dir.path().join("package"), Some("Test <test@example.com>".to_owned()))?;
+        let url = upstream.to_string_lossy().into_owned();
+        package.init_subrepo(&url, &first)?;
+        let manifest = package.manifest()?;
+        assert_eq!(manifest.metadata().decomp_repo(), Some(url.as_str()));
+        assert_eq!(manifest.metadata().decomp_branch(), Some("trunk"));
+
+        // A commit made upstream after cloning is fetched from the recorded repository
+        test_commit(&upstream, "second")?;
+        let second = git_output(&upstream, &["rev-parse", "HEAD"])?;
+        let subrepo = package.path().join(SUBREPO_DIR_NAME);
+        assert!(!commit_exists(&subrepo, &second)?);
+        fetch_decomp_rev(&subrepo, &second, manifest.metadata())?;
+        assert!(commit_exists(&subrepo, &second)?);
+
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert!(fetch_decomp_rev(&subrepo, missing, manifest.metadata()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merlon_files_are_excluded_from_patches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path();
+        git_output(repo, &["init", "--quiet"])?;
+        test_commit(repo, "base")?;
+        create_dir_all(repo.join("src/.merlon"))?;
+        write(repo.join("src/main.c"), "int main(void) {}\n")?;
+        write(repo.join("src/.merlon/state"), "internal\n")?;
+        write(repo.join("src/merlon.lock"), "internal\n")?;
+        git_output(repo, &["add", "--force", "src"])?;
+        test_commit(repo, "change")?;
+
+        let mut args = vec!["format-patch".to_owned(), "HEAD~1..HEAD".to_owned(), "--stdout".to_owned(), "--".to_owned()];
+        args.extend(export_pathspec_with_paths(DEFAULT_TARGET, "assets/test".to_owned(), vec![]));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let patch = git_output(repo, &args)?;
+        assert!(patch.contains("src/main.c"), "{}", patch);
+        assert!(!patch.contains(".merlon"), "{}", patch);
+        assert!(!patch.contains("merlon.lock"), "{}", patch);
+        Ok(())
+    }
+
+    #[test]
+    fn include_paths_are_added_to_patches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path();
+        git_output(repo, &["init", "--quiet"])?;
+        test_commit(repo, "base")?;
+        create_dir_all(repo.join("tools"))?;
+        create_dir_all(repo.join("docs"))?;
+        write(repo.join("tools/build.py"), "print('hi')\n")?;
+        write(repo.join("docs/notes.md"), "notes\n")?;
+        git_output(repo, &["add", "tools", "docs"])?;
+        test_commit(repo, "change")?;
+
+        let patch_with = |include_paths: Vec<String>| -> Result<String> {
+            let mut args = vec!["format-patch".to_owned(), "HEAD~1..HEAD".to_owned(), "--stdout".to_owned(), "--".to_owned()];
+            args.extend(export_pathspec_with_paths(DEFAULT_TARGET, "assets/test".to_owned(), include_paths));
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            git_output(repo, &args)
+        };
+        assert!(!patch_with(vec![])?.contains("tools/build.py"));
+        let patch = patch_with(vec!["tools".to_owned()])?;
+        assert!(patch.contains("tools/build.py"), "{}", patch);
+        assert!(!patch.contains("docs/notes.md"), "{}", patch);
+        Ok(())
+    }
+
+    #[test]
+    fn merlonignored_paths_are_excluded_from_patches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path().join("papermario");
+        create_dir_all(repo.join("src/scratch"))?;
+        git_output(&repo, &["init", "--quiet"])?;
+        test_commit(&repo, "base")?;
+        let base = git_output(&repo, &["rev-parse", "HEAD"])?;
+        write(repo.join("src/main.c"), "int main(void) {}\n")?;
+        write(repo.join("src/big.wip.bin"), "work in progress\n")?;
+        write(repo.join("src/scratch/notes.txt"), "scratch\n")?;
+        git_output(&repo, &["add", "src"])?;
+        test_commit(&repo, "change")?;
+
+        let merlonignore = dir.path().join(MERLONIGNORE_FILE_NAME);
+        assert!(read_merlonignore(&merlonignore, &repo)?.is_none());
+        write(&merlonignore, "# Not for release\n/src/scratch/\n*.wip.bin\n")?;
+        let ignore = read_merlonignore(&merlonignore, &repo)?.expect(".merlonignore should be read");
+
+        let mut pathspec = export_pathspec_with_paths(DEFAULT_TARGET, "assets/test".to_owned(), vec![]);
+        let changed = ["src/big.wip.bin", "src/main.c", "src/scratch/notes.txt"].map(str::to_owned);
+        let ignored = exclude_ignored_paths(&ignore, &changed, &mut pathspec);
+        assert_eq!(ignored, ["src/big.wip.bin", "src/scratch/notes.txt"]);
+
+        let out_dir = dir.path().join("patches");
+        create_dir(&out_dir)?;
+        let patches = super::super::format_patch::format_patches(&repo, &base, &pathspec, &out_dir, &Progress::default())?;
+        assert_eq!(patches.len(), 1);
+        let patch = std::fs::read_to_string(&patches[0])?;
+        assert!(patch.contains("src/main.c"), "{}", patch);
+        assert!(!patch.contains("wip.bin"), "{}", patch);
+        assert!(!patch.contains("scratch"), "{}", patch);
+        Ok(())
+    }
+
+    #[test]
+    fn uncommitted_changes_are_listed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let repo = dir.path();
+        git_output(repo, &["init", "--quiet"])?;
+        create_dir_all(repo.join("src"))?;
+        create_dir_all(repo.join("tools"))?;
+        write(repo.join("src/main.c"), "int main(void) {}\n")?;
+        write(repo.join("src/old.c"), "old\n")?;
+        write(repo.join("tools/build.py"), "print('hi')\n")?;
+        git_output(repo, &["add", "."])?;
+        git_output(repo, &["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "--quiet", "-m", "base"])?;
+        let pathspec = vec!["src".to_owned()];
+        assert!(uncommitted_paths(repo, &pathspec)?.is_empty());
+
+        write(repo.join("src/main.c"), "int main(void) { return 1; }\n")?;
+        write(repo.join("src/new file.c"), "new\n")?;
+        write(repo.join("tools/build.py"), "outside the pathspec\n")?;
+        git_output(repo, &["mv", "src/old.c", "src/renamed.c"])?;
+        let mut paths = uncommitted_paths(repo, &pathspec)?;
+        paths.sort();
+        assert_eq!(paths, ["src/main.c", "src/new file.c", "src/renamed.c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn targets_are_read_from_ver_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        create_dir_all(dir.path().join("ver/us/build"))?;
+        create_dir_all(dir.path().join("ver/jp"))?;
+        write(dir.path().join("ver/README.md"), "")?;
+        assert_eq!(list_targets(dir.path())?, vec!["jp".to_owned(), "us".to_owned()]);
+        Ok(())
+    }
+
+    #[test]
+    fn region_selects_ver_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        create_dir_all(dir.path().join("ver/us"))?;
+        create_dir_all(dir.path().join("ver/jp"))?;
+        assert_eq!(region_dir(dir.path(), "jp")?, dir.path().join("ver/jp"));
+        let error = region_dir(dir.path(), "pal").unwrap_err().to_string();
+        assert!(error.contains("jp, us"), "{}", error);
+
+        let pathspec = export_pathspec_with_paths("jp", "assets/test".to_owned(), vec![]);
+        assert!(pathspec.contains(&"ver/jp".to_owned()), "{:?}", pathspec);
+        assert!(!pathspec.contains(&"ver/us".to_owned()), "{:?}", pathspec);
+        Ok(())
+    }
+
+    #[test]
+    fn initialises_package_for_another_region() -> Result<()> {
+        let dir = TempDir::new()?;
+        let initialised = test_initialised_package(dir.path(), "Japan")?;
+        let copied = initialised.subrepo_path().join("ver/jp").join(BASEROM_FILE_NAME);
+        assert_eq!(std::fs::read(copied)?, std::fs::read(dir.path().join("papermario-jp.z64"))?);
+        Ok(())
+    }
+}
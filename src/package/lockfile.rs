@@ -0,0 +1,144 @@
+//! Lockfiles record the exact dependency set that a package was last set up with, so that it can be reproduced later.
+//! They are stored in `merlon.lock` files next to `merlon.toml`, and are written whenever the decomp repository's
+//! branches are set up.
+
+use std::io::prelude::*;
+use std::{fs::File, path::Path, io::BufReader};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{Id, Name, Registry, manifest::Version, write_file_atomically};
+
+/// The resolved dependencies of a package. Corresponds to a `merlon.lock` file.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    /// Git revision of the decomp that the package is built on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decomp_rev: Option<String>,
+
+    /// Resolved package dependencies, direct and transitive, sorted by ID.
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// A resolved package dependency. Corresponds to a `[[package]]` entry in `merlon.lock`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedPackage {
+    /// The package ID.
+    pub id: Id,
+
+    /// The package name at the time it was resolved.
+    pub name: Name,
+
+    /// The exact version that was resolved.
+    pub version: Version,
+
+    /// SHA1 of the package's patches, so changes are noticed even if the version was not bumped.
+    pub checksum: String,
+}
+
+impl Lockfile {
+    /// Creates a lockfile describing the resolved dependencies of `root`, which must be in the registry.
+    /// Every other package in the registry is locked.
+    pub fn from_registry(registry: &Registry, root: Id) -> Result<Self> {
+        let decomp_rev = registry.get_or_error(root)?
+            .manifest()?
+            .get_direct_decomp_dependency_rev()
+            .map(str::to_owned);
+        let mut packages = Vec::new();
+        for id in registry.package_ids() {
+            if id == root {
+                continue;
+            }
+            let package = registry.get_or_error(id)?;
+            let manifest = package.manifest()?;
+            let metadata = manifest.metadata();
+            packages.push(LockedPackage {
+                id,
+                name: metadata.name().clone(),
+                version: metadata.version().clone(),
+                checksum: package.patches_checksum()?,
+            });
+        }
+        packages.sort_by_key(|package| package.id);
+        Ok(Self { decomp_rev, packages })
+    }
+
+    /// Reads a lockfile from a file. Typically, lockfiles are named `merlon.lock`.
+    pub fn read_from_path(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+        let mut toml_string = String::new();
+        reader.read_to_string(&mut toml_string)?;
+        toml::from_str(&toml_string).map_err(Into::into)
+    }
+
+    /// Writes a lockfile to a file. Like `merlon.toml`, the file is replaced atomically, so it is never left
+    /// half-written.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let toml_string = toml::to_string_pretty(self)?;
+        write_file_atomically(path, |writer| {
+            writer.write_all(b"# This file is generated by Merlon. Do not edit it by hand.\n")?;
+            writer.write_all(toml_string.as_bytes())?;
+            Ok(())
+        })
+    }
+
+    /// Returns the locked entry for the given package, if any.
+    pub fn get(&self, id: Id) -> Option<&LockedPackage> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+    use anyhow::Result;
+
+    use super::*;
+    use crate::package::Package;
+
+    #[test]
+    fn lockfile_round_trips() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut registry = Registry::new();
+        let root = registry.register(Package::new("Root", dir.path().join("root"))?)?;
+        let dependency = registry.register(Package::new("Dependency", dir.path().join("dependency"))?)?;
+        registry.add_direct_dependency(root, dependency)?;
+
+        let lockfile = Lockfile::from_registry(&registry, root)?;
+        assert_eq!(lockfile.packages.len(), 1);
+        let locked = lockfile.get(dependency).unwrap();
+        assert_eq!(locked.name.to_string(), "Dependency");
+        assert_eq!(locked.checksum, registry.get_or_error(dependency)?.patches_checksum()?);
+        assert!(lockfile.get(root).is_none());
+
+        let path = dir.path().join("merlon.lock");
+        lockfile.write_to_file(&path)?;
+        assert_eq!(Lockfile::read_from_path(&path)?, lockfile);
+        Ok(())
+    }
+
+    #[test]
+    fn lockfile_is_replaced_atomically() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("merlon.lock");
+        Lockfile { decomp_rev: Some("a".repeat(40)), packages: vec![] }.write_to_file(&path)?;
+        let original = std::fs::read_to_string(&path)?;
+
+        let updated = Lockfile { decomp_rev: Some("b".repeat(40)), packages: vec![] };
+        updated.write_to_file(&path)?;
+        assert_eq!(Lockfile::read_from_path(&path)?, updated);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1, "temporary files should be cleaned up");
+
+        // Nothing can be renamed over a directory, so this write only fails once the new lockfile has been written.
+        // What was there is left as it was.
+        let blocked = dir.path().join("blocked");
+        std::fs::create_dir_all(blocked.join("merlon.lock"))?;
+        std::fs::write(blocked.join("merlon.lock/kept"), &original)?;
+        assert!(updated.write_to_file(&blocked.join("merlon.lock")).is_err());
+        assert_eq!(std::fs::read_to_string(blocked.join("merlon.lock/kept"))?, original);
+        assert_eq!(std::fs::read_dir(&blocked)?.count(), 1, "temporary files should be cleaned up");
+        Ok(())
+    }
+}
@@ -3,11 +3,11 @@
 //! The manifest format is loosely inspired by Cargo's `Cargo.toml` format.
 
 use std::io::prelude::*;
-use std::{fs::File, path::Path, io::{BufReader, BufWriter}};
-use anyhow::{Result, bail};
+use std::{fmt, fs::{self, File}, path::{Path, PathBuf}, io::BufReader};
+use anyhow::{Result, anyhow, bail};
 use pyo3::exceptions::PyValueError;
 use pyo3::types::PyDict;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 pub use semver::{Version, VersionReq};
 use pyo3::prelude::*;
 
@@ -18,33 +18,167 @@ use name::Name;
 mod id;
 pub use id::Id;
 
-use super::Package;
+/// Name and version package identifiers
+pub mod package_id;
+pub use package_id::PackageId;
 
-// TODO: use taplo instead of toml to preserve comments etc
+/// Valid package keywords and their aliases
+pub mod keywords;
+use keywords::{KeywordConfig, MAX_KEYWORDS};
+
+mod document;
+
+use super::{Package, normalize_relative_path, resolve_relative_path, write_file_atomically};
+use super::init::DEFAULT_TARGET;
+
+/// The version of the `merlon.toml` format that this version of Merlon reads and writes.
+///
+/// Manifests without a `format_version` are version 0. Manifests in older formats are upgraded when read, see
+/// [`Manifest::migrate`].
+pub const FORMAT_VERSION: u32 = 1;
 
 /// Package manifest data.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[pyclass(module = "merlon.package.manifest")]
 pub struct Manifest {
+    /// Version of the manifest format, see [`FORMAT_VERSION`].
+    #[serde(default)]
+    format_version: u32,
+
     /// Package metadata
     #[serde(rename = "package")]
     metadata: Metadata,
 
     /// Direct dependencies (not transitive)
     dependencies: Vec<Dependency>,
+
+    /// External tools used when working on the package
+    #[serde(default, skip_serializing_if = "Tools::is_empty")]
+    tools: Tools,
+}
+
+/// External tools used when working on a package. Corresponds to the `[tools]` section in `merlon.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tools {
+    /// Emulator executable used by `merlon run`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emulator: Option<String>,
+}
+
+impl Tools {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 /// Metadata about a package. Corresponds to the `[package]` section in `merlon.toml`.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[pyclass(module = "merlon.package.manifest")]
 pub struct Metadata {
     id: Id,
     name: Name,
+    #[serde(deserialize_with = "deserialize_version")]
     version: Version,
     authors: Vec<String>,
+    /// Optional file listing additional authors, one `Name <email>` per line.
+    /// Relative to the directory containing `merlon.toml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    authors_file: Option<PathBuf>,
     description: String,
     license: String,
     keywords: Vec<String>,
+    /// Website for the package, such as a forum thread or mod listing. Must be an `http` or `https` URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    homepage: Option<String>,
+    /// Where the package's source is hosted. Must be an `http` or `https` URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repository: Option<String>,
+    /// Additional files to include in distributables, such as `INSTALL.md`.
+    /// Relative to the directory containing `merlon.toml`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_files: Vec<PathBuf>,
+    /// Subdirectory of decomp's `assets/` that this package's asset changes live in.
+    /// Defaults to the package ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assets_dir: Option<String>,
+    /// Whether to export changes to all of `assets/` rather than just `assets_dir`.
+    /// Only set this if the package needs to edit assets shared with the base game or other packages.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    shared_assets: bool,
+    /// How the package's changes are stored in `patches/`.
+    #[serde(default, skip_serializing_if = "PatchFormat::is_default")]
+    patch_format: PatchFormat,
+    /// Additional paths in the decomp repository whose changes are exported, besides `src`, `include`, the region's
+    /// `ver/` directory and the assets directory. Relative to the decomp repository.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include_paths: Vec<PathBuf>,
+    /// Oldest decomp commit the package works with, for packages that rely on upstream changes.
+    /// Decomp revisions that do not contain this commit are refused by `init --rev`, export and apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_decomp_rev: Option<String>,
+    /// URL of the decomp repository that the decomp dependency's revision was taken from.
+    /// Recorded when the decomp is cloned or updated, so that the revision can be fetched from it if it is missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decomp_repo: Option<String>,
+    /// Branch of `decomp_repo` that the decomp dependency's revision was on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decomp_branch: Option<String>,
+    /// Game version the package is made for, as named by a directory in decomp's `ver/`, such as `jp`.
+    /// Its baserom is the one distributables are encrypted with. Defaults to `us`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    /// Base ROM to use instead of the one in the decomp clone, such as a copy shared between packages.
+    /// Relative to the directory containing `merlon.toml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    baserom: Option<PathBuf>,
+
+    /// Authors read from `authors_file` when the manifest was loaded.
+    #[serde(skip)]
+    file_authors: Vec<String>,
+
+    /// Problems found while reading `authors_file`, reported by `validate`.
+    #[serde(skip)]
+    authors_file_errors: Vec<String>,
+}
+
+/// Checks that `url` is an absolute `http` or `https` URL with a host, explaining why not if it isn't.
+fn validate_http_url(url: &str) -> Result<(), String> {
+    let parsed = url::Url::parse(url).map_err(|error| format!("is invalid: {}", error))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("has scheme {:?}", parsed.scheme()));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("has no host".to_owned());
+    }
+    Ok(())
+}
+
+/// Parses a package version, explaining why it is not valid semver if it isn't.
+pub(crate) fn parse_version(version: &str) -> Result<Version> {
+    Version::parse(version)
+        .map_err(|error| anyhow!("version must be valid semver (like 1.0.0): {:?} is invalid: {}", version, error))
+}
+
+fn deserialize_version_req<'de, D: Deserializer<'de>>(deserializer: D) -> Result<VersionReq, D::Error> {
+    let requirement = String::deserialize(deserializer)?;
+    VersionReq::parse(&requirement).map_err(|error| serde::de::Error::custom(format!(
+        "dependency version must be a valid requirement (like ^1.2 or >=0.3, <0.5): {:?} is invalid: {}",
+        requirement,
+        error,
+    )))
+}
+
+fn any_version() -> VersionReq {
+    VersionReq::STAR
+}
+
+fn is_any_version(version: &VersionReq) -> bool {
+    *version == VersionReq::STAR
+}
+
+fn deserialize_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Version, D::Error> {
+    let version = String::deserialize(deserializer)?;
+    parse_version(&version).map_err(serde::de::Error::custom)
 }
 
 #[pymethods]
@@ -70,7 +204,7 @@ impl Metadata {
     /// Set the package version. Must be a valid semver version (e.g. `1.0.0-rc1`).
     #[setter(version)]
     fn py_set_version(&mut self, version: String) -> Result<()> {
-        self.version = version.parse()?;
+        self.version = parse_version(&version)?;
         Ok(())
     }
 
@@ -80,46 +214,108 @@ impl Metadata {
         &self.description
     }
 
-    /// Validate package metadata, returning a list of errors
+    /// The package license.
+    #[getter]
+    pub fn license(&self) -> &str {
+        &self.license
+    }
+
+    /// The package homepage URL, if it has one.
+    #[getter]
+    pub fn homepage(&self) -> Option<&str> {
+        self.homepage.as_deref()
+    }
+
+    /// The URL of the package's source repository, if it has one.
+    #[getter]
+    pub fn repository(&self) -> Option<&str> {
+        self.repository.as_deref()
+    }
+
+    /// Validate package metadata, returning a list of errors.
+    /// Keywords are checked against the built-in keywords and the user's `keywords.toml`.
     pub fn validate(&self) -> Vec<String> {
+        self.validate_with_keywords(&KeywordConfig::load())
+    }
+
+    /// Returns whether the package metadata is valid.
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_empty()
+    }
+
+    /// The package authors, including any listed in the authors file.
+    #[getter]
+    fn get_authors(&self) -> Vec<String> {
+        self.authors()
+    }
+}
+
+impl Metadata {
+    /// Validates package metadata like [`Metadata::validate`], checking keywords against the given config.
+    pub fn validate_with_keywords(&self, keyword_config: &KeywordConfig) -> Vec<String> {
         let mut errors = Vec::new();
         // TODO: use newtypes for these, like Name
-        // TODO: validate version
-        if self.authors.is_empty() {
+        if self.authors().is_empty() {
             errors.push("authors cannot be empty".to_owned());
         }
-        if self.description.is_empty() {
-            errors.push("description cannot be empty".to_owned());
+        for author in &self.authors {
+            if !is_valid_author(author) {
+                errors.push(format!("invalid author {:?}, expected `Name <email>`", author));
+            }
+        }
+        errors.extend(self.authors_file_errors.iter().cloned());
+        for path in &self.include_paths {
+            if let Err(error) = normalize_relative_path(path) {
+                errors.push(format!("invalid include path: {}", error));
+            }
+        }
+        if let Some(region) = &self.region {
+            let valid = !region.is_empty()
+                && region.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+            if !valid {
+                errors.push(format!("invalid region {:?}, expected the name of a directory in decomp's ver/ such as \"us\"", region));
+            }
         }
-        if self.description.len() > 100 {
-            errors.push("description must be less than 100 characters".to_owned());
+        if let Err(error) = check_description(&self.description) {
+            errors.push(error.to_owned());
         }
         if self.license.is_empty() {
             errors.push("license cannot be empty".to_owned());
+        } else if let Err(error) = spdx::Expression::parse(&self.license) {
+            errors.push(format!(
+                "license must be an SPDX license expression (like `MIT OR Apache-2.0`): {} `{}`",
+                error.reason,
+                &error.original[error.span.clone()],
+            ));
         }
-        // TODO: validate license
+        let mut seen_keywords: Vec<(String, &str)> = Vec::new();
         for keyword in &self.keywords {
-            const VALID_KEYWORDS: &[&str] = &["qol", "cheat", "bugfix", "cosmetic", "feature"];
-            if !VALID_KEYWORDS.contains(&keyword.as_str()) {
-                errors.push(format!("invalid keyword: {} (valid keywords: {:?})", keyword, VALID_KEYWORDS));
+            let Some(resolved) = keyword_config.resolve(keyword) else {
+                errors.push(format!("invalid keyword: {} (valid keywords: {:?})", keyword, keyword_config.keywords()));
+                continue;
+            };
+            match seen_keywords.iter().find(|(seen, _)| *seen == resolved) {
+                Some((_, first)) if *first == keyword.as_str() => errors.push(format!("duplicate keyword: {}", keyword)),
+                Some((_, first)) => errors.push(format!("duplicate keyword: {} (same as {})", keyword, first)),
+                None => seen_keywords.push((resolved, keyword)),
+            }
+        }
+        if self.keywords.len() > MAX_KEYWORDS {
+            errors.push(format!("too many keywords: {} (at most {} are allowed)", self.keywords.len(), MAX_KEYWORDS));
+        }
+        for (field, url) in [("homepage", &self.homepage), ("repository", &self.repository)] {
+            if let Some(Err(error)) = url.as_deref().map(validate_http_url) {
+                errors.push(format!("{} must be an http or https URL: {:?} {}", field, url.as_deref().unwrap_or_default(), error));
             }
         }
         errors
     }
 
-    /// Returns whether the package metadata is valid.
-    pub fn is_valid(&self) -> bool {
-        self.validate().is_empty()
-    }
-
-    /// The package authors.
-    #[getter]
-    fn get_authors(&self) -> Vec<String> {
-        self.authors.clone()
+    /// Returns the `name@version` identifier of this release of the package.
+    pub fn versioned_id(&self) -> PackageId {
+        PackageId::new(self.name.clone(), self.version.clone())
     }
-}
 
-impl Metadata {
     /// Returns the package name.
     pub fn name(&self) -> &Name {
         &self.name
@@ -135,9 +331,203 @@ impl Metadata {
         self.version = version;
     }
 
-    /// Returns the package authors.
-    pub fn authors(&self) -> &Vec<String> {
-        &self.authors
+    /// Returns the package authors, including any listed in the authors file.
+    pub fn authors(&self) -> Vec<String> {
+        let mut authors = self.authors.clone();
+        for author in &self.file_authors {
+            if !authors.contains(author) {
+                authors.push(author.clone());
+            }
+        }
+        authors
+    }
+
+    /// Lists the authors from the authors file in `merlon.toml` itself, and stops referring to the file. Returns
+    /// whether there was an authors file. Used when the manifest is copied somewhere the file is not.
+    pub(crate) fn inline_authors_file(&mut self) -> bool {
+        if self.authors_file.is_none() {
+            return false;
+        }
+        self.authors = self.authors();
+        self.authors_file = None;
+        self.file_authors.clear();
+        true
+    }
+
+    /// Replaces the package authors listed in `merlon.toml`. Authors from the authors file are unaffected.
+    pub fn set_authors(&mut self, authors: Vec<String>) {
+        self.authors = authors;
+    }
+
+    /// Adds an author, formatted as `Name <email>`, unless they are already listed.
+    pub fn add_author(&mut self, author: String) -> Result<()> {
+        if !is_valid_author(&author) {
+            bail!("invalid author {:?}, expected `Name <email>`", author);
+        }
+        if !self.authors.contains(&author) {
+            self.authors.push(author);
+        }
+        Ok(())
+    }
+
+    /// Updates the package's one-line description, which must not be empty or longer than 100 characters.
+    pub fn set_description(&mut self, description: String) -> Result<()> {
+        if let Err(error) = check_description(&description) {
+            bail!("{}", error);
+        }
+        self.description = description;
+        Ok(())
+    }
+
+    /// Returns the package keywords.
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Adds a keyword, checked against the built-in keywords and the user's `keywords.toml`. An alias is added as
+    /// the keyword it stands for.
+    pub fn add_keyword(&mut self, keyword: &str) -> Result<()> {
+        self.add_keyword_with(keyword, &KeywordConfig::load())
+    }
+
+    /// Adds a keyword like [`Metadata::add_keyword`], checking it against the given config.
+    pub fn add_keyword_with(&mut self, keyword: &str, keyword_config: &KeywordConfig) -> Result<()> {
+        let Some(keyword) = keyword_config.resolve(keyword) else {
+            bail!("invalid keyword: {} (valid keywords: {:?})", keyword, keyword_config.keywords());
+        };
+        if self.keywords.iter().any(|existing| keyword_config.resolve(existing).as_ref() == Some(&keyword)) {
+            return Ok(());
+        }
+        if self.keywords.len() >= MAX_KEYWORDS {
+            bail!("cannot add keyword {}: packages may have at most {} keywords", keyword, MAX_KEYWORDS);
+        }
+        self.keywords.push(keyword);
+        Ok(())
+    }
+
+    /// Returns the additional files to include in distributables, relative to the package directory.
+    pub fn extra_files(&self) -> &[PathBuf] {
+        &self.extra_files
+    }
+
+    /// Updates the additional files to include in distributables.
+    pub fn set_extra_files(&mut self, extra_files: Vec<PathBuf>) {
+        self.extra_files = extra_files;
+    }
+
+    /// Returns the subdirectory of decomp's `assets/` that this package's asset changes live in.
+    pub fn assets_dir(&self) -> String {
+        self.assets_dir.clone().unwrap_or_else(|| self.id.to_string())
+    }
+
+    /// Returns whether changes to all of decomp's `assets/` are exported, rather than just [`Metadata::assets_dir`].
+    pub fn shared_assets(&self) -> bool {
+        self.shared_assets
+    }
+
+    /// Returns how the package's changes are stored in `patches/`.
+    pub fn patch_format(&self) -> PatchFormat {
+        self.patch_format
+    }
+
+    /// Sets how the package's changes are stored in `patches/`.
+    pub fn set_patch_format(&mut self, patch_format: PatchFormat) {
+        self.patch_format = patch_format;
+    }
+
+    /// Returns the additional decomp paths whose changes are exported, relative to the decomp repository.
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+
+    /// Updates the additional decomp paths whose changes are exported.
+    pub fn set_include_paths(&mut self, include_paths: Vec<PathBuf>) {
+        self.include_paths = include_paths;
+    }
+
+    /// Sets the package homepage URL.
+    pub fn set_homepage(&mut self, homepage: Option<String>) {
+        self.homepage = homepage;
+    }
+
+    /// Sets the URL of the package's source repository.
+    pub fn set_repository(&mut self, repository: Option<String>) {
+        self.repository = repository;
+    }
+
+    /// Returns the oldest decomp commit the package works with, if it declares one.
+    pub fn min_decomp_rev(&self) -> Option<&str> {
+        self.min_decomp_rev.as_deref()
+    }
+
+    /// Sets the oldest decomp commit the package works with.
+    pub fn set_min_decomp_rev(&mut self, min_decomp_rev: Option<String>) {
+        self.min_decomp_rev = min_decomp_rev;
+    }
+
+    /// Returns the URL of the decomp repository that the decomp dependency's revision was taken from, if recorded.
+    pub fn decomp_repo(&self) -> Option<&str> {
+        self.decomp_repo.as_deref()
+    }
+
+    /// Returns the branch of [`Metadata::decomp_repo`] that the decomp dependency's revision was on, if recorded.
+    pub fn decomp_branch(&self) -> Option<&str> {
+        self.decomp_branch.as_deref()
+    }
+
+    /// Records where the decomp dependency's revision was taken from.
+    pub fn set_decomp_remote(&mut self, repo: Option<String>, branch: Option<String>) {
+        self.decomp_repo = repo;
+        self.decomp_branch = branch;
+    }
+
+    /// Returns the game version the package is made for, as named by a directory in decomp's `ver/`.
+    pub fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or(DEFAULT_TARGET)
+    }
+
+    /// Sets the game version the package is made for. `None` means `us`.
+    pub fn set_region(&mut self, region: Option<String>) {
+        self.region = region;
+    }
+
+    /// Returns the base ROM path set in the manifest, relative to the directory containing `merlon.toml`.
+    pub fn baserom(&self) -> Option<&Path> {
+        self.baserom.as_deref()
+    }
+
+    /// Sets the base ROM path, relative to the directory containing `merlon.toml`. `None` means the one in the
+    /// decomp clone.
+    pub fn set_baserom(&mut self, baserom: Option<PathBuf>) {
+        self.baserom = baserom;
+    }
+
+    /// Reads `Name <email>` lines from an authors file. Blank lines and `#` comments are skipped.
+    /// Malformed lines are recorded and reported by [`Metadata::validate`].
+    fn load_authors_file(&mut self, path: &Path) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                self.authors_file_errors.push(format!("failed to read authors file {}: {}", path.display(), error));
+                return;
+            }
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if is_valid_author(line) {
+                self.file_authors.push(line.to_owned());
+            } else {
+                self.authors_file_errors.push(format!(
+                    "{}:{}: malformed author {:?} (expected `Name <email>`)",
+                    path.display(),
+                    index + 1,
+                    line,
+                ));
+            }
+        }
     }
 
     /// Prints validation warnings to stderr.
@@ -149,6 +539,26 @@ impl Metadata {
     }
 }
 
+/// How a package's changes are stored in its `patches/` directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+#[pyclass(module = "merlon.package.manifest")]
+pub enum PatchFormat {
+    /// One patch per commit, generated by `git format-patch` and applied with `git am`.
+    /// This preserves commit history.
+    #[default]
+    GitAm,
+    /// A single unified diff of all changes, generated by `git diff` and applied with `git apply`.
+    /// This can also be applied without Git, using `patch -p1`.
+    Diff,
+}
+
+impl PatchFormat {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 /// A dependency description. Corresponds to values of the `[[dependencies]]` list in `merlon.toml`.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
@@ -159,9 +569,16 @@ pub enum Dependency {
         /// The ID of the dependency package.
         id: Id,
 
-        /// The semantic version requirement for the dependency.
+        /// The semantic version requirement for the dependency, such as `^1.2` or `>=0.3, <0.5`.
         /// See https://doc.rust-lang.org/cargo/reference/specifying-dependencies.html
+        ///
+        /// If omitted, any version is accepted, which is mostly useful with a `path` or `git` source.
+        #[serde(default = "any_version", skip_serializing_if = "is_any_version", deserialize_with = "deserialize_version_req")]
         version: VersionReq,
+
+        /// Where the dependency comes from, given by the `path`, or `git` and `rev`, keys.
+        #[serde(flatten)]
+        source: Source,
     },
     /// Dependency on the Paper Mario decompilation.
     Decomp {
@@ -170,6 +587,126 @@ pub enum Dependency {
     },
 }
 
+/// Where a package dependency comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "SourceFields", into = "SourceFields")]
+pub enum Source {
+    /// A distributable in the packages directory, chosen by version.
+    #[default]
+    Index,
+
+    /// A package directory on disk, relative to the directory of the package that depends on it.
+    Path(PathBuf),
+
+    /// A commit of the Git repository at `url`.
+    Git {
+        /// URL of the repository.
+        url: String,
+
+        /// Git commit hash, branch or tag.
+        rev: String,
+    },
+}
+
+/// The keys of a `[[dependencies]]` entry that make up its [`Source`].
+#[derive(Serialize, Deserialize)]
+struct SourceFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+}
+
+impl TryFrom<SourceFields> for Source {
+    type Error = String;
+
+    fn try_from(fields: SourceFields) -> Result<Self, Self::Error> {
+        match fields {
+            SourceFields { path: None, git: None, rev: None } => Ok(Self::Index),
+            SourceFields { path: Some(path), git: None, rev: None } => Ok(Self::Path(path)),
+            SourceFields { path: None, git: Some(url), rev: Some(rev) } => Ok(Self::Git { url, rev }),
+            SourceFields { path: None, git: Some(url), rev: None } => Err(format!("git dependency {:?} must have a rev", url)),
+            SourceFields { path: None, git: None, rev: Some(rev) } => Err(format!("dependency rev {:?} requires a git repository", rev)),
+            SourceFields { path: Some(_), .. } => Err("dependency cannot have both a path and a git repository".to_owned()),
+        }
+    }
+}
+
+impl From<Source> for SourceFields {
+    fn from(source: Source) -> Self {
+        match source {
+            Source::Index => Self { path: None, git: None, rev: None },
+            Source::Path(path) => Self { path: Some(path), git: None, rev: None },
+            Source::Git { url, rev } => Self { path: None, git: Some(url), rev: Some(rev) },
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index => write!(f, "packages directory"),
+            Self::Path(path) => write!(f, "path {}", path.display()),
+            Self::Git { url, rev } => write!(f, "git {} {}", url, rev),
+        }
+    }
+}
+
+impl Dependency {
+    /// Returns whether `version` of the depended-on package satisfies this dependency.
+    /// Decomp dependencies are not versioned, so they never match.
+    pub fn matches(&self, version: &Version) -> bool {
+        match self {
+            Self::Package { version: requirement, .. } => requirement.matches(version),
+            Self::Decomp { .. } => false,
+        }
+    }
+
+    /// Creates a dependency on the package described by `metadata` with the given version requirement, such as
+    /// `^1.2`. Errors if the requirement is malformed, or if the package's own version does not satisfy it.
+    pub fn on_package(metadata: &Metadata, requirement: &str) -> Result<Self> {
+        let version = VersionReq::parse(requirement)
+            .map_err(|error| anyhow!("invalid version requirement {:?}: {}", requirement, error))?;
+        if !version.matches(metadata.version()) {
+            bail!(
+                "version requirement {} does not match version {} of {}",
+                version,
+                metadata.version(),
+                metadata.name(),
+            );
+        }
+        Ok(Self::Package { id: metadata.id(), version, source: Source::Index })
+    }
+
+    /// Returns the package directory of a path dependency, given the directory of the package that declares it.
+    pub fn path_in(&self, package_dir: &Path) -> Option<PathBuf> {
+        match self {
+            Self::Package { source: Source::Path(path), .. } => Some(package_dir.join(path)),
+            _ => None,
+        }
+    }
+
+    /// Key that orders decomp dependencies first, then package dependencies by ID.
+    pub(crate) fn sort_key(&self) -> (u8, String) {
+        match self {
+            Self::Decomp { rev } => (0, rev.clone()),
+            Self::Package { id, .. } => (1, id.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Package { id, version, source: Source::Index } => write!(f, "package {} {}", id, version),
+            Self::Package { id, version, source } => write!(f, "package {} {} ({})", id, version, source),
+            Self::Decomp { rev } => write!(f, "decomp {}", rev),
+        }
+    }
+}
+
 impl From<&Metadata> for Dependency {
     fn from(metadata: &Metadata) -> Self {
         let version = metadata.version();
@@ -185,7 +722,8 @@ impl From<&Metadata> for Dependency {
                         pre: version.pre.clone(),
                     }
                 ]
-            }
+            },
+            source: Source::Index,
         }
     }
 }
@@ -203,11 +741,19 @@ impl TryFrom<&Package> for Dependency {
 impl ToPyObject for Dependency {
     fn to_object(&self, py: Python<'_>) -> PyObject {
         match self {
-            Self::Package { id, version } => {
+            Self::Package { id, version, source } => {
                 let dict = PyDict::new(py);
                 dict.set_item("type", "package").unwrap();
                 dict.set_item("id", id.to_string()).unwrap();
                 dict.set_item("version", version.to_string()).unwrap();
+                match source {
+                    Source::Index => {}
+                    Source::Path(path) => dict.set_item("path", path).unwrap(),
+                    Source::Git { url, rev } => {
+                        dict.set_item("git", url).unwrap();
+                        dict.set_item("rev", rev).unwrap();
+                    }
+                }
                 dict.into()
             }
             Self::Decomp { rev } => {
@@ -231,12 +777,18 @@ impl FromPyObject<'_> for Dependency {
                 let id: Id = dict.get_item("id")
                     .ok_or(PyValueError::new_err("missing dependency id"))?
                     .extract()?;
-                let version: String = dict.get_item("version")
-                    .ok_or(PyValueError::new_err("missing dependency version"))?
-                    .extract()?;
-                let version: VersionReq = version.parse()
-                    .map_err(|e| PyValueError::new_err(format!("invalid dependency version: {}", e)))?;
-                Ok(Self::Package { id, version })
+                let version: VersionReq = match dict.get_item("version") {
+                    Some(version) => version.extract::<String>()?.parse()
+                        .map_err(|e| PyValueError::new_err(format!("invalid dependency version: {}", e)))?,
+                    None => any_version(),
+                };
+                let source = SourceFields {
+                    path: dict.get_item("path").map(PyAny::extract).transpose()?,
+                    git: dict.get_item("git").map(PyAny::extract).transpose()?,
+                    rev: dict.get_item("rev").map(PyAny::extract).transpose()?,
+                };
+                let source = Source::try_from(source).map_err(PyValueError::new_err)?;
+                Ok(Self::Package { id, version, source })
             }
             "decomp" => {
                 let rev: String = dict.get_item("rev")
@@ -258,19 +810,46 @@ impl IntoPy<PyObject> for Dependency {
 #[pymethods]
 impl Manifest {
     /// Creates a new manifest for a package with the given name.
+    /// The author is taken from git config.
     #[new]
     pub fn new(name: Name) -> Result<Self> {
+        Self::with_author(name, get_author()?)
+    }
+
+    /// Creates a new manifest for a package with the given name and author, formatted as `Name <email>`.
+    #[staticmethod]
+    pub fn with_author(name: Name, author: String) -> Result<Self> {
+        if !is_valid_author(&author) {
+            bail!("invalid author {:?}, expected `Name <email>`", author);
+        }
         Ok(Self {
+            format_version: FORMAT_VERSION,
             metadata: Metadata {
                 id: Id::new(),
                 name,
                 version: "0.1.0".parse()?,
-                authors: vec![get_author()?],
+                authors: vec![author],
+                authors_file: None,
                 description: "An amazing mod".to_owned(),
                 license: "CC-BY-SA-4.0".to_owned(),
                 keywords: vec![],
+                homepage: None,
+                repository: None,
+                extra_files: vec![],
+                assets_dir: None,
+                shared_assets: false,
+                patch_format: PatchFormat::GitAm,
+                include_paths: vec![],
+                min_decomp_rev: None,
+                decomp_repo: None,
+                decomp_branch: None,
+                region: None,
+                baserom: None,
+                file_authors: vec![],
+                authors_file_errors: vec![],
             },
             dependencies: vec![], // note: no Dependency::Decomp (init will add this)
+            tools: Tools::default(),
         })
     }
 
@@ -285,44 +864,179 @@ impl Manifest {
     pub fn set_metadata(&mut self, metadata: Metadata) {
         self.metadata = metadata;
     }
+
+    /// Validate the whole manifest, returning a list of errors.
+    /// This includes everything [`Metadata::validate`] checks, as well as the dependencies.
+    pub fn validate(&self) -> Vec<String> {
+        self.validate_with_keywords(&KeywordConfig::load())
+    }
 }
 
 impl Manifest {
+    /// Validates the manifest like [`Manifest::validate`], checking keywords against the given config.
+    pub fn validate_with_keywords(&self, keyword_config: &KeywordConfig) -> Vec<String> {
+        let mut errors = self.metadata.validate_with_keywords(keyword_config);
+        let mut seen_decomp = false;
+        let mut seen_ids = Vec::new();
+        for dependency in &self.dependencies {
+            match dependency {
+                Dependency::Decomp { rev } => {
+                    if !is_full_commit_hash(rev) {
+                        errors.push(format!("decomp dependency rev {:?} must be a full 40-character commit hash", rev));
+                    }
+                    if seen_decomp {
+                        errors.push("only one decomp dependency can be declared".to_owned());
+                    }
+                    seen_decomp = true;
+                }
+                Dependency::Package { id, .. } => {
+                    if *id == self.metadata.id {
+                        errors.push("package cannot depend on itself".to_owned());
+                    }
+                    if seen_ids.contains(id) {
+                        errors.push(format!("dependency on {} is declared more than once", id));
+                    }
+                    seen_ids.push(*id);
+                }
+            }
+        }
+        errors
+    }
+
     /// Borrows the package metadata.
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
 
+    /// Borrows the `[tools]` settings.
+    pub fn tools(&self) -> &Tools {
+        &self.tools
+    }
+
+    /// Mutably borrows the `[tools]` settings.
+    pub fn tools_mut(&mut self) -> &mut Tools {
+        &mut self.tools
+    }
+
     /// Mutably borrows the package metadata.
     pub fn metadata_mut(&mut self) -> &mut Metadata {
         &mut self.metadata
     }
 
+    /// Parses a manifest from a TOML string. Manifests in older formats are upgraded, see [`Manifest::migrate`].
+    pub fn from_toml_str(toml_string: &str) -> Result<Self> {
+        Self::from_toml_str_migrating(toml_string).map(|(manifest, _)| manifest)
+    }
+
+    /// Parses a manifest like [`Manifest::from_toml_str`], also returning the format version it was in.
+    fn from_toml_str_migrating(toml_string: &str) -> Result<(Self, u32)> {
+        let mut table: toml::Table = toml_string.parse()?;
+        let format_version = Self::migrate(&mut table)?;
+        if format_version == FORMAT_VERSION {
+            // Deserializing the string rather than the table keeps line numbers in errors
+            return Ok((toml::from_str(toml_string)?, format_version));
+        }
+        Ok((toml::Value::Table(table).try_into()?, format_version))
+    }
+
+    /// Upgrades a parsed `merlon.toml` in an older format to the current [`FORMAT_VERSION`] in place, and returns the
+    /// format version it was in. Errors if it is in a newer format than this version of Merlon understands.
+    ///
+    /// Version 0 manifests could leave out the `dependencies` list, and the `authors`, `description`, `license` and
+    /// `keywords` of the package. These are filled in as empty.
+    pub fn migrate(table: &mut toml::Table) -> Result<u32> {
+        let format_version = match table.get("format_version") {
+            None => 0,
+            Some(toml::Value::Integer(version)) => u32::try_from(*version)
+                .map_err(|_| anyhow!("format_version must be a non-negative integer, not {}", version))?,
+            Some(value) => bail!("format_version must be an integer, not {}", value),
+        };
+        if format_version > FORMAT_VERSION {
+            bail!(
+                "merlon.toml is in format version {}, but this version of Merlon only understands up to version {}. \
+                Please upgrade Merlon.",
+                format_version,
+                FORMAT_VERSION,
+            );
+        }
+
+        if format_version < 1 {
+            table.entry("dependencies").or_insert_with(|| toml::Value::Array(Vec::new()));
+            if let Some(toml::Value::Table(package)) = table.get_mut("package") {
+                for key in ["authors", "keywords"] {
+                    package.entry(key).or_insert_with(|| toml::Value::Array(Vec::new()));
+                }
+                for key in ["description", "license"] {
+                    package.entry(key).or_insert_with(|| toml::Value::String(String::new()));
+                }
+            }
+        }
+
+        table.insert("format_version".to_owned(), toml::Value::Integer(FORMAT_VERSION.into()));
+        Ok(format_version)
+    }
+
+    /// The version of the manifest format, which is always [`FORMAT_VERSION`] once read.
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Serializes the manifest to a TOML string. Dependencies are written in sorted order so output is stable.
+    pub fn to_toml_string(&self) -> Result<String> {
+        let mut sorted = self.clone();
+        sorted.dependencies.sort_by_key(Dependency::sort_key);
+        toml::to_string_pretty(&sorted).map_err(Into::into)
+    }
+
     /// Reads a manifest from a file. Typically, manifest files are named `merlon.toml`.
     pub fn read_from_path(path: &Path) -> Result<Self> {
         let file = File::open(path)?;
         let mut reader = BufReader::new(file);
         let mut toml_string = String::new();
         reader.read_to_string(&mut toml_string)?;
-        let config = toml::from_str(&toml_string)?;
+        let (mut config, format_version) = Self::from_toml_str_migrating(&toml_string)?;
+        if format_version != FORMAT_VERSION {
+            log::info!(
+                "{} is in format version {}, run `merlon migrate` to upgrade it to version {}",
+                path.display(),
+                format_version,
+                FORMAT_VERSION,
+            );
+        }
+        if let Some(authors_file) = config.metadata.authors_file.clone() {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            match resolve_relative_path(dir, &authors_file) {
+                Ok(authors_file) => config.metadata.load_authors_file(&authors_file),
+                Err(error) => config.metadata.authors_file_errors.push(format!("invalid authors_file: {}", error)),
+            }
+        }
         Ok(config)
     }
 
-    /// Writes a manifest to a file.
+    /// Writes a manifest to a file. The file is replaced atomically, so it is never left half-written.
+    ///
+    /// If the file already exists, only the values that changed are rewritten, so comments, blank lines and key
+    /// order are preserved.
     pub fn write_to_file(&self, path: &Path) -> Result<()> {
-        let file = File::create(path)?;
-        let mut writer = BufWriter::new(file);
-        let toml_string = toml::to_string_pretty(self)?;
-        writer.write_all(toml_string.as_bytes())?;
-        Ok(())
+        let mut toml_string = self.to_toml_string()?;
+        if let Ok(existing) = fs::read_to_string(path) {
+            match document::merge_into(&existing, &toml_string) {
+                Ok(merged) => toml_string = merged,
+                Err(error) => log::warn!("rewriting {} from scratch: {:#}", path.display(), error),
+            }
+        }
+        write_file_atomically(path, |writer| {
+            writer.write_all(toml_string.as_bytes())?;
+            Ok(())
+        })
     }
 
     /// Adds a dependency to the manifest.
     /// If the dependency is declared already but with a different version/revision, errors.
     pub fn declare_direct_dependency(&mut self, dependency: Dependency) -> Result<()> {
         match &dependency {
-            Dependency::Package { id, version } => {
-                if let Some(Dependency::Package { version: existing_version, .. }) = self.dependencies
+            Dependency::Package { id, version, source } => {
+                if let Some(Dependency::Package { version: existing_version, source: existing_source, .. }) = self.dependencies
                     .iter_mut()
                     .find(|dep| matches!(dep, Dependency::Package { id: dep_id, .. } if *id == *dep_id))
                 {
@@ -330,6 +1044,9 @@ impl Manifest {
                     if *existing_version != *version {
                         bail!("dependency on package ID {} already declared with incompatible version", id);
                     }
+                    if *existing_source != *source {
+                        bail!("dependency on package ID {} already declared from {}", id, existing_source);
+                    }
                     return Ok(());
                 }
             }
@@ -355,6 +1072,74 @@ impl Manifest {
         self.dependencies.iter()
     }
 
+    /// Fills in fields that are unset or empty in this manifest from `base`, for sharing common values such as
+    /// authors and license between related packages. Values set in this manifest take precedence.
+    ///
+    /// The package ID, name, and version are never inherited. Dependencies declared in `base` are added unless this
+    /// manifest already declares a dependency on the same package or on the decomp.
+    ///
+    /// Errors if the merged manifest is invalid.
+    pub fn merge(&mut self, base: &Manifest) -> Result<()> {
+        let metadata = &mut self.metadata;
+        if metadata.authors().is_empty() {
+            metadata.authors = base.metadata.authors();
+        }
+        if metadata.description.is_empty() {
+            metadata.description = base.metadata.description.clone();
+        }
+        if metadata.license.is_empty() {
+            metadata.license = base.metadata.license.clone();
+        }
+        if metadata.keywords.is_empty() {
+            metadata.keywords = base.metadata.keywords.clone();
+        }
+        if metadata.extra_files.is_empty() {
+            metadata.extra_files = base.metadata.extra_files.clone();
+        }
+
+        for dependency in &base.dependencies {
+            let declared = self.dependencies.iter().any(|existing| match (existing, dependency) {
+                (Dependency::Package { id: a, .. }, Dependency::Package { id: b, .. }) => a == b,
+                (Dependency::Decomp { .. }, Dependency::Decomp { .. }) => true,
+                _ => false,
+            });
+            if !declared {
+                self.dependencies.push(dependency.clone());
+            }
+        }
+
+        let errors = self.validate();
+        if !errors.is_empty() {
+            bail!("merged manifest is invalid: {}", errors.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Returns the dependencies that are declared in the manifest, with the decomp first and packages sorted by ID.
+    pub fn dependencies_sorted(&self) -> Vec<&Dependency> {
+        let mut dependencies: Vec<&Dependency> = self.dependencies.iter().collect();
+        dependencies.sort_by_key(|dependency| dependency.sort_key());
+        dependencies
+    }
+
+    /// Returns the IDs of the packages that the manifest declares dependencies on, sorted.
+    pub fn package_dependency_ids(&self) -> Vec<Id> {
+        self.dependencies_sorted()
+            .into_iter()
+            .filter_map(|dependency| match dependency {
+                Dependency::Package { id, .. } => Some(*id),
+                Dependency::Decomp { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Removes the dependency on the package with the given ID, returning it if one was declared.
+    pub fn remove_direct_dependency(&mut self, id: Id) -> Option<Dependency> {
+        let index = self.dependencies.iter()
+            .position(|dependency| matches!(dependency, Dependency::Package { id: dep_id, .. } if *dep_id == id))?;
+        Some(self.dependencies.remove(index))
+    }
+
     /// Returns true if the manifest has a decomp-type dependency.
     pub fn has_direct_decomp_dependency(&self) -> bool {
         self.dependencies.iter().any(|dep| matches!(dep, Dependency::Decomp { .. }))
@@ -383,22 +1168,675 @@ impl Manifest {
     }
 }
 
-/// Get author from git config as `name <email>`
-fn get_author() -> Result<String> {
-    let git_user_name = std::process::Command::new("git")
-        .arg("config")
-        .arg("user.name")
-        .output()?
-        .stdout;
-    let git_user_name = String::from_utf8(git_user_name)?;
-    let git_user_name = git_user_name.trim().to_owned();
-    let git_user_email = std::process::Command::new("git")
+/// Returns true if the given string is a full SHA1 commit hash, as written by `git rev-parse`.
+fn is_full_commit_hash(rev: &str) -> bool {
+    rev.len() == 40 && rev.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Checks that a package description is not empty and at most 100 characters long.
+fn check_description(description: &str) -> std::result::Result<(), &'static str> {
+    if description.is_empty() {
+        Err("description cannot be empty")
+    } else if description.len() > 100 {
+        Err("description must be less than 100 characters")
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns true if the given string looks like `Name <email>`.
+fn is_valid_author(author: &str) -> bool {
+    let Some((name, rest)) = author.split_once('<') else {
+        return false;
+    };
+    let Some(email) = rest.strip_suffix('>') else {
+        return false;
+    };
+    !name.trim().is_empty() && email.contains('@') && !email.contains(['<', '>'])
+}
+
+/// Get author from git config as `name <email>`, falling back to the `USER` and `EMAIL` environment variables for
+/// whichever of the name and email git doesn't have.
+pub(crate) fn get_author() -> Result<String> {
+    author_from(
+        git_config("user.name").or_else(|| env_var("USER")),
+        git_config("user.email").or_else(|| env_var("EMAIL")),
+    )
+}
+
+/// Formats an author from its parts, or explains how to set whichever is missing.
+fn author_from(name: Option<String>, email: Option<String>) -> Result<String> {
+    let author = format!("{} <{}>", name.as_deref().unwrap_or_default(), email.as_deref().unwrap_or_default());
+    if !is_valid_author(&author) {
+        bail!(
+            "could not determine author from git config or the USER and EMAIL environment variables (got {:?}). \
+            Set your identity with `git config --global user.name \"Your Name\"` and \
+            `git config --global user.email you@example.com`, or pass `--author \"Name <email>\"`",
+            author,
+        );
+    }
+    Ok(author)
+}
+
+/// Reads a git config value, returning `None` if it is unset or empty.
+fn git_config(key: &str) -> Option<String> {
+    let output = std::process::Command::new("git")
         .arg("config")
-        .arg("user.email")
-        .output()?
-        .stdout;
-    let git_user_email = String::from_utf8(git_user_email)?;
-    let git_user_email = git_user_email.trim().to_owned();
+        .arg(key)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Reads an environment variable, returning `None` if it is unset or empty.
+fn env_var(key: &str) -> Option<String> {
+    let value = std::env::var(key).ok()?.trim().to_owned();
+    (!value.is_empty()).then_some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+    use anyhow::Result;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn arb_version() -> impl Strategy<Value = Version> {
+        (0..100u64, 0..100u64, 0..100u64, prop_oneof![Just(""), Just("alpha.1"), Just("rc.2")])
+            .prop_map(|(major, minor, patch, pre)| Version {
+                pre: semver::Prerelease::new(pre).unwrap(),
+                ..Version::new(major, minor, patch)
+            })
+    }
+
+    fn arb_id() -> impl Strategy<Value = Id> {
+        any::<u128>().prop_map(|n| Id::from(Uuid::from_u128(n)))
+    }
+
+    fn arb_dependency() -> impl Strategy<Value = Dependency> {
+        prop_oneof![
+            (arb_id(), arb_version(), prop_oneof![Just("^"), Just("~"), Just("=")])
+                .prop_map(|(id, version, op)| Dependency::Package {
+                    id,
+                    version: format!("{}{}", op, version).parse().unwrap(),
+                    source: Source::Index,
+                }),
+            "[0-9a-f]{40}".prop_map(|rev| Dependency::Decomp { rev }),
+        ]
+    }
+
+    fn arb_manifest() -> impl Strategy<Value = Manifest> {
+        (
+            arb_id(),
+            "[A-Za-z0-9 _-]{1,32}",
+            arb_version(),
+            prop::collection::vec("[A-Z][a-z]{0,8} <[a-z]{1,8}@example\\.com>", 0..4),
+            prop::option::of("[a-z]{1,8}"),
+            ("\\PC{0,40}", "[A-Za-z0-9.-]{0,16}"),
+            prop::collection::vec("[a-z]{1,10}", 0..5),
+            prop::collection::vec(arb_dependency(), 0..4),
+        ).prop_map(|(id, name, version, authors, authors_file, (description, license), keywords, dependencies)| {
+            Manifest {
+                format_version: FORMAT_VERSION,
+                metadata: Metadata {
+                    id,
+                    name: Name::new(name).unwrap(),
+                    version,
+                    authors,
+                    authors_file: authors_file.map(PathBuf::from),
+                    description,
+                    license,
+                    keywords,
+                    homepage: None,
+                    repository: None,
+                    extra_files: vec![],
+                    assets_dir: None,
+                    shared_assets: false,
+                    patch_format: PatchFormat::GitAm,
+                    include_paths: vec![],
+                    min_decomp_rev: None,
+                    decomp_repo: None,
+                    decomp_branch: None,
+                    region: None,
+                    baserom: None,
+                    file_authors: vec![],
+                    authors_file_errors: vec![],
+                },
+                dependencies,
+                tools: Tools::default(),
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn manifest_round_trips_through_toml(manifest in arb_manifest()) {
+            let toml_string = manifest.to_toml_string().unwrap();
+            let parsed = Manifest::from_toml_str(&toml_string).unwrap();
+            let mut expected = manifest;
+            expected.dependencies.sort_by_key(Dependency::sort_key);
+            prop_assert_eq!(parsed, expected);
+        }
+    }
+
+    #[test]
+    fn dependencies_sorted_ignores_declaration_order() -> Result<()> {
+        let dependencies = vec![
+            Dependency::Package { id: Id::from(Uuid::from_u128(2)), version: "^1.0.0".parse()?, source: Source::Index },
+            Dependency::Decomp { rev: "7a9df943ad079e7b19df0f8690bdc92e2beed964".to_owned() },
+            Dependency::Package { id: Id::from(Uuid::from_u128(1)), version: "~0.2.0".parse()?, source: Source::Index },
+        ];
+        let mut a = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let mut b = a.clone();
+        for dependency in dependencies.iter().cloned() {
+            a.declare_direct_dependency(dependency)?;
+        }
+        for dependency in dependencies.into_iter().rev() {
+            b.declare_direct_dependency(dependency)?;
+        }
+        assert_eq!(a.dependencies_sorted(), b.dependencies_sorted());
+        assert!(matches!(a.dependencies_sorted()[0], Dependency::Decomp { .. }));
+        assert_eq!(a.to_toml_string()?, b.to_toml_string()?);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_setters_reject_invalid_values() -> Result<()> {
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let metadata = manifest.metadata_mut();
+
+        assert!(metadata.set_description(String::new()).is_err());
+        assert!(metadata.set_description("a".repeat(101)).is_err());
+        metadata.set_description("Skips the intro".to_owned())?;
+        assert_eq!(metadata.description(), "Skips the intro");
+
+        assert!(metadata.add_author("Nobody".to_owned()).is_err());
+        metadata.add_author("John Doe <john@example.com>".to_owned())?;
+        metadata.add_author("John Doe <john@example.com>".to_owned())?;
+        assert_eq!(metadata.authors(), ["Jane Doe <jane@example.com>", "John Doe <john@example.com>"]);
+
+        let keywords = KeywordConfig::from_toml_str("[aliases]\n\"fix-*\" = \"bugfix\"")?;
+        assert!(metadata.add_keyword_with("not-a-keyword", &keywords).is_err());
+        metadata.add_keyword_with("qol", &keywords)?;
+        metadata.add_keyword_with("fix-crash", &keywords)?;
+        metadata.add_keyword_with("bugfix", &keywords)?;
+        assert_eq!(metadata.keywords(), ["qol", "bugfix"]);
+
+        assert!(metadata.validate_with_keywords(&keywords).is_empty(), "{:?}", metadata.validate_with_keywords(&keywords));
+        Ok(())
+    }
+
+    #[test]
+    fn keywords_are_deduplicated_and_limited() -> Result<()> {
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let keywords = KeywordConfig::from_toml_str("[aliases]\nquality-of-life = \"qol\"")?;
+        let to_strings = |keywords: &[&str]| keywords.iter().map(|keyword| keyword.to_string()).collect::<Vec<_>>();
+
+        // Duplicates are reported whether they differ in case or are aliases
+        manifest.metadata.keywords = to_strings(&["qol", "QoL", "quality-of-life", "cheat", "cheat"]);
+        assert_eq!(manifest.metadata().validate_with_keywords(&keywords), vec![
+            "duplicate keyword: QoL (same as qol)".to_owned(),
+            "duplicate keyword: quality-of-life (same as qol)".to_owned(),
+            "duplicate keyword: cheat".to_owned(),
+        ]);
+
+        manifest.metadata.keywords = to_strings(&["QoL", "Cheat"]);
+        assert!(manifest.metadata().validate_with_keywords(&keywords).is_empty());
+
+        manifest.metadata.keywords = to_strings(&["qol", "cheat", "bugfix", "cosmetic", "feature", "debug"]);
+        assert_eq!(manifest.metadata().validate_with_keywords(&keywords), vec![
+            format!("too many keywords: 6 (at most {} are allowed)", MAX_KEYWORDS),
+        ]);
+
+        // Adding keywords normalises their case and stops at the limit
+        let metadata = manifest.metadata_mut();
+        metadata.keywords.clear();
+        metadata.add_keyword_with("QoL", &keywords)?;
+        metadata.add_keyword_with("qol", &keywords)?;
+        assert_eq!(metadata.keywords(), ["qol"]);
+        for keyword in ["cheat", "bugfix", "cosmetic", "feature"] {
+            metadata.add_keyword_with(keyword, &keywords)?;
+        }
+        let error = metadata.add_keyword_with("debug", &keywords).unwrap_err();
+        assert!(error.to_string().contains("at most 5 keywords"), "{}", error);
+        assert_eq!(metadata.keywords().len(), MAX_KEYWORDS);
+        Ok(())
+    }
+
+    #[test]
+    fn package_dependencies_can_be_listed_and_removed() -> Result<()> {
+        let first = Id::from(Uuid::from_u128(1));
+        let second = Id::from(Uuid::from_u128(2));
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(Dependency::Package { id: second, version: "^1.0.0".parse()?, source: Source::Index })?;
+        manifest.upsert_decomp_dependency("7a9df943ad079e7b19df0f8690bdc92e2beed964".to_owned())?;
+        manifest.declare_direct_dependency(Dependency::Package { id: first, version: "^1.0.0".parse()?, source: Source::Index })?;
+        assert_eq!(manifest.package_dependency_ids(), [first, second]);
+
+        let removed = manifest.remove_direct_dependency(second);
+        assert!(matches!(removed, Some(Dependency::Package { id, .. }) if id == second));
+        assert!(manifest.remove_direct_dependency(second).is_none());
+        assert_eq!(manifest.package_dependency_ids(), [first]);
+        assert!(manifest.has_direct_decomp_dependency());
+        Ok(())
+    }
+
+    #[test]
+    fn with_author_rejects_malformed_author() -> Result<()> {
+        let name = Name::new("Test".to_owned())?;
+        assert!(Manifest::with_author(name.clone(), "Jane Doe <jane@example.com>".to_owned()).is_ok());
+        assert!(Manifest::with_author(name.clone(), " <>".to_owned()).is_err());
+        assert!(Manifest::with_author(name, "Jane Doe".to_owned()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_checks_each_author() -> Result<()> {
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let keywords = KeywordConfig::builtin();
+        assert_eq!(manifest.metadata().validate_with_keywords(&keywords), Vec::<String>::new());
+
+        manifest.metadata.authors.push("John Doe".to_owned());
+        manifest.metadata.authors.push("John Doe <>".to_owned());
+        let errors = manifest.metadata().validate_with_keywords(&keywords);
+        assert_eq!(errors, vec![
+            "invalid author \"John Doe\", expected `Name <email>`".to_owned(),
+            "invalid author \"John Doe <>\", expected `Name <email>`".to_owned(),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_checks_urls() -> Result<()> {
+        let keywords = KeywordConfig::builtin();
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        assert_eq!(manifest.metadata().homepage(), None);
+        assert_eq!(manifest.metadata().repository(), None);
+        assert!(!manifest.to_toml_string()?.contains("homepage"));
+
+        manifest.metadata_mut().set_homepage(Some("https://example.com/my-mod".to_owned()));
+        manifest.metadata_mut().set_repository(Some("http://git.example.com/me/my-mod.git".to_owned()));
+        assert_eq!(manifest.metadata().validate_with_keywords(&keywords), Vec::<String>::new());
+        let parsed = Manifest::from_toml_str(&manifest.to_toml_string()?)?;
+        assert_eq!(parsed.metadata().homepage(), Some("https://example.com/my-mod"));
+
+        manifest.metadata_mut().set_homepage(Some("ftp://example.com/my-mod".to_owned()));
+        manifest.metadata_mut().set_repository(Some("not a url".to_owned()));
+        let errors = manifest.metadata().validate_with_keywords(&keywords);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(errors[0], "homepage must be an http or https URL: \"ftp://example.com/my-mod\" has scheme \"ftp\"");
+        assert!(errors[1].starts_with("repository must be an http or https URL: \"not a url\" is invalid"), "{}", errors[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_checks_dependencies() -> Result<()> {
+        let keywords = KeywordConfig::builtin();
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(Dependency::Decomp { rev: "7a9df943ad079e7b19df0f8690bdc92e2beed964".to_owned() })?;
+        assert_eq!(manifest.validate_with_keywords(&keywords), Vec::<String>::new());
+
+        manifest.upsert_decomp_dependency("main".to_owned())?;
+        let self_id = manifest.metadata().id();
+        manifest.declare_direct_dependency(Dependency::Package { id: self_id, version: "^0.1.0".parse()?, source: Source::Index })?;
+        assert_eq!(manifest.validate_with_keywords(&keywords), vec![
+            "decomp dependency rev \"main\" must be a full 40-character commit hash".to_owned(),
+            "package cannot depend on itself".to_owned(),
+        ]);
+
+        // Malformed version requirements never make it into a manifest
+        let toml = manifest.to_toml_string()?.replace("^0.1.0", "not a requirement");
+        assert!(Manifest::from_toml_str(&toml).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn author_requires_name_and_email() {
+        let some = |value: &str| Some(value.to_owned());
+        assert_eq!(author_from(some("Jane Doe"), some("jane@example.com")).unwrap(), "Jane Doe <jane@example.com>");
+
+        // Unset git config with no environment fallback, which used to produce " <>"
+        let error = author_from(None, None).unwrap_err().to_string();
+        assert!(error.contains("git config --global user.name"), "{}", error);
+        assert!(author_from(some("jane"), None).is_err());
+        assert!(author_from(None, some("jane@example.com")).is_err());
+    }
+
+    #[test]
+    fn failed_write_leaves_original_file_intact() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("merlon.toml");
+        let manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.write_to_file(&path)?;
+        let original = fs::read_to_string(&path)?;
+
+        let result = write_file_atomically(&path, |writer| {
+            writer.write_all(b"[package")?;
+            bail!("disk full");
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path)?, original);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1, "temporary file should be cleaned up");
+        Ok(())
+    }
+
+    #[test]
+    fn aliased_and_configured_keywords_are_valid() -> Result<()> {
+        let config = KeywordConfig::from_toml_str(r#"
+            keywords = ["battle"]
+
+            [aliases]
+            quality-of-life = "qol"
+        "#)?;
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.metadata.keywords = vec!["quality-of-life".to_owned(), "battle".to_owned()];
+        assert_eq!(manifest.metadata().validate_with_keywords(&config), Vec::<String>::new());
+        assert_eq!(manifest.metadata().validate_with_keywords(&KeywordConfig::builtin()).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn version_must_be_semver() -> Result<()> {
+        let manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let toml = manifest.to_toml_string()?;
+        let with_version = |version: &str| toml.replace("version = \"0.1.0\"", &format!("version = \"{}\"", version));
+
+        let parsed = Manifest::from_toml_str(&with_version("0.1.0"))?;
+        assert_eq!(parsed.metadata().version(), &Version::new(0, 1, 0));
+
+        let error = Manifest::from_toml_str(&with_version("1.0")).unwrap_err().to_string();
+        assert!(error.contains("version must be valid semver"), "{}", error);
+        assert!(error.contains("\"1.0\""), "{}", error);
+
+        let error = Manifest::from_toml_str(&with_version("")).unwrap_err().to_string();
+        assert!(error.contains("version must be valid semver"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn license_must_be_spdx_expression() -> Result<()> {
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let keywords = KeywordConfig::builtin();
+        for license in ["MIT", "MIT OR Apache-2.0", "CC-BY-SA-4.0"] {
+            manifest.metadata.license = license.to_owned();
+            assert_eq!(manifest.metadata().validate_with_keywords(&keywords), Vec::<String>::new(), "{}", license);
+        }
+
+        manifest.metadata.license = "MIT OR CC-BY-SA4.0".to_owned();
+        let errors = manifest.metadata().validate_with_keywords(&keywords);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("`CC-BY-SA4.0`"), "{}", errors[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn edits_preserve_comments() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("merlon.toml");
+        let manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let toml = manifest.to_toml_string()?
+            .replace("[package]", "# Comment above the package\n[package]")
+            .replace("version = \"0.1.0\"", "version = \"0.1.0\" # bump me");
+        fs::write(&path, &toml)?;
+
+        let mut manifest = Manifest::read_from_path(&path)?;
+        manifest.metadata_mut().set_version(Version::new(0, 2, 0));
+        manifest.declare_direct_dependency(Dependency::Decomp { rev: "a".repeat(40) })?;
+        manifest.write_to_file(&path)?;
+
+        let written = fs::read_to_string(&path)?;
+        assert!(written.contains("# Comment above the package\n[package]"), "{}", written);
+        assert!(written.contains("version = \"0.2.0\" # bump me"), "{}", written);
+        assert_eq!(Manifest::read_from_path(&path)?, manifest);
+        Ok(())
+    }
 
-    Ok(format!("{} <{}>", git_user_name, git_user_email))
+    #[test]
+    fn dependency_version_requirements() -> Result<()> {
+        let id = Id::from(Uuid::from_u128(1));
+        let dependency = |requirement: &str| -> Result<Dependency> {
+            Ok(Dependency::Package { id, version: requirement.parse()?, source: Source::Index })
+        };
+        let version = |version: &str| Version::parse(version).unwrap();
+
+        let caret = dependency("^1.2")?;
+        assert!(caret.matches(&version("1.2.0")));
+        assert!(caret.matches(&version("1.9.3")));
+        assert!(!caret.matches(&version("2.0.0")));
+        assert!(!caret.matches(&version("1.1.9")));
+
+        let range = dependency(">=0.3, <0.5")?;
+        assert!(range.matches(&version("0.3.0")));
+        assert!(range.matches(&version("0.4.7")));
+        assert!(!range.matches(&version("0.5.0")));
+
+        let exact = dependency("=1.0.0")?;
+        assert!(exact.matches(&version("1.0.0")));
+        assert!(!exact.matches(&version("1.0.1")));
+
+        assert!(!Dependency::Decomp { rev: "a".repeat(40) }.matches(&version("1.0.0")));
+
+        // Malformed requirements are rejected when the manifest is read
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(caret)?;
+        let toml = manifest.to_toml_string()?.replace("^1.2", ">=banana");
+        let error = Manifest::from_toml_str(&toml).unwrap_err().to_string();
+        assert!(error.contains("dependency version must be a valid requirement"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn dependency_on_package_checks_requirement() -> Result<()> {
+        let mut manifest = Manifest::with_author(Name::new("Base".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.metadata_mut().set_version(Version::new(1, 2, 3));
+        let metadata = manifest.metadata();
+
+        let dependency = Dependency::on_package(metadata, "^1.2")?;
+        assert_eq!(dependency, Dependency::Package { id: metadata.id(), version: "^1.2".parse()?, source: Source::Index });
+        assert!(Dependency::on_package(metadata, "not a requirement").is_err());
+        let error = Dependency::on_package(metadata, "^2").unwrap_err();
+        assert!(error.to_string().contains("does not match version 1.2.3"), "{}", error);
+
+        // Declaring the same package again with a different requirement is refused
+        let mut dependent = Manifest::with_author(Name::new("Dependent".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        dependent.declare_direct_dependency(dependency.clone())?;
+        dependent.declare_direct_dependency(dependency)?;
+        assert!(dependent.declare_direct_dependency(Dependency::on_package(metadata, "=1.2.3")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dependency_sources_deserialize() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Dependencies {
+            dependencies: Vec<Dependency>,
+        }
+        let id = Id::from(Uuid::from_u128(1));
+        let parse = |keys: &str| -> Result<Dependency> {
+            let toml_string = format!("[[dependencies]]\ntype = \"package\"\nid = \"{}\"\n{}", id, keys);
+            let mut parsed: Dependencies = toml::from_str(&toml_string)?;
+            Ok(parsed.dependencies.remove(0))
+        };
+
+        assert_eq!(
+            parse("version = \"^1.2\"")?,
+            Dependency::Package { id, version: "^1.2".parse()?, source: Source::Index },
+        );
+        assert_eq!(
+            parse("path = \"../logos\"")?,
+            Dependency::Package { id, version: VersionReq::STAR, source: Source::Path("../logos".into()) },
+        );
+        assert_eq!(
+            parse("version = \"^1.2\"\npath = \"../logos\"")?,
+            Dependency::Package { id, version: "^1.2".parse()?, source: Source::Path("../logos".into()) },
+        );
+        let rev = "7a9df943ad079e7b19df0f8690bdc92e2beed964";
+        assert_eq!(
+            parse(&format!("git = \"https://example.com/logos.git\"\nrev = \"{}\"", rev))?,
+            Dependency::Package {
+                id,
+                version: VersionReq::STAR,
+                source: Source::Git { url: "https://example.com/logos.git".to_owned(), rev: rev.to_owned() },
+            },
+        );
+
+        for (keys, expected_error) in [
+            ("git = \"https://example.com/logos.git\"", "must have a rev"),
+            ("rev = \"main\"", "requires a git repository"),
+            ("path = \"../logos\"\ngit = \"https://example.com/logos.git\"\nrev = \"main\"", "both a path and a git"),
+        ] {
+            let error = parse(keys).unwrap_err().to_string();
+            assert!(error.contains(expected_error), "{}: {}", keys, error);
+        }
+
+        // Sources survive a round trip through a manifest, and the default version is left out
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let dependency = parse("path = \"../logos\"")?;
+        manifest.declare_direct_dependency(dependency.clone())?;
+        let toml_string = manifest.to_toml_string()?;
+        assert!(toml_string.contains("path = \"../logos\""), "{}", toml_string);
+        assert!(!toml_string.contains("version = \"*\""), "{}", toml_string);
+        assert_eq!(Manifest::from_toml_str(&toml_string)?, manifest);
+        assert!(manifest.declare_direct_dependency(parse(&format!("git = \"https://example.com/logos.git\"\nrev = \"{}\"", rev))?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn v0_manifest_migrates_to_current_format() -> Result<()> {
+        let id = Id::from(Uuid::from_u128(1));
+        let v0 = format!("[package]\nid = \"{}\"\nname = \"Old\"\nversion = \"1.0.0\"\n", id);
+
+        let mut table: toml::Table = v0.parse()?;
+        assert_eq!(Manifest::migrate(&mut table)?, 0);
+        assert_eq!(table["format_version"].as_integer(), Some(FORMAT_VERSION.into()));
+        assert_eq!(Manifest::migrate(&mut table)?, FORMAT_VERSION);
+
+        let manifest = Manifest::from_toml_str(&v0)?;
+        let mut expected = Manifest::with_author(Name::new("Old".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        expected.metadata.id = id;
+        expected.metadata.version = Version::new(1, 0, 0);
+        expected.metadata.authors = vec![];
+        expected.metadata.description = String::new();
+        expected.metadata.license = String::new();
+        assert_eq!(manifest, expected);
+        assert_eq!(manifest.format_version(), FORMAT_VERSION);
+        assert!(manifest.to_toml_string()?.starts_with(&format!("format_version = {}\n", FORMAT_VERSION)));
+
+        let future = format!("format_version = {}\n{}", FORMAT_VERSION + 1, v0);
+        let error = Manifest::from_toml_str(&future).unwrap_err().to_string();
+        assert!(error.contains("upgrade Merlon"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn assets_dir_defaults_to_package_id() -> Result<()> {
+        let manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let metadata = manifest.metadata();
+        assert_eq!(metadata.assets_dir(), metadata.id().to_string());
+        assert!(!metadata.shared_assets());
+
+        let toml = manifest.to_toml_string()?.replace("[package]", "[package]\nassets_dir = \"shared\"\nshared_assets = true");
+        let manifest = Manifest::from_toml_str(&toml)?;
+        assert_eq!(manifest.metadata().assets_dir(), "shared");
+        assert!(manifest.metadata().shared_assets());
+        Ok(())
+    }
+
+    #[test]
+    fn region_defaults_to_us() -> Result<()> {
+        let manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        assert_eq!(manifest.metadata().region(), "us");
+        assert!(!manifest.to_toml_string()?.contains("region"));
+
+        let toml = manifest.to_toml_string()?.replace("[package]", "[package]\nregion = \"jp\"");
+        let manifest = Manifest::from_toml_str(&toml)?;
+        assert_eq!(manifest.metadata().region(), "jp");
+        assert!(!manifest.metadata().validate().iter().any(|error| error.contains("region")));
+
+        let mut metadata = manifest.metadata().clone();
+        metadata.set_region(Some("../us".to_owned()));
+        assert!(metadata.validate().iter().any(|error| error.contains("invalid region")));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_fills_empty_fields_from_base() -> Result<()> {
+        let mut base = Manifest::with_author(Name::new("Base".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        base.metadata.license = "MIT".to_owned();
+        base.metadata.keywords = vec!["qol".to_owned()];
+        base.declare_direct_dependency(Dependency::Decomp { rev: "a".repeat(40) })?;
+
+        let mut local = Manifest::with_author(Name::new("Local".to_owned())?, "John Doe <john@example.com>".to_owned())?;
+        local.metadata.license = String::new();
+        local.metadata.description = "Local description".to_owned();
+        local.declare_direct_dependency(Dependency::Decomp { rev: "b".repeat(40) })?;
+        let id = local.metadata.id();
+
+        local.merge(&base)?;
+        assert_eq!(local.metadata.id(), id);
+        assert_eq!(local.metadata.name().to_string(), "Local");
+        assert_eq!(local.metadata.authors(), vec!["John Doe <john@example.com>".to_owned()]);
+        assert_eq!(local.metadata.description(), "Local description");
+        assert_eq!(local.metadata.license(), "MIT");
+        assert_eq!(local.metadata.keywords, vec!["qol".to_owned()]);
+        assert_eq!(local.get_direct_decomp_dependency_rev(), Some("b".repeat(40).as_str()));
+        assert_eq!(local.dependencies.len(), 1);
+
+        // Nothing to inherit the license from, so the result is invalid
+        let mut orphan = Manifest::with_author(Name::new("Orphan".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        orphan.metadata.license = String::new();
+        base.metadata.license = String::new();
+        assert!(orphan.merge(&base).is_err());
+
+        // Dependencies are validated too, not only the metadata
+        base.metadata.license = "MIT".to_owned();
+        let mut twice = Manifest::with_author(Name::new("Twice".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        twice.dependencies.push(Dependency::Decomp { rev: "b".repeat(40) });
+        twice.dependencies.push(Dependency::Decomp { rev: "c".repeat(40) });
+        let error = twice.merge(&base).unwrap_err();
+        assert!(error.to_string().contains("only one decomp dependency"), "{}", error);
+
+        let other = Manifest::with_author(Name::new("Other".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        let mut duplicated = Manifest::with_author(Name::new("Duplicated".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        for _ in 0..2 {
+            duplicated.dependencies.push(Dependency::Package {
+                id: other.metadata().id(),
+                version: "^0.1.0".parse()?,
+                source: Source::Index,
+            });
+        }
+        let error = duplicated.merge(&base).unwrap_err();
+        assert!(error.to_string().contains("declared more than once"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn authors_file_is_merged() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("merlon.toml");
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.metadata.authors = vec!["Inline Author <inline@example.com>".to_owned()];
+        manifest.metadata.authors_file = Some(PathBuf::from("AUTHORS"));
+        manifest.write_to_file(&path)?;
+        fs::write(
+            dir.path().join("AUTHORS"),
+            "# Maintainers\nFile Author <file@example.com>\nnot an author\n\nInline Author <inline@example.com>\n",
+        )?;
+
+        let manifest = Manifest::read_from_path(&path)?;
+        assert_eq!(
+            manifest.metadata().authors(),
+            vec!["Inline Author <inline@example.com>", "File Author <file@example.com>"],
+        );
+        let errors = manifest.metadata().validate_with_keywords(&KeywordConfig::builtin());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("not an author"));
+        Ok(())
+    }
 }
@@ -0,0 +1,102 @@
+//! Writing manifests without losing the user's formatting.
+//!
+//! Manifests are (de)serialized with serde, which knows nothing about comments, blank lines or key order. When a
+//! manifest that already exists on disk is written, the freshly serialized TOML is merged into the existing document
+//! instead, so only the values that changed are touched.
+
+use anyhow::{Result, Context};
+use toml_edit::{Document, Item, Table, Value};
+
+/// Updates `existing` TOML to have the same values as `updated`, keeping the comments, whitespace and key order of
+/// `existing` wherever a value did not change. Keys only in `updated` are appended.
+pub fn merge_into(existing: &str, updated: &str) -> Result<String> {
+    let mut document: Document = existing.parse().context("failed to parse existing manifest")?;
+    let updated: Document = updated.parse().context("failed to parse updated manifest")?;
+    merge_table(document.as_table_mut(), updated.as_table());
+    Ok(document.to_string())
+}
+
+fn merge_table(old: &mut Table, new: &Table) {
+    let removed: Vec<String> = old.iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|key| !new.contains_key(key))
+        .collect();
+    for key in removed {
+        old.remove(&key);
+    }
+
+    for (key, new_item) in new.iter() {
+        match (old.get_mut(key), new_item) {
+            (Some(Item::Table(old_table)), Item::Table(new_table)) => merge_table(old_table, new_table),
+            (Some(Item::Value(old_value)), Item::Value(new_value)) => merge_value(old_value, new_value),
+            (Some(Item::ArrayOfTables(old_array)), Item::ArrayOfTables(new_array))
+                if old_array.len() == new_array.len() =>
+            {
+                for (old_table, new_table) in old_array.iter_mut().zip(new_array.iter()) {
+                    merge_table(old_table, new_table);
+                }
+            }
+            _ => {
+                // `insert` keeps the position of an existing key
+                old.insert(key, new_item.clone());
+            }
+        }
+    }
+}
+
+/// Replaces `old` with `new` if they differ, keeping the comments and whitespace around `old`.
+fn merge_value(old: &mut Value, new: &Value) {
+    if normalized(old) == normalized(new) {
+        return;
+    }
+    let decor = old.decor().clone();
+    *old = new.clone();
+    *old.decor_mut() = decor;
+}
+
+/// Renders a value without any of its formatting, so values can be compared.
+fn normalized(value: &Value) -> String {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    match &mut value {
+        Value::Array(array) => array.fmt(),
+        Value::InlineTable(table) => table.fmt(),
+        _ => {}
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unchanged_values_keep_their_formatting() -> Result<()> {
+        let existing = r#"# My package
+dependencies = []
+
+[package]
+name = "Test" # keep this name!
+version = "0.1.0"
+keywords = [ "qol" ]
+"#;
+        let updated = r#"dependencies = []
+
+[package]
+name = "Test"
+version = "0.2.0"
+keywords = ["qol"]
+license = "MIT"
+"#;
+        assert_eq!(merge_into(existing, updated)?, r#"# My package
+dependencies = []
+
+[package]
+name = "Test" # keep this name!
+version = "0.2.0"
+keywords = [ "qol" ]
+license = "MIT"
+"#);
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! The set of keywords that packages may use, and aliases for them.
+//!
+//! The built-in set can be extended with a `keywords.toml` file in the user's config directory
+//! (e.g. `~/.config/merlon/keywords.toml`):
+//!
+//! ```toml
+//! keywords = ["battle", "map-*"]
+//!
+//! [aliases]
+//! quality-of-life = "qol"
+//! "fix-*" = "bugfix"
+//! ```
+//!
+//! Keywords and alias names may contain `*`, which matches any sequence of characters. Case is ignored, so `QoL` is
+//! the same keyword as `qol`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Result, Context};
+use serde::Deserialize;
+
+/// Keywords that are always valid.
+pub const BUILTIN_KEYWORDS: &[&str] = &[
+    "qol",
+    "cheat",
+    "bugfix",
+    "cosmetic",
+    "feature",
+    "translation",
+    "randomizer",
+    "debug",
+];
+
+/// The most keywords a package may have, like crates.io's limit, so that listings stay readable.
+pub const MAX_KEYWORDS: usize = 5;
+
+/// Name of the keywords config file in the user's config directory.
+pub const KEYWORDS_FILE_NAME: &str = "keywords.toml";
+
+/// Valid keywords and aliases for them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct KeywordConfig {
+    /// Additional valid keywords.
+    #[serde(default)]
+    keywords: Vec<String>,
+
+    /// Map of alias to the keyword it stands for.
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+}
+
+impl KeywordConfig {
+    /// Returns the built-in keywords, with no aliases.
+    pub fn builtin() -> Self {
+        Self {
+            keywords: BUILTIN_KEYWORDS.iter().map(|keyword| keyword.to_string()).collect(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// Parses a keywords config and merges it with the built-in keywords.
+    pub fn from_toml_str(toml: &str) -> Result<Self> {
+        let config: Self = toml::from_str(toml).context("failed to parse keywords config")?;
+        let mut merged = Self::builtin();
+        merged.merge(config);
+        Ok(merged)
+    }
+
+    /// Loads the built-in keywords merged with the user's `keywords.toml`, if there is one.
+    /// A malformed config is reported as a warning and otherwise ignored.
+    pub fn load() -> Self {
+        let Some(path) = user_config_dir().map(|dir| dir.join(KEYWORDS_FILE_NAME)) else {
+            return Self::builtin();
+        };
+        if !path.is_file() {
+            return Self::builtin();
+        }
+        let result = fs::read_to_string(&path)
+            .context("failed to read keywords config")
+            .and_then(|toml| Self::from_toml_str(&toml));
+        match result {
+            Ok(config) => config,
+            Err(error) => {
+                log::warn!("ignoring {}: {:#}", path.display(), error);
+                Self::builtin()
+            },
+        }
+    }
+
+    /// Adds the keywords and aliases of `other` to this config.
+    pub fn merge(&mut self, other: Self) {
+        for keyword in other.keywords {
+            if !self.keywords.contains(&keyword) {
+                self.keywords.push(keyword);
+            }
+        }
+        self.aliases.extend(other.aliases);
+    }
+
+    /// Returns the valid keywords, which may contain `*` wildcards.
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Returns the valid keyword that `keyword` is or is an alias of, in lowercase, or `None` if it is not valid.
+    pub fn resolve(&self, keyword: &str) -> Option<String> {
+        let keyword = keyword.to_lowercase();
+        if self.is_valid_keyword(&keyword) {
+            return Some(keyword);
+        }
+        self.aliases.iter()
+            .find(|(alias, target)| glob_matches(&alias.to_lowercase(), &keyword) && self.is_valid_keyword(target))
+            .map(|(_, target)| target.to_lowercase())
+    }
+
+    fn is_valid_keyword(&self, keyword: &str) -> bool {
+        let keyword = keyword.to_lowercase();
+        self.keywords.iter().any(|pattern| glob_matches(&pattern.to_lowercase(), &keyword))
+    }
+}
+
+impl Default for KeywordConfig {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` in `pattern` matches any sequence of characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, so the whole text must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn user_config_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|base| base.join("merlon"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("qol", "qol"));
+        assert!(!glob_matches("qol", "qols"));
+        assert!(glob_matches("map-*", "map-kmr"));
+        assert!(glob_matches("*-fix", "crash-fix"));
+        assert!(glob_matches("a*b*c", "aXXbYYc"));
+        assert!(!glob_matches("a*b*c", "aXXcYYb"));
+    }
+
+    #[test]
+    fn builtin_keywords_include_requested_categories() {
+        let config = KeywordConfig::builtin();
+        for keyword in ["translation", "randomizer", "debug"] {
+            assert_eq!(config.resolve(keyword).as_deref(), Some(keyword));
+        }
+    }
+
+    #[test]
+    fn aliases_resolve_to_valid_keywords() -> Result<()> {
+        let config = KeywordConfig::from_toml_str(r#"
+            keywords = ["battle"]
+
+            [aliases]
+            quality-of-life = "qol"
+            "fix-*" = "bugfix"
+            broken = "not-a-keyword"
+        "#)?;
+        assert_eq!(config.resolve("qol").as_deref(), Some("qol"));
+        assert_eq!(config.resolve("battle").as_deref(), Some("battle"));
+        assert_eq!(config.resolve("quality-of-life").as_deref(), Some("qol"));
+        assert_eq!(config.resolve("fix-softlock").as_deref(), Some("bugfix"));
+        assert_eq!(config.resolve("broken"), None);
+        assert_eq!(config.resolve("unknown"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn keywords_resolve_case_insensitively() -> Result<()> {
+        let config = KeywordConfig::from_toml_str(r#"
+            keywords = ["Battle"]
+
+            [aliases]
+            Quality-Of-Life = "QoL"
+        "#)?;
+        assert_eq!(config.resolve("QoL").as_deref(), Some("qol"));
+        assert_eq!(config.resolve("BATTLE").as_deref(), Some("battle"));
+        assert_eq!(config.resolve("quality-of-life").as_deref(), Some("qol"));
+        Ok(())
+    }
+}
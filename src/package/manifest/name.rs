@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 use pyo3::prelude::*;
 
 /// A validated package name.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct Name(String);
 
@@ -0,0 +1,120 @@
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+use serde::{Deserialize, Serialize};
+use pyo3::{prelude::*, exceptions::PyValueError};
+
+use super::{Version, name::{self, Name}};
+
+/// A human-readable package identifier made of a package's name and version, formatted as `name@version`.
+///
+/// Unlike [`Id`](super::Id), this changes whenever the package is renamed or its version is bumped, so it
+/// identifies a specific release of a package.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PackageId {
+    /// The package name.
+    pub name: Name,
+
+    /// The package version.
+    pub version: Version,
+}
+
+/// Errors that can occur when parsing a [`PackageId`].
+#[derive(Error, Debug)]
+pub enum Error {
+    /// There is no `@` separating the name and version.
+    #[error("package ID must be formatted as `name@version`")]
+    MissingSeparator,
+
+    /// The name part is not a valid package name.
+    #[error("invalid name in package ID: {0}")]
+    Name(#[from] name::Error),
+
+    /// The version part is not a valid semantic version.
+    #[error("invalid version in package ID: {0}")]
+    Version(#[from] semver::Error),
+}
+
+impl PackageId {
+    /// Creates a package ID from a name and version.
+    pub fn new(name: Name, version: Version) -> Self {
+        Self { name, version }
+    }
+}
+
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
+impl FromStr for PackageId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Versions can't contain '@', but names can, so split on the last one
+        let (name, version) = s.rsplit_once('@').ok_or(Error::MissingSeparator)?;
+        Ok(Self {
+            name: name.parse()?,
+            version: version.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for PackageId {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PackageId> for String {
+    fn from(id: PackageId) -> Self {
+        id.to_string()
+    }
+}
+
+impl FromPyObject<'_> for PackageId {
+    fn extract(ob: &PyAny) -> PyResult<Self> {
+        let string: String = ob.extract()?;
+        string.parse().map_err(|e: Error| PyValueError::new_err(e.to_string()))
+    }
+}
+
+impl ToPyObject for PackageId {
+    fn to_object(&self, py: Python) -> PyObject {
+        self.to_string().to_object(py)
+    }
+}
+
+impl IntoPy<PyObject> for PackageId {
+    fn into_py(self, py: Python) -> PyObject {
+        self.to_object(py)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_valid() {
+        let id: PackageId = "Skip intro@1.2.3".parse().unwrap();
+        assert_eq!(id.name.to_string(), "Skip intro");
+        assert_eq!(id.version, Version::new(1, 2, 3));
+        assert_eq!(id.to_string(), "Skip intro@1.2.3");
+
+        let id: PackageId = "me@example@0.1.0-rc.1".parse().unwrap();
+        assert_eq!(id.name.to_string(), "me@example");
+        assert_eq!(id.version.to_string(), "0.1.0-rc.1");
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(matches!("Skip intro".parse::<PackageId>(), Err(Error::MissingSeparator)));
+        assert!(matches!("@1.0.0".parse::<PackageId>(), Err(Error::Name(_))));
+        assert!(matches!("Skip intro@1.0".parse::<PackageId>(), Err(Error::Version(_))));
+        assert!(matches!("Skip intro@".parse::<PackageId>(), Err(Error::Version(_))));
+    }
+}
@@ -0,0 +1,159 @@
+//! Utilities for inspecting patch files produced by `git format-patch`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use anyhow::Result;
+
+/// Path patterns that are produced by building decomp and should never be distributed.
+/// Patterns ending in `/` match a directory anywhere in the path, patterns starting with `*` match a suffix,
+/// and anything else matches a file name.
+const GENERATED_PATH_PATTERNS: &[&str] = &[
+    "build/",
+    "build.ninja",
+    ".ninja_log",
+    ".ninja_deps",
+    "*.o",
+    "*.elf",
+    "*.z64",
+    "*.v64",
+    "*.n64",
+];
+
+/// Returns the paths touched by a patch, as they are named after the patch applies (the `b/` side of each
+/// `diff --git` header). Paths are relative to the root of the decomp repository.
+pub fn touched_paths(patch: &str) -> Vec<String> {
+    patch.lines()
+        .filter_map(|line| line.strip_prefix("diff --git "))
+        .filter_map(|paths| paths.split_once(" b/"))
+        .map(|(_, path)| path.to_owned())
+        .collect()
+}
+
+/// Returns the subject of a patch written by `git format-patch`, without its `[PATCH]` prefix, or `None` if it has no
+/// `Subject:` header, such as a unified diff. Subjects that were folded over several lines, or encoded because they are
+/// not ASCII, are decoded.
+pub fn subject(patch: &str) -> Option<String> {
+    let mut headers = patch.lines().take_while(|line| !line.is_empty());
+    let mut subject = headers.by_ref().find_map(|line| line.strip_prefix("Subject: "))?.to_owned();
+    for line in headers {
+        if !line.starts_with([' ', '\t']) {
+            break;
+        }
+        subject.push_str(line);
+    }
+    let subject = decode_encoded_words(&subject);
+    let subject = match subject.strip_prefix("[PATCH") {
+        Some(rest) => rest.split_once("] ").map_or(subject.as_str(), |(_, subject)| subject),
+        None => &subject,
+    };
+    Some(subject.to_owned())
+}
+
+/// Decodes the RFC 2047 `=?UTF-8?q?...?=` words that `git format-patch` writes non-ASCII subjects as. Whitespace
+/// between two encoded words is dropped.
+fn decode_encoded_words(text: &str) -> String {
+    const PREFIX: &str = "=?UTF-8?q?";
+    let mut decoded = Vec::new();
+    let mut rest = text;
+    let mut after_word = false;
+    while let Some(start) = rest.find(PREFIX) {
+        let (before, word) = rest.split_at(start);
+        let word = &word[PREFIX.len()..];
+        let Some(end) = word.find("?=") else {
+            break;
+        };
+        if !(after_word && before.trim().is_empty()) {
+            decoded.extend_from_slice(before.as_bytes());
+        }
+        let mut bytes = word[..end].bytes();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'_' => decoded.push(b' '),
+                b'=' => {
+                    let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                    match std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                        Some(byte) => decoded.push(byte),
+                        None => {
+                            decoded.push(b'=');
+                            decoded.extend_from_slice(&hex);
+                        }
+                    }
+                }
+                byte => decoded.push(byte),
+            }
+        }
+        rest = &word[end + 2..];
+        after_word = true;
+    }
+    decoded.extend_from_slice(rest.as_bytes());
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads every `.patch` file in a directory and returns the set of paths they touch.
+pub fn touched_paths_in_dir(dir: &Path) -> Result<BTreeSet<String>> {
+    let mut paths = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "patch").unwrap_or(false) {
+            paths.extend(touched_paths(&fs::read_to_string(&path)?));
+        }
+    }
+    Ok(paths)
+}
+
+/// Returns true if the path looks like a build artifact, such as an object file or anything under `build/`.
+pub fn is_generated_path(path: &str) -> bool {
+    GENERATED_PATH_PATTERNS.iter().any(|pattern| {
+        if let Some(dir) = pattern.strip_suffix('/') {
+            path.split('/').rev().skip(1).any(|component| component == dir)
+        } else if let Some(suffix) = pattern.strip_prefix('*') {
+            path.ends_with(suffix)
+        } else {
+            path.rsplit('/').next() == Some(*pattern)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subject_strips_prefix_and_decodes() {
+        let patch = "From 0123456789abcdef Mon Sep 17 00:00:00 2001\n\
+            From: Jane Doe <jane@example.com>\n\
+            Subject: [PATCH 2/3] Make the intro\n \
+            skippable\n\
+            \n\
+            Subject: not a header\n";
+        assert_eq!(subject(patch).as_deref(), Some("Make the intro skippable"));
+
+        let encoded = "Subject: [PATCH] =?UTF-8?q?Traduire_l'=C3=A9cran?=\n =?UTF-8?q?_titre?=\n\n";
+        assert_eq!(subject(encoded).as_deref(), Some("Traduire l'écran titre"));
+
+        assert_eq!(subject("Subject: No prefix\n").as_deref(), Some("No prefix"));
+        assert_eq!(subject("diff --git a/src/main.c b/src/main.c\n"), None);
+    }
+
+    #[test]
+    fn touched_paths_reads_diff_headers() {
+        let patch = "Subject: [PATCH] test\n\
+            ---\n\
+            diff --git a/src/main.c b/src/main.c\n\
+            index 0000000..e69de29\n\
+            diff --git a/src/old.c b/src/new.c\n\
+            similarity index 100%\n";
+        assert_eq!(touched_paths(patch), vec!["src/main.c", "src/new.c"]);
+    }
+
+    #[test]
+    fn generated_paths_are_detected() {
+        assert!(is_generated_path("ver/us/build/papermario.z64"));
+        assert!(is_generated_path("build/src/main.o"));
+        assert!(is_generated_path("build.ninja"));
+        assert!(!is_generated_path("src/build.c"));
+        assert!(!is_generated_path("assets/build"));
+        assert!(!is_generated_path("src/main.c"));
+    }
+}
@@ -0,0 +1,80 @@
+//! Reporting the progress of exporting a package, so that a user interface can show it.
+//!
+//! Exporting a large package can take minutes. Functions that do so accept a [`Progress`], which is called with an
+//! [`Update`] as each [`Phase`] advances. The command line shows these as progress bars.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// A phase of exporting a package, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Finding the commits in the decomp repository to make patches from.
+    EnumeratingCommits,
+
+    /// Writing a patch for each commit into `patches/`, or staging the patches to be put in the distributable.
+    WritingPatches,
+
+    /// Compressing the staged package. Progress is measured in bytes.
+    Compressing,
+
+    /// Encrypting the compressed package.
+    Encrypting,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EnumeratingCommits => write!(f, "Enumerating commits"),
+            Self::WritingPatches => write!(f, "Writing patches"),
+            Self::Compressing => write!(f, "Compressing"),
+            Self::Encrypting => write!(f, "Encrypting"),
+        }
+    }
+}
+
+/// How far through a [`Phase`] an export is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Update {
+    /// The phase that advanced.
+    pub phase: Phase,
+
+    /// Units of work done so far in the phase, such as commits, patches or bytes.
+    pub done: u64,
+
+    /// Total units of work in the phase, if known in advance.
+    pub total: Option<u64>,
+}
+
+/// A callback to report [`Update`]s to. Clones share the same callback. The default does nothing.
+///
+/// The callback may be called from several threads at once, so updates for a phase can arrive out of order.
+#[derive(Clone, Default)]
+pub struct Progress {
+    callback: Option<Arc<dyn Fn(Update) + Send + Sync>>,
+}
+
+impl Progress {
+    /// Reports updates to `callback`.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Update) + Send + Sync + 'static,
+    {
+        Self { callback: Some(Arc::new(callback)) }
+    }
+
+    /// Reports that `done` of `total` units of work in `phase` are done.
+    pub fn report(&self, phase: Phase, done: u64, total: Option<u64>) {
+        if let Some(callback) = &self.callback {
+            callback(Update { phase, done, total });
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("callback", &self.callback.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
@@ -160,7 +160,7 @@ impl Registry {
     pub fn check_version_compatibility(&self) -> Result<()> {
         let map = self.package_version_map()?;
         for dependency in self.all_dependencies()? {
-            if let Dependency::Package { id, version } = dependency {
+            if let Dependency::Package { id, version, .. } = dependency {
                 match map.get(&id) {
                     None => bail!("dependency exists for {id} {version}, but it is not in registry"),
                     Some(actual_version) => {
@@ -206,7 +206,7 @@ impl Registry {
         // https://en.wikipedia.org/wiki/Topological_sorting#Depth-first_search
         let mut topological_ordering = Vec::new();
         let mut not_visited: BinaryHeap<Id> = self.packages.keys().map(|&k| k).collect();
-        let mut visit_in_progress = HashSet::new(); // "temporary mark"
+        let mut visit_in_progress = Vec::new(); // "temporary mark", in the order visited
         let mut visited = HashSet::new(); // "permanent mark"
         while let Some(id) = not_visited.pop() {
             self.topological_ordering_visit(id, &mut topological_ordering, &mut visit_in_progress, &mut visited)?;
@@ -267,14 +267,18 @@ impl Registry {
         &self,
         id: Id,
         topological_ordering: &mut Vec<Id>, 
-        visit_in_progress: &mut HashSet<Id>,
+        visit_in_progress: &mut Vec<Id>,
         visited: &mut HashSet<Id>,
     ) -> Result<()> {
         if !visited.contains(&id) {
-            if visit_in_progress.contains(&id) {
-                bail!("found circular dependency {}", id);
+            if let Some(start) = visit_in_progress.iter().position(|&in_progress| in_progress == id) {
+                let mut names = Vec::new();
+                for &member in visit_in_progress[start..].iter().chain([&id]) {
+                    names.push(self.get_or_error(member)?.manifest()?.metadata().name().to_string());
+                }
+                bail!("found circular dependency: {}", names.join(" -> "));
             }
-            visit_in_progress.insert(id);
+            visit_in_progress.push(id);
             let package = self.get_or_error(id)?;
             let manifest = package.manifest()?;
             for dependency in manifest.iter_direct_dependencies() {
@@ -282,7 +286,7 @@ impl Registry {
                     self.topological_ordering_visit(*id, topological_ordering, visit_in_progress, visited)?;
                 }
             }
-            visit_in_progress.remove(&id);
+            visit_in_progress.pop();
             visited.insert(id);
             topological_ordering.push(id);
         }
@@ -377,7 +381,13 @@ mod test {
 
         // Add a circular dependency
         registry.add_direct_dependency(c, a)?;
-        assert!(registry.topological_ordering().is_err());
+        let error = registry.topological_ordering().unwrap_err().to_string();
+        let cycle = error.strip_prefix("found circular dependency: ").expect(&error);
+        let mut members: Vec<&str> = cycle.split(" -> ").collect();
+        assert_eq!(members.first(), members.last(), "{}", error);
+        members.pop();
+        members.sort();
+        assert_eq!(members, ["A", "B", "C"]);
 
         Ok(())
     }
@@ -0,0 +1,600 @@
+//! Offline dependency resolution against a local directory of distributables.
+//!
+//! Each distributable's unencrypted [header](super::distribute::Header) records the ID, name and version of the
+//! package inside it, so a directory of `.merlon` files can be indexed without the base ROM. Resolving dependencies
+//! of dependencies, as [`LocalIndex::lock`] does, needs the base ROM to read their manifests.
+//!
+//! Dependencies with a `path` source are read directly from the package directory they point to instead, see
+//! [`LocalIndex::with_path_dependencies`]. Dependencies with a `git` source are matched against the index by version,
+//! like those without a source.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+
+use super::{Id, Manifest, Package, PackageId, Distributable, Lockfile};
+use super::distribute::is_distributable_package;
+use super::lockfile::LockedPackage;
+use super::manifest::Dependency;
+
+/// A distributable found in a packages directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Path to the distributable, or to the package directory of a path dependency.
+    pub path: PathBuf,
+
+    /// ID of the package in the distributable.
+    pub id: Id,
+
+    /// Name and version of the package in the distributable.
+    pub package: PackageId,
+}
+
+/// An index of the distributables in a directory, built from their headers.
+#[derive(Debug, Clone, Default)]
+pub struct LocalIndex {
+    entries: Vec<IndexEntry>,
+}
+
+/// A package dependency matched to a distributable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// The dependency as declared in the manifest.
+    pub dependency: Dependency,
+
+    /// The distributable that satisfies it.
+    pub entry: IndexEntry,
+}
+
+/// The result of [`LocalIndex::resolve`].
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    /// Dependencies that a distributable in the index satisfies.
+    pub resolved: Vec<Resolved>,
+
+    /// Dependencies that no distributable in the index satisfies.
+    pub unmet: Vec<Dependency>,
+}
+
+/// What [`LocalIndex::lock_with`] needs to know about a distributable beyond its header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistributableInfo {
+    /// The dependencies declared by the package inside the distributable.
+    pub dependencies: Vec<Dependency>,
+
+    /// SHA1 of the package's patches, as recorded in [`LockedPackage::checksum`].
+    pub checksum: String,
+}
+
+impl LocalIndex {
+    /// Reads the header of every distributable in the directory.
+    ///
+    /// Distributables without package information in their header, such as those created before it was recorded,
+    /// are skipped with a warning.
+    pub fn scan(dir: &Path) -> Result<Self> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("failed to read packages directory {}", dir.display()))? {
+            let path = entry?.path();
+            if !is_distributable_package(&path) {
+                continue;
+            }
+            let header = Distributable::try_from(path.clone())?.header()?;
+            match header.and_then(|header| Some((header.package_id?, header.package?))) {
+                Some((id, package)) => entries.push(IndexEntry { path, id, package }),
+                None => log::warn!("skipping {}: its header does not say which package it contains", path.display()),
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { entries })
+    }
+
+    /// Returns a copy of the index in which the packages that `package` depends on by path, directly or through other
+    /// path dependencies, take the place of any distributables of the same packages.
+    pub fn with_path_dependencies(&self, package: &Package) -> Result<Self> {
+        let mut path_entries: Vec<IndexEntry> = Vec::new();
+        let mut pending = vec![package.clone()];
+        while let Some(dependent) = pending.pop() {
+            for dependency in dependent.manifest()?.iter_direct_dependencies() {
+                let (Dependency::Package { id, .. }, Some(dir)) = (dependency, dependency.path_in(dependent.path())) else {
+                    continue;
+                };
+                let on_disk = Package::try_from(dir.clone())
+                    .with_context(|| format!("failed to read path dependency of {}", dependent.path().display()))?;
+                let metadata = on_disk.manifest()?.metadata().clone();
+                if metadata.id() != *id {
+                    bail!("path dependency {} contains package {}, not {}", dir.display(), metadata.id(), id);
+                }
+                if path_entries.iter().any(|entry| entry.id == *id) {
+                    continue;
+                }
+                path_entries.push(IndexEntry { path: dir, id: *id, package: metadata.versioned_id() });
+                pending.push(on_disk);
+            }
+        }
+
+        let mut entries: Vec<IndexEntry> = self.entries.iter()
+            .filter(|entry| !path_entries.iter().any(|path_entry| path_entry.id == entry.id))
+            .cloned()
+            .collect();
+        entries.extend(path_entries);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { entries })
+    }
+
+    /// Returns the distributables in the index, sorted by path.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Returns the distributable with the highest version of package `id` that satisfies the dependency.
+    pub fn find(&self, dependency: &Dependency) -> Option<&IndexEntry> {
+        let Dependency::Package { id, .. } = dependency else {
+            return None;
+        };
+        self.entries.iter()
+            .filter(|entry| entry.id == *id && dependency.matches(&entry.package.version))
+            .max_by(|a, b| a.package.version.cmp(&b.package.version))
+    }
+
+    /// Matches the manifest's direct package dependencies against the index.
+    /// The decomp dependency is not a package, so it is not resolved here.
+    pub fn resolve(&self, manifest: &Manifest) -> Resolution {
+        let mut resolution = Resolution::default();
+        for dependency in manifest.dependencies_sorted() {
+            if !matches!(dependency, Dependency::Package { .. }) {
+                continue;
+            }
+            match self.find(dependency) {
+                Some(entry) => resolution.resolved.push(Resolved { dependency: dependency.clone(), entry: entry.clone() }),
+                None => resolution.unmet.push(dependency.clone()),
+            }
+        }
+        resolution
+    }
+}
+
+impl LocalIndex {
+    /// Chooses one version of every package that `package` depends on, directly or through other packages, so that
+    /// every version requirement is satisfied, and returns the choice as a lockfile. It is not written to disk.
+    ///
+    /// Each distributable considered is opened with `baserom` to read its dependencies. Path dependencies are read
+    /// from disk, see [`LocalIndex::with_path_dependencies`].
+    pub fn lock(&self, package: &Package, baserom: &Path) -> Result<Lockfile> {
+        self.with_path_dependencies(package)?.lock_with(&package.manifest()?, |entry| {
+            if entry.path.is_dir() {
+                let package = Package::try_from(entry.path.clone())?;
+                return Ok(DistributableInfo {
+                    dependencies: package.manifest()?.dependencies_sorted().into_iter().cloned().collect(),
+                    checksum: package.patches_checksum()?,
+                });
+            }
+            Distributable::try_from(entry.path.clone())?
+                .open_scoped(baserom.to_owned(), |package| {
+                    Ok(DistributableInfo {
+                        dependencies: package.manifest()?.dependencies_sorted().into_iter().cloned().collect(),
+                        checksum: package.patches_checksum()?,
+                    })
+                })
+                .with_context(|| format!("failed to read dependencies of {}", entry.path.display()))
+        })
+    }
+
+    /// Like [`LocalIndex::lock`], but reads what is inside each distributable with `inspect`, which is called at most
+    /// once per distributable.
+    ///
+    /// Newer versions are tried first. If a choice leads to requirements that cannot all be met, the next newest
+    /// version is tried instead. If no combination works, the error names a package and the conflicting requirements
+    /// on it.
+    pub fn lock_with<F>(&self, manifest: &Manifest, inspect: F) -> Result<Lockfile>
+    where
+        F: FnMut(&IndexEntry) -> Result<DistributableInfo>,
+    {
+        let root = manifest.metadata().versioned_id();
+        let mut solver = Solver {
+            index: self,
+            root_id: manifest.metadata().id(),
+            inspect,
+            inspected: HashMap::new(),
+            conflict: None,
+        };
+        let requirements = solver.requirements(manifest.dependencies_sorted().into_iter().cloned(), &root);
+        let Some(chosen) = solver.search(&requirements, &BTreeMap::new())? else {
+            let Some((id, requirements)) = solver.conflict else {
+                bail!("dependencies of {} could not be resolved", root);
+            };
+            let name = self.entries.iter()
+                .find(|entry| entry.id == id)
+                .map_or_else(|| id.to_string(), |entry| entry.package.name.to_string());
+            let requirements: Vec<String> = requirements.iter()
+                .map(|requirement| format!("{} (required by {})", requirement.version_req(), requirement.required_by))
+                .collect();
+            bail!("no version of {} satisfies every requirement on it: {}", name, requirements.join(", "));
+        };
+
+        if let Some(cycle) = solver.find_cycle(&manifest.dependencies_sorted().into_iter().cloned().collect::<Vec<_>>(), &chosen) {
+            let names: Vec<String> = cycle.iter()
+                .map(|id| match chosen.get(id) {
+                    Some(entry) => entry.package.name.to_string(),
+                    None => root.name.to_string(),
+                })
+                .collect();
+            bail!("dependency cycle: {}", names.join(" -> "));
+        }
+
+        let mut packages = Vec::new();
+        for (id, entry) in chosen {
+            packages.push(LockedPackage {
+                id,
+                name: entry.package.name.clone(),
+                version: entry.package.version.clone(),
+                checksum: solver.inspected[&entry.path].checksum.clone(),
+            });
+        }
+        Ok(Lockfile {
+            decomp_rev: manifest.get_direct_decomp_dependency_rev().map(str::to_owned),
+            packages,
+        })
+    }
+}
+
+/// A version requirement on a package, and the package that made it, for explaining conflicts.
+#[derive(Debug, Clone)]
+struct Requirement {
+    id: Id,
+    dependency: Dependency,
+    required_by: PackageId,
+}
+
+impl Requirement {
+    fn version_req(&self) -> String {
+        match &self.dependency {
+            Dependency::Package { version, .. } => version.to_string(),
+            Dependency::Decomp { rev } => rev.clone(),
+        }
+    }
+}
+
+/// Backtracking search for [`LocalIndex::lock_with`].
+struct Solver<'a, F> {
+    index: &'a LocalIndex,
+    root_id: Id,
+    inspect: F,
+    inspected: HashMap<PathBuf, DistributableInfo>,
+    /// The most recent package that no version could be chosen for, and the requirements on it at the time.
+    conflict: Option<(Id, Vec<Requirement>)>,
+}
+
+impl<'a, F> Solver<'a, F>
+where
+    F: FnMut(&IndexEntry) -> Result<DistributableInfo>,
+{
+    /// Keeps the package dependencies, leaving out the decomp and any dependency on the root package itself, which
+    /// [`Solver::find_cycle`] reports.
+    fn requirements(&self, dependencies: impl IntoIterator<Item = Dependency>, required_by: &PackageId) -> Vec<Requirement> {
+        dependencies.into_iter()
+            .filter_map(|dependency| match dependency {
+                Dependency::Package { id, .. } if id != self.root_id => Some(Requirement {
+                    id,
+                    dependency,
+                    required_by: required_by.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn dependencies_of(&mut self, entry: &IndexEntry) -> Result<Vec<Requirement>> {
+        if !self.inspected.contains_key(&entry.path) {
+            let info = (self.inspect)(entry)?;
+            self.inspected.insert(entry.path.clone(), info);
+        }
+        let dependencies = self.inspected[&entry.path].dependencies.clone();
+        Ok(self.requirements(dependencies, &entry.package))
+    }
+
+    /// Looks for a package that depends on itself through the chosen packages, starting from the root package's
+    /// `dependencies`. Returns the cycle's members in order, ending with the first one again.
+    fn find_cycle(&self, dependencies: &[Dependency], chosen: &BTreeMap<Id, &'a IndexEntry>) -> Option<Vec<Id>> {
+        // Depth-first search, keeping the current path. Packages are only fully explored once, so this terminates
+        let mut path = vec![self.root_id];
+        let mut explored = HashSet::new();
+        self.find_cycle_from(dependencies, chosen, &mut path, &mut explored)
+    }
+
+    fn find_cycle_from(
+        &self,
+        dependencies: &[Dependency],
+        chosen: &BTreeMap<Id, &'a IndexEntry>,
+        path: &mut Vec<Id>,
+        explored: &mut HashSet<Id>,
+    ) -> Option<Vec<Id>> {
+        for dependency in dependencies {
+            let Dependency::Package { id, .. } = dependency else {
+                continue;
+            };
+            if let Some(start) = path.iter().position(|member| member == id) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(*id);
+                return Some(cycle);
+            }
+            let Some(entry) = chosen.get(id) else {
+                continue;
+            };
+            if !explored.insert(*id) {
+                continue;
+            }
+            path.push(*id);
+            let next = &self.inspected[&entry.path].dependencies;
+            if let Some(cycle) = self.find_cycle_from(next, chosen, path, explored) {
+                return Some(cycle);
+            }
+            path.pop();
+        }
+        None
+    }
+
+    /// Chooses a version for the first required package that has not been chosen yet, then recurses. Returns `None`
+    /// if no choice leads to a solution.
+    fn search(
+        &mut self,
+        requirements: &[Requirement],
+        chosen: &BTreeMap<Id, &'a IndexEntry>,
+    ) -> Result<Option<BTreeMap<Id, &'a IndexEntry>>> {
+        let Some(id) = requirements.iter().map(|requirement| requirement.id).find(|id| !chosen.contains_key(id)) else {
+            return Ok(Some(chosen.clone()));
+        };
+        let on_id: Vec<&Requirement> = requirements.iter().filter(|requirement| requirement.id == id).collect();
+        let index = self.index;
+        let mut candidates: Vec<&'a IndexEntry> = index.entries.iter()
+            .filter(|entry| entry.id == id)
+            .filter(|entry| on_id.iter().all(|requirement| requirement.dependency.matches(&entry.package.version)))
+            .collect();
+        if candidates.is_empty() {
+            self.conflict = Some((id, on_id.into_iter().cloned().collect()));
+            return Ok(None);
+        }
+        candidates.sort_by(|a, b| b.package.version.cmp(&a.package.version));
+
+        for candidate in candidates {
+            let mut next = requirements.to_vec();
+            next.extend(self.dependencies_of(candidate)?);
+            let mut chosen = chosen.clone();
+            chosen.insert(id, candidate);
+
+            // The candidate's own dependencies may rule out a version that was already chosen
+            let broken = next.iter().find(|requirement| {
+                chosen.get(&requirement.id).is_some_and(|entry| !requirement.dependency.matches(&entry.package.version))
+            });
+            if let Some(broken) = broken {
+                let broken_id = broken.id;
+                self.conflict = Some((broken_id, next.iter().filter(|requirement| requirement.id == broken_id).cloned().collect()));
+                continue;
+            }
+            if let Some(solution) = self.search(&next, &chosen)? {
+                return Ok(Some(solution));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Resolution {
+    /// Returns true if every dependency was resolved.
+    pub fn is_complete(&self) -> bool {
+        self.unmet.is_empty()
+    }
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.entry.package, self.entry.path.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::package::distribute::Header;
+    use crate::package::Name;
+    use crate::package::manifest::{Source, VersionReq};
+
+    fn write_fixture(dir: &Path, file_name: &str, id: Id, package: &str) -> Result<()> {
+        let header = Header {
+            package_id: Some(id),
+            package: Some(package.parse()?),
+            ..Header::new()
+        };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes)?;
+        bytes.extend_from_slice(b"Salted__payload");
+        fs::write(dir.join(file_name), bytes)?;
+        Ok(())
+    }
+
+    /// Writes a fixture for each `(name, id, version, dependencies)` and returns the index with the dependencies of
+    /// each distributable keyed by its path.
+    fn graph(dir: &Path, packages: &[(&str, Id, &str, Vec<Dependency>)]) -> Result<(LocalIndex, HashMap<PathBuf, Vec<Dependency>>)> {
+        let mut dependencies = HashMap::new();
+        for (name, id, version, deps) in packages {
+            let file_name = format!("{}-{}.merlon", name, version);
+            write_fixture(dir, &file_name, *id, &format!("{}@{}", name, version))?;
+            dependencies.insert(dir.join(file_name), deps.clone());
+        }
+        Ok((LocalIndex::scan(dir)?, dependencies))
+    }
+
+    fn requires(id: Id, version: &str) -> Result<Dependency> {
+        Ok(Dependency::Package { id, version: version.parse()?, source: Source::Index })
+    }
+
+    fn lock(index: &LocalIndex, manifest: &Manifest, dependencies: &HashMap<PathBuf, Vec<Dependency>>) -> Result<Lockfile> {
+        index.lock_with(manifest, |entry| Ok(DistributableInfo {
+            dependencies: dependencies[&entry.path].clone(),
+            checksum: format!("checksum of {}", entry.package),
+        }))
+    }
+
+    #[test]
+    fn lock_backtracks_to_a_compatible_version() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (a, b, c) = (Id::new(), Id::new(), Id::new());
+        // The newest a needs a version of c that b rules out, so an older a must be chosen
+        let (index, dependencies) = graph(dir.path(), &[
+            ("a", a, "1.0.0", vec![requires(c, "^1.0.0")?]),
+            ("a", a, "1.1.0", vec![requires(c, "^2.0.0")?]),
+            ("b", b, "1.0.0", vec![requires(c, "^1.0.0")?]),
+            ("c", c, "1.0.0", vec![]),
+            ("c", c, "1.5.0", vec![]),
+            ("c", c, "2.0.0", vec![]),
+        ])?;
+        let mut manifest = Manifest::with_author(Name::new("Root".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(requires(a, "^1.0.0")?)?;
+        manifest.declare_direct_dependency(requires(b, "^1.0.0")?)?;
+
+        let lockfile = lock(&index, &manifest, &dependencies)?;
+        let versions: Vec<(Id, String)> = lockfile.packages.iter()
+            .map(|package| (package.id, package.version.to_string()))
+            .collect();
+        let mut expected = vec![(a, "1.0.0".to_owned()), (b, "1.0.0".to_owned()), (c, "1.5.0".to_owned())];
+        expected.sort();
+        assert_eq!(versions, expected);
+        assert_eq!(lockfile.get(c).unwrap().checksum, "checksum of c@1.5.0");
+        assert_eq!(lockfile.get(a).unwrap().name.to_string(), "a");
+        Ok(())
+    }
+
+    #[test]
+    fn lock_reports_unsatisfiable_conflict() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (a, c) = (Id::new(), Id::new());
+        let (index, dependencies) = graph(dir.path(), &[
+            ("a", a, "1.0.0", vec![requires(c, "^2.0.0")?]),
+            ("c", c, "1.0.0", vec![]),
+            ("c", c, "2.0.0", vec![]),
+        ])?;
+        let mut manifest = Manifest::with_author(Name::new("Root".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(requires(a, "^1.0.0")?)?;
+        manifest.declare_direct_dependency(requires(c, "^1.0.0")?)?;
+
+        let error = lock(&index, &manifest, &dependencies).unwrap_err().to_string();
+        assert!(error.contains("no version of c satisfies"), "{}", error);
+        assert!(error.contains("^1.0.0 (required by Root@"), "{}", error);
+        assert!(error.contains("^2.0.0 (required by a@1.0.0)"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_reports_dependency_cycle() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (a, b, c) = (Id::new(), Id::new(), Id::new());
+        let (index, dependencies) = graph(dir.path(), &[
+            ("a", a, "1.0.0", vec![requires(b, "^1.0.0")?]),
+            ("b", b, "1.0.0", vec![requires(c, "^1.0.0")?]),
+            ("c", c, "1.0.0", vec![requires(a, "^1.0.0")?]),
+        ])?;
+        let mut manifest = Manifest::with_author(Name::new("Root".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(requires(a, "^1.0.0")?)?;
+
+        let error = lock(&index, &manifest, &dependencies).unwrap_err().to_string();
+        assert_eq!(error, "dependency cycle: a -> b -> c -> a");
+
+        // Depending back on the root package is a cycle too
+        let root = manifest.metadata().id();
+        let dir = TempDir::new()?;
+        let (index, dependencies) = graph(dir.path(), &[("a", a, "1.0.0", vec![requires(root, "^0.1.0")?])])?;
+        let error = lock(&index, &manifest, &dependencies).unwrap_err().to_string();
+        assert_eq!(error, "dependency cycle: Root -> a -> Root");
+        Ok(())
+    }
+
+    #[test]
+    fn lock_round_trips() -> Result<()> {
+        let dir = TempDir::new()?;
+        let packages = dir.path().join("packages");
+        fs::create_dir(&packages)?;
+        let (a, c) = (Id::new(), Id::new());
+        let (index, dependencies) = graph(&packages, &[
+            ("a", a, "1.0.0", vec![requires(c, "~1.2.0")?]),
+            ("c", c, "1.2.3", vec![]),
+        ])?;
+        let mut manifest = Manifest::with_author(Name::new("Root".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(requires(a, "^1.0.0")?)?;
+
+        let lockfile = lock(&index, &manifest, &dependencies)?;
+        assert_eq!(lockfile.packages.len(), 2);
+        let path = dir.path().join("merlon.lock");
+        lockfile.write_to_file(&path)?;
+        assert_eq!(Lockfile::read_from_path(&path)?, lockfile);
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_highest_matching_version() -> Result<()> {
+        let dir = TempDir::new()?;
+        let logos = Id::new();
+        let missing = Id::new();
+        write_fixture(dir.path(), "logos-1.0.0.merlon", logos, "Skip logos@1.0.0")?;
+        write_fixture(dir.path(), "logos-1.2.0.merlon", logos, "Skip logos@1.2.0")?;
+        write_fixture(dir.path(), "logos-2.0.0.merlon", logos, "Skip logos@2.0.0")?;
+        fs::write(dir.path().join("legacy.merlon"), b"Salted__payload")?;
+        fs::write(dir.path().join("README.md"), "not a package")?;
+
+        let index = LocalIndex::scan(dir.path())?;
+        assert_eq!(index.entries().len(), 3);
+
+        let mut manifest = Manifest::with_author(Name::new("Test".to_owned())?, "Jane Doe <jane@example.com>".to_owned())?;
+        manifest.declare_direct_dependency(Dependency::Package { id: logos, version: "^1.0.0".parse()?, source: Source::Index })?;
+        manifest.declare_direct_dependency(Dependency::Package { id: missing, version: "^1.0.0".parse()?, source: Source::Index })?;
+
+        let resolution = index.resolve(&manifest);
+        assert_eq!(resolution.resolved.len(), 1);
+        assert_eq!(resolution.resolved[0].entry.package.to_string(), "Skip logos@1.2.0");
+        assert_eq!(resolution.unmet, vec![Dependency::Package { id: missing, version: "^1.0.0".parse()?, source: Source::Index }]);
+        assert!(!resolution.is_complete());
+        Ok(())
+    }
+
+    #[test]
+    fn path_dependencies_are_read_from_disk() -> Result<()> {
+        let dir = TempDir::new()?;
+        let logos = Package::new("Skip logos", dir.path().join("logos"))?;
+        logos.edit_manifest(|manifest| {
+            manifest.metadata_mut().set_version("1.5.0".parse()?);
+            Ok(())
+        })?;
+        let logos_id = logos.id()?;
+
+        // A distributable of the same package in the packages directory is ignored in favour of the path
+        let packages_dir = dir.path().join("packages");
+        fs::create_dir(&packages_dir)?;
+        write_fixture(&packages_dir, "logos-1.2.0.merlon", logos_id, "Skip logos@1.2.0")?;
+
+        let package = Package::new("Root", dir.path().join("root"))?;
+        package.edit_manifest(|manifest| manifest.declare_direct_dependency(Dependency::Package {
+            id: logos_id,
+            version: "^1.0.0".parse()?,
+            source: Source::Path("../logos".into()),
+        }))?;
+
+        let index = LocalIndex::scan(&packages_dir)?.with_path_dependencies(&package)?;
+        let resolution = index.resolve(&package.manifest()?);
+        assert!(resolution.is_complete());
+        assert_eq!(resolution.resolved[0].entry.package.to_string(), "Skip logos@1.5.0");
+        assert_eq!(resolution.resolved[0].entry.path, package.path().join("../logos"));
+
+        // The declared ID must match the package on disk
+        let other = Package::new("Other", dir.path().join("other"))?;
+        other.edit_manifest(|manifest| manifest.declare_direct_dependency(Dependency::Package {
+            id: Id::new(),
+            version: VersionReq::STAR,
+            source: Source::Path("../logos".into()),
+        }))?;
+        let error = LocalIndex::scan(&packages_dir)?.with_path_dependencies(&other).unwrap_err();
+        assert!(error.to_string().contains("contains package"), "{}", error);
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! A summary of a package's state, for checking that it is ready to export.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use git2::{Repository, StatusOptions};
+use serde::Serialize;
+
+use super::{Package, InitialisedPackage};
+use crate::rom::{Rom, PAPERMARIO_US_SHA1};
+
+/// The state of a package, as reported by `merlon status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Status {
+    /// Name and version of the package, or `None` if `merlon.toml` could not be read.
+    pub package: Option<String>,
+
+    /// The decomp commit that the package is built on, from `merlon.toml` or else `merlon.lock`.
+    pub decomp_rev: Option<String>,
+
+    /// Whether the package has been set up with `merlon init`.
+    pub initialised: bool,
+
+    /// Number of commits in the decomp repository on top of [`Status::decomp_rev`]. `None` if the package is not
+    /// initialised or the commit is not known.
+    pub commits: Option<usize>,
+
+    /// Whether the decomp repository has uncommitted changes, including untracked files. `None` if the package is not
+    /// initialised.
+    pub dirty: Option<bool>,
+
+    /// Problems with `merlon.toml`, as reported by `merlon validate`.
+    pub problems: Vec<String>,
+
+    /// The base ROM the package uses, from [`Package::resolve_baserom`]. `None` if the package is not initialised.
+    pub baserom: Option<BaseromStatus>,
+}
+
+/// Whether a package's base ROM is in place. Part of [`Status`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BaseromStatus {
+    /// Where the base ROM should be.
+    pub path: PathBuf,
+
+    /// SHA1 of the base ROM, or `None` if it is missing.
+    pub sha1: Option<String>,
+
+    /// Whether the base ROM is an unmodified US ROM.
+    pub verified: bool,
+}
+
+impl Package {
+    /// Gathers the package's [`Status`]. Problems with the package are reported in the status rather than as errors.
+    pub fn status(&self) -> Result<Status> {
+        let (package, problems, manifest_rev) = match self.manifest() {
+            Ok(manifest) => (
+                Some(manifest.metadata().versioned_id().to_string()),
+                manifest.validate(),
+                manifest.get_direct_decomp_dependency_rev().map(str::to_owned),
+            ),
+            Err(error) => (None, vec![format!("{:#}", error)], None),
+        };
+        let decomp_rev = match manifest_rev {
+            Some(rev) => Some(rev),
+            None => self.lockfile().ok().flatten().and_then(|lockfile| lockfile.decomp_rev),
+        };
+
+        let mut status = Status {
+            package,
+            decomp_rev,
+            initialised: InitialisedPackage::is_initialised(self)?,
+            commits: None,
+            dirty: None,
+            problems,
+            baserom: None,
+        };
+        if !status.initialised {
+            return Ok(status);
+        }
+
+        let initialised = InitialisedPackage::try_from(self.clone())?;
+        let subrepo = initialised.subrepo_path();
+        let repo = Repository::open(&subrepo)
+            .with_context(|| format!("failed to open decomp repository {}", subrepo.display()))?;
+        if let Some(rev) = &status.decomp_rev {
+            match count_commits_since(&repo, rev) {
+                Ok(commits) => status.commits = Some(commits),
+                Err(error) => log::warn!("cannot count commits on top of {}: {:#}", rev, error),
+            }
+        }
+        status.dirty = Some(is_dirty(&repo)?);
+        status.baserom = Some(baserom_status(&self.resolve_baserom(None)?)?);
+        Ok(status)
+    }
+}
+
+/// Counts the commits reachable from HEAD but not from `base`, like `git rev-list --count base..HEAD`.
+fn count_commits_since(repo: &Repository, base: &str) -> Result<usize> {
+    let base = repo.revparse_single(base)
+        .with_context(|| format!("{} is not in the decomp repository", base))?
+        .peel_to_commit()?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.hide(base.id())?;
+    let mut commits = 0;
+    for oid in revwalk {
+        oid?;
+        commits += 1;
+    }
+    Ok(commits)
+}
+
+/// Whether the repository has staged, unstaged, or untracked changes, like a non-empty `git status --porcelain`.
+fn is_dirty(repo: &Repository) -> Result<bool> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options)).context("failed to read decomp repository status")?;
+    Ok(!statuses.is_empty())
+}
+
+fn baserom_status(path: &Path) -> Result<BaseromStatus> {
+    if !path.is_file() {
+        return Ok(BaseromStatus { path: path.to_owned(), sha1: None, verified: false });
+    }
+    let sha1 = Rom::from(path.to_owned()).sha1_string()?;
+    Ok(BaseromStatus {
+        path: path.to_owned(),
+        verified: sha1 == PAPERMARIO_US_SHA1,
+        sha1: Some(sha1),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::process::Command;
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn git(repo: &Path, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo)
+            .output()?;
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+
+    #[test]
+    fn commits_and_dirty_flag_are_reported() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path();
+        git(path, &["init", "--quiet"])?;
+        git(path, &["commit", "--quiet", "--allow-empty", "-m", "base"])?;
+        let base = git(path, &["rev-parse", "HEAD"])?;
+        for i in 0..3 {
+            fs::write(path.join("file.txt"), format!("version {}\n", i))?;
+            git(path, &["add", "file.txt"])?;
+            git(path, &["commit", "--quiet", "-m", &format!("change {}", i)])?;
+        }
+
+        let repo = Repository::open(path)?;
+        assert_eq!(count_commits_since(&repo, &base)?, 3);
+        assert_eq!(count_commits_since(&repo, "HEAD")?, 0);
+        assert!(count_commits_since(&repo, "0000000000000000000000000000000000000000").is_err());
+        assert!(!is_dirty(&repo)?);
+
+        fs::write(path.join("untracked.txt"), "new\n")?;
+        assert!(is_dirty(&repo)?);
+        fs::remove_file(path.join("untracked.txt"))?;
+        fs::write(path.join("file.txt"), "modified\n")?;
+        assert!(is_dirty(&repo)?);
+        Ok(())
+    }
+
+    #[test]
+    fn uninitialised_package_has_partial_status() -> Result<()> {
+        let dir = TempDir::new()?;
+        let package = Package::new("Status", dir.path().join("status"))?;
+        let status = package.status()?;
+        assert_eq!(status.package.as_deref(), Some("Status@0.1.0"));
+        assert!(!status.initialised);
+        assert_eq!((status.commits, status.dirty, status.baserom), (None, None, None));
+
+        let missing = baserom_status(&dir.path().join("baserom.z64"))?;
+        assert_eq!((missing.sha1, missing.verified), (None, false));
+        Ok(())
+    }
+}
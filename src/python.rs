@@ -13,14 +13,20 @@ fn merlon(py: Python, merlon: &PyModule) -> PyResult<()> {
             let manifest = PyModule::new(py, "manifest")?;
             manifest.add_class::<package::manifest::Manifest>()?;
             manifest.add_class::<package::manifest::Metadata>()?;
+            manifest.add_class::<package::manifest::PatchFormat>()?;
             manifest
         })?;
         package.add_submodule({
             let distribute = PyModule::new(py, "distribute")?;
             distribute.add_class::<package::distribute::Distributable>()?;
+            distribute.add_class::<package::distribute::Header>()?;
+            distribute.add_class::<package::distribute::Compression>()?;
+            distribute.add_class::<package::distribute::PayloadSignature>()?;
             distribute.add_class::<package::distribute::ExportOptions>()?;
             distribute.add_class::<package::distribute::ApplyOptions>()?;
+            distribute.add_class::<package::distribute::InstallOptions>()?;
             distribute.add_class::<package::distribute::OpenOptions>()?;
+            distribute.add_class::<package::distribute::VerifyOptions>()?;
             distribute
         })?;
         package.add_submodule({
@@ -46,13 +52,8 @@ fn merlon(py: Python, merlon: &PyModule) -> PyResult<()> {
     merlon.add_submodule({
         let rom = PyModule::new(py, "rom")?;
         rom.add_class::<rom::Rom>()?;
+        rom.add_class::<rom::DigestAlgo>()?;
         rom
     })?;
     Ok(())
 }
-
-/// Returns the current version of Merlon as a string.
-#[pyfunction]
-fn version() -> &'static str {
-    env!("CARGO_PKG_VERSION")
-}
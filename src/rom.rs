@@ -1,18 +1,93 @@
 //! ROM file handling
 
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::fs::File;
+use std::fs::{self, File};
 use std::fmt;
+use std::cell::OnceCell;
 use sha1::{Sha1, Digest};
-use anyhow::Result;
+use sha2::Sha256;
+use anyhow::{Result, Context, bail};
 use pyo3::prelude::*;
 
+pub mod compressed;
+pub mod patch;
+
+/// SHA1 of an unmodified US-release Paper Mario (N64) ROM in z64 (big-endian) byte order.
+pub const PAPERMARIO_US_SHA1: &str = "3837f44cda784b466c9a2d99df70d77c322b97a0";
+
+/// Size in bytes of a US-release Paper Mario (N64) ROM.
+pub const PAPERMARIO_US_SIZE: u64 = 40 * 1024 * 1024;
+
+/// Number of bytes read from the ROM at a time when hashing.
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A hash algorithm that [`Rom::digest`] can compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[pyclass(module = "merlon.rom")]
+pub enum DigestAlgo {
+    /// SHA-1, as used by decomp for `baserom.z64` and by `sha1sum`.
+    Sha1,
+    /// SHA-256, as used by `sha256sum`.
+    Sha256,
+    /// CRC-32 (ISO-HDLC), as used by zip and `crc32`. This detects accidental corruption, but is not secure.
+    Crc32,
+}
+
+impl fmt::Display for DigestAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestAlgo::Sha1 => write!(f, "SHA1"),
+            DigestAlgo::Sha256 => write!(f, "SHA256"),
+            DigestAlgo::Crc32 => write!(f, "CRC32"),
+        }
+    }
+}
+
+/// A running hash for one of the [`DigestAlgo`]s.
+enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn new(algo: DigestAlgo) -> Self {
+        match algo {
+            DigestAlgo::Sha1 => Hasher::Sha1(Sha1::new()),
+            DigestAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgo::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the digest as lowercase hex. CRC32 is written big-endian, as `crc32` prints it.
+    fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha1(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
 /// An N64 ROM file on disk.
 #[derive(Debug)]
 #[pyclass(module = "merlon.rom")]
 pub struct Rom {
     path: PathBuf,
+
+    /// SHA1 of the file, calculated the first time it is needed. The file is assumed not to change while this `Rom`
+    /// exists, so that formatting a `Rom` doesn't rehash it every time.
+    sha1: OnceCell<String>,
 }
 
 #[pymethods]
@@ -25,21 +100,99 @@ impl Rom {
 
     /// Reads the ROM file into a [`Vec`] of bytes.
     pub fn read_bytes(&self) -> std::io::Result<Vec<u8>> {
-        let mut file = self.file()?;
+        let mut file = self.reader()?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
         Ok(buffer)
     }
     
-    /// Calculates the SHA1 hash of the ROM.
+    /// Returns the size of the ROM in bytes. For a compressed ROM, this is the size once decompressed.
+    pub fn size(&self) -> std::io::Result<u64> {
+        match compressed::Container::detect(self.path())? {
+            compressed::Container::Raw => Ok(fs::metadata(self.path())?.len()),
+            _ => std::io::copy(&mut self.reader()?, &mut std::io::sink()),
+        }
+    }
+
+    /// Errors if the ROM file is not exactly `expected` bytes long.
+    /// This is much faster than hashing, so it is a useful first check for obviously wrong files.
+    pub fn expect_size(&self, expected: u64) -> Result<()> {
+        let size = self.size()
+            .with_context(|| format!("failed to read size of {}", self.path.display()))?;
+        if size != expected {
+            bail!("{} is {} bytes, expected {} bytes", self.path.display(), size, expected);
+        }
+        Ok(())
+    }
+
+    /// Errors if the ROM file is not the size of a Paper Mario (US) ROM, saying whether it looks truncated or padded.
+    /// Call this before [`Rom::verify_baserom`] to catch a bad dump without hashing it.
+    pub fn validate_size(&self) -> Result<()> {
+        let size = self.size()
+            .with_context(|| format!("failed to read size of {}", self.path.display()))?;
+        let problem = match size.cmp(&PAPERMARIO_US_SIZE) {
+            std::cmp::Ordering::Equal => return Ok(()),
+            std::cmp::Ordering::Less => "truncated",
+            std::cmp::Ordering::Greater => "padded",
+        };
+        bail!(
+            "{} is {} bytes, but Paper Mario (US) is {} bytes; the dump looks {}",
+            self.path.display(),
+            size,
+            PAPERMARIO_US_SIZE,
+            problem,
+        );
+    }
+
+    /// Calculates the SHA1 hash of the ROM. It is only calculated once per `Rom`.
     pub fn sha1_string(&self) -> Result<String> {
-        let mut bytes = self.read_bytes()?;
-        let generic_arr = Sha1::digest(&mut bytes);
-        let mut hex = String::new();
-        for byte in generic_arr.iter() {
-            hex.push_str(&format!("{:02x}", byte));
+        self.digest(DigestAlgo::Sha1)
+    }
+
+    /// Calculates a hash of the ROM as lowercase hex.
+    /// The file is read in chunks, so the whole ROM is never held in memory.
+    pub fn digest(&self, algo: DigestAlgo) -> Result<String> {
+        if algo != DigestAlgo::Sha1 {
+            return self.compute_digest(algo);
+        }
+        if let Some(sha1) = self.sha1.get() {
+            return Ok(sha1.clone());
         }
-        Ok(hex)
+        let sha1 = self.compute_digest(algo)?;
+        Ok(self.sha1.get_or_init(|| sha1).clone())
+    }
+
+    /// Errors if the ROM is not an unmodified US-release Paper Mario ROM in z64 byte order, naming both the expected
+    /// and actual SHA1.
+    pub fn verify_baserom(&self) -> Result<()> {
+        let sha1 = self.sha1_string()?;
+        if sha1 != PAPERMARIO_US_SHA1 {
+            bail!(
+                "{} is not the supported US revision of Paper Mario (expected SHA1 {}, got {})",
+                self.path.display(),
+                PAPERMARIO_US_SHA1,
+                sha1,
+            );
+        }
+        Ok(())
+    }
+
+    /// Calculates the SHA256 hash of the ROM.
+    pub fn sha256_string(&self) -> Result<String> {
+        self.digest(DigestAlgo::Sha256)
+    }
+
+    /// Returns whether this ROM has the same contents as `other`, wherever either file is and however it is
+    /// compressed. Sizes are compared first, so ROMs of different sizes are not hashed.
+    pub fn content_eq(&self, other: &Rom) -> Result<bool> {
+        let size = self.size()
+            .with_context(|| format!("failed to read size of {}", self.path.display()))?;
+        let other_size = other.size()
+            .with_context(|| format!("failed to read size of {}", other.path.display()))?;
+        if size != other_size {
+            return Ok(false);
+        }
+        Ok(self.sha1_string()? == other.sha1_string()?)
     }
 
     fn __str__(&self) -> String {
@@ -48,24 +201,750 @@ impl Rom {
 }
 
 impl Rom {
-    /// Returns the ROM as a [`File`].
+    /// Creates a `Rom` for a file whose SHA1 is already known, such as because it was just written.
+    fn with_sha1(path: PathBuf, sha1: String) -> Self {
+        Self { path, sha1: OnceCell::from(sha1) }
+    }
+
+    /// Hashes the file, ignoring the cached SHA1.
+    fn compute_digest(&self, algo: DigestAlgo) -> Result<String> {
+        let file = self.reader()
+            .with_context(|| format!("failed to open ROM {}", self.path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut hasher = Hasher::new(algo);
+        let mut buffer = vec![0; HASH_CHUNK_SIZE];
+        loop {
+            let len = read_full(&mut reader, &mut buffer)
+                .with_context(|| format!("failed to read ROM {}", self.path.display()))?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buffer[..len]);
+        }
+        Ok(hasher.finalize_hex())
+    }
+
+    /// Returns the ROM as a [`File`]. If the ROM is compressed, this is the compressed file; use [`Rom::reader`] to
+    /// read the ROM itself.
     pub fn file(&self) -> std::io::Result<File> {
         File::open(self.path())
     }
+
+    /// Opens the ROM for reading, decompressing it if it is stored gzip-compressed or in a zip archive.
+    pub fn reader(&self) -> std::io::Result<Box<dyn Read>> {
+        compressed::open(self.path())
+    }
+
+    /// Reads the ROM's region from the country code in its header. Works with any byte order.
+    pub fn region(&self) -> Result<Region> {
+        Ok(self.header()?.region)
+    }
+
+    /// Reads and parses the ROM's 64-byte header. Works with any byte order.
+    pub fn header(&self) -> Result<RomHeader> {
+        let mut header = [0; HEADER_SIZE];
+        let mut file = self.reader()
+            .with_context(|| format!("failed to open ROM {}", self.path.display()))?;
+        if read_full(&mut file, &mut header)? < header.len() {
+            bail!("{} is too small to be an N64 ROM", self.path.display());
+        }
+        let byte_order = RomFormat::detect(&header)
+            .with_context(|| format!("{} is not an N64 ROM", self.path.display()))?;
+        byte_order.to_big_endian(&mut header);
+        Ok(RomHeader::from_z64(&header))
+    }
+
+    /// Applies a BPS patch to the ROM, writing the patched ROM to `out`.
+    ///
+    /// The CRC32s in the patch are checked, so this errors if the patch is for a different ROM, rather than writing a
+    /// broken one. BPS patches for N64 ROMs are almost always made against z64 byte order.
+    pub fn apply_bps(&self, patch: &Path, out: &Path) -> Result<Rom> {
+        self.apply_patch_file(patch, out, patch::apply_bps)
+    }
+
+    /// Applies an IPS patch to the ROM, writing the patched ROM to `out`.
+    ///
+    /// IPS patches have no checksums, so it is up to the caller to check that the patch is for this ROM.
+    pub fn apply_ips(&self, patch: &Path, out: &Path) -> Result<Rom> {
+        self.apply_patch_file(patch, out, patch::apply_ips)
+    }
+
+    /// Writes a BPS patch to `out` that turns this ROM into `target`. The ROMs may be different sizes.
+    pub fn diff_bps(&self, target: &Rom, out: &Path) -> Result<()> {
+        let source_bytes = self.read_bytes()
+            .with_context(|| format!("failed to read ROM {}", self.path.display()))?;
+        let target_bytes = target.read_bytes()
+            .with_context(|| format!("failed to read ROM {}", target.path.display()))?;
+        fs::write(out, patch::diff_bps(&source_bytes, &target_bytes))
+            .with_context(|| format!("failed to write {}", out.display()))
+    }
+
+    fn apply_patch_file(&self, patch: &Path, out: &Path, apply: fn(&[u8], &[u8]) -> Result<Vec<u8>>) -> Result<Rom> {
+        let source = self.read_bytes()
+            .with_context(|| format!("failed to read ROM {}", self.path.display()))?;
+        let patch_bytes = fs::read(patch)
+            .with_context(|| format!("failed to read patch {}", patch.display()))?;
+        let target = apply(&source, &patch_bytes)
+            .with_context(|| format!("failed to apply {} to {}", patch.display(), self.path.display()))?;
+        fs::write(out, &target)
+            .with_context(|| format!("failed to write {}", out.display()))?;
+        Ok(Rom::with_sha1(out.to_owned(), to_hex(&Sha1::digest(&target))))
+    }
+
+    /// Recalculates the boot checksum and returns whether it matches the CRCs in the header.
+    ///
+    /// The checksum covers the first megabyte after the boot code, so this is much faster than hashing the whole ROM,
+    /// and catches most dumps that are corrupt but still the right size. It assumes the CIC-NUS-6102 boot chip, which
+    /// Paper Mario uses: ROMs for other CIC chips are checksummed with a different seed, so they never match.
+    pub fn verify_internal_crc(&self) -> Result<bool> {
+        let mut data = vec![0; CHECKSUM_END];
+        let mut file = self.reader()
+            .with_context(|| format!("failed to open ROM {}", self.path.display()))?;
+        let len = read_full(&mut file, &mut data)
+            .with_context(|| format!("failed to read ROM {}", self.path.display()))?;
+        if len < data.len() {
+            bail!("{} is too small to be an N64 ROM", self.path.display());
+        }
+        let byte_order = RomFormat::detect(&data)
+            .with_context(|| format!("{} is not an N64 ROM", self.path.display()))?;
+        byte_order.to_big_endian(&mut data);
+
+        let header = RomHeader::from_z64(data[..HEADER_SIZE].try_into().unwrap());
+        Ok(boot_crc_6102(&data[CHECKSUM_START..]) == (header.crc1, header.crc2))
+    }
+
+    /// Detects the byte order of the ROM from the magic bytes at the start of the file.
+    pub fn detect_format(&self) -> Result<RomFormat> {
+        let mut magic = [0; 4];
+        let mut file = self.reader()
+            .with_context(|| format!("failed to open ROM {}", self.path.display()))?;
+        read_full(&mut file, &mut magic)
+            .with_context(|| format!("failed to read ROM {}", self.path.display()))?;
+        RomFormat::detect(&magic)
+            .with_context(|| format!("{} is not an N64 ROM", self.path.display()))
+    }
+
+    /// Writes a copy of the ROM in z64 (big-endian) byte order to `out`, whatever order it was dumped in.
+    /// If anything fails, `out` is left as it was. `out` may be the ROM itself, to convert it in place.
+    pub fn to_z64(&self, out: &Path) -> Result<Rom> {
+        self.write_z64(out, |_| Ok(()))
+    }
+
+    /// Copies the ROM to `dest`, converting it to z64 byte order if needed, and verifies that the copy is an
+    /// unmodified US-release Paper Mario ROM. If anything fails, `dest` is left as it was, so copying a ROM onto
+    /// itself never loses it.
+    pub fn copy_to(&self, dest: &Path) -> Result<Rom> {
+        self.write_z64(dest, |copy| {
+            let sha1 = copy.sha1_string()?;
+            if sha1 != PAPERMARIO_US_SHA1 {
+                bail!(
+                    "{} is not an unmodified US-release Paper Mario ROM (expected SHA1 {}, got {})",
+                    self.path.display(),
+                    PAPERMARIO_US_SHA1,
+                    sha1,
+                );
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies the ROM to `dest`, converting it to z64 byte order if needed, if `check` accepts the converted copy.
+    /// `check` is given the copy before it is moved to `dest`, so if it errors, `dest` is left as it was.
+    pub fn copy_to_checked(&self, dest: &Path, check: impl FnOnce(&Rom) -> Result<()>) -> Result<Rom> {
+        self.write_z64(dest, check)
+    }
+
+    /// Writes the ROM to `dest` in z64 byte order. If `check` errors on what was written, so does this, and `dest` is
+    /// not touched.
+    ///
+    /// The ROM is written to a temporary file next to `dest` that is renamed over it at the end, so `dest` is never
+    /// truncated while it might still be the file being read.
+    fn write_z64(&self, dest: &Path, check: impl FnOnce(&Rom) -> Result<()>) -> Result<Rom> {
+        let file_name = dest.file_name()
+            .with_context(|| format!("cannot write ROM to {}: not a file path", dest.display()))?;
+        let temp_path = dest.with_file_name(format!(".{}.tmp-{}", file_name.to_string_lossy(), uuid::Uuid::new_v4()));
+        let result = self.write_z64_unchecked(&temp_path).and_then(|sha1| {
+            check(&Rom::with_sha1(temp_path.clone(), sha1.clone()))?;
+            fs::rename(&temp_path, dest)
+                .with_context(|| format!("failed to write {}", dest.display()))?;
+            Ok(Rom::with_sha1(dest.to_owned(), sha1))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn write_z64_unchecked(&self, dest: &Path) -> Result<String> {
+        let mut reader = self.reader()
+            .with_context(|| format!("failed to open ROM {}", self.path.display()))?;
+        let mut writer = File::create(dest)
+            .with_context(|| format!("failed to create {}", dest.display()))?;
+        let mut hasher = Sha1::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut byte_order = None;
+        loop {
+            let len = read_full(&mut reader, &mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            let chunk = &mut buffer[..len];
+            let order = match byte_order {
+                Some(order) => order,
+                None => {
+                    let order = RomFormat::detect(chunk)
+                        .with_context(|| format!("{} is not an N64 ROM", self.path.display()))?;
+                    byte_order = Some(order);
+                    order
+                }
+            };
+            order.to_big_endian(chunk);
+            hasher.update(&chunk[..]);
+            writer.write_all(chunk)?;
+        }
+        writer.flush()?;
+        Ok(to_hex(&hasher.finalize()))
+    }
+}
+
+/// Size of the ROM header in bytes.
+const HEADER_SIZE: usize = 0x40;
+
+/// Offset of the first CRC in the ROM header. The second CRC follows it.
+const CRC_OFFSET: usize = 0x10;
+
+/// The boot checksum covers `CHECKSUM_START..CHECKSUM_END`, the data following the boot code.
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_END: usize = CHECKSUM_START + 0x100000;
+
+/// Seed of the boot checksum for the CIC-NUS-6102 boot chip.
+const CIC_6102_SEED: u32 = 0xF8CA4DDC;
+
+/// Offset and length of the internal name in the ROM header.
+const NAME_OFFSET: usize = 0x20;
+const NAME_LEN: usize = 20;
+
+/// Offset of the game code in the ROM header. It ends with the country code.
+const GAME_CODE_OFFSET: usize = 0x3B;
+
+/// Offset of the country code byte in the ROM header.
+const COUNTRY_CODE_OFFSET: usize = 0x3E;
+
+/// The parts of an N64 ROM header that are useful for identifying a dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomHeader {
+    /// Internal name, with trailing padding removed. Paper Mario's is `PAPER MARIO`.
+    pub name: String,
+
+    /// Four-character game code, such as `NMQE`.
+    pub game_code: String,
+
+    /// Region from the country code.
+    pub region: Region,
+
+    /// First checksum of the boot code and data.
+    pub crc1: u32,
+
+    /// Second checksum of the boot code and data.
+    pub crc2: u32,
+}
+
+impl RomHeader {
+    /// Parses a header that has already been converted to z64 byte order.
+    fn from_z64(header: &[u8; HEADER_SIZE]) -> Self {
+        let word = |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        let text = |bytes: &[u8]| {
+            String::from_utf8_lossy(bytes)
+                .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_owned()
+        };
+        Self {
+            name: text(&header[NAME_OFFSET..NAME_OFFSET + NAME_LEN]),
+            game_code: text(&header[GAME_CODE_OFFSET..GAME_CODE_OFFSET + 4]),
+            region: Region::from(header[COUNTRY_CODE_OFFSET]),
+            crc1: word(CRC_OFFSET),
+            crc2: word(CRC_OFFSET + 4),
+        }
+    }
+}
+
+/// Region of an N64 ROM, identified by the country code in its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// North America (`E`).
+    Us,
+    /// Japan (`J`).
+    Jp,
+    /// Europe (`P`).
+    Eu,
+    /// Any other country code.
+    Unknown(u8),
+}
+
+impl From<u8> for Region {
+    fn from(country_code: u8) -> Self {
+        match country_code {
+            b'E' => Self::Us,
+            b'J' => Self::Jp,
+            b'P' => Self::Eu,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl Region {
+    /// Returns the region of the game version that the decomp builds from `ver/<name>`, such as [`Region::Eu`] for
+    /// `pal`, or `None` if the name isn't one of the decomp's versions.
+    pub fn from_decomp_version(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Self::Us),
+            "jp" => Some(Self::Jp),
+            "pal" => Some(Self::Eu),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Us => write!(f, "US"),
+            Self::Jp => write!(f, "JP"),
+            Self::Eu => write!(f, "EU"),
+            Self::Unknown(country_code) => write!(f, "unknown region (country code {:#04x})", country_code),
+        }
+    }
+}
+
+/// Byte order of an N64 ROM dump, identified by the first four bytes of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomFormat {
+    /// Big-endian. The native byte order.
+    Z64,
+    /// Byte-swapped 16-bit words.
+    V64,
+    /// Little-endian 32-bit words.
+    N64,
+}
+
+impl RomFormat {
+    /// Identifies the byte order from the first four bytes of a ROM, or returns `None` if they aren't N64 magic.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match bytes.get(..4)? {
+            [0x80, 0x37, 0x12, 0x40] => Some(Self::Z64),
+            [0x37, 0x80, 0x40, 0x12] => Some(Self::V64),
+            [0x40, 0x12, 0x37, 0x80] => Some(Self::N64),
+            _ => None,
+        }
+    }
+
+    /// Converts a chunk in this byte order to big-endian in place. Trailing bytes that don't fill a word are left as-is.
+    fn to_big_endian(self, chunk: &mut [u8]) {
+        match self {
+            Self::Z64 => {}
+            Self::V64 => chunk.chunks_exact_mut(2).for_each(|word| word.swap(0, 1)),
+            Self::N64 => chunk.chunks_exact_mut(4).for_each(|word| word.reverse()),
+        }
+    }
+}
+
+impl fmt::Display for RomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Z64 => write!(f, "z64"),
+            Self::V64 => write!(f, "v64"),
+            Self::N64 => write!(f, "n64"),
+        }
+    }
+}
+
+/// Calculates the CRC1 and CRC2 that the CIC-NUS-6102 boot code checks, over big-endian `data`.
+fn boot_crc_6102(data: &[u8]) -> (u32, u32) {
+    let [mut t1, mut t2, mut t3, mut t4, mut t5, mut t6] = [CIC_6102_SEED; 6];
+    for word in data.chunks_exact(4) {
+        let d = u32::from_be_bytes(word.try_into().unwrap());
+        let (sum, carry) = t6.overflowing_add(d);
+        if carry {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+        t1 = t1.wrapping_add(t5 ^ d);
+    }
+    (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1)
+}
+
+/// Reads until `buffer` is full or EOF is reached, returning the number of bytes read.
+/// Unlike a single `read`, this guarantees chunks stay word-aligned for byte order conversion.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(len)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for byte in bytes {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
+
+/// Parses the `N` bytes written by [`to_hex`], in either case.
+pub(crate) fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        bail!("expected {} hex digits, found {:?}", N * 2, hex);
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(bytes)
 }
 
 impl From<PathBuf> for Rom {
     fn from(path: PathBuf) -> Self {
-        Self { path }
+        Self { path, sha1: OnceCell::new() }
     }
 }
 
 impl fmt::Display for Rom {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.path().display())?;
-        if let Ok(sha1) = self.sha1_string() {
-            write!(f, " (SHA1: {})", sha1)?;
+        let name = self.header().ok().map(|header| header.name).filter(|name| !name.is_empty());
+        match (name, self.sha1_string().ok()) {
+            (Some(name), Some(sha1)) => write!(f, " ({}, SHA1: {})", name, sha1)?,
+            (Some(name), None) => write!(f, " ({})", name)?,
+            (None, Some(sha1)) => write!(f, " (SHA1: {})", sha1)?,
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use temp_dir::TempDir;
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn digests_match_known_vectors() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rom");
+        fs::write(&path, b"The quick brown fox jumps over the lazy dog")?;
+        let rom = Rom::from(path);
+        assert_eq!(rom.digest(DigestAlgo::Sha1)?, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+        assert_eq!(rom.digest(DigestAlgo::Sha256)?, "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592");
+        assert_eq!(rom.digest(DigestAlgo::Crc32)?, "414fa339");
+        assert_eq!(rom.sha1_string()?, rom.digest(DigestAlgo::Sha1)?);
+
+        // Larger than one chunk, so the hashes are built up over several reads
+        fs::write(rom.path(), vec![b'a'; HASH_CHUNK_SIZE * 2 + 1])?;
+        let rom = Rom::from(rom.path().to_owned());
+        let expected = vec![b'a'; HASH_CHUNK_SIZE * 2 + 1];
+        assert_eq!(rom.digest(DigestAlgo::Sha1)?, to_hex(&Sha1::digest(&expected)));
+        assert_eq!(rom.digest(DigestAlgo::Sha256)?, to_hex(&Sha256::digest(&expected)));
+        assert_eq!(rom.digest(DigestAlgo::Crc32)?, format!("{:08x}", crc32fast::hash(&expected)));
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_roms_hash_like_the_raw_file() -> Result<()> {
+        use flate2::{Compression, write::GzEncoder};
+
+        let dir = TempDir::new()?;
+        let mut contents = vec![0x80, 0x37, 0x12, 0x40];
+        contents.extend((0..8192u32).map(|i| (i % 251) as u8));
+        let raw = Rom::from(dir.path().join("baserom.z64"));
+        fs::write(raw.path(), &contents)?;
+
+        let gzip = Rom::from(dir.path().join("baserom.z64.gz"));
+        let mut encoder = GzEncoder::new(File::create(gzip.path())?, Compression::default());
+        encoder.write_all(&contents)?;
+        encoder.finish()?;
+
+        let zip = Rom::from(dir.path().join("baserom.zip"));
+        let mut writer = zip::ZipWriter::new(File::create(zip.path())?);
+        writer.start_file("README.txt", zip::write::FileOptions::default())?;
+        writer.write_all(b"not a rom")?;
+        writer.start_file("Paper Mario (USA).z64", zip::write::FileOptions::default())?;
+        writer.write_all(&contents)?;
+        writer.finish()?;
+
+        assert_eq!(compressed::Container::detect(raw.path())?, compressed::Container::Raw);
+        assert_eq!(compressed::Container::detect(gzip.path())?, compressed::Container::Gzip);
+        assert_eq!(compressed::Container::detect(zip.path())?, compressed::Container::Zip);
+        for rom in [&gzip, &zip] {
+            assert_eq!(rom.sha1_string()?, raw.sha1_string()?, "{}", rom.path().display());
+            assert_eq!(rom.size()?, contents.len() as u64, "{}", rom.path().display());
+            assert_eq!(rom.read_bytes()?, contents, "{}", rom.path().display());
+            assert_eq!(rom.detect_format()?, RomFormat::Z64);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sha1_is_calculated_once() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rom");
+        fs::write(&path, b"The quick brown fox jumps over the lazy dog")?;
+        let rom = Rom::from(path.clone());
+        assert!(rom.sha1.get().is_none(), "constructing a Rom should not hash it");
+        let displayed = rom.to_string();
+        assert!(displayed.contains("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"), "{}", displayed);
+
+        // Had the file been read again, the changed contents would show
+        fs::write(&path, b"The quick brown fox jumps over the lazy cat")?;
+        assert_eq!(rom.to_string(), displayed);
+        assert_eq!(rom.sha1_string()?, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+        assert_ne!(Rom::from(path).sha1_string()?, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+        Ok(())
+    }
+
+    #[test]
+    fn internal_crc_is_checked() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rom.z64");
+        let mut bytes = vec![0; CHECKSUM_END];
+        bytes[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        for (i, byte) in bytes[CHECKSUM_START..].iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        bytes[CRC_OFFSET..CRC_OFFSET + 8].copy_from_slice(&[0xFA, 0xC0, 0x47, 0xE2, 0x0D, 0x23, 0x31, 0x37]);
+        fs::write(&path, &bytes)?;
+        let rom = Rom::from(path.clone());
+        assert!(rom.verify_internal_crc()?);
+
+        // Byte order doesn't matter
+        let v64: Vec<u8> = bytes.chunks_exact(2).flat_map(|pair| [pair[1], pair[0]]).collect();
+        fs::write(&path, &v64)?;
+        assert!(rom.verify_internal_crc()?);
+
+        bytes[CHECKSUM_START + 0x1234] ^= 1;
+        fs::write(&path, &bytes)?;
+        assert!(!rom.verify_internal_crc()?);
+
+        fs::write(&path, &bytes[..CHECKSUM_END - 1])?;
+        assert!(rom.verify_internal_crc().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn every_format_converts_to_z64() -> Result<()> {
+        let dir = TempDir::new()?;
+        let z64 = [0x80, 0x37, 0x12, 0x40, 0x01, 0x02, 0x03, 0x04];
+        let v64 = [0x37, 0x80, 0x40, 0x12, 0x02, 0x01, 0x04, 0x03];
+        let n64 = [0x40, 0x12, 0x37, 0x80, 0x04, 0x03, 0x02, 0x01];
+        for (bytes, format) in [(z64, RomFormat::Z64), (v64, RomFormat::V64), (n64, RomFormat::N64)] {
+            let mut chunk = bytes;
+            assert_eq!(RomFormat::detect(&chunk), Some(format));
+            format.to_big_endian(&mut chunk);
+            assert_eq!(chunk, z64);
+
+            let path = dir.path().join("dump");
+            fs::write(&path, bytes)?;
+            let rom = Rom::from(path);
+            assert_eq!(rom.detect_format()?, format);
+
+            let out = rom.to_z64(&dir.path().join("out.z64"))?;
+            assert_eq!(out.detect_format()?, RomFormat::Z64);
+            assert_eq!(out.read_bytes()?, z64);
+        }
+
+        assert_eq!(RomFormat::detect(b"PK\x03\x04"), None);
+        let not_a_rom = dir.path().join("not-a-rom");
+        fs::write(&not_a_rom, b"PK\x03\x04")?;
+        let out = dir.path().join("not-a-rom.z64");
+        assert!(Rom::from(not_a_rom.clone()).detect_format().is_err());
+        assert!(Rom::from(not_a_rom).to_z64(&out).is_err());
+        assert!(!out.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn region_is_read_from_header() -> Result<()> {
+        let dir = TempDir::new()?;
+        for (country_code, region) in [(b'E', Region::Us), (b'J', Region::Jp), (b'P', Region::Eu), (b'X', Region::Unknown(b'X'))] {
+            let mut header = [0; HEADER_SIZE];
+            header[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+            header[COUNTRY_CODE_OFFSET] = country_code;
+            let path = dir.path().join("header.z64");
+            fs::write(&path, header)?;
+            assert_eq!(Rom::from(path.clone()).region()?, region);
+
+            // Byte-swapped dumps have the country code at a different offset on disk
+            RomFormat::V64.to_big_endian(&mut header);
+            fs::write(&path, header)?;
+            assert_eq!(Rom::from(path).region()?, region);
         }
+        assert_eq!(Region::Us.to_string(), "US");
+        assert_eq!(Region::Unknown(0x58).to_string(), "unknown region (country code 0x58)");
+        Ok(())
+    }
+
+    #[test]
+    fn header_is_parsed_in_any_byte_order() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        header[CRC_OFFSET..CRC_OFFSET + 8].copy_from_slice(&[0x65, 0xEE, 0xE5, 0x3A, 0xED, 0x7D, 0x73, 0x3C]);
+        header[NAME_OFFSET..NAME_OFFSET + NAME_LEN].copy_from_slice(b"PAPER MARIO         ");
+        header[GAME_CODE_OFFSET..GAME_CODE_OFFSET + 4].copy_from_slice(b"NMQE");
+        let expected = RomHeader {
+            name: "PAPER MARIO".to_owned(),
+            game_code: "NMQE".to_owned(),
+            region: Region::Us,
+            crc1: 0x65EEE53A,
+            crc2: 0xED7D733C,
+        };
+
+        let path = dir.path().join("header.z64");
+        fs::write(&path, header)?;
+        assert_eq!(Rom::from(path.clone()).header()?, expected);
+
+        // Little-endian conversion is its own inverse, so applying it again gives an n64 dump
+        RomFormat::N64.to_big_endian(&mut header);
+        fs::write(&path, header)?;
+        assert_eq!(Rom::from(path.clone()).header()?, expected);
+        assert!(Rom::from(path).to_string().contains("(PAPER MARIO, SHA1: "));
+        Ok(())
+    }
+
+    #[test]
+    fn expect_size_checks_length() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("truncated.z64");
+        fs::write(&path, [0x80, 0x37, 0x12, 0x40])?;
+        let rom = Rom::from(path);
+        assert_eq!(rom.size()?, 4);
+        assert!(rom.expect_size(4).is_ok());
+        assert!(rom.expect_size(PAPERMARIO_US_SIZE).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_size_reports_truncated_and_padded_dumps() -> Result<()> {
+        let dir = TempDir::new()?;
+        let with_size = |name: &str, size: u64| -> Result<Rom> {
+            let path = dir.path().join(name);
+            File::create(&path)?.set_len(size)?;
+            Ok(Rom::from(path))
+        };
+
+        assert!(with_size("exact.z64", PAPERMARIO_US_SIZE)?.validate_size().is_ok());
+        let error = with_size("short.z64", PAPERMARIO_US_SIZE - 1)?.validate_size().unwrap_err().to_string();
+        assert!(error.contains("truncated"), "{}", error);
+        assert!(error.contains(&(PAPERMARIO_US_SIZE - 1).to_string()), "{}", error);
+        assert!(error.contains(&PAPERMARIO_US_SIZE.to_string()), "{}", error);
+        let error = with_size("long.z64", PAPERMARIO_US_SIZE * 2)?.validate_size().unwrap_err().to_string();
+        assert!(error.contains("padded"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn content_eq_compares_contents_not_paths() -> Result<()> {
+        let dir = TempDir::new()?;
+        let rom = |name: &str, contents: &[u8]| -> Result<Rom> {
+            let path = dir.path().join(name);
+            fs::write(&path, contents)?;
+            Ok(Rom::from(path))
+        };
+
+        let original = rom("original.z64", b"abcd")?;
+        assert!(original.content_eq(&rom("copy.z64", b"abcd")?)?);
+        assert!(original.content_eq(&original)?);
+        assert!(!original.content_eq(&rom("changed.z64", b"abce")?)?);
+        assert!(!original.content_eq(&rom("longer.z64", b"abcde")?)?);
+
+        // Files that differ in size are never hashed
+        let longer = rom("unhashed.z64", b"abcdef")?;
+        assert!(!original.content_eq(&longer)?);
+        assert!(longer.sha1.get().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn sha1_is_streamed_across_chunks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("pattern.z64");
+        // Not a multiple of the chunk size, so the final chunk is partial
+        let bytes: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, bytes)?;
+        assert_eq!(Rom::from(path).sha1_string()?, "3acb0cbfb1ced6f43415bb298df6842936daf48f");
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_rom_is_an_error() -> Result<()> {
+        let dir = TempDir::new()?;
+        // Opening a directory succeeds on some platforms, but reading from it always fails
+        let rom = Rom::from(dir.path().to_owned());
+        assert!(rom.read_bytes().is_err());
+        assert!(rom.sha1_string().is_err());
+        assert!(rom.sha256_string().is_err());
+        assert!(rom.region().is_err());
+
+        let missing = Rom::from(dir.path().join("missing.z64"));
+        assert!(missing.read_bytes().is_err());
+        assert!(missing.sha1_string().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_baserom_names_both_hashes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("baserom.z64");
+        fs::write(&path, [0x80, 0x37, 0x12, 0x40])?;
+        let rom = Rom::from(path);
+        let error = rom.verify_baserom().unwrap_err().to_string();
+        assert!(error.contains(PAPERMARIO_US_SHA1));
+        assert!(error.contains(&rom.sha1_string()?));
+        Ok(())
+    }
+
+    #[test]
+    fn copy_to_removes_unverified_copy() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("not-papermario.v64");
+        fs::write(&source, [0x37, 0x80, 0x40, 0x12, 0x00, 0x00, 0x00, 0x00])?;
+        let dest = dir.path().join("baserom.z64");
+
+        let error = Rom::from(source).copy_to(&dest).unwrap_err();
+        assert!(error.to_string().contains(PAPERMARIO_US_SHA1));
+        assert!(!dest.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_onto_itself_keeps_the_rom() -> Result<()> {
+        let dir = TempDir::new()?;
+        let bytes = [0x37, 0x80, 0x40, 0x12, 0x00, 0x00, 0x00, 0x00];
+        let source = dir.path().join("not-papermario.v64");
+        fs::write(&source, bytes)?;
+
+        assert!(Rom::from(source.clone()).copy_to(&source).is_err());
+        assert_eq!(fs::read(&source)?, bytes);
+
+        // Converting in place is allowed, and reads the whole ROM before replacing it
+        let converted = Rom::from(source.clone()).to_z64(&source)?;
+        assert_eq!(converted.read_bytes()?, [0x80, 0x37, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1, "temporary files should be cleaned up");
         Ok(())
     }
 }
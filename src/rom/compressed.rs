@@ -0,0 +1,92 @@
+//! Reading ROMs that are stored compressed, such as `baserom.z64.gz` or a ROM inside a zip archive.
+//!
+//! The container is detected from the first bytes of the file rather than its extension, so a renamed file is still
+//! read correctly. Everything that reads a [`Rom`](super::Rom) goes through [`open`], so hashes, headers and the
+//! distributable passphrase are all taken from the decompressed ROM.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use flate2::read::GzDecoder;
+
+/// Magic bytes at the start of a gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic bytes at the start of a zip archive, the signature of its first local file header.
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+/// Extensions of the ROM files that are looked for in a zip archive.
+const ROM_EXTENSIONS: &[&str] = &["z64", "v64", "n64"];
+
+/// How a ROM file is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// The ROM itself.
+    Raw,
+    /// A gzip-compressed ROM, such as `baserom.z64.gz`.
+    Gzip,
+    /// A zip archive containing the ROM.
+    Zip,
+}
+
+impl Container {
+    /// Detects how the file at `path` is stored from its first bytes.
+    pub fn detect(path: &Path) -> io::Result<Self> {
+        let mut magic = Vec::with_capacity(ZIP_MAGIC.len());
+        File::open(path)?.take(ZIP_MAGIC.len() as u64).read_to_end(&mut magic)?;
+        if magic.starts_with(&GZIP_MAGIC) {
+            Ok(Self::Gzip)
+        } else if magic.starts_with(&ZIP_MAGIC) {
+            Ok(Self::Zip)
+        } else {
+            Ok(Self::Raw)
+        }
+    }
+}
+
+/// Opens the file at `path` for reading, decompressing it if it is gzip-compressed or a zip archive.
+///
+/// A zip archive must contain exactly one `.z64`, `.v64` or `.n64` file, or else exactly one file. It is read into
+/// memory, as entries of a zip archive can't outlive it.
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    match Container::detect(path)? {
+        Container::Raw => Ok(Box::new(File::open(path)?)),
+        Container::Gzip => Ok(Box::new(GzDecoder::new(File::open(path)?))),
+        Container::Zip => Ok(Box::new(Cursor::new(read_rom_from_zip(path)?))),
+    }
+}
+
+fn read_rom_from_zip(path: &Path) -> io::Result<Vec<u8>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut archive = zip::ZipArchive::new(File::open(path)?)
+        .map_err(|error| invalid(format!("failed to read zip archive {}: {}", path.display(), error)))?;
+
+    let mut files = Vec::new();
+    let mut roms = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)
+            .map_err(|error| invalid(format!("failed to read zip archive {}: {}", path.display(), error)))?;
+        if !entry.is_file() {
+            continue;
+        }
+        let is_rom = Path::new(entry.name())
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| ROM_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()));
+        if is_rom {
+            roms.push(index);
+        }
+        files.push(index);
+    }
+    let index = match (roms.as_slice(), files.as_slice()) {
+        ([index], _) | ([], [index]) => *index,
+        ([], _) => return Err(invalid(format!("zip archive {} does not contain a ROM", path.display()))),
+        _ => return Err(invalid(format!("zip archive {} contains more than one ROM", path.display()))),
+    };
+
+    let mut entry = archive.by_index(index)
+        .map_err(|error| invalid(format!("failed to read zip archive {}: {}", path.display(), error)))?;
+    let mut contents = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut contents)?;
+    Ok(contents)
+}
@@ -0,0 +1,431 @@
+//! Applying BPS and IPS patches, the formats that ROM hacks are traditionally distributed in.
+//!
+//! See <https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md> for BPS, and
+//! <https://zerosoft.zophar.net/ips.php> for IPS.
+
+use anyhow::{Result, Context, bail};
+
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Size of the BPS footer: the CRC32s of the source, target, and patch.
+const BPS_FOOTER_LEN: usize = 12;
+
+/// Largest target a BPS patch may declare, the size of the largest N64 cartridge. The size is read from the patch, so
+/// it is checked before the target is allocated.
+const MAX_BPS_TARGET_SIZE: usize = 64 * 1024 * 1024;
+
+/// Runs of matching bytes shorter than this are stored as literals when diffing, since a separate action for them
+/// would take about as much space and make the surrounding literals cost another action.
+const MIN_SOURCE_RUN: usize = 4;
+
+/// Runs of a repeated byte at least this long are stored as a self-overlapping copy when diffing, so that padding
+/// in an expanded ROM doesn't take up space in the patch.
+const MIN_REPEAT_RUN: usize = 16;
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+
+/// Applies a BPS patch to `source`, returning the target.
+///
+/// Errors if `source` is not the ROM the patch was made for, or the patch or its result are corrupt, according to
+/// the CRC32s in the patch.
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if !patch.starts_with(BPS_MAGIC) {
+        bail!("not a BPS patch");
+    }
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_LEN {
+        bail!("BPS patch is truncated");
+    }
+    let footer = &patch[patch.len() - BPS_FOOTER_LEN..];
+    let crc = |offset: usize| u32::from_le_bytes(footer[offset..offset + 4].try_into().unwrap());
+    let (source_crc, target_crc, patch_crc) = (crc(0), crc(4), crc(8));
+    let actual_patch_crc = crc32fast::hash(&patch[..patch.len() - 4]);
+    if actual_patch_crc != patch_crc {
+        bail!("BPS patch is corrupt (expected CRC32 {:08x}, got {:08x})", patch_crc, actual_patch_crc);
+    }
+    let actual_source_crc = crc32fast::hash(source);
+    if actual_source_crc != source_crc {
+        bail!("BPS patch is for a different ROM (expected CRC32 {:08x}, got {:08x})", source_crc, actual_source_crc);
+    }
+
+    let mut reader = Reader { data: &patch[..patch.len() - BPS_FOOTER_LEN], pos: BPS_MAGIC.len() };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        bail!("BPS patch is for a {} byte ROM, but this ROM is {} bytes", source_size, source.len());
+    }
+    if target_size > MAX_BPS_TARGET_SIZE {
+        bail!("BPS patch is corrupt: its {} byte target is larger than any N64 ROM", target_size);
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while !reader.is_empty() {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_size {
+            bail!("BPS patch writes past the end of its {} byte target", target_size);
+        }
+        match action & 3 {
+            SOURCE_READ => {
+                let start = target.len();
+                let bytes = source.get(start..start + len).context("BPS patch reads past the end of the source")?;
+                target.extend_from_slice(bytes);
+            }
+            TARGET_READ => target.extend_from_slice(reader.bytes(len)?),
+            SOURCE_COPY => {
+                source_offset = reader.relative_offset(source_offset)?;
+                let bytes = source.get(source_offset..source_offset + len)
+                    .context("BPS patch copies from past the end of the source")?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // TARGET_COPY, which may overlap the bytes it is writing, so is copied one byte at a time
+            _ => {
+                target_offset = reader.relative_offset(target_offset)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset).context("BPS patch copies from past the end of the target")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        bail!("BPS patch produced {} bytes, expected {}", target.len(), target_size);
+    }
+    let actual_target_crc = crc32fast::hash(&target);
+    if actual_target_crc != target_crc {
+        bail!("patched ROM has CRC32 {:08x}, but the BPS patch expects {:08x}", actual_target_crc, target_crc);
+    }
+    Ok(target)
+}
+
+/// Creates a BPS patch that turns `source` into `target`. They may be different lengths.
+///
+/// Matching bytes are read from the source at the same offset, changed bytes are stored literally, and long runs of a
+/// repeated byte are copied from the target. This suits ROM hacks, where most data stays in place.
+pub fn diff_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_varint(source.len(), &mut patch);
+    write_varint(target.len(), &mut patch);
+    write_varint(0, &mut patch); // No metadata
+
+    let run_len = |pos: usize, matches: &dyn Fn(usize) -> bool| (pos..target.len()).take_while(|&i| matches(i)).count();
+    let mut literal_start = None;
+    let mut target_offset = 0;
+    let mut pos = 0;
+    while pos < target.len() {
+        let source_run = run_len(pos, &|i| source.get(i) == Some(&target[i]));
+        let repeat_run = run_len(pos, &|i| target[i] == target[pos]);
+        if source_run >= MIN_SOURCE_RUN || (source_run > 0 && pos + source_run == target.len()) {
+            flush_literal(&mut literal_start, pos, target, &mut patch);
+            write_action(SOURCE_READ, source_run, &mut patch);
+            pos += source_run;
+        } else if repeat_run >= MIN_REPEAT_RUN {
+            // Write the byte once, then copy it over itself
+            literal_start.get_or_insert(pos);
+            flush_literal(&mut literal_start, pos + 1, target, &mut patch);
+            write_action(TARGET_COPY, repeat_run - 1, &mut patch);
+            write_relative_offset(target_offset, pos, &mut patch);
+            target_offset = pos + repeat_run - 1;
+            pos += repeat_run;
+        } else {
+            literal_start.get_or_insert(pos);
+            pos += 1;
+        }
+    }
+    flush_literal(&mut literal_start, pos, target, &mut patch);
+
+    patch.extend(crc32fast::hash(source).to_le_bytes());
+    patch.extend(crc32fast::hash(target).to_le_bytes());
+    patch.extend(crc32fast::hash(&patch).to_le_bytes());
+    patch
+}
+
+/// Writes `target[start..end]` as a TargetRead action, if there is a literal pending.
+fn flush_literal(start: &mut Option<usize>, end: usize, target: &[u8], patch: &mut Vec<u8>) {
+    if let Some(start) = start.take() {
+        write_action(TARGET_READ, end - start, patch);
+        patch.extend_from_slice(&target[start..end]);
+    }
+}
+
+fn write_action(kind: usize, len: usize, patch: &mut Vec<u8>) {
+    write_varint((len - 1) << 2 | kind, patch);
+}
+
+fn write_relative_offset(from: usize, to: usize, patch: &mut Vec<u8>) {
+    if to >= from {
+        write_varint((to - from) << 1, patch);
+    } else {
+        write_varint((from - to) << 1 | 1, patch);
+    }
+}
+
+/// Writes a BPS variable-length number.
+fn write_varint(mut value: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte | 0x80);
+            return;
+        }
+        out.push(byte);
+        value -= 1;
+    }
+}
+
+/// Applies an IPS patch to `source`, returning the target.
+///
+/// IPS patches have no checksums, so patching the wrong ROM cannot be detected.
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if !patch.starts_with(IPS_MAGIC) {
+        bail!("not an IPS patch");
+    }
+    let mut reader = Reader { data: patch, pos: IPS_MAGIC.len() };
+    let mut target = source.to_vec();
+    loop {
+        let offset = reader.bytes(3).context("IPS patch is missing its EOF marker")?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(reader.bytes(2)?);
+        let (len, data) = if size == 0 {
+            // Run-length encoded record
+            let len = be(reader.bytes(2)?);
+            let value = reader.bytes(1)?[0];
+            (len, vec![value; len])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        target[offset..offset + len].copy_from_slice(&data);
+    }
+    // Some patchers append the size to truncate the target to
+    if let Ok(len) = reader.bytes(3) {
+        target.truncate(be(len));
+    }
+    Ok(target)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).context("patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads a BPS variable-length number.
+    fn varint(&mut self) -> Result<usize> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = (byte as usize & 0x7F).checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .context("BPS patch has a number that is too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).filter(|&shift| shift != 0)
+                .context("BPS patch has a number that is too large")?;
+            value = value.checked_add(shift).context("BPS patch has a number that is too large")?;
+        }
+    }
+
+    /// Reads a BPS signed offset and applies it to `offset`.
+    fn relative_offset(&mut self, offset: usize) -> Result<usize> {
+        let data = self.varint()?;
+        let delta = data >> 1;
+        let offset = if data & 1 == 0 { offset.checked_add(delta) } else { offset.checked_sub(delta) };
+        offset.context("BPS patch copies from outside the ROM")
+    }
+}
+
+/// Interprets bytes as a big-endian number, as IPS stores them.
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use temp_dir::TempDir;
+
+    use proptest::prelude::*;
+
+    use crate::rom::{Rom, DigestAlgo};
+    use super::*;
+
+    /// Writes a BPS patch from `source` to `target` using the given actions.
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        write_varint(source.len(), &mut patch);
+        write_varint(target.len(), &mut patch);
+        write_varint(0, &mut patch);
+        patch.extend_from_slice(actions);
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    /// `hello world` to `hello hello world!`, using every kind of action.
+    fn hello_patch() -> Vec<u8> {
+        let mut actions = Vec::new();
+        write_varint((6 - 1) << 2, &mut actions); // SourceRead "hello "
+        write_varint((6 - 1) << 2 | 3, &mut actions); // TargetCopy "hello " from the start of the target
+        write_varint(0, &mut actions);
+        write_varint((5 - 1) << 2 | 2, &mut actions); // SourceCopy "world"
+        write_varint(6 << 1, &mut actions);
+        write_varint(1, &mut actions); // TargetRead "!", a length of 1 leaves only the action bits
+        actions.push(b'!');
+        bps(b"hello world", b"hello hello world!", &actions)
+    }
+
+    #[test]
+    fn varints_round_trip() -> Result<()> {
+        for value in [0, 1, 127, 128, 129, 16511, 16512, 1 << 30] {
+            let mut bytes = Vec::new();
+            write_varint(value, &mut bytes);
+            assert_eq!(Reader { data: &bytes, pos: 0 }.varint()?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bps_patch_applies() -> Result<()> {
+        assert_eq!(apply_bps(b"hello world", &hello_patch())?, b"hello hello world!");
+        Ok(())
+    }
+
+    #[test]
+    fn rom_is_patched_to_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source.z64");
+        let patch = dir.path().join("hello.bps");
+        let out = dir.path().join("out.z64");
+        fs::write(&source, b"hello world")?;
+        fs::write(&patch, hello_patch())?;
+
+        let patched = Rom::from(source).apply_bps(&patch, &out)?;
+        assert_eq!(patched.path(), out.as_path());
+        assert_eq!(fs::read(&out)?, b"hello hello world!");
+        let target_crc = &hello_patch()[hello_patch().len() - 8..hello_patch().len() - 4];
+        assert_eq!(patched.digest(DigestAlgo::Crc32)?, format!("{:08x}", u32::from_le_bytes(target_crc.try_into()?)));
+        assert_eq!(patched.sha1_string()?, Rom::from(out).sha1_string()?);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_round_trips() -> Result<()> {
+        let source: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut changed = source.clone();
+        changed[10..13].copy_from_slice(b"abc");
+        changed[500] ^= 0xFF;
+        let mut longer = source.clone();
+        longer.extend(vec![0; 300]);
+        let shorter = source[..600].to_vec();
+        for target in [source.clone(), changed, longer, shorter, Vec::new()] {
+            let patch = diff_bps(&source, &target);
+            assert_eq!(apply_bps(&source, &patch)?, target);
+        }
+
+        // Padding is copied rather than stored
+        let padded = [source.clone(), vec![0xFF; 10_000]].concat();
+        assert!(diff_bps(&source, &padded).len() < 100);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_diffs_round_trip(
+            source in proptest::collection::vec(0..4u8, 0..200),
+            target in proptest::collection::vec(0..4u8, 0..200),
+        ) {
+            let patch = diff_bps(&source, &target);
+            prop_assert_eq!(apply_bps(&source, &patch).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn rom_diff_applies_to_source() -> Result<()> {
+        let dir = TempDir::new()?;
+        let [a, b, patch, out] = ["a.z64", "b.z64", "a-to-b.bps", "out.z64"].map(|name| dir.path().join(name));
+        fs::write(&a, b"hello world")?;
+        fs::write(&b, b"hello merlon, goodbye world")?;
+        let (a, b) = (Rom::from(a), Rom::from(b));
+
+        a.diff_bps(&b, &patch)?;
+        let patched = a.apply_bps(&patch, &out)?;
+        assert_eq!(patched.read_bytes()?, b.read_bytes()?);
+        assert_eq!(patched.sha1_string()?, b.sha1_string()?);
+        Ok(())
+    }
+
+    #[test]
+    fn bps_checksums_are_checked() {
+        let error = apply_bps(b"hello wordl", &hello_patch()).unwrap_err();
+        assert!(error.to_string().contains("different ROM"), "{}", error);
+
+        let mut corrupt = hello_patch();
+        corrupt[10] ^= 1;
+        let error = apply_bps(b"hello world", &corrupt).unwrap_err();
+        assert!(error.to_string().contains("corrupt"), "{}", error);
+
+        assert!(apply_bps(b"hello world", b"PATCHEOF").is_err());
+    }
+
+    #[test]
+    fn oversized_bps_target_is_rejected_before_allocating() {
+        let source = b"hello world";
+        let mut patch = BPS_MAGIC.to_vec();
+        write_varint(source.len(), &mut patch);
+        write_varint(usize::MAX / 2, &mut patch);
+        write_varint(0, &mut patch);
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(0u32.to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+
+        let error = apply_bps(source, &patch).unwrap_err();
+        assert!(error.to_string().contains("larger than any N64 ROM"), "{}", error);
+    }
+
+    #[test]
+    fn ips_patch_applies() -> Result<()> {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0, 0, 6, 0, 6]); // "merlon" at 6
+        patch.extend(b"merlon");
+        patch.extend([0, 0, 12, 0, 0, 0, 3, b'!']); // "!!!" at 12, past the end
+        patch.extend(IPS_EOF);
+        assert_eq!(apply_ips(b"hello world", &patch)?, b"hello merlon!!!");
+
+        patch.extend([0, 0, 13]);
+        assert_eq!(apply_ips(b"hello world", &patch)?, b"hello merlon!");
+
+        assert!(apply_ips(b"hello world", &patch[..patch.len() - 6]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Builds packages with stand-ins for the decomp's `configure` and `ninja`, so that neither the decomp nor its
+//! toolchain are needed. Run with `cargo test --features stub-build-tests`.
+#![cfg(all(unix, feature = "stub-build-tests"))]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use temp_dir::TempDir;
+use anyhow::Result;
+use merlon::package::{*, init::*, distribute::{ApplyOptions, ExportOptions, InstallOptions}};
+
+#[path = "rom.rs"]
+mod rom;
+
+/// Puts the stub `ninja` first on `PATH`.
+fn use_stub_ninja() -> Result<()> {
+    // Every test sets the same value, so it doesn't matter that tests run in parallel
+    let stub_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/build");
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![stub_dir];
+    paths.extend(std::env::split_paths(&path).filter(|path| !path.ends_with("tests/build")));
+    std::env::set_var("PATH", std::env::join_paths(paths)?);
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(dir)
+        .output()?;
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Creates a stand-in for the decomp repository, with scripts in place of `install.sh` and `configure`, and
+/// returns it with the commit that packages should be based on.
+fn stub_decomp(dir: &Path) -> Result<(PathBuf, String)> {
+    let repo = dir.join("decomp");
+    fs::create_dir_all(repo.join("ver/us"))?;
+    fs::write(repo.join("ver/us/.gitkeep"), "")?;
+    fs::write(repo.join(".gitignore"), "ver/us/baserom.z64\nver/us/build/\n")?;
+    for (name, script) in [("install.sh", "#!/bin/sh\n"), ("configure", "#!/bin/sh\nmkdir -p ver/us/build\n")] {
+        fs::write(repo.join(name), script)?;
+        fs::set_permissions(repo.join(name), fs::Permissions::from_mode(0o755))?;
+    }
+    git(&repo, &["init", "--quiet"])?;
+    git(&repo, &["symbolic-ref", "HEAD", "refs/heads/main"])?;
+    git(&repo, &["add", "."])?;
+    git(&repo, &["commit", "--quiet", "-m", "Stub decomp"])?;
+    let rev = git(&repo, &["rev-parse", "HEAD"])?;
+    Ok((repo, rev))
+}
+
+/// Exports a package based on the stub decomp, with a patch that adds `file` to it.
+fn export_stub_package(dir: &Path, decomp: &Path, rev: &str, file: &str) -> Result<Distributable> {
+    let package = Package::new(file, dir.join(file))?;
+    package.edit_manifest(|manifest| {
+        manifest.metadata_mut().set_decomp_remote(Some(decomp.to_string_lossy().into_owned()), Some("main".to_owned()));
+        manifest.upsert_decomp_dependency(rev.to_owned())
+    })?;
+
+    // Make the patch on a branch, so that the stub decomp's main branch stays as it was
+    git(decomp, &["checkout", "--quiet", "-b", file])?;
+    fs::write(decomp.join(file), "")?;
+    git(decomp, &["add", file])?;
+    git(decomp, &["commit", "--quiet", "-m", &format!("Add {}", file)])?;
+    let patch = git(decomp, &["format-patch", "--stdout", "-1"])?;
+    fs::write(package.path().join("patches/0001-add-file.patch"), patch + "\n")?;
+    git(decomp, &["checkout", "--quiet", "main"])?;
+
+    package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(dir.join(format!("{}.merlon", file))),
+        ..Default::default()
+    })
+}
+
+/// Creates a package whose `papermario/` is a stub with a `configure` script, and puts the stub `ninja` on `PATH`.
+fn stub_package(dir: &Path) -> Result<InitialisedPackage> {
+    let package = Package::new("Stubbed", dir.join("stubbed"))?;
+    let subrepo = package.path().join("papermario");
+    fs::create_dir_all(subrepo.join("ver/us"))?;
+    fs::create_dir_all(package.path().join(".merlon"))?;
+    assert!(Command::new("git").arg("init").arg("--quiet").current_dir(&subrepo).status()?.success());
+
+    let configure = subrepo.join("configure");
+    fs::write(&configure, "#!/bin/sh\nmkdir -p ver/us/build\n")?;
+    fs::set_permissions(&configure, fs::Permissions::from_mode(0o755))?;
+
+    use_stub_ninja()?;
+    InitialisedPackage::try_from(package)
+}
+
+#[test]
+fn build_copies_rom_to_output() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let initialised = stub_package(tempdir.path())?;
+    let output = tempdir.path().join("out.z64");
+    let rom = initialised.build_rom(BuildRomOptions {
+        output: Some(output.clone()),
+        ..Default::default()
+    })?;
+    assert_eq!(rom.path(), output.as_path());
+    assert_eq!(fs::read(&output)?, b"stub rom");
+    assert_eq!(rom.sha1_string()?.len(), 40);
+    Ok(())
+}
+
+#[test]
+fn failed_build_reports_exit_status() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let initialised = stub_package(tempdir.path())?;
+    fs::write(initialised.subrepo_path().join("fail"), "")?;
+    let error = initialised.build_rom(BuildRomOptions::default()).unwrap_err();
+    let message = format!("{:#}", error);
+    assert!(message.contains("failed to build"), "{}", message);
+    assert!(message.contains("exit status: 3"), "{}", message);
+    Ok(())
+}
+
+#[test]
+fn install_builds_rom_and_cleans_up() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    use_stub_ninja()?;
+    let (decomp, rev) = stub_decomp(tempdir.path())?;
+    let options = |name: &str| InstallOptions {
+        apply_options: ApplyOptions {
+            baserom: rom::baserom(),
+            build_rom_options: BuildRomOptions {
+                output: Some(tempdir.path().join(format!("{}.z64", name))),
+                ..Default::default()
+            },
+            verify_after: false,
+        },
+        work_dir: Some(tempdir.path().join(format!("{}-work", name))),
+        keep_intermediates: false,
+    };
+
+    let distributable = export_stub_package(tempdir.path(), &decomp, &rev, "hello")?;
+    let rom = distributable.install(options("hello"))?;
+    assert_eq!(fs::read(rom.path())?, b"stub rom");
+    assert!(!tempdir.path().join("hello-work").exists());
+
+    // The stub build fails when a patch adds `fail`, and the build directory is kept to look into
+    let distributable = export_stub_package(tempdir.path(), &decomp, &rev, "fail")?;
+    let error = distributable.install(options("fail")).unwrap_err();
+    assert!(format!("{:#}", error).contains("failed to build"), "{:#}", error);
+    assert!(tempdir.path().join("fail-work/papermario/fail").exists());
+    Ok(())
+}
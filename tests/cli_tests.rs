@@ -1,4 +1,13 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use anyhow::Result;
+use temp_dir::TempDir;
+use merlon::package::{Package, Distributable};
+
+#[path = "rom.rs"]
+mod rom;
 
 #[test]
 fn cli_tests() -> Result<()> {
@@ -7,3 +16,105 @@ fn cli_tests() -> Result<()> {
         .run();
     Ok(())
 }
+
+/// Reads every file under `dir`, keyed by its path relative to `dir`.
+fn read_tree(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pending.push(relative.join(entry.file_name()));
+            } else {
+                files.insert(relative.join(entry.file_name()), fs::read(entry.path())?);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn export_to_stdout_matches_file() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Piped", tempdir.path().join("piped"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let export = |output: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_merlon"))
+            .arg("export")
+            .arg("--output").arg(output)
+            .arg("--baserom").arg(rom::baserom())
+            .current_dir(package.path())
+            .output()
+    };
+
+    let piped = export(Path::new("-"))?;
+    let stderr = String::from_utf8_lossy(&piped.stderr);
+    assert!(piped.status.success(), "{}", stderr);
+    assert!(stderr.contains("Wrote distributable to stdout"), "{}", stderr);
+    let from_stdout = tempdir.path().join("from-stdout.merlon");
+    fs::write(&from_stdout, &piped.stdout)?;
+
+    let file = tempdir.path().join("piped.merlon");
+    let written = export(&file)?;
+    assert!(written.status.success(), "{}", String::from_utf8_lossy(&written.stderr));
+    assert!(!package.path().join("-").exists());
+
+    // Each export encrypts with a random salt, so compare what they decrypt to
+    assert_eq!(piped.stdout.len() as u64, fs::metadata(&file)?.len());
+    let contents = |path: PathBuf| {
+        Distributable::try_from(path)?.open_scoped(rom::baserom(), |package| read_tree(package.path()))
+    };
+    assert_eq!(contents(from_stdout)?, contents(file)?);
+    Ok(())
+}
+
+#[test]
+fn json_summary_reports_export_and_failure() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Summarised", tempdir.path().join("summarised"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let export = |baserom: PathBuf| {
+        Command::new(env!("CARGO_BIN_EXE_merlon"))
+            .args(["export", "--format", "json", "--force"])
+            .arg("--output").arg(tempdir.path().join("summarised.merlon"))
+            .arg("--baserom").arg(baserom)
+            .current_dir(package.path())
+            .output()
+    };
+
+    let exported = export(rom::baserom())?;
+    assert!(exported.status.success(), "{}", String::from_utf8_lossy(&exported.stderr));
+    let summary: serde_json::Value = serde_json::from_slice(&exported.stdout)?;
+    let metadata = package.manifest()?.metadata().clone();
+    assert_eq!(summary["name"], metadata.name().to_string());
+    assert_eq!(summary["version"], metadata.version().to_string());
+    assert!(Path::new(summary["path"].as_str().unwrap()).ends_with("summarised.merlon"), "{}", summary);
+    assert_eq!(summary["patches"], 1);
+    assert_eq!(summary["size"], fs::metadata(tempdir.path().join("summarised.merlon"))?.len());
+    assert!(summary["uncompressed_size"].as_u64().unwrap() > 0, "{}", summary);
+    assert_eq!(summary["payload_sha1"].as_str().map(str::len), Some(40), "{}", summary);
+
+    let failed = export(tempdir.path().join("missing.z64"))?;
+    assert!(!failed.status.success());
+    let error: serde_json::Value = serde_json::from_slice(&failed.stdout)?;
+    assert_eq!(error["kind"], "missing_baserom");
+    assert!(error["error"].as_str().unwrap().contains("baserom not found"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn bash_completions_list_subcommands() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let output = Command::new(env!("CARGO_BIN_EXE_merlon"))
+        .args(["completions", "bash"])
+        .current_dir(tempdir.path())
+        .output()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let script = String::from_utf8(output.stdout)?;
+    assert!(script.contains("_merlon()"), "{}", script);
+    for subcommand in ["export", "apply", "build", "migrate"] {
+        assert!(script.contains(subcommand), "missing {}: {}", subcommand, script);
+    }
+    Ok(())
+}
@@ -21,8 +21,9 @@ fn initialising_package_gives_decomp_dependency() -> Result<()> {
     assert_eq!(registry.all_dependencies()?.len(), 0);
     let package = registry.get_or_error(id)?;
     let _initialised = package.clone().to_initialised(InitialiseOptions {
-        baserom: rom::baserom(),
+        baserom: Some(rom::baserom()),
         rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
     })?;
     let all_dependencies = registry.all_dependencies()?;
     assert_eq!(all_dependencies.len(), 1);
@@ -68,8 +69,9 @@ fn sync_complex_dependency_graph_to_repo() -> Result<()> {
     // Initialise the root package and sync
     let root_package = registry.get_or_error(root)?.clone();
     let mut initialised = root_package.clone().to_initialised(InitialiseOptions {
-        baserom: rom::baserom(),
+        baserom: Some(rom::baserom()),
         rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
     })?;
     initialised.set_registry(registry); // XXX
     initialised.setup_git_branches()?;
@@ -130,8 +132,9 @@ fn single_dependency() -> Result<()> {
     // Root package with no commits
     let root = Package::new("Root", tempdir.path().join("root"))?;
     let mut root = root.to_initialised(InitialiseOptions {
-        baserom: rom::baserom(),
+        baserom: Some(rom::baserom()),
         rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
     })?;
 
     // Dependency package with single commit
@@ -142,6 +145,7 @@ fn single_dependency() -> Result<()> {
     // Add dependency, sync repo, check skip intro commit was added
     root.add_dependency(AddDependencyOptions {
         path: dependency.path().to_path_buf(),
+        version: None,
     })?;
     root.setup_git_branches()?;
     let output = Command::new("git")
@@ -157,6 +161,7 @@ fn single_dependency() -> Result<()> {
     let distributable = root.package().export_distributable(ExportOptions {
         baserom: Some(rom::baserom()),
         output: Some(tempdir.path().join("output.merlon")),
+        ..Default::default()
     })?;
     distributable.open_scoped(rom::baserom(), |package| {
         let manifest = package.manifest()?;
@@ -201,8 +206,9 @@ fn initialised_patches_maintained() -> Result<()> {
 
     // Initialise package
     let initialised = package.to_initialised(InitialiseOptions {
-        baserom: rom::baserom(),
+        baserom: Some(rom::baserom()),
         rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
     })?;
     assert!(patch_path.is_file());
 
@@ -0,0 +1,666 @@
+use std::fs;
+use temp_dir::TempDir;
+use anyhow::Result;
+use merlon::package::{*, init::*, distribute::{self, Compression, ExportError, ExportOptions}};
+use merlon::package::manifest::{PatchFormat, keywords::KeywordConfig};
+
+/// Pinned decomp commit hash so that tests don't break when decomp updates
+const DECOMP_REV: &str = "7a9df943ad079e7b19df0f8690bdc92e2beed964";
+
+#[path = "rom.rs"]
+mod rom;
+
+#[test]
+fn extra_files_are_bundled() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Extra", tempdir.path().join("extra"))?;
+    fs::create_dir(package.path().join("docs"))?;
+    fs::write(package.path().join("docs/INSTALL.md"), "# Installing")?;
+    package.edit_manifest(|manifest| {
+        manifest.metadata_mut().set_extra_files(vec!["docs/INSTALL.md".into(), "MISSING.md".into()]);
+        Ok(())
+    })?;
+
+    // The missing file only warns
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("extra.merlon")),
+        ..Default::default()
+    })?;
+    let summary = distributable.export_summary().expect("exported distributable should have a summary");
+    assert!(summary.files.contains(&"docs/INSTALL.md".to_owned()));
+    assert_eq!(summary.size, fs::metadata(distributable.path())?.len());
+    distributable.open_scoped(rom::baserom(), |package| {
+        assert_eq!(fs::read_to_string(package.path().join("docs/INSTALL.md"))?, "# Installing");
+        assert!(!package.path().join("MISSING.md").exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn authors_file_is_merged_into_exported_manifest() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let author = Some("Jane Doe <jane@example.com>".to_owned());
+    let package = Package::new_with_author("Credited", tempdir.path().join("credited"), author)?;
+    fs::write(package.path().join("AUTHORS"), "John Doe <john@example.com>\n")?;
+    let toml = fs::read_to_string(package.manifest_path())?;
+    fs::write(package.manifest_path(), toml.replacen("[package]\n", "[package]\nauthors_file = \"AUTHORS\"\n", 1))?;
+    let package = Package::try_from(package.path().to_owned())?;
+    let authors = ["Jane Doe <jane@example.com>", "John Doe <john@example.com>"];
+    assert_eq!(package.manifest()?.metadata().authors(), authors);
+
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("credited.merlon")),
+        ..Default::default()
+    })?;
+    distributable.open_scoped(rom::baserom(), |opened| {
+        let manifest = opened.manifest()?;
+        assert_eq!(manifest.metadata().authors(), authors);
+        let errors = manifest.metadata().validate_with_keywords(&KeywordConfig::builtin());
+        assert!(errors.is_empty(), "{:?}", errors);
+        Ok(())
+    })?;
+
+    // The package's own manifest still uses the authors file
+    assert!(fs::read_to_string(package.manifest_path())?.contains("authors_file"));
+    Ok(())
+}
+
+#[test]
+fn export_without_commits_errors_early() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Empty", tempdir.path().join("empty"))?;
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+
+    let output = tempdir.path().join("empty.merlon");
+    let error = initialised.package().export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(error.to_string().contains("package has no commits"));
+    assert!(matches!(ExportError::of(&error), Some(ExportError::NoCommits { .. })), "{:?}", error);
+    assert!(!output.exists());
+    Ok(())
+}
+
+#[test]
+fn export_without_license_is_missing_metadata() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Unlicensed", tempdir.path().join("unlicensed"))?;
+    fs::remove_file(package.path().join("LICENSE"))?;
+
+    let output = tempdir.path().join("unlicensed.merlon");
+    let error = package.export_distributable(ExportOptions {
+        baserom: Some(tempdir.path().join("missing.z64")),
+        output: Some(output.clone()),
+        ..Default::default()
+    }).unwrap_err();
+    assert_eq!(ExportError::of(&error), Some(&ExportError::MissingMetadata("LICENSE")));
+    assert!(!output.exists());
+
+    // Other failures are still distinguishable once the metadata is there
+    fs::write(package.path().join("LICENSE"), "All rights reserved\n")?;
+    let error = package.export_distributable(ExportOptions {
+        baserom: Some(tempdir.path().join("missing.z64")),
+        output: Some(output),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(matches!(ExportError::of(&error), Some(ExportError::MissingBaserom { .. })), "{:?}", error);
+    Ok(())
+}
+
+#[test]
+fn missing_baserom_errors_before_patches_are_generated() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("No ROM", tempdir.path().join("no-rom"))?;
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+    let subrepo = initialised.subrepo_path();
+    fs::write(subrepo.join("new_file.txt"), "hello\n")?;
+    for args in [&["add", "new_file.txt"][..], &["commit", "--quiet", "-m", "Add new file"]] {
+        assert!(std::process::Command::new("git").args(args).current_dir(&subrepo).status()?.success());
+    }
+
+    let missing = tempdir.path().join("missing.z64");
+    let options = ExportOptions {
+        baserom: Some(missing.clone()),
+        output: Some(tempdir.path().join("no-rom.merlon")),
+        patch_format: Some(PatchFormat::Diff),
+        ..Default::default()
+    };
+    let error = initialised.package().export_baserom(&options).unwrap_err();
+    assert!(error.to_string().contains(&missing.display().to_string()), "{}", error);
+    let error = initialised.package().export_distributable(options).unwrap_err();
+    assert!(error.to_string().contains("baserom not found"), "{}", error);
+
+    // Changing the patch format would regenerate the patches, which must not have happened
+    assert_eq!(fs::read_dir(initialised.package().path().join("patches"))?.count(), 0);
+    assert_eq!(initialised.package().manifest()?.metadata().patch_format(), PatchFormat::GitAm);
+    Ok(())
+}
+
+#[test]
+fn verify_rejects_patches_that_do_not_apply() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Broken", tempdir.path().join("broken"))?;
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+    let subrepo = initialised.subrepo_path();
+    fs::write(subrepo.join("new_file.txt"), "hello\n")?;
+    for args in [&["add", "new_file.txt"][..], &["commit", "--quiet", "-m", "Add new file"]] {
+        assert!(std::process::Command::new("git").args(args).current_dir(&subrepo).status()?.success());
+    }
+
+    // A patch that changes a line the decomp doesn't have
+    fs::write(initialised.package().path().join("patches/0001-broken.patch"), "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: Test <test@example.com>
+Date: Thu, 1 Jan 1970 00:00:00 +0000
+Subject: [PATCH] Broken
+
+---
+ README.md | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -1,1 +1,1 @@
+-This line is not in the decomp
++Neither is this one
+--
+2.40.0
+")?;
+
+    let output = tempdir.path().join("broken.merlon");
+    let options = ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        ..Default::default()
+    };
+    let error = initialised.package().export_distributable(ExportOptions { verify: true, ..options.clone() }).unwrap_err();
+    assert!(format!("{:#}", error).contains("do not apply cleanly"), "{:#}", error);
+    assert!(!output.exists());
+
+    // Without --verify, the broken patch is only found when the distributable is applied
+    initialised.package().export_distributable(options)?;
+    assert!(output.exists());
+    Ok(())
+}
+
+#[test]
+fn oversized_patch_is_caught_and_listed() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Huge", tempdir.path().join("huge"))?;
+    fs::write(package.path().join("patches/0001-small.patch"), "diff --git a/x b/x\n")?;
+
+    // Pseudo-random text doesn't compress much, like the binary patch of an accidentally committed file would
+    let mut state: u32 = 1;
+    let mut patch = String::from("Subject: [PATCH] Add a big file\n\ndiff --git a/big b/big\n");
+    for _ in 0..256 * 1024 {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        patch.push((b'!' + (state >> 16) as u8 % 90) as char);
+    }
+    fs::write(package.path().join("patches/0002-big.patch"), patch)?;
+
+    let output = tempdir.path().join("huge.merlon");
+    let options = ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        max_size: Some(64 * 1024),
+        ..Default::default()
+    };
+    let plan = package.plan_export(&options)?;
+    assert_eq!(plan.largest_patches[0].path, "patches/0002-big.patch");
+    assert_eq!(plan.largest_patches[0].subject.as_deref(), Some("Add a big file"));
+    assert_eq!(plan.largest_patches.len(), 2);
+
+    let error = package.export_distributable(options).unwrap_err();
+    assert!(matches!(ExportError::of(&error), Some(ExportError::TooLarge { max_size: 65536, .. })), "{:?}", error);
+    assert!(!output.exists());
+    Ok(())
+}
+
+#[test]
+fn allow_empty_exports_metadata_only_package() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Meta", tempdir.path().join("meta"))?;
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+
+    let distributable = initialised.package().export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("meta.merlon")),
+        allow_empty: true,
+        ..Default::default()
+    })?;
+    assert!(distributable.header()?.expect("distributable should have a header").patch_empty);
+    Ok(())
+}
+
+#[test]
+fn plan_export_writes_nothing() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Dry run", tempdir.path().join("dry-run"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+
+    let output = tempdir.path().join("dry-run.merlon");
+    let plan = package.plan_export(&ExportOptions {
+        output: Some(output.clone()),
+        ..Default::default()
+    })?;
+    assert_eq!(plan.path, output);
+    assert_eq!(plan.patches, ["patches/0001-x.patch"]);
+    assert!(plan.files.contains(&"merlon.toml".to_owned()));
+    assert!(plan.uncompressed_size > 0);
+
+    assert!(!output.exists());
+    assert!(!package.path().join(".merlon/export").exists());
+    for entry in fs::read_dir(tempdir.path())? {
+        assert_ne!(entry?.path().extension(), Some("merlon".as_ref()));
+    }
+    Ok(())
+}
+
+#[test]
+fn plain_export_is_readable_tar() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Plain", tempdir.path().join("plain"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+
+    let plan = package.plan_export(&ExportOptions { plain: true, ..Default::default() })?;
+    assert_eq!(plan.path.file_name(), Some("Plain 0.1.0.patches.tar".as_ref()));
+
+    let path = package.export_plain(&ExportOptions {
+        output: Some(tempdir.path().join("plain.patches.tar")),
+        ..Default::default()
+    })?;
+    let mut archive = tar::Archive::new(fs::File::open(&path)?);
+    let mut patches = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.starts_with("merlon_v1/patches") && entry.header().entry_type().is_file() {
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut contents)?;
+            patches.push((entry.path()?.display().to_string(), contents));
+        }
+    }
+    assert_eq!(patches, [("merlon_v1/patches/0001-x.patch".to_owned(), "diff --git a/x b/x\n".to_owned())]);
+
+    let error = package.export_plain(&ExportOptions {
+        output: Some(tempdir.path().join("plain.merlon")),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(error.to_string().contains(".merlon extension"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn export_is_identical_with_any_number_of_jobs() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Jobs", tempdir.path().join("jobs"))?;
+    for i in 0..300 {
+        let patch: String = (0..i).map(|line| format!("+line {}\r\n", line)).collect();
+        fs::write(package.path().join(format!("patches/{:04}-change.patch", i)), format!("diff --git a/{i} b/{i}\n{patch}"))?;
+    }
+
+    // The payload is encrypted with a random salt, so compare the compressed archives kept as intermediates
+    let mut archives = Vec::new();
+    for jobs in [1, 4] {
+        let target_dir = tempdir.path().join(format!("target-{}", jobs));
+        package.export_distributable(ExportOptions {
+            baserom: Some(rom::baserom()),
+            output: Some(tempdir.path().join(format!("jobs-{}.merlon", jobs))),
+            target_dir: Some(target_dir.clone()),
+            keep_intermediates: true,
+            jobs: Some(jobs),
+            ..Default::default()
+        })?;
+        archives.push(fs::read(target_dir.join("export/package.tar.bz2"))?);
+    }
+    assert!(archives[0] == archives[1], "archives differ between --jobs 1 and --jobs 4");
+
+    assert!(package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("jobs-0.merlon")),
+        jobs: Some(0),
+        ..Default::default()
+    }).is_err());
+    Ok(())
+}
+
+#[test]
+fn dependency_is_bundled_and_extracted() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let packages_dir = tempdir.path().join("packages");
+    fs::create_dir(&packages_dir)?;
+
+    let dependency = Package::new("Dependency", tempdir.path().join("dependency"))?;
+    fs::write(dependency.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    dependency.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(packages_dir.join("dependency.merlon")),
+        ..Default::default()
+    })?;
+
+    let root = Package::new("Root", tempdir.path().join("root"))?;
+    fs::write(root.path().join("patches/0001-y.patch"), "diff --git a/y b/y\n")?;
+    let declared = manifest::Dependency::try_from(&dependency)?;
+    root.edit_manifest(|manifest| manifest.declare_direct_dependency(declared))?;
+
+    let distributable = root.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("root.merlon")),
+        bundle_deps: Some(packages_dir.clone()),
+        ..Default::default()
+    })?;
+    let header = distributable.header()?.expect("distributable should have a header");
+    assert_eq!(header.bundled_dependencies, vec![dependency.manifest()?.metadata().versioned_id()]);
+
+    distributable.open_scoped(rom::baserom(), |package| {
+        let bundled = package.path().join("deps").join(format!("{}.merlon", dependency.id()?));
+        let bundled = distribute::Distributable::try_from(bundled)?;
+        assert_eq!(bundled.header()?.and_then(|header| header.package_id), Some(dependency.id()?));
+        Ok(())
+    })?;
+
+    // A dependency that is not in the packages directory cannot be bundled
+    fs::remove_file(packages_dir.join("dependency.merlon"))?;
+    let error = root.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("root-unbundled.merlon")),
+        bundle_deps: Some(packages_dir),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(error.to_string().contains("cannot bundle dependency"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn fresh_distributable_verifies() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Verified", tempdir.path().join("verified"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("verified.merlon")),
+        ..Default::default()
+    })?;
+
+    let report = distributable.verify(distribute::VerifyOptions { baserom: rom::baserom(), repo: None, check_signature: false })?;
+    assert!(report.passed(), "{:?}", report);
+    assert!(report.checks.iter().any(|check| check.name == "payload checksum"
+        && check.outcome == distribute::verify::Outcome::Passed));
+    Ok(())
+}
+
+#[test]
+fn nonstandard_kdf_parameters_are_recorded_and_decrypt() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Stronger", tempdir.path().join("stronger"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("stronger.merlon")),
+        kdf_iter: Some(123_456),
+        kdf_digest: Some("sha256".to_owned()),
+        ..Default::default()
+    })?;
+
+    let header = distributable.header()?.expect("distributable should have a header");
+    assert_eq!(header.kdf, distribute::header::DEFAULT_KDF);
+    assert_eq!(header.kdf_iterations, 123_456);
+    assert_eq!(header.kdf_digest, "sha256");
+    distributable.open_scoped(rom::baserom(), |package| {
+        assert_eq!(fs::read_to_string(package.path().join("patches/0001-x.patch"))?, "diff --git a/x b/x\n");
+        Ok(())
+    })
+}
+
+#[test]
+fn tampered_distributable_fails_verification() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Tampered", tempdir.path().join("tampered"))?;
+    let path = tempdir.path().join("tampered.merlon");
+    package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(path.clone()),
+        allow_empty: true,
+        ..Default::default()
+    })?;
+    let mut bytes = fs::read(&path)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    fs::write(&path, bytes)?;
+
+    let report = Distributable::try_from(path)?
+        .verify(distribute::VerifyOptions { baserom: rom::baserom(), repo: None, check_signature: false })?;
+    assert!(!report.passed());
+    assert!(report.checks.iter().any(|check| check.name == "payload checksum"
+        && matches!(check.outcome, distribute::verify::Outcome::Failed(_))));
+    Ok(())
+}
+
+#[test]
+fn signed_distributable_verifies() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Signed", tempdir.path().join("signed"))?;
+    let key = tempdir.path().join("key");
+    fs::write(&key, "01".repeat(32))?;
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("signed.merlon")),
+        allow_empty: true,
+        sign: Some(key),
+        ..Default::default()
+    })?;
+    assert!(distributable.header()?.and_then(|header| header.signature).is_some());
+
+    let options = distribute::VerifyOptions { baserom: rom::baserom(), repo: None, check_signature: true };
+    let report = distributable.verify(options.clone())?;
+    assert!(report.passed(), "{:?}", report);
+
+    // Requiring a signature fails for unsigned distributables
+    let unsigned = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("unsigned.merlon")),
+        allow_empty: true,
+        ..Default::default()
+    })?;
+    let report = unsigned.verify(options)?;
+    assert!(report.checks.iter().any(|check| check.name == "signature"
+        && matches!(check.outcome, distribute::verify::Outcome::Failed(_))));
+    Ok(())
+}
+
+#[test]
+fn diff_lists_patch_added_by_new_release() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Diffed", tempdir.path().join("diffed"))?;
+    fs::write(package.path().join("patches/0001-first.patch"), "diff --git a/src/first.c b/src/first.c\n")?;
+    let old = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("old.merlon")),
+        ..Default::default()
+    })?;
+
+    // One more commit, exported with a different compression
+    fs::write(package.path().join("patches/0002-second.patch"), "diff --git a/src/second.c b/src/second.c\n")?;
+    let new = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("new.merlon")),
+        compression: Some(Compression::Zstd),
+        ..Default::default()
+    })?;
+
+    let diff = old.diff(&new, rom::baserom())?;
+    assert_eq!(diff.added, ["0002-second.patch"]);
+    assert!(diff.removed.is_empty() && diff.changed.is_empty(), "{:?}", diff);
+    assert!(diff.unified.contains("+diff --git a/src/second.c b/src/second.c"), "{}", diff.unified);
+    assert!(!diff.unified.contains("src/first.c"), "{}", diff.unified);
+    Ok(())
+}
+
+#[test]
+fn list_shows_patch_subjects() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Listed", tempdir.path().join("listed"))?;
+    let patch = |subject: &str| format!(
+        "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n\
+        From: Jane Doe <jane@example.com>\n\
+        Subject: [PATCH] {}\n\
+        \n\
+        ---\n\
+        diff --git a/src/main.c b/src/main.c\n",
+        subject,
+    );
+    fs::write(package.path().join("patches/0001-skip-logos.patch"), patch("Skip the developer logos"))?;
+    fs::write(package.path().join("patches/0002-faster-text.patch"), patch("Make text faster"))?;
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("listed.merlon")),
+        ..Default::default()
+    })?;
+
+    let listing = distributable.list(rom::baserom())?;
+    let subjects: Vec<_> = listing.patches.iter().map(|patch| patch.subject.as_deref()).collect();
+    assert_eq!(subjects, [Some("Skip the developer logos"), Some("Make text faster")]);
+    assert_eq!(listing.patches[0].path, "patches/0001-skip-logos.patch");
+    assert!(listing.files.contains(&"merlon.toml".to_owned()), "{:?}", listing.files);
+    assert!(listing.files.contains(&"README.md".to_owned()), "{:?}", listing.files);
+    let total: u64 = listing.patches.iter().map(|patch| patch.size).sum();
+    assert!(listing.uncompressed_size > total);
+    assert_eq!(listing.manifest.metadata().name().to_string(), "Listed");
+    Ok(())
+}
+
+#[test]
+fn export_refuses_to_overwrite_without_force() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Overwrite", tempdir.path().join("overwrite"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let output = tempdir.path().join("overwrite.merlon");
+    fs::write(&output, "published")?;
+
+    let options = ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        ..Default::default()
+    };
+    let error = package.export_distributable(options.clone()).unwrap_err();
+    assert!(error.to_string().contains("already exists"), "{}", error);
+    assert_eq!(fs::read_to_string(&output)?, "published");
+    let plain = tempdir.path().join("overwrite.patches.tar");
+    fs::write(&plain, "published")?;
+    assert!(package.export_plain(&ExportOptions { output: Some(plain.clone()), ..Default::default() }).is_err());
+    assert_eq!(fs::read_to_string(&plain)?, "published");
+
+    let distributable = package.export_distributable(ExportOptions { force: true, ..options })?;
+    assert!(distributable.header()?.is_some());
+    package.export_plain(&ExportOptions { output: Some(plain.clone()), force: true, ..Default::default() })?;
+    assert_ne!(fs::read_to_string(&plain)?, "published");
+    Ok(())
+}
+
+#[test]
+fn strict_export_requires_merlon_extension() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Strict", tempdir.path().join("strict"))?;
+    fs::write(package.path().join("patches/0001-x.patch"), "diff --git a/x b/x\n")?;
+    let output = tempdir.path().join("strict.bin");
+
+    let error = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        strict: true,
+        ..Default::default()
+    }).unwrap_err();
+    assert!(error.to_string().contains(".merlon extension"), "{}", error);
+    assert!(!output.exists());
+
+    // Without --strict, it is only a warning
+    let distributable = package.export_distributable(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(output.clone()),
+        ..Default::default()
+    })?;
+    assert_eq!(distributable.path(), output);
+    Ok(())
+}
+
+#[test]
+fn export_reports_progress_for_each_phase() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+    use merlon::package::progress::{Phase, Progress};
+
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Progress", tempdir.path().join("progress"))?;
+    let mut initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+    let subrepo = initialised.subrepo_path();
+    fs::write(subrepo.join("new_file.txt"), "hello\n")?;
+    for args in [&["add", "new_file.txt"][..], &["commit", "--quiet", "-m", "Add new file"]] {
+        assert!(std::process::Command::new("git").args(args).current_dir(&subrepo).status()?.success());
+    }
+
+    let updates = Arc::new(Mutex::new(Vec::new()));
+    let progress = Progress::new({
+        let updates = Arc::clone(&updates);
+        move |update| updates.lock().unwrap().push(update)
+    });
+    initialised.set_progress(progress.clone());
+    initialised.update_patches_dir()?;
+    initialised.package().export_distributable_with_progress(ExportOptions {
+        baserom: Some(rom::baserom()),
+        output: Some(tempdir.path().join("progress.merlon")),
+        ..Default::default()
+    }, &progress)?;
+
+    let updates = updates.lock().unwrap();
+    for phase in [Phase::EnumeratingCommits, Phase::WritingPatches, Phase::Compressing, Phase::Encrypting] {
+        assert!(
+            updates.iter().any(|update| update.phase == phase && update.total == Some(update.done)),
+            "{} was not reported as finished: {:?}", phase, updates,
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn missing_decomp_rev_errors_before_patches_are_regenerated() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let package = Package::new("Lost Base", tempdir.path().join("lost-base"))?;
+    let initialised = package.to_initialised(InitialiseOptions {
+        baserom: Some(rom::baserom()),
+        rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
+    })?;
+    let existing_patch = initialised.package().path().join("patches/0001-existing.patch");
+    fs::write(&existing_patch, "diff --git a/x b/x\n")?;
+
+    let missing = "0123456789abcdef0123456789abcdef01234567";
+    initialised.package().edit_manifest(|manifest| manifest.upsert_decomp_dependency(missing.to_owned()))?;
+    let error = initialised.update_patches_dir().unwrap_err();
+    assert!(error.to_string().contains(&format!("decomp revision {} not found", missing)), "{}", error);
+    assert!(error.to_string().contains("fetch"), "{}", error);
+    assert!(existing_patch.is_file(), "patches should not have been removed");
+    Ok(())
+}
@@ -15,8 +15,9 @@ fn new_init_build() -> Result<()> {
     let pkg_path = tempdir.path().join("test");
     let package = Package::new("Test", pkg_path)?;
     let initialised = package.to_initialised(InitialiseOptions {
-        baserom: rom::baserom(),
+        baserom: Some(rom::baserom()),
         rev: Some(DECOMP_REV.to_string()),
+        from_shortlog: false,
     })?;
     let rom = initialised.build_rom(BuildRomOptions::default())?;
     assert_eq!(rom.sha1_string()?, "e1f9c77fa35549897ace8b8627e821a27309d538");